@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+
+use crate::parser::Type;
+use crate::{Diagnostic, Node, Warning};
+
+// Forward dataflow over a function body tracking pointer variables that are
+// known to hold the literal 0. A dereference of such a variable on a path that
+// is always executed is reported; dereferences inside branches and loop bodies
+// are not, but assignments there still kill the fact at the join point.
+pub fn null_deref_warnings(nodes: &[Node]) -> Vec<Diagnostic> {
+    let mut analysis = NullDeref {
+        warnings: Vec::new(),
+    };
+    let mut state = HashSet::new();
+    for node in nodes {
+        if !analysis.stmt(node, &mut state, true) {
+            break;
+        }
+    }
+    analysis.warnings
+}
+
+type NullSet = HashSet<String>;
+
+struct NullDeref {
+    warnings: Vec<Diagnostic>,
+}
+
+impl NullDeref {
+    // Returns false when control never falls through `node`.
+    fn stmt(&mut self, node: &Node, state: &mut NullSet, report: bool) -> bool {
+        match node {
+            Node::Return { lhs, .. } => {
+                if let Some(lhs) = lhs {
+                    self.expr(lhs, state, report);
+                }
+                false
+            }
+            Node::ExprStmt { expr, .. } => {
+                self.expr(expr, state, report);
+                true
+            }
+            Node::Block { nodes, .. } => nodes.iter().all(|node| self.stmt(node, state, report)),
+            // Inline assembly may write anything.
+            Node::Asm { .. } => {
+                state.clear();
+                true
+            }
+            Node::If {
+                cond, then, els, ..
+            } => {
+                self.expr(cond, state, report);
+                let mut then_state = state.clone();
+                let then_falls = match then {
+                    Some(then) => self.stmt(then, &mut then_state, false),
+                    None => true,
+                };
+                let mut els_state = state.clone();
+                let els_falls = match els {
+                    Some(els) => self.stmt(els, &mut els_state, false),
+                    None => true,
+                };
+                // Join: only paths that reach the end of the `if` contribute.
+                *state = match (then_falls, els_falls) {
+                    (true, true) => then_state.intersection(&els_state).cloned().collect(),
+                    (true, false) => then_state,
+                    (false, true) => els_state,
+                    (false, false) => return false,
+                };
+                true
+            }
+            Node::For {
+                init,
+                cond,
+                inc,
+                then,
+                ..
+            } => {
+                if let Some(init) = init {
+                    if !self.stmt(init, state, report) {
+                        return false;
+                    }
+                }
+                // The first evaluation of the condition always happens.
+                if let Some(cond) = cond {
+                    self.expr(cond, state, report);
+                }
+                let mut killed = NullSet::new();
+                for node in [cond, inc, then].into_iter().flatten() {
+                    Self::assigned_vars(node, &mut killed);
+                }
+                state.retain(|name| !killed.contains(name));
+                true
+            }
+            _ => {
+                self.expr(node, state, report);
+                true
+            }
+        }
+    }
+
+    fn expr(&mut self, node: &Node, state: &mut NullSet, report: bool) {
+        match node {
+            Node::Assign { lhs, rhs, .. } => {
+                self.expr(rhs, state, report);
+                if let Node::Var { name, r#type, .. } = lhs.as_ref() {
+                    if matches!(r#type, Type::Ptr { .. })
+                        && matches!(rhs.as_ref(), Node::Num { val: 0, .. })
+                    {
+                        state.insert(name.clone());
+                    } else {
+                        state.remove(name);
+                    }
+                } else {
+                    self.expr(lhs, state, report);
+                }
+            }
+            Node::Deref { lhs, span, .. } => {
+                if let Node::Var { name, .. } = lhs.as_ref() {
+                    if report && state.contains(name) {
+                        self.warnings.push(Diagnostic::warning(
+                            Warning::NullDereference,
+                            span.clone(),
+                            format!("dereference of null pointer '{}'", name),
+                        ));
+                    }
+                }
+                self.expr(lhs, state, report);
+            }
+            Node::Addr { lhs, .. } => {
+                // Once its address is taken the variable may change behind our back.
+                let mut escaped = NullSet::new();
+                Self::vars(lhs, &mut escaped);
+                state.retain(|name| !escaped.contains(name));
+                self.expr(lhs, state, report);
+            }
+            Node::Add { lhs, rhs, .. }
+            | Node::Sub { lhs, rhs, .. }
+            | Node::Mul { lhs, rhs, .. }
+            | Node::Div { lhs, rhs, .. }
+            | Node::Eq { lhs, rhs, .. }
+            | Node::Ne { lhs, rhs, .. }
+            | Node::Lt { lhs, rhs, .. }
+            | Node::Le { lhs, rhs, .. }
+            | Node::Exchange { lhs, rhs, .. }
+            | Node::FetchAdd { lhs, rhs, .. } => {
+                self.expr(lhs, state, report);
+                self.expr(rhs, state, report);
+            }
+            Node::CompareSwap { lhs, old, new, .. } => {
+                self.expr(lhs, state, report);
+                self.expr(old, state, report);
+                self.expr(new, state, report);
+            }
+            Node::Neg { lhs, .. } => self.expr(lhs, state, report),
+            Node::FuncCall { args, .. } => {
+                for arg in args {
+                    self.expr(arg, state, report);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Variables that may be written anywhere inside `node`.
+    fn assigned_vars(node: &Node, rv: &mut NullSet) {
+        match node {
+            Node::Assign { lhs, .. } => Self::vars(lhs, rv),
+            Node::Addr { lhs, .. } => Self::vars(lhs, rv),
+            _ => {}
+        }
+        for child in node.children() {
+            Self::assigned_vars(child, rv);
+        }
+    }
+
+    fn vars(node: &Node, rv: &mut NullSet) {
+        if let Node::Var { name, .. } = node {
+            rv.insert(name.clone());
+        }
+        for child in node.children() {
+            Self::vars(child, rv);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn warnings(src: &str) -> Vec<String> {
+        let (_, program) = crate::parse(src);
+        null_deref_warnings(&program.nodes)
+            .into_iter()
+            .map(|warning| warning.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_null_deref() {
+        assert_eq!(
+            warnings("{ int *p=0; return *p; }"),
+            vec!["dereference of null pointer 'p'"]
+        );
+        assert_eq!(
+            warnings("{ int x=1; int *p; p=0; *p=3; return x; }"),
+            vec!["dereference of null pointer 'p'"]
+        );
+    }
+
+    #[test]
+    fn test_null_deref_reassigned() {
+        assert!(warnings("{ int x=1; int *p=0; p=&x; return *p; }").is_empty());
+        assert!(warnings("{ int x=1; int *p=0; if (x) p=&x; return *p; }").is_empty());
+        assert!(warnings("{ int x=1; int *p=0; while (x) p=&x; return *p; }").is_empty());
+        assert!(warnings("{ int *p=0; int **q=&p; *q=0; return 0; }").is_empty());
+    }
+
+    #[test]
+    fn test_null_deref_conditional() {
+        // Only unconditional dereferences are reported.
+        assert!(warnings("{ int x=0; int *p=0; if (x) return *p; return 0; }").is_empty());
+        // Both branches keep p null, so the join still knows it.
+        assert_eq!(
+            warnings("{ int x=0; int *p=0; if (x) x=1; else x=2; return *p; }"),
+            vec!["dereference of null pointer 'p'"]
+        );
+    }
+}
@@ -0,0 +1,633 @@
+use crate::MyError;
+use object::write::{Object, Relocation, StandardSection, Symbol, SymbolSection};
+use object::{
+    elf, Architecture, BinaryFormat, Endianness, RelocationEncoding, RelocationFlags,
+    RelocationKind, SectionKind, SymbolFlags, SymbolKind, SymbolScope,
+};
+use std::collections::{HashMap, HashSet};
+
+// An assembler for the x86-64 assembly the native backend emits, writing an
+// ELF object without a system `as`. It knows the instructions the backend
+// uses rather than the whole instruction set, so inline assembly using
+// anything else is an error. CFI and line directives and the .debug_ sections
+// are dropped: the object has no unwind tables or debug info.
+
+const REGS64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const REGS8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
+// Mnemonics that take an AT&T size suffix.
+const SIZED: [&str; 16] = [
+    "mov", "add", "sub", "cmp", "and", "or", "xor", "imul", "neg", "idiv", "shl", "shr", "sar",
+    "xchg", "xadd", "cmpxchg",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Arg {
+    Reg(u8, u8), // number, then size in bytes
+    Imm(i64),
+    Mem {
+        base: Option<u8>, // None for an absolute address
+        disp: i32,
+        fs: bool, // relative to the %fs segment
+    },
+    Sym(String),
+}
+
+impl Arg {
+    fn parse(text: &str) -> Result<Arg, MyError> {
+        let bad = || MyError {
+            info: format!("unsupported operand: {}", text),
+        };
+        if let Some(imm) = text.strip_prefix('$') {
+            return imm.parse().map(Arg::Imm).map_err(|_| bad());
+        }
+        if let Some(disp) = text.strip_prefix("%fs:") {
+            let disp = disp.parse().map_err(|_| bad())?;
+            return Ok(Arg::Mem {
+                base: None,
+                disp,
+                fs: true,
+            });
+        }
+        if let Some(name) = text.strip_prefix('%') {
+            return Self::reg(name).ok_or_else(bad);
+        }
+        if let Some((disp, base)) = text.split_once('(') {
+            let base = base
+                .strip_suffix(')')
+                .and_then(|base| base.strip_prefix('%'))
+                .and_then(Self::reg);
+            let Some(Arg::Reg(base, 8)) = base else {
+                return Err(bad());
+            };
+            let disp = if disp.is_empty() {
+                0
+            } else {
+                disp.parse().map_err(|_| bad())?
+            };
+            return Ok(Arg::Mem {
+                base: Some(base),
+                disp,
+                fs: false,
+            });
+        }
+        // a call through the PLT is what a plain call to a symbol is anyway
+        let name = text.strip_suffix("@PLT").unwrap_or(text);
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == ',') {
+            return Err(bad());
+        }
+        Ok(Arg::Sym(name.to_string()))
+    }
+
+    fn reg(name: &str) -> Option<Arg> {
+        [(REGS64, 8), (REGS32, 4), (REGS8, 1)]
+            .iter()
+            .find_map(|(regs, size)| {
+                let n = regs.iter().position(|reg| *reg == name)?;
+                Some(Arg::Reg(n as u8, *size))
+            })
+    }
+
+    // The register number that goes in the r/m field or the base of an
+    // address, which may need REX.B.
+    fn rm_number(&self) -> u8 {
+        match self {
+            Arg::Reg(n, _) | Arg::Mem { base: Some(n), .. } => *n,
+            _ => 0,
+        }
+    }
+}
+
+// A rel32 field at `offset` in the code that is still to be filled in.
+pub(crate) struct Fixup {
+    pub offset: usize,
+    pub target: String,
+    pub call: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct Assembler {
+    pub code: Vec<u8>,
+    pub labels: HashMap<String, usize>,
+    globals: HashSet<String>,
+    sizes: HashMap<String, usize>,
+    fixups: Vec<Fixup>,
+    debug: bool, // in a .debug_ section, until the next .text
+}
+
+// Assemble `asm`, as printed by the native backend, into an ELF object.
+pub fn assemble(asm: &str) -> Result<Vec<u8>, MyError> {
+    Assembler::parse(asm)?.object()
+}
+
+impl Assembler {
+    pub fn parse(asm: &str) -> Result<Self, MyError> {
+        let mut assembler = Assembler::default();
+        for line in asm.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            assembler.line(line).map_err(|e| MyError {
+                info: format!("integrated assembler: {}: {}", line, e.info),
+            })?;
+        }
+        Ok(assembler)
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), MyError> {
+        if self.debug && line != ".text" && !line.starts_with(".section ") {
+            return Ok(());
+        }
+        if let Some(label) = line.strip_suffix(':') {
+            if self
+                .labels
+                .insert(label.to_string(), self.code.len())
+                .is_some()
+            {
+                return Err(MyError {
+                    info: "label defined twice".to_string(),
+                });
+            }
+            return Ok(());
+        }
+        if line.starts_with('.') {
+            return self.directive(line);
+        }
+        let (op, args) = match line.strip_prefix("lock ") {
+            Some(rest) => {
+                self.code.push(0xF0);
+                rest.split_once(' ').unwrap_or((rest, ""))
+            }
+            None => line.split_once(' ').unwrap_or((line, "")),
+        };
+        let args = args
+            .split(", ")
+            .filter(|arg| !arg.is_empty())
+            .map(Arg::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.inst(op, &args)
+    }
+
+    fn directive(&mut self, line: &str) -> Result<(), MyError> {
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match name {
+            ".text" => self.debug = false,
+            ".section" if rest.starts_with(".debug_") => self.debug = true,
+            // the object always gets one
+            ".section" if rest.starts_with(".note.GNU-stack,") => {}
+            ".type" | ".file" | ".loc" => {}
+            _ if name.starts_with(".cfi_") => {}
+            ".globl" | ".global" => {
+                self.globals.insert(rest.to_string());
+            }
+            // only the `.size name, .-name` the backend writes
+            ".size" => {
+                let (symbol, _) = rest.split_once(',').unwrap_or((rest, ""));
+                let start = self.labels.get(symbol).ok_or_else(|| MyError {
+                    info: format!("size of undefined symbol {}", symbol),
+                })?;
+                self.sizes
+                    .insert(symbol.to_string(), self.code.len() - start);
+            }
+            _ => {
+                return Err(MyError {
+                    info: "unsupported directive".to_string(),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn inst(&mut self, op: &str, args: &[Arg]) -> Result<(), MyError> {
+        let unsupported = || {
+            Err(MyError {
+                info: "unsupported instruction".to_string(),
+            })
+        };
+        // An explicit suffix gives the operand size, or else the registers
+        // do: the destination's, as the source of a shift may be %cl.
+        let (op, suffix) = match SIZED.iter().find(|base| op == **base) {
+            Some(base) => (*base, None),
+            None => match SIZED.iter().find_map(|base| {
+                let size = match op.strip_prefix(base)? {
+                    "q" => 8,
+                    "l" => 4,
+                    "b" => 1,
+                    _ => return None,
+                };
+                Some((*base, size))
+            }) {
+                Some((base, size)) => (base, Some(size)),
+                None => (op, None),
+            },
+        };
+        let size = suffix.unwrap_or_else(|| {
+            args.iter()
+                .rev()
+                .find_map(|arg| match arg {
+                    Arg::Reg(_, size) => Some(*size),
+                    _ => None,
+                })
+                .unwrap_or(8)
+        });
+        let alu = |op: &str| match op {
+            "add" => Some(0),
+            "or" => Some(1),
+            "and" => Some(4),
+            "sub" => Some(5),
+            "xor" => Some(6),
+            "cmp" => Some(7),
+            _ => None,
+        };
+        let shift = |op: &str| match op {
+            "shl" => Some(4),
+            "shr" => Some(5),
+            "sar" => Some(7),
+            _ => None,
+        };
+        let condition = |op: &str, prefix: &str| match op.strip_prefix(prefix)? {
+            "e" | "z" => Some(0x4),
+            "ne" | "nz" => Some(0x5),
+            "l" => Some(0xC),
+            "ge" => Some(0xD),
+            "le" => Some(0xE),
+            "g" => Some(0xF),
+            _ => None,
+        };
+        match (op, args) {
+            ("ret", []) => self.code.push(0xC3),
+            ("nop", []) => self.code.push(0x90),
+            ("cqo", []) => self.code.extend([0x48, 0x99]),
+            ("cltd", []) => self.code.push(0x99),
+            ("push" | "pop", [Arg::Reg(n, 8)]) => {
+                if *n >= 8 {
+                    self.code.push(0x41);
+                }
+                let base = if op == "push" { 0x50 } else { 0x58 };
+                self.code.push(base + (n & 7));
+            }
+            ("call" | "jmp", [Arg::Sym(target)]) => {
+                self.code.push(if op == "call" { 0xE8 } else { 0xE9 });
+                self.rel32(target, op == "call");
+            }
+            (_, [Arg::Sym(target)]) if condition(op, "j").is_some() => {
+                let cc = condition(op, "j").expect("checked above");
+                self.code.extend([0x0F, 0x80 + cc]);
+                self.rel32(target, false);
+            }
+            (_, [rm]) if condition(op, "set").is_some() => {
+                let cc = condition(op, "set").expect("checked above");
+                self.op_rm(1, &[0x0F, 0x90 + cc], 0, rm);
+            }
+            ("cmove", [src, Arg::Reg(r, size)]) => self.op_rm(*size, &[0x0F, 0x44], *r, src),
+            ("lea", [src @ Arg::Mem { .. }, Arg::Reg(r, 8)]) => self.op_rm(8, &[0x8D], *r, src),
+            ("movsbq", [src, Arg::Reg(r, 8)]) => self.op_rm(8, &[0x0F, 0xBE], *r, src),
+            ("movzbq" | "movzb", [src, Arg::Reg(r, 8)]) => self.op_rm(8, &[0x0F, 0xB6], *r, src),
+            ("movslq", [src, Arg::Reg(r, 8)]) => self.op_rm(8, &[0x63], *r, src),
+            ("neg", [rm]) => self.op_rm(size, &[Self::wide(0xF6, size)], 3, rm),
+            ("idiv", [rm]) => self.op_rm(size, &[Self::wide(0xF6, size)], 7, rm),
+            ("imul", [Arg::Imm(val), dst @ Arg::Reg(r, _)]) => match i8::try_from(*val) {
+                Ok(val) => {
+                    self.op_rm(size, &[0x6B], *r, dst);
+                    self.code.push(val as u8);
+                }
+                Err(_) => {
+                    self.op_rm(size, &[0x69], *r, dst);
+                    self.imm32(*val)?;
+                }
+            },
+            ("imul", [src, Arg::Reg(r, _)]) => self.op_rm(size, &[0x0F, 0xAF], *r, src),
+            (_, [Arg::Imm(val), dst]) if alu(op).is_some() => {
+                let ext = alu(op).expect("checked above");
+                match i8::try_from(*val) {
+                    Ok(val) if size > 1 => {
+                        self.op_rm(size, &[0x83], ext, dst);
+                        self.code.push(val as u8);
+                    }
+                    // the short form for the accumulator
+                    _ if matches!(dst, Arg::Reg(0, _)) => {
+                        if size == 8 {
+                            self.code.push(0x48);
+                        }
+                        self.code.push(Self::wide(ext * 8 + 4, size));
+                        self.imm(*val, size)?;
+                    }
+                    _ => {
+                        self.op_rm(size, &[Self::wide(0x80, size)], ext, dst);
+                        self.imm(*val, size)?;
+                    }
+                }
+            }
+            (_, [Arg::Reg(r, _), dst]) if alu(op).is_some() => {
+                let opcode = Self::wide(alu(op).expect("checked above") * 8, size);
+                self.op_rm(size, &[opcode], *r, dst);
+            }
+            (_, [src @ Arg::Mem { .. }, Arg::Reg(r, _)]) if alu(op).is_some() => {
+                let opcode = Self::wide(alu(op).expect("checked above") * 8 + 2, size);
+                self.op_rm(size, &[opcode], *r, src);
+            }
+            (_, [Arg::Imm(1), dst]) if shift(op).is_some() => {
+                let ext = shift(op).expect("checked above");
+                self.op_rm(size, &[Self::wide(0xD0, size)], ext, dst);
+            }
+            (_, [Arg::Imm(count), dst]) if shift(op).is_some() => {
+                let ext = shift(op).expect("checked above");
+                self.op_rm(size, &[Self::wide(0xC0, size)], ext, dst);
+                self.code.push(*count as u8);
+            }
+            (_, [Arg::Reg(1, 1), dst]) if shift(op).is_some() => {
+                let ext = shift(op).expect("checked above");
+                self.op_rm(size, &[Self::wide(0xD2, size)], ext, dst);
+            }
+            ("mov", [Arg::Imm(val), Arg::Reg(r, 8)]) if i32::try_from(*val).is_err() => {
+                // movabs
+                self.code.push(0x48 | (r >> 3));
+                self.code.push(0xB8 + (r & 7));
+                self.code.extend(val.to_le_bytes());
+            }
+            ("mov", [Arg::Imm(val), dst]) => {
+                self.op_rm(size, &[Self::wide(0xC6, size)], 0, dst);
+                self.imm(*val, size)?;
+            }
+            ("mov", [Arg::Reg(r, _), dst]) => self.op_rm(size, &[Self::wide(0x88, size)], *r, dst),
+            ("mov", [src @ Arg::Mem { .. }, Arg::Reg(r, _)]) => {
+                self.op_rm(size, &[Self::wide(0x8A, size)], *r, src)
+            }
+            ("xchg" | "xadd" | "cmpxchg", [Arg::Reg(r, _), dst @ Arg::Mem { .. }]) => {
+                let opcode = match op {
+                    "xchg" => vec![Self::wide(0x86, size)],
+                    "xadd" => vec![0x0F, Self::wide(0xC0, size)],
+                    _ => vec![0x0F, Self::wide(0xB0, size)],
+                };
+                self.op_rm(size, &opcode, *r, dst);
+            }
+            _ => return unsupported(),
+        }
+        Ok(())
+    }
+
+    // The opcode for `size`, where the byte form is `opcode` and the others
+    // are the next one up.
+    fn wide(opcode: u8, size: u8) -> u8 {
+        if size == 1 {
+            opcode
+        } else {
+            opcode + 1
+        }
+    }
+
+    // `opcode` followed by a ModRM byte with `reg` (a register or an opcode
+    // extension) in the reg field and `rm` as the other operand, with the
+    // prefixes they need.
+    fn op_rm(&mut self, size: u8, opcode: &[u8], reg: u8, rm: &Arg) {
+        if let Arg::Mem { fs: true, .. } = rm {
+            self.code.push(0x64);
+        }
+        let b = rm.rm_number();
+        let rex = 0x40 | u8::from(size == 8) << 3 | (reg >> 3) << 2 | b >> 3;
+        // without a REX prefix, byte registers 4-7 are %ah..%bh
+        let byte_reg = |n: u8| size == 1 && (4..8).contains(&n);
+        let rm_byte_reg = matches!(rm, Arg::Reg(n, _) if byte_reg(*n));
+        if rex != 0x40 || byte_reg(reg) || rm_byte_reg {
+            self.code.push(rex);
+        }
+        self.code.extend(opcode);
+        let reg = (reg & 7) << 3;
+        match rm {
+            Arg::Reg(n, _) => self.code.push(0xC0 | reg | (n & 7)),
+            Arg::Mem {
+                base: None, disp, ..
+            } => {
+                // SIB with neither base nor index: an absolute disp32
+                self.code.extend([0x04 | reg, 0x25]);
+                self.code.extend(disp.to_le_bytes());
+            }
+            Arg::Mem {
+                base: Some(base),
+                disp,
+                ..
+            } => {
+                let base = base & 7;
+                // %rbp and %r13 have no form without a displacement
+                let mode = match i8::try_from(*disp) {
+                    Ok(0) if base != 5 => 0x00,
+                    Ok(_) => 0x40,
+                    Err(_) => 0x80,
+                };
+                self.code.push(mode | reg | base);
+                // %rsp and %r12 as a base need a SIB byte
+                if base == 4 {
+                    self.code.push(0x24);
+                }
+                match mode {
+                    0x40 => self.code.push(*disp as u8),
+                    0x80 => self.code.extend(disp.to_le_bytes()),
+                    _ => {}
+                }
+            }
+            Arg::Imm(_) | Arg::Sym(_) => unreachable!("not an r/m operand"),
+        }
+    }
+
+    // An immediate of `size` bytes; 8-byte operations take 4, sign-extended.
+    fn imm(&mut self, val: i64, size: u8) -> Result<(), MyError> {
+        if size == 1 {
+            self.code.push(val as u8);
+            return Ok(());
+        }
+        self.imm32(val)
+    }
+
+    fn imm32(&mut self, val: i64) -> Result<(), MyError> {
+        let val = i32::try_from(val).map_err(|_| MyError {
+            info: "immediate out of range".to_string(),
+        })?;
+        self.code.extend(val.to_le_bytes());
+        Ok(())
+    }
+
+    fn rel32(&mut self, target: &str, call: bool) {
+        self.fixups.push(Fixup {
+            offset: self.code.len(),
+            target: target.to_string(),
+            call,
+        });
+        self.code.extend([0; 4]);
+    }
+
+    // Resolve the jumps to local labels, returning the references to other
+    // symbols, even defined ones as gas does, which are left to the linker.
+    pub fn resolve(&mut self) -> Result<Vec<Fixup>, MyError> {
+        let mut relocations = Vec::new();
+        for fixup in std::mem::take(&mut self.fixups) {
+            match self.labels.get(&fixup.target) {
+                Some(&target) if fixup.target.starts_with(".L") => {
+                    let rel = target as i64 - (fixup.offset as i64 + 4);
+                    let rel = i32::try_from(rel).expect("code is under 2GB");
+                    self.code[fixup.offset..fixup.offset + 4].copy_from_slice(&rel.to_le_bytes());
+                }
+                None if fixup.target.starts_with(".L") => {
+                    return Err(MyError {
+                        info: format!("integrated assembler: undefined label {}", fixup.target),
+                    })
+                }
+                _ => relocations.push(fixup),
+            }
+        }
+        Ok(relocations)
+    }
+
+    fn object(mut self) -> Result<Vec<u8>, MyError> {
+        let relocations = self.resolve()?;
+        let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let text = obj.section_id(StandardSection::Text);
+        obj.append_section_data(text, &self.code, 16);
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_by_key(|(_, offset)| **offset);
+        for (name, offset) in labels {
+            if name.starts_with(".L") {
+                continue;
+            }
+            let scope = if self.globals.contains(name) {
+                SymbolScope::Dynamic
+            } else {
+                SymbolScope::Compilation
+            };
+            obj.add_symbol(Symbol {
+                name: name.as_bytes().to_vec(),
+                value: *offset as u64,
+                size: self.sizes.get(name).copied().unwrap_or(0) as u64,
+                kind: SymbolKind::Text,
+                scope,
+                weak: false,
+                section: SymbolSection::Section(text),
+                flags: SymbolFlags::None,
+            });
+        }
+        for fixup in relocations {
+            let symbol = match obj.symbol_id(fixup.target.as_bytes()) {
+                Some(symbol) => symbol,
+                None => obj.add_symbol(Symbol {
+                    name: fixup.target.as_bytes().to_vec(),
+                    value: 0,
+                    size: 0,
+                    kind: SymbolKind::Unknown,
+                    scope: SymbolScope::Unknown,
+                    weak: false,
+                    section: SymbolSection::Undefined,
+                    flags: SymbolFlags::None,
+                }),
+            };
+            let kind = if fixup.call {
+                RelocationKind::PltRelative
+            } else {
+                RelocationKind::Relative
+            };
+            obj.add_relocation(
+                text,
+                Relocation {
+                    offset: fixup.offset as u64,
+                    symbol,
+                    addend: -4,
+                    flags: RelocationFlags::Generic {
+                        kind,
+                        encoding: RelocationEncoding::Generic,
+                        size: 32,
+                    },
+                },
+            )
+            .map_err(|e| MyError {
+                info: format!("integrated assembler: {}", e),
+            })?;
+        }
+        // an empty .note.GNU-stack asks for a non-executable stack
+        obj.add_section(
+            Vec::new(),
+            b".note.GNU-stack".to_vec(),
+            SectionKind::Elf(elf::SHT_PROGBITS),
+        );
+        obj.write().map_err(|e| MyError {
+            info: format!("integrated assembler: {}", e),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(line: &str) -> Vec<u8> {
+        let mut assembler = Assembler::default();
+        assembler.line(line).expect("assembler error");
+        assembler.code
+    }
+
+    #[test]
+    fn test_encode() {
+        // checked against GNU as
+        assert_eq!(encode("push %rbp"), [0x55]);
+        assert_eq!(encode("pop %r12"), [0x41, 0x5C]);
+        assert_eq!(encode("mov %rsp, %rbp"), [0x48, 0x89, 0xE5]);
+        assert_eq!(encode("mov %rbx, -24(%rbp)"), [0x48, 0x89, 0x5D, 0xE8]);
+        assert_eq!(encode("mov (%rax), %rax"), [0x48, 0x8B, 0x00]);
+        assert_eq!(encode("mov $0, %rax"), [0x48, 0xC7, 0xC0, 0, 0, 0, 0]);
+        assert_eq!(
+            encode("mov $4294967296, %rdi"),
+            [0x48, 0xBF, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(encode("movb %al, (%rdi)"), [0x88, 0x07]);
+        assert_eq!(encode("movl %eax, (%rdi)"), [0x89, 0x07]);
+        assert_eq!(encode("lea -8(%rbp), %rax"), [0x48, 0x8D, 0x45, 0xF8]);
+        assert_eq!(encode("sub $16, %rsp"), [0x48, 0x83, 0xEC, 0x10]);
+        assert_eq!(encode("subl $256, %eax"), [0x2D, 0, 1, 0, 0]);
+        assert_eq!(encode("addl %r12d, %eax"), [0x44, 0x01, 0xE0]);
+        assert_eq!(encode("imul $3, %rax"), [0x48, 0x6B, 0xC0, 0x03]);
+        assert_eq!(encode("sarl %cl, %eax"), [0xD3, 0xF8]);
+        assert_eq!(encode("sarl $1, %eax"), [0xD1, 0xF8]);
+        assert_eq!(encode("movslq %eax, %rax"), [0x48, 0x63, 0xC0]);
+        assert_eq!(encode("sete %al"), [0x0F, 0x94, 0xC0]);
+        assert_eq!(encode("movzb %al, %rax"), [0x48, 0x0F, 0xB6, 0xC0]);
+        assert_eq!(encode("idivl %edi"), [0xF7, 0xFF]);
+        assert_eq!(
+            encode("mov %fs:40, %rax"),
+            [0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0, 0, 0]
+        );
+        assert_eq!(encode("lock xadd %eax, (%rdi)"), [0xF0, 0x0F, 0xC1, 0x07]);
+        assert_eq!(encode("mov %r12, -8(%rsp)"), [0x4C, 0x89, 0x64, 0x24, 0xF8]);
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut assembler = Assembler::default();
+        for line in [
+            ".L.begin.1.main:",
+            "jmp .L.begin.1.main",
+            "je .L.end.1.main",
+        ] {
+            assembler.line(line).expect("assembler error");
+        }
+        assert!(assembler.line("jmp").is_err());
+        assert!(assembler.line("vaddps %ymm0, %ymm1, %ymm2").is_err());
+        assert!(assemble("  jmp .L.nowhere\n").is_err());
+        assert!(
+            assemble("  .globl main\nmain:\n  call f@PLT\n  ret\n  .size main, .-main\n").is_ok()
+        );
+        // debug info is dropped, labels and all
+        let assembler = Assembler::parse(
+            "main:\n  ret\n  .section .debug_info,\"\",@progbits\nmain:\n  .quad main\n  .text\n  ret\n",
+        )
+        .expect("assembler error");
+        assert_eq!(assembler.code, [0xC3, 0xC3]);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::ir::{Function, Inst};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+// The control-flow graph of an IR function: its instructions split into
+// basic blocks, which are only entered at the top and only left at the
+// bottom, and the edges between them. Inline assembly is taken to fall
+// through, although it may jump anywhere.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub label: Option<String>, // the label it starts with, if any
+    pub insts: Range<usize>,   // indices into the function's instructions
+    pub succs: Vec<usize>,     // blocks control goes to next, the fall-through first
+    pub preds: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>, // in instruction order; the entry is block 0
+}
+
+impl Cfg {
+    pub fn new(function: &Function) -> Cfg {
+        let insts = &function.insts;
+        // A block starts at a label and after a jump or return.
+        let mut starts: Vec<usize> = (0..insts.len())
+            .filter(|&i| {
+                i == 0
+                    || matches!(insts[i], Inst::Label(_))
+                    || matches!(
+                        insts[i - 1],
+                        Inst::Jump(_) | Inst::JumpIfZero { .. } | Inst::Ret(_)
+                    )
+            })
+            .collect();
+        if starts.is_empty() {
+            starts.push(0);
+        }
+        let mut blocks: Vec<Block> = starts
+            .iter()
+            .enumerate()
+            .map(|(b, &start)| Block {
+                label: match insts.get(start) {
+                    Some(Inst::Label(label)) => Some(label.clone()),
+                    _ => None,
+                },
+                insts: start..starts.get(b + 1).copied().unwrap_or(insts.len()),
+                succs: Vec::new(),
+                preds: Vec::new(),
+            })
+            .collect();
+        let by_label: HashMap<String, usize> = blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(b, block)| Some((block.label.clone()?, b)))
+            .collect();
+        let target = |label: &String| by_label[label];
+
+        for b in 0..blocks.len() {
+            let next = (b + 1 < blocks.len()).then_some(b + 1);
+            let last = blocks[b].insts.end.checked_sub(1).map(|i| &insts[i]);
+            let succs: Vec<usize> = match last {
+                Some(Inst::Jump(label)) => vec![target(label)],
+                Some(Inst::JumpIfZero { target: label, .. }) => {
+                    next.into_iter().chain([target(label)]).collect()
+                }
+                Some(Inst::Ret(_)) => Vec::new(),
+                _ => next.into_iter().collect(),
+            };
+            for &succ in &succs {
+                if !blocks[succ].preds.contains(&b) {
+                    blocks[succ].preds.push(b);
+                }
+            }
+            blocks[b].succs = succs;
+            blocks[b].succs.dedup();
+        }
+        Cfg { blocks }
+    }
+
+    // Whether each block can be reached from the entry.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut work = vec![0];
+        while let Some(b) = work.pop() {
+            if !std::mem::replace(&mut seen[b], true) {
+                work.extend(&self.blocks[b].succs);
+            }
+        }
+        seen
+    }
+
+    // The immediate dominator of each block, None for one that can't be
+    // reached; the entry is its own. This is the iterative algorithm of Cooper,
+    // Harvey and Kennedy.
+    pub fn dominators(&self) -> Vec<Option<usize>> {
+        let n = self.blocks.len();
+        let mut postorder = Vec::with_capacity(n);
+        let mut seen = vec![false; n];
+        seen[0] = true;
+        let mut stack = vec![(0, 0)];
+        while let Some((b, i)) = stack.last_mut() {
+            match self.blocks[*b].succs.get(*i) {
+                Some(&succ) => {
+                    *i += 1;
+                    if !std::mem::replace(&mut seen[succ], true) {
+                        stack.push((succ, 0));
+                    }
+                }
+                None => {
+                    postorder.push(*b);
+                    stack.pop();
+                }
+            }
+        }
+        let mut rank = vec![0; n];
+        for (i, &b) in postorder.iter().enumerate() {
+            rank[b] = i;
+        }
+        let mut idom: Vec<Option<usize>> = vec![None; n];
+        idom[0] = Some(0);
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rank[a] < rank[b] {
+                    a = idom[a].expect("processed");
+                }
+                while rank[b] < rank[a] {
+                    b = idom[b].expect("processed");
+                }
+            }
+            a
+        };
+        loop {
+            let mut changed = false;
+            for &b in postorder.iter().rev().skip(1) {
+                let mut new = None;
+                for &p in &self.blocks[b].preds {
+                    if idom[p].is_some() {
+                        new = Some(new.map_or(p, |q| intersect(&idom, p, q)));
+                    }
+                }
+                if idom[b] != new {
+                    idom[b] = new;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return idom;
+            }
+        }
+    }
+
+    // The graph in Graphviz's dot language, each block listing its
+    // instructions, for drawing with `dot -Tsvg`.
+    pub fn dot(&self, function: &Function) -> String {
+        let mut out = format!(
+            "digraph {:?} {{\n  node [shape=box, fontname=monospace];\n",
+            function.name
+        );
+        for (b, block) in self.blocks.iter().enumerate() {
+            let mut text = format!("b{}:\\l", b);
+            for inst in &function.insts[block.insts.clone()] {
+                let inst = inst.to_string().replace('\\', "\\\\").replace('"', "\\\"");
+                text.push_str(&inst);
+                text.push_str("\\l");
+            }
+            writeln!(out, "  b{} [label=\"{}\"];", b, text).expect("writing to a String");
+            for succ in &block.succs {
+                writeln!(out, "  b{} -> b{};", b, succ).expect("writing to a String");
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CodeGenerator, CompileOptions};
+
+    fn lower(src: &str) -> Function {
+        let (parser, program) = crate::parse(src);
+        CodeGenerator::new(parser, CompileOptions::default()).lower(program.nodes)
+    }
+
+    #[test]
+    fn test_cfg() {
+        let function =
+            lower("{ int i; int j=0; for (i=0; i<3; i=i+1) if (i==1) j=j+i; return j; }");
+        let cfg = Cfg::new(&function);
+        let edges: Vec<(Option<&str>, Vec<usize>)> = cfg
+            .blocks
+            .iter()
+            .map(|block| (block.label.as_deref(), block.succs.clone()))
+            .collect();
+        assert_eq!(
+            edges,
+            [
+                (None, vec![1]),                       // j=0; i=0
+                (Some(".L.begin.1.main"), vec![2, 6]), // i<3
+                (None, vec![3, 4]),                    // i==1
+                (None, vec![5]),                       // j=j+i
+                (Some(".L.else.2.main"), vec![5]),
+                (Some(".L.end.2.main"), vec![1]), // i=i+1
+                (Some(".L.end.1.main"), vec![]),  // return j
+            ]
+        );
+        assert_eq!(cfg.blocks[1].preds, [0, 5]);
+        assert_eq!(cfg.blocks[5].preds, [3, 4]);
+        assert!(cfg.reachable().iter().all(|&r| r));
+
+        let function = lower("{ return 1; return 2; }");
+        let cfg = Cfg::new(&function);
+        assert_eq!(cfg.blocks.len(), 2);
+        assert!(cfg.blocks[0].succs.is_empty());
+        assert_eq!(cfg.reachable(), [true, false]);
+        let dot = cfg.dot(&function);
+        assert!(dot.starts_with("digraph \"main\" {\n"), "{}", dot);
+        assert!(
+            dot.contains("  b1 [label=\"b1:\\l  span 12..21\\l  ret 2\\l\"];\n"),
+            "{}",
+            dot
+        );
+        assert!(!dot.contains("->"), "{}", dot);
+    }
+
+    #[test]
+    fn test_empty() {
+        let function = lower("{ }");
+        assert_eq!(
+            Cfg::new(&function).blocks,
+            [Block {
+                label: None,
+                insts: 0..0,
+                succs: Vec::new(),
+                preds: Vec::new(),
+            }]
+        );
+    }
+}
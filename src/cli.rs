@@ -0,0 +1,1012 @@
+use crate::driver::DriverOptions;
+use chibicc_rust::{Backend, CompileOptions, Os, PassManager, Std, Target, Warning};
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+
+// Exit codes, so wrappers can tell a bad command line from a bad program.
+pub const EXIT_COMPILE_ERROR: u8 = 1;
+pub const EXIT_USAGE_ERROR: u8 = 2;
+
+pub const USAGE: &str =
+    "usage: chibicc_rust [-v] [-I <dir>]... [--target <triple>] [-std=<level>] [-g] [-fpic] [-fstack-protector] [-fintegrated-as] [--asm-comments] [-O<level>] [-fpasses=<list>] [-static] [--save-temps] [-Wall] [-W[no-]<name>]... [-Werror[=<name>]] [-ferror-limit=<n>] [--color=<when>] [--backend=<name>] [-S | -c | --jit | --emit=(llvm-ir | qbe) | --dump-tokens | --dump-ast[=json]] [-o <file>] (<file.c> | <file.s> | <file.o> | - | -e <program>)...
+       chibicc_rust [options] diff <old.c> <new.c>
+       chibicc_rust [options] --server";
+
+struct Flag {
+    name: &'static str,
+    help: &'static str,
+}
+
+const FLAGS: &[Flag] = &[
+    Flag {
+        name: "-e",
+        help: "-e <program>       compile <program> given as text instead of a file",
+    },
+    Flag {
+        name: "-c",
+        help: "-c                 emit an object file <input>.o instead of an executable",
+    },
+    Flag {
+        name: "-o",
+        help: "-o <file>          write the output to <file>, - for stdout (default a.out)",
+    },
+    Flag {
+        name: "-S",
+        help: "-S                 emit assembly to <input>.s instead of an executable",
+    },
+    Flag {
+        name: "--emit=",
+        help: "--emit=<ir>        emit llvm-ir (<input>.ll) or qbe (<input>.ssa) instead",
+    },
+    Flag {
+        name: "--backend=",
+        help: "--backend=<name>   generate code with native (default) or cranelift",
+    },
+    Flag {
+        name: "--jit",
+        help: "--jit              run main in-process instead, exiting with what it returns",
+    },
+    Flag {
+        name: "--dump-tokens",
+        help: "--dump-tokens      print the tokens of each input, one per line, and stop",
+    },
+    Flag {
+        name: "--dump-ast",
+        help: "--dump-ast[=json]  print the syntax tree of each input, or as JSON, and stop",
+    },
+    Flag {
+        name: "--save-temps",
+        help: "--save-temps       keep the .i, .s and .o files of each input next to it",
+    },
+    Flag {
+        name: "-static",
+        help: "-static            link the executable statically",
+    },
+    Flag {
+        name: "-v",
+        help: "-v                 print each cc command, temporary file and phase time",
+    },
+    Flag {
+        name: "-I",
+        help: "-I <dir>           add <dir> to the #include search path",
+    },
+    Flag {
+        name: "--target",
+        help: "--target <triple>  compile for <triple> (defaults to the host)",
+    },
+    Flag {
+        name: "-std=",
+        help: "-std=<level>       language level c89, c99 or c11 (default c11)",
+    },
+    Flag {
+        name: "-g",
+        help: "-g                 emit DWARF line tables and variables for debugging the source",
+    },
+    Flag {
+        name: "-fpic",
+        help: "-fpic, -fPIC       emit position-independent code, -fno-pic turns it off",
+    },
+    Flag {
+        name: "-fstack-protector",
+        help: "-fstack-protector  check a canary on return, aborting if the frame was overrun",
+    },
+    Flag {
+        name: "-fintegrated-as",
+        help: "-fintegrated-as    write ELF objects directly instead of running the system as",
+    },
+    Flag {
+        name: "--asm-comments",
+        help: "--asm-comments     comment the assembly with each statement's source line",
+    },
+    Flag {
+        name: "-O",
+        help: "-O<level>          optimization level 0-2 (default 0, -O means -O1)",
+    },
+    Flag {
+        name: "-fpasses=",
+        help:
+            "-fpasses=<list>    run these comma-separated passes (fold, dce, ifcvt, cse, peephole) instead of -O's",
+    },
+    Flag {
+        name: "-Wall",
+        help: "-Wall              enable all warnings",
+    },
+    Flag {
+        name: "-W",
+        help: "-W<name>           enable warning <name>, -Wno-<name> disables it",
+    },
+    Flag {
+        name: "-Werror",
+        help: "-Werror[=<name>]   treat all warnings, or warning <name>, as errors",
+    },
+    Flag {
+        name: "-ferror-limit=",
+        help: "-ferror-limit=<n>  stop after <n> errors (default 20, 0 for no limit)",
+    },
+    Flag {
+        name: "--max-errors=",
+        help: "--max-errors=<n>   the same as -ferror-limit=<n>",
+    },
+    Flag {
+        name: "--color=",
+        help: "--color=<when>     color diagnostics: auto (on a terminal), always or never",
+    },
+    Flag {
+        name: "--server",
+        help: "--server           read JSON compile requests from stdin, one per line",
+    },
+    Flag {
+        name: "--help",
+        help: "--help             print this message",
+    },
+];
+
+pub struct Args {
+    pub include_paths: Vec<PathBuf>,
+    pub options: CompileOptions,
+    pub command: Command,
+    pub driver: DriverOptions,
+    pub verbose: bool, // -v
+}
+
+pub enum Input {
+    File(PathBuf),
+    Text(String), // program text given with -e
+    Stdin,        // `-`
+}
+
+impl Input {
+    // Where output with extension `ext` goes when there is no -o, as gcc
+    // names it: after the input file, in the current directory. Output for
+    // -e programs and stdin goes to stdout.
+    pub fn default_output(&self, ext: &str) -> Option<PathBuf> {
+        match self {
+            Input::File(path) => Some(
+                PathBuf::from(path.file_stem().unwrap_or(path.as_os_str())).with_extension(ext),
+            ),
+            Input::Text(_) | Input::Stdin => None,
+        }
+    }
+
+    // Where --save-temps keeps the intermediate file with extension `ext` of
+    // the `index`th input: next to the input file, or in the current
+    // directory named after stdin or the position of a -e program.
+    pub fn temp_path(&self, index: usize, ext: &str) -> PathBuf {
+        match self {
+            Input::File(path) => path.with_extension(ext),
+            Input::Text(_) => PathBuf::from(format!("e{}", index)).with_extension(ext),
+            Input::Stdin => PathBuf::from("stdin").with_extension(ext),
+        }
+    }
+}
+
+// What a compile produces.
+pub enum Emit {
+    Asm,
+    Object,
+    Executable, // assembled and linked by the system C compiler
+    Jit,        // assembled into memory and run, without writing anything
+    Tokens,     // the preprocessed input as tokens, without compiling it
+    Ast,        // the syntax tree, without generating code
+    AstJson,    // the syntax tree serialized as JSON
+}
+
+pub enum Command {
+    Compile {
+        inputs: Vec<Input>, // each compiled on its own
+        output: Option<PathBuf>,
+        emit: Emit,
+    },
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+    }, // compare the assembly of two files
+    Server,
+}
+
+pub enum ArgsError {
+    Help,
+    Usage(String),
+}
+
+pub fn help() -> String {
+    let mut rv = format!("{}\n\noptions:\n", USAGE);
+    for flag in FLAGS {
+        rv.push_str(&format!("  {}\n", flag.help));
+    }
+    rv
+}
+
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, ArgsError> {
+    let mut include_paths = Vec::new();
+    let mut inputs = Vec::new();
+    let mut texts = Vec::new();
+    let mut output = None;
+    let mut emit = Emit::Executable;
+    let mut options = CompileOptions {
+        color: io::stderr().is_terminal(), // --color=auto
+        ..CompileOptions::default()
+    };
+    let mut server = false;
+    let mut driver = DriverOptions::default();
+    let mut verbose = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Err(ArgsError::Help);
+        }
+        if arg == "--server" {
+            server = true;
+        } else if arg == "-e" {
+            let text = args
+                .next()
+                .ok_or(ArgsError::Usage("-e requires a program".to_string()))?;
+            texts.push(text);
+        } else if arg == "-S" {
+            emit = Emit::Asm;
+        } else if arg == "-c" {
+            emit = Emit::Object;
+        } else if arg == "--jit" {
+            emit = Emit::Jit;
+        } else if let Some(kind) = arg.strip_prefix("--emit=") {
+            options.backend = match kind {
+                "llvm-ir" => Backend::LlvmIr,
+                "qbe" => Backend::Qbe,
+                _ => {
+                    return Err(ArgsError::Usage(format!(
+                        "unknown output kind '{}', expected llvm-ir or qbe",
+                        kind
+                    )))
+                }
+            };
+            emit = Emit::Asm;
+        } else if let Some(name) = arg.strip_prefix("--backend=") {
+            options.backend = match name {
+                "native" => Backend::Native,
+                "cranelift" if cfg!(feature = "cranelift") => Backend::Cranelift,
+                "cranelift" => {
+                    return Err(ArgsError::Usage(
+                        "--backend=cranelift needs a build with the cranelift feature".to_string(),
+                    ))
+                }
+                _ => {
+                    return Err(ArgsError::Usage(format!(
+                        "unknown backend '{}', expected native or cranelift",
+                        name
+                    )))
+                }
+            };
+        } else if arg == "--dump-tokens" {
+            emit = Emit::Tokens;
+        } else if arg == "--dump-ast" {
+            emit = Emit::Ast;
+        } else if let Some(format) = arg.strip_prefix("--dump-ast=") {
+            emit = match format {
+                "json" => Emit::AstJson,
+                _ => {
+                    return Err(ArgsError::Usage(format!(
+                        "unknown AST format '{}', expected json",
+                        format
+                    )))
+                }
+            };
+        } else if arg == "-o" {
+            let path = args
+                .next()
+                .ok_or(ArgsError::Usage("-o requires a file name".to_string()))?;
+            output = Some(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix("-o").filter(|path| !path.is_empty()) {
+            output = Some(PathBuf::from(path));
+        } else if arg == "--target" || arg.starts_with("--target=") {
+            let name = match arg.strip_prefix("--target=") {
+                Some(name) => name.to_string(),
+                None => args
+                    .next()
+                    .ok_or(ArgsError::Usage("--target requires a triple".to_string()))?,
+            };
+            options.target = Target::lookup(&name).ok_or_else(|| {
+                ArgsError::Usage(format!(
+                    "unknown target '{}', supported targets: {}",
+                    name,
+                    Target::NAMES.join(", ")
+                ))
+            })?;
+        } else if let Some(name) = arg.strip_prefix("-std=") {
+            options.std = Std::from_name(name).ok_or_else(|| {
+                ArgsError::Usage(format!(
+                    "unknown language level '{}', expected c89, c99 or c11",
+                    name
+                ))
+            })?;
+        } else if arg == "--save-temps" {
+            driver.save_temps = true;
+        } else if arg == "-static" {
+            driver.static_link = true;
+        } else if arg == "-v" {
+            verbose = true;
+        } else if arg == "-g" {
+            options.debug_info = true;
+        } else if arg == "-fpic" || arg == "-fPIC" {
+            options.pic = true;
+        } else if arg == "-fno-pic" || arg == "-fno-PIC" {
+            options.pic = false;
+        } else if let "-fstack-protector" | "-fstack-protector-strong" | "-fstack-protector-all" =
+            arg.as_str()
+        {
+            // every function is protected, so the variants are the same
+            options.stack_protector = true;
+        } else if arg == "-fno-stack-protector" {
+            options.stack_protector = false;
+        } else if arg == "--asm-comments" {
+            options.asm_comments = true;
+        } else if arg == "-fintegrated-as" {
+            driver.integrated_as = true;
+        } else if arg == "-fno-integrated-as" {
+            driver.integrated_as = false;
+        } else if arg == "-I" {
+            let dir = args
+                .next()
+                .ok_or(ArgsError::Usage("-I requires a directory".to_string()))?;
+            include_paths.push(PathBuf::from(dir));
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            include_paths.push(PathBuf::from(dir));
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            options.opt_level = match level {
+                "" | "1" => 1,
+                "0" => 0,
+                "2" => 2,
+                _ => {
+                    return Err(ArgsError::Usage(format!(
+                        "invalid optimization level '{}'",
+                        arg
+                    )))
+                }
+            };
+        } else if let Some(list) = arg.strip_prefix("-fpasses=") {
+            let passes: Vec<String> = list
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string())
+                .collect();
+            PassManager::default()
+                .check(&passes)
+                .map_err(|e| ArgsError::Usage(e.info))?;
+            options.passes = Some(passes);
+        } else if arg == "-Wall" {
+            options.warnings.extend(Warning::ALL);
+        } else if arg == "-Werror" {
+            options.werror.extend(Warning::ALL);
+        } else if let Some(name) = arg.strip_prefix("-Werror=") {
+            let warning = warning_named(name, &arg)?;
+            options.warnings.insert(warning);
+            options.werror.insert(warning);
+        } else if let Some(name) = arg.strip_prefix("-Wno-error=") {
+            options.werror.remove(&warning_named(name, &arg)?);
+        } else if let Some(name) = arg.strip_prefix("-W") {
+            let (enable, name) = match name.strip_prefix("no-") {
+                Some(name) => (false, name),
+                None => (true, name),
+            };
+            let warning = warning_named(name, &arg)?;
+            if enable {
+                options.warnings.insert(warning);
+            } else {
+                options.warnings.remove(&warning);
+            }
+        } else if let Some(limit) = arg
+            .strip_prefix("-ferror-limit=")
+            .or_else(|| arg.strip_prefix("--max-errors="))
+        {
+            options.error_limit = limit
+                .parse()
+                .map_err(|_| ArgsError::Usage(format!("invalid error limit '{}'", arg)))?;
+        } else if let Some(when) = arg.strip_prefix("--color=") {
+            options.color = match when {
+                "auto" => io::stderr().is_terminal(),
+                "always" => true,
+                "never" => false,
+                _ => {
+                    return Err(ArgsError::Usage(format!(
+                        "invalid color choice '{}', expected auto, always or never",
+                        when
+                    )))
+                }
+            };
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            return Err(ArgsError::Usage(unknown_flag(&arg)));
+        } else {
+            inputs.push(arg);
+        }
+    }
+    let command = if server {
+        // Programs arrive on stdin instead.
+        if !inputs.is_empty() || !texts.is_empty() {
+            return Err(ArgsError::Usage(
+                "--server does not take a program".to_string(),
+            ));
+        }
+        Command::Server
+    } else if texts.is_empty() && inputs.first().is_some_and(|input| input == "diff") {
+        match <[String; 3]>::try_from(inputs) {
+            Ok([_, old, new]) => Command::Diff {
+                old: PathBuf::from(old),
+                new: PathBuf::from(new),
+            },
+            Err(_) => return Err(ArgsError::Usage("diff takes two files".to_string())),
+        }
+    } else {
+        let inputs: Vec<Input> = inputs
+            .into_iter()
+            .map(|input| match input.as_str() {
+                "-" => Input::Stdin,
+                _ => Input::File(PathBuf::from(input)),
+            })
+            .chain(texts.into_iter().map(Input::Text))
+            .collect();
+        if inputs.is_empty() {
+            return Err(ArgsError::Usage("no input files".to_string()));
+        }
+        if inputs
+            .iter()
+            .filter(|input| matches!(input, Input::Stdin))
+            .count()
+            > 1
+        {
+            return Err(ArgsError::Usage("stdin can only be read once".to_string()));
+        }
+        if let (Backend::Cranelift, Emit::Asm) = (options.backend, &emit) {
+            return Err(ArgsError::Usage(
+                "--backend=cranelift writes objects, not assembly".to_string(),
+            ));
+        }
+        let text_backend = matches!(options.backend, Backend::LlvmIr | Backend::Qbe);
+        if text_backend && matches!(emit, Emit::Object | Emit::Executable) {
+            return Err(ArgsError::Usage(
+                "--emit cannot be combined with -c".to_string(),
+            ));
+        }
+        if driver.integrated_as && options.target.os != Os::Linux {
+            return Err(ArgsError::Usage(
+                "-fintegrated-as only writes ELF objects".to_string(),
+            ));
+        }
+        if driver.integrated_as && options.debug_info {
+            return Err(ArgsError::Usage(
+                "-g cannot be combined with -fintegrated-as".to_string(),
+            ));
+        }
+        let single = inputs.len() == 1;
+        if let Emit::Jit = emit {
+            let conflict = if !single {
+                Some("--jit runs a single input")
+            } else if output.is_some() {
+                Some("--jit cannot be combined with -o")
+            } else if text_backend {
+                Some("--jit cannot be combined with --emit")
+            } else if options.target.os != Os::Linux {
+                Some("--jit only runs code for x86_64-linux")
+            } else {
+                None
+            };
+            if let Some(info) = conflict {
+                return Err(ArgsError::Usage(info.to_string()));
+            }
+        }
+        if !single && output.is_some() && matches!(emit, Emit::Asm | Emit::Object) {
+            return Err(ArgsError::Usage(
+                "cannot specify -o with -S or -c and multiple inputs".to_string(),
+            ));
+        }
+        if matches!(emit, Emit::Object)
+            && output.is_none()
+            && inputs
+                .iter()
+                .any(|input| input.default_output("o").is_none())
+        {
+            return Err(ArgsError::Usage(
+                "-c with -e or stdin input requires -o".to_string(),
+            ));
+        }
+        Command::Compile {
+            inputs,
+            output,
+            emit,
+        }
+    };
+    Ok(Args {
+        include_paths,
+        options,
+        command,
+        driver,
+        verbose,
+    })
+}
+
+// The warning called `name` in the -W option `arg`.
+fn warning_named(name: &str, arg: &str) -> Result<Warning, ArgsError> {
+    Warning::from_name(name).ok_or_else(|| {
+        ArgsError::Usage(format!(
+            "unknown warning option '{}', known warnings: {}",
+            arg,
+            Warning::ALL.map(Warning::name).join(", ")
+        ))
+    })
+}
+
+fn unknown_flag(flag: &str) -> String {
+    let nearest = nearest_flags(flag);
+    if nearest.is_empty() {
+        format!("unknown argument '{}'", flag)
+    } else {
+        format!(
+            "unknown argument '{}', did you mean {}?",
+            flag,
+            nearest
+                .iter()
+                .map(|name| format!("'{}'", name))
+                .collect::<Vec<_>>()
+                .join(" or ")
+        )
+    }
+}
+
+// Flags within a small edit distance of `flag`, compared case-insensitively,
+// closest first.
+fn nearest_flags(flag: &str) -> Vec<&'static str> {
+    let flag = flag.to_lowercase();
+    let limit = 2.max(flag.len() / 3);
+    let mut candidates: Vec<(usize, &'static str)> = FLAGS
+        .iter()
+        .map(|f| (edit_distance(&flag, &f.name.to_lowercase()), f.name))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    candidates.sort();
+    // A case-insensitive exact match is the only sensible suggestion.
+    if candidates
+        .first()
+        .is_some_and(|(distance, _)| *distance == 0)
+    {
+        candidates.retain(|(distance, _)| *distance == 0);
+    }
+    candidates
+        .into_iter()
+        .take(3)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("--help", "--help"), 0);
+        assert_eq!(edit_distance("--hlep", "--help"), 2);
+        assert_eq!(edit_distance("-x", "--help"), 5);
+    }
+
+    #[test]
+    fn test_unknown_flag_suggestions() {
+        assert_eq!(nearest_flags("-i"), vec!["-I"]);
+        assert_eq!(nearest_flags("--HELP"), vec!["--help"]);
+        assert_eq!(nearest_flags("--hepl"), vec!["--help"]);
+        assert!(nearest_flags("--completely-different").is_empty());
+    }
+
+    #[test]
+    fn test_default_output() {
+        let input = Input::File(PathBuf::from("dir/prog.c"));
+        assert_eq!(input.default_output("s"), Some(PathBuf::from("prog.s")));
+        let input = Input::Text("{ return 0; }".to_string());
+        assert_eq!(input.default_output("s"), None);
+    }
+
+    #[test]
+    fn test_temp_path() {
+        let input = Input::File(PathBuf::from("dir/prog.c"));
+        assert_eq!(input.temp_path(0, "i"), PathBuf::from("dir/prog.i"));
+        let input = Input::Text("{ return 0; }".to_string());
+        assert_eq!(input.temp_path(2, "s"), PathBuf::from("e2.s"));
+        assert_eq!(Input::Stdin.temp_path(0, "o"), PathBuf::from("stdin.o"));
+    }
+
+    #[test]
+    fn test_parse_inputs() {
+        let args = parse(&["-e", "{ return 0; }"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile { inputs, output: None, .. }
+                if matches!(inputs.as_slice(), [Input::Text(text)] if text == "{ return 0; }")
+        ));
+        let args = parse(&["prog.c"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile { inputs, output: None, emit: Emit::Executable }
+                if matches!(inputs.as_slice(), [Input::File(path)] if path == Path::new("prog.c"))
+        ));
+        let args = parse(&["-S", "-"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile {
+                inputs,
+                ..
+            } if matches!(inputs.as_slice(), [Input::Stdin])
+        ));
+        let args = parse(&["a.c", "-e", "x"]).ok().expect("parse error");
+        assert!(matches!(args.command, Command::Compile { inputs, .. } if inputs.len() == 2));
+        assert!(parse(&["a.c", "b.c", "-o", "prog"]).is_ok());
+        assert!(matches!(parse(&["-", "-"]), Err(ArgsError::Usage(_))));
+        assert!(matches!(parse(&["-e"]), Err(ArgsError::Usage(_))));
+        assert!(matches!(parse(&[]), Err(ArgsError::Usage(_))));
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let args = parse(&["x"]).ok().expect("parse error");
+        assert_eq!(args.options.opt_level, 0);
+        assert!(!args.options.debug_info);
+        assert!(!args.verbose);
+        assert!(!args.driver.static_link);
+        assert_eq!(args.options.target, Target::x86_64());
+        assert!(args.options.warnings.contains(&Warning::NullDereference));
+        assert!(!args.options.warnings.contains(&Warning::UnusedVariable));
+        assert_eq!(args.options.error_limit, 20);
+    }
+
+    #[test]
+    fn test_parse_include() {
+        let args = parse(&["-I", "a", "-Ib", "x"]).ok().expect("parse error");
+        assert_eq!(
+            args.include_paths,
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert!(matches!(parse(&["x", "-I"]), Err(ArgsError::Usage(_))));
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let args = parse(&["-o", "out.s", "a.c"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile { output: Some(path), .. } if path == Path::new("out.s")
+        ));
+        let args = parse(&["-oout.s", "a.c"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile { output: Some(path), .. } if path == Path::new("out.s")
+        ));
+        assert!(matches!(parse(&["a.c", "-o"]), Err(ArgsError::Usage(_))));
+    }
+
+    #[test]
+    fn test_parse_compile_only() {
+        let args = parse(&["-c", "prog.c"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile {
+                emit: Emit::Object,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse(&["-c", "-e", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+        assert!(matches!(parse(&["-c", "-"]), Err(ArgsError::Usage(_))));
+        let args = parse(&["-c", "a.c", "b.c"]).ok().expect("parse error");
+        assert!(matches!(args.command, Command::Compile { inputs, .. } if inputs.len() == 2));
+        assert!(matches!(
+            parse(&["-c", "a.c", "b.c", "-o", "x.o"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_asm() {
+        let args = parse(&["-S", "prog.c"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile {
+                emit: Emit::Asm,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_jit() {
+        let args = parse(&["--jit", "-"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile {
+                emit: Emit::Jit,
+                ..
+            }
+        ));
+        for args in [
+            &["--jit", "a.c", "b.c"][..],
+            &["--jit", "a.c", "-o", "a"],
+            &["--emit=qbe", "--jit", "a.c"],
+            &["--jit", "--target=x86_64-macos", "a.c"],
+        ] {
+            assert!(matches!(parse(args), Err(ArgsError::Usage(_))));
+        }
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        let args = parse(&["--dump-tokens", "a.c", "b.c", "-o", "toks"])
+            .ok()
+            .expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile {
+                emit: Emit::Tokens,
+                ..
+            }
+        ));
+        let args = parse(&["--dump-ast", "-"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile {
+                emit: Emit::Ast,
+                ..
+            }
+        ));
+        let args = parse(&["--dump-ast=json", "-"]).ok().expect("parse error");
+        assert!(matches!(
+            args.command,
+            Command::Compile {
+                emit: Emit::AstJson,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse(&["--dump-ast=xml", "-"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_emit() {
+        let args = parse(&["--emit=llvm-ir", "prog.c"])
+            .ok()
+            .expect("parse error");
+        assert_eq!(args.options.backend, Backend::LlvmIr);
+        assert!(matches!(
+            args.command,
+            Command::Compile {
+                emit: Emit::Asm,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse(&["--emit=llvm-ir", "-c", "prog.c"]),
+            Err(ArgsError::Usage(_))
+        ));
+        let args = parse(&["--emit=qbe", "-"]).ok().expect("parse error");
+        assert_eq!(args.options.backend, Backend::Qbe);
+        assert!(matches!(
+            parse(&["--emit=wasm", "prog.c"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        let args = parse(&["--backend=native", "-c", "prog.c"])
+            .ok()
+            .expect("parse error");
+        assert_eq!(args.options.backend, Backend::Native);
+        assert_eq!(
+            parse(&["--backend=cranelift", "-c", "prog.c"]).is_ok(),
+            cfg!(feature = "cranelift")
+        );
+        for args in [
+            &["--backend=cranelift", "-S", "prog.c"][..],
+            &["--backend=llvm", "prog.c"],
+        ] {
+            assert!(matches!(parse(args), Err(ArgsError::Usage(_))));
+        }
+    }
+
+    #[test]
+    fn test_parse_driver() {
+        assert!(parse(&["-v", "x"]).is_ok_and(|args| args.verbose));
+        assert!(parse(&["-static", "x"]).is_ok_and(|args| args.driver.static_link));
+        assert!(parse(&["--save-temps", "x"]).is_ok_and(|args| args.driver.save_temps));
+    }
+
+    #[test]
+    fn test_parse_integrated_as() {
+        assert!(parse(&["-fintegrated-as", "x"]).is_ok_and(|args| args.driver.integrated_as));
+        assert!(parse(&["-fintegrated-as", "-fno-integrated-as", "x"])
+            .is_ok_and(|args| !args.driver.integrated_as));
+        assert!(matches!(
+            parse(&["-fintegrated-as", "--target=x86_64-macos", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+        assert!(matches!(
+            parse(&["-g", "-fintegrated-as", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+        assert!(parse(&["-g", "-fintegrated-as", "-fno-integrated-as", "x"]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_std() {
+        assert!(parse(&["-std=c90", "x"]).is_ok_and(|args| args.options.std == Std::C89));
+        assert!(matches!(
+            parse(&["-std=c23", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert!(parse(&["--color=always", "x"]).is_ok_and(|args| args.options.color));
+        assert!(parse(&["--color=never", "x"]).is_ok_and(|args| !args.options.color));
+        assert!(matches!(
+            parse(&["--color=sometimes", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let args = parse(&["--target", "x86_64-unknown-linux-gnu", "x"])
+            .ok()
+            .expect("parse error");
+        assert_eq!(args.options.target, Target::x86_64());
+        assert!(parse(&["--target=x86_64-linux", "x"]).is_ok());
+        let Err(ArgsError::Usage(msg)) = parse(&["--target=arm", "x"]) else {
+            panic!("expected usage error");
+        };
+        assert!(msg.contains("x86_64-linux"), "{}", msg);
+    }
+
+    #[test]
+    fn test_parse_warnings() {
+        let args = parse(&["-Wall", "-Wno-unused-value", "x"])
+            .ok()
+            .expect("parse error");
+        assert!(args.options.warnings.contains(&Warning::UnusedVariable));
+        assert!(!args.options.warnings.contains(&Warning::UnusedValue));
+        let args = parse(&["-Wno-null-dereference", "-Wunused-value", "x"])
+            .ok()
+            .expect("parse error");
+        assert_eq!(
+            args.options.warnings.into_iter().collect::<Vec<_>>(),
+            vec![Warning::UnusedValue]
+        );
+        assert!(matches!(parse(&["-Wbogus", "x"]), Err(ArgsError::Usage(_))));
+    }
+
+    #[test]
+    fn test_parse_werror() {
+        let args = parse(&["-Werror", "-Wno-error=null-dereference", "x"])
+            .ok()
+            .expect("parse error");
+        assert!(args.options.werror.contains(&Warning::UnusedValue));
+        assert!(!args.options.werror.contains(&Warning::NullDereference));
+        let args = parse(&["-Werror=unused-variable", "x"])
+            .ok()
+            .expect("parse error");
+        assert!(args.options.warnings.contains(&Warning::UnusedVariable));
+        assert_eq!(
+            args.options.werror.into_iter().collect::<Vec<_>>(),
+            vec![Warning::UnusedVariable]
+        );
+        assert!(matches!(
+            parse(&["-Werror=bogus", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_codegen_flags() {
+        let args = parse(&["-g", "x"]).ok().expect("parse error");
+        assert!(args.options.debug_info);
+        let args = parse(&["-fPIC", "x"]).ok().expect("parse error");
+        assert!(args.options.pic);
+        let args = parse(&["-fpic", "-fno-pic", "x"])
+            .ok()
+            .expect("parse error");
+        assert!(!args.options.pic);
+        let args = parse(&["-fstack-protector-strong", "x"])
+            .ok()
+            .expect("parse error");
+        assert!(args.options.stack_protector);
+        let args = parse(&["--asm-comments", "x"]).ok().expect("parse error");
+        assert!(args.options.asm_comments);
+    }
+
+    #[test]
+    fn test_parse_opt_level() {
+        for (flag, level) in [("-O", 1), ("-O0", 0), ("-O1", 1), ("-O2", 2)] {
+            let args = parse(&[flag, "x"]).ok().expect("parse error");
+            assert_eq!(args.options.opt_level, level, "{}", flag);
+        }
+        for flag in ["-Ofast", "-O3", "-O7", "-O-1"] {
+            let Err(ArgsError::Usage(msg)) = parse(&[flag, "x"]) else {
+                panic!("expected usage error for {}", flag);
+            };
+            assert!(msg.contains("invalid optimization level"), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn test_parse_passes() {
+        let args = parse(&["-fpasses=cse,fold", "x"])
+            .ok()
+            .expect("parse error");
+        assert_eq!(args.options.pipeline(), ["cse", "fold"]);
+        let args = parse(&["-O2", "-fpasses=", "x"]).ok().expect("parse error");
+        assert!(args.options.pipeline().is_empty());
+        assert!(matches!(
+            parse(&["-fpasses=fold,licm", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_error_limit() {
+        let args = parse(&["-ferror-limit=3", "x"]).ok().expect("parse error");
+        assert_eq!(args.options.error_limit, 3);
+        let args = parse(&["--max-errors=0", "x"]).ok().expect("parse error");
+        assert_eq!(args.options.error_limit, 0);
+        assert!(matches!(
+            parse(&["-ferror-limit=", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_help() {
+        let Err(ArgsError::Usage(msg)) = parse(&["--hepl", "{ return 0; }"]) else {
+            panic!("expected usage error");
+        };
+        assert!(msg.contains("did you mean '--help'"), "{}", msg);
+        assert!(matches!(parse(&["-h"]), Err(ArgsError::Help)));
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        assert!(matches!(
+            parse(&["--server"]).ok().expect("parse error").command,
+            Command::Server
+        ));
+        assert!(matches!(
+            parse(&["--server", "x"]),
+            Err(ArgsError::Usage(_))
+        ));
+        assert!(matches!(
+            parse(&["-O2", "diff", "a.c", "b.c"])
+                .ok()
+                .expect("parse error")
+                .command,
+            Command::Diff { .. }
+        ));
+        assert!(matches!(parse(&["diff", "a.c"]), Err(ArgsError::Usage(_))));
+    }
+}
@@ -1,17 +1,48 @@
-use crate::{Node, Parser};
+use crate::dwarf::{self, Variable};
+use crate::ir::{BinOp, Function, Inst, Operand, Reg, Ty};
+use crate::parser::Type;
+use crate::x86_64;
+use crate::{Arch, CompileOptions, CostModel, LineMap, Node, Os, Parser, PassManager};
+use std::io;
+use std::ops::Range;
 
+// Lowers the AST to the IR, which the x86_64 module turns into assembly.
 pub struct CodeGenerator {
-    depth: usize,
+    insts: Vec<Inst>,
+    regs: usize,
     parser: Parser,
     counter: usize,
+    function: String,           // being lowered; the whole program is main for now
+    span: Option<Range<usize>>, // of the statement being lowered
+    cost_model: CostModel,
+    options: CompileOptions,
+    // Where the lines of the preprocessed source the program was parsed from
+    // came from, and that source, for the line table when debug info is on.
+    pub line_map: LineMap,
+    pub source: String,
+    // Once the assembly is generated, the span of the preprocessed source
+    // that each line of it came from, if any.
+    pub source_map: Vec<Option<Range<usize>>>,
+    pub passes: PassManager, // those named in options.passes are run
 }
 
 impl CodeGenerator {
-    pub fn new(parser: Parser) -> CodeGenerator {
+    pub fn new(parser: Parser, options: CompileOptions) -> CodeGenerator {
         Self {
-            depth: 0,
+            insts: Vec::new(),
+            regs: 0,
             counter: 0,
+            function: "main".to_string(),
+            span: None,
             parser,
+            cost_model: match options.target.arch {
+                Arch::X86_64 => CostModel::x86_64(),
+            },
+            options,
+            line_map: LineMap::default(),
+            source: String::new(),
+            source_map: Vec::new(),
+            passes: PassManager::default(),
         }
     }
     fn count(&mut self) -> usize {
@@ -19,193 +50,466 @@ impl CodeGenerator {
         self.counter
     }
 
-    fn push(&mut self) {
-        println!("  push %rax");
-        self.depth += 1;
+    // The `n`th label of `kind`, scoped to the current function so the
+    // labels of different functions never collide.
+    fn label(&self, kind: &str, n: usize) -> String {
+        format!(".L.{}.{}.{}", kind, n, self.function)
     }
 
-    fn pop(&mut self, register: &str) {
-        println!("  pop %{}", register);
-        self.depth -= 1;
+    // A fresh virtual register.
+    fn reg(&mut self) -> Reg {
+        self.regs += 1;
+        self.regs - 1
     }
 
-    fn gen_addr(&mut self, node: Option<&Node>) {
-        let Some(node) = node else {
-            return;
+    fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    // Returns the assembly for the whole program.
+    pub fn generate(&mut self, nodes: Vec<Node>) -> String {
+        let mut asm = Vec::new();
+        self.generate_to(nodes, &mut asm)
+            .expect("writing to a Vec cannot fail");
+        String::from_utf8(asm).expect("assembly is UTF-8")
+    }
+
+    // Write the assembly for the whole program to `out`, such as a file or
+    // a buffer. The assembly passes need it all, so it is written in one go
+    // at the end.
+    pub fn generate_to(&mut self, nodes: Vec<Node>, out: &mut dyn io::Write) -> io::Result<()> {
+        let function = self.lower(nodes);
+        let mut lines = x86_64::emit(&function, &self.options);
+        // the sections are ELF ones; Mach-O only gets the line table
+        if self.options.debug_info && self.options.target.os == Os::Linux {
+            lines.extend(self.debug_info(&function.name));
+        }
+        let lines = self.passes.run_asm(&self.options.pipeline(), lines);
+        self.source_map = x86_64::source_map(&lines);
+        out.write_all(x86_64::print(&lines).as_bytes())
+    }
+
+    // Returns the IR for the whole program, which is the body of main.
+    pub fn lower(&mut self, nodes: Vec<Node>) -> Function {
+        let pipeline = self.options.pipeline();
+        let nodes = self.passes.run_ast(&pipeline, nodes, &self.parser);
+        for node in &nodes {
+            self.gen_stmt(Some(node));
+        }
+        let mut function = Function {
+            name: self.function.clone(),
+            insts: std::mem::take(&mut self.insts),
+            regs: std::mem::take(&mut self.regs),
+            frame_size: self.parser.stack_size,
+        };
+        self.passes.run_ir(&pipeline, &mut function);
+        function
+    }
+
+    // DWARF for `function` and the locals, which are below the saved %rbp
+    // and the return address.
+    fn debug_info(&self, function: &str) -> Vec<x86_64::Line> {
+        let canary = if self.options.stack_protector {
+            x86_64::CANARY_SIZE
+        } else {
+            0
         };
+        let variables: Vec<Variable> = self
+            .parser
+            .locals_dequeue
+            .iter()
+            .rev()
+            .map(|name| {
+                let item = &self.parser.locals[name];
+                Variable {
+                    name,
+                    r#type: &item.r#type,
+                    cfa_offset: -16 - (canary + item.offset) as i64,
+                }
+            })
+            .collect();
+        dwarf::debug_info(
+            function,
+            &x86_64::end_label(function),
+            &self.line_map.file,
+            &variables,
+            &self.options.target,
+        )
+    }
+
+    // How an object of `r#type` is loaded and stored.
+    fn mem_ty(r#type: &Type) -> Ty {
+        match r#type.unqualified() {
+            Type::Char | Type::SChar => Ty::I8,
+            Type::UChar | Type::Bool => Ty::U8,
+            Type::I32 => Ty::I32,
+            _ => Ty::I64,
+        }
+    }
+
+    // The width arithmetic on values of `r#type` is done in. Anything
+    // narrower than an int is promoted to one.
+    fn arith_ty(r#type: &Type) -> Ty {
+        match r#type.unqualified() {
+            Type::Long | Type::I64 | Type::Ptr { .. } | Type::Array { .. } | Type::Func { .. } => {
+                Ty::I64
+            }
+            _ => Ty::I32,
+        }
+    }
+
+    fn gen_addr(&mut self, node: &Node) -> Operand {
         match node {
             Node::Var { name, .. } => {
                 let item = self.parser.locals.get(name).expect("name not found");
-                println!("  lea -{}(%rbp), %rax", item.offset);
-            }
-            Node::Deref { lhs } => {
-                self.gen_expr(Some(lhs.as_ref()));
+                let offset = item.offset;
+                let dst = self.reg();
+                self.emit(Inst::LocalAddr { dst, offset });
+                Operand::Reg(dst)
             }
+            Node::Deref { lhs, .. } => self.gen_expr(lhs),
             _ => {
                 panic!("not a lvalue: {:?}", node)
             }
         }
     }
 
-    pub fn generate(&mut self, nodes: Vec<Node>) {
-        self.parser.assign_lvar_offset();
-        println!("  .global main");
-        println!("main:");
-        // prologur
-        println!("  push %rbp");
-        println!("  mov %rsp, %rbp");
-        println!("  sub ${}, %rsp", self.parser.stack_size);
-
-        for node in nodes {
-            self.gen_stmt(Some(&node));
-            assert!(self.depth == 0);
+    // Load the object of `r#type` at `addr`. An array is not loaded: its
+    // address is its value.
+    fn load(&mut self, addr: Operand, r#type: &Type) -> Operand {
+        if let Type::Array { .. } = r#type {
+            return addr;
         }
-        println!(".L.return:");
-        println!("  mov %rbp, %rsp");
-        println!("  pop %rbp");
-        println!("  ret");
+        let dst = self.reg();
+        self.emit(Inst::Load {
+            dst,
+            addr,
+            ty: Self::mem_ty(r#type),
+            volatile: r#type.is_volatile(),
+        });
+        Operand::Reg(dst)
     }
-    // generate code for a given node
-    pub fn gen_expr(&mut self, node: Option<&Node>) {
-        let Some(node) = node else {
-            return;
+
+    // Store `val` to `lhs`, whose address is `addr`, returning the value as
+    // stored.
+    fn store(&mut self, addr: Operand, val: Operand, r#type: &Type, lhs: &Node) -> Operand {
+        let ty = Self::mem_ty(r#type);
+        let val = if ty == Ty::I64 {
+            val
+        } else {
+            let dst = self.reg();
+            self.emit(Inst::Extend { dst, src: val, ty });
+            Operand::Reg(dst)
         };
+        // a store to an atomic object is sequentially consistent
+        let object = self.object_type(lhs);
+        self.emit(Inst::Store {
+            addr,
+            src: val,
+            ty,
+            atomic: object.as_ref().is_some_and(Type::is_atomic),
+            volatile: object.as_ref().is_some_and(Type::is_volatile),
+        });
+        val
+    }
+
+    fn binary(&mut self, op: BinOp, ty: Ty, lhs: Operand, rhs: Operand) -> Operand {
+        let dst = self.reg();
+        self.emit(Inst::Binary {
+            op,
+            ty,
+            dst,
+            lhs,
+            rhs,
+        });
+        Operand::Reg(dst)
+    }
+
+    // The type of the object the lvalue `node` designates, with its
+    // qualifiers.
+    fn object_type(&self, node: &Node) -> Option<Type> {
         match node {
-            Node::Num { val } => {
-                println!("  mov ${}, %rax", val);
-                return;
-            }
-            Node::Neg { lhs } => {
-                self.gen_expr(Some(lhs.as_ref()));
-                println!("  neg %rax");
-                return;
-            }
-            Node::Var { .. } => {
-                self.gen_addr(Some(node));
-                println!("  mov (%rax), %rax");
-                return;
-            }
-            Node::Deref { lhs } => {
-                self.gen_expr(Some(lhs.as_ref()));
-                println!("  mov (%rax), %rax");
-                return;
-            }
-            Node::Addr { lhs } => {
-                self.gen_addr(Some(lhs.as_ref()));
-                return;
-            }
-            Node::Assign { lhs, rhs } => {
-                self.gen_addr(Some(lhs.as_ref()));
-                self.push();
-                self.gen_expr(Some(rhs.as_ref()));
-                self.pop("rdi");
-                println!(" mov %rax, (%rdi)");
-                return;
-            }
-            _ => {}
+            Node::Var { name, .. } => Some(self.parser.locals[name].r#type.clone()),
+            Node::Deref { lhs, .. } => lhs.get_type()?.base().cloned(),
+            _ => None,
+        }
+    }
+
+    // Multiplication and division by a power of two as shifts, when the cost
+    // model says that is cheaper. Returns None if `node` was not handled.
+    fn gen_shift(&mut self, node: &Node) -> Option<Operand> {
+        let (Node::Mul {
+            lhs, rhs, r#type, ..
+        }
+        | Node::Div {
+            lhs, rhs, r#type, ..
+        }) = node
+        else {
+            return None;
+        };
+        let Node::Num { val, .. } = rhs.as_ref() else {
+            return None;
+        };
+        if *val <= 0 || val & (val - 1) != 0 {
+            return None;
         }
+        let shift = val.trailing_zeros() as i64;
+        let ty = Self::arith_ty(r#type);
+        let bits = if ty == Ty::I32 { 32 } else { 64 };
         match node {
-            Node::Add { lhs, rhs }
-            | Node::Sub { lhs, rhs }
-            | Node::Mul { lhs, rhs }
-            | Node::Div { lhs, rhs }
-            | Node::Eq { lhs, rhs }
-            | Node::Ne { lhs, rhs }
-            | Node::Lt { lhs, rhs }
-            | Node::Le { lhs, rhs } => {
-                self.gen_expr(Some(rhs.as_ref()));
-                self.push();
-                self.gen_expr(Some(lhs.as_ref()));
-                self.pop("rdi");
+            Node::Mul { .. } if self.cost_model.mul_by_shift() => {
+                let lhs = self.gen_expr(lhs);
+                Some(self.binary(BinOp::Shl, ty, lhs, Operand::Imm(shift)))
             }
-            _ => {
-                panic!("invalid expression, {:?}", node)
+            Node::Div { .. } if self.cost_model.div_by_shift() => {
+                let lhs = self.gen_expr(lhs);
+                if shift == 0 {
+                    return Some(lhs);
+                }
+                // Bias negative dividends by 2^shift-1 to round toward zero.
+                let sign = self.binary(BinOp::Sar, ty, lhs, Operand::Imm(bits - 1));
+                let bias = self.binary(BinOp::Shr, ty, sign, Operand::Imm(bits - shift));
+                let biased = self.binary(BinOp::Add, ty, lhs, bias);
+                Some(self.binary(BinOp::Sar, ty, biased, Operand::Imm(shift)))
             }
+            _ => None,
+        }
+    }
+
+    // generate code for a given node, returning where its value is
+    pub fn gen_expr(&mut self, node: &Node) -> Operand {
+        if let Some(val) = self.gen_shift(node) {
+            return val;
         }
-        let print_eq = |eq_str: &str| {
-            println!("  cmp %rdi, %rax");
-            println!("{}", eq_str);
-            println!("  movzb %al, %rax");
-        };
         match node {
-            Node::Add { .. } => {
-                println!("  add %rdi, %rax");
+            Node::Num { val, .. } => Operand::Imm(*val),
+            Node::Neg { lhs, r#type, .. } => {
+                let src = self.gen_expr(lhs);
+                let dst = self.reg();
+                let ty = Self::arith_ty(r#type);
+                self.emit(Inst::Neg { dst, src, ty });
+                Operand::Reg(dst)
+            }
+            Node::Var { name, r#type, .. } => {
+                // the node has the value's type, without the local's qualifiers
+                let r#type = match &self.parser.locals[name].r#type {
+                    local if local.is_volatile() => local.clone(),
+                    _ => r#type.clone(),
+                };
+                let addr = self.gen_addr(node);
+                self.load(addr, &r#type)
+            }
+            Node::Deref { lhs, r#type, .. } => {
+                let addr = self.gen_expr(lhs);
+                self.load(addr, r#type)
+            }
+            Node::Addr { lhs, .. } => self.gen_addr(lhs),
+            Node::FuncCall {
+                name, args, r#type, ..
+            } => {
+                let args = args.iter().map(|arg| self.gen_expr(arg)).collect();
+                // The callee only defines the low bits of a narrow value.
+                let ret = Self::mem_ty(r#type);
+                let dst = self.reg();
+                self.emit(Inst::Call {
+                    dst,
+                    name: name.clone(),
+                    args,
+                    ret,
+                });
+                Operand::Reg(dst)
+            }
+            Node::Assign {
+                lhs, rhs, r#type, ..
+            } => {
+                let addr = self.gen_addr(lhs);
+                let val = self.gen_expr(rhs);
+                self.store(addr, val, r#type, lhs)
+            }
+            Node::Exchange {
+                lhs, rhs, r#type, ..
+            }
+            | Node::FetchAdd {
+                lhs, rhs, r#type, ..
+            } => {
+                let addr = self.gen_expr(lhs);
+                let src = self.gen_expr(rhs);
+                let (dst, ty) = (self.reg(), Self::mem_ty(r#type));
+                self.emit(if let Node::Exchange { .. } = node {
+                    Inst::Exchange { dst, addr, src, ty }
+                } else {
+                    Inst::FetchAdd { dst, addr, src, ty }
+                });
+                Operand::Reg(dst)
+            }
+            Node::CompareSwap {
+                lhs,
+                old,
+                new,
+                r#type,
+                ..
+            } => {
+                let addr = self.gen_expr(lhs);
+                let old = self.gen_expr(old);
+                let new = self.gen_expr(new);
+                let (dst, ty) = (self.reg(), Self::mem_ty(r#type));
+                self.emit(Inst::CompareSwap {
+                    dst,
+                    addr,
+                    old,
+                    new,
+                    ty,
+                });
+                Operand::Reg(dst)
+            }
+            Node::Add {
+                lhs, rhs, r#type, ..
             }
-            Node::Sub { .. } => {
-                println!("  sub %rdi, %rax");
+            | Node::Sub {
+                lhs, rhs, r#type, ..
             }
-            Node::Mul { .. } => {
-                println!("  imul %rdi, %rax");
+            | Node::Mul {
+                lhs, rhs, r#type, ..
             }
-            Node::Div { .. } => {
-                println!("  cqo");
-                println!("  idiv %rdi");
+            | Node::Div {
+                lhs, rhs, r#type, ..
             }
-            Node::Eq { .. } => {
-                print_eq("  sete %al");
+            | Node::Eq {
+                lhs, rhs, r#type, ..
             }
-            Node::Ne { .. } => {
-                print_eq("  setne %al");
+            | Node::Ne {
+                lhs, rhs, r#type, ..
             }
-            Node::Lt { .. } => {
-                print_eq("  setl %al");
+            | Node::Lt {
+                lhs, rhs, r#type, ..
             }
-            Node::Le { .. } => {
-                print_eq("  setle %al");
+            | Node::Le {
+                lhs, rhs, r#type, ..
+            } => {
+                // a comparison is as wide as its operands, not its result
+                let ty = match node {
+                    Node::Eq { .. } | Node::Ne { .. } | Node::Lt { .. } | Node::Le { .. } => {
+                        Self::arith_ty(&Type::common(
+                            &lhs.get_type().expect("should have a type"),
+                            &rhs.get_type().expect("should have a type"),
+                        ))
+                    }
+                    _ => Self::arith_ty(r#type),
+                };
+                // rhs first
+                let rhs = self.gen_expr(rhs);
+                let lhs = self.gen_expr(lhs);
+                let op = match node {
+                    Node::Add { .. } => BinOp::Add,
+                    Node::Sub { .. } => BinOp::Sub,
+                    Node::Mul { .. } => BinOp::Mul,
+                    Node::Div { .. } => BinOp::Div,
+                    Node::Eq { .. } => BinOp::Eq,
+                    Node::Ne { .. } => BinOp::Ne,
+                    Node::Lt { .. } => BinOp::Lt,
+                    _ => BinOp::Le,
+                };
+                self.binary(op, ty, lhs, rhs)
             }
             _ => {
-                panic!("invalid expression, node: {:?}", node)
+                panic!("invalid expression, {:?}", node)
             }
         }
     }
+
     fn gen_stmt(&mut self, node: Option<&Node>) {
         let Some(node) = node else {
             return;
         };
+        if let Some(span) = node.stmt_span().filter(|_| self.options.debug_info) {
+            let (file, line, col) = self.line_map.locate(&self.source, span.start);
+            let file = file.to_string();
+            self.emit(Inst::Loc { file, line, col });
+        }
+        if let Some(span) = node.stmt_span().filter(|_| self.options.asm_comments) {
+            let (file, line, _) = self.line_map.locate(&self.source, span.start);
+            let text = format!(
+                "{}:{}: {} ({})",
+                file,
+                line,
+                source_line(&self.source, span.start),
+                node.kind()
+            );
+            self.emit(Inst::Comment(text));
+        }
+        // Instructions after a nested statement, such as the jump at the end
+        // of a then branch, belong to the enclosing one again.
+        let outer = self.span.clone();
+        if let Some(span) = node.stmt_span() {
+            self.span = Some(span.clone());
+            self.emit(Inst::Span(span));
+        }
+        self.gen_stmt_kind(node);
+        if node.stmt_span().is_some() {
+            if let Some(outer) = &outer {
+                self.emit(Inst::Span(outer.clone()));
+            }
+            self.span = outer;
+        }
+    }
+
+    fn gen_stmt_kind(&mut self, node: &Node) {
         match node {
-            Node::Return { lhs } => {
-                self.gen_expr(lhs.as_deref());
-                println!("  jmp .L.return");
+            Node::Return { lhs, .. } => {
+                let val = lhs.as_deref().map(|lhs| self.gen_expr(lhs));
+                self.emit(Inst::Ret(val));
             }
-            Node::ExprStmt { expr } => {
-                self.gen_expr(Some(expr.as_ref()));
+            Node::ExprStmt { expr, .. } => {
+                self.gen_expr(expr);
             }
 
-            Node::If { cond, then, els } => {
+            Node::If {
+                cond, then, els, ..
+            } => {
                 let c = self.count();
-                self.gen_expr(Some(cond.as_ref()));
-                println!("  cmp $0, %rax");
-                println!("  je .L.else.{}", c);
+                let cond = self.gen_expr(cond);
+                self.emit(Inst::JumpIfZero {
+                    cond,
+                    target: self.label("else", c),
+                });
                 self.gen_stmt(then.as_deref());
-                println!("  jmp .L.end.{}", c);
-                println!(".L.else.{}:", c);
+                self.emit(Inst::Jump(self.label("end", c)));
+                self.emit(Inst::Label(self.label("else", c)));
                 self.gen_stmt(els.as_deref());
-                println!(".L.end.{}:", c);
+                self.emit(Inst::Label(self.label("end", c)));
             }
             Node::For {
                 init,
                 cond,
                 inc,
                 then,
+                ..
             } => {
                 let c = self.count();
                 self.gen_stmt(init.as_deref());
-                println!(".L.begin.{}:", c);
-                if cond.is_some() {
-                    self.gen_expr(cond.as_deref());
-                    println!("  cmp $0, %rax");
-                    println!("  je .L.end.{}", c);
+                self.emit(Inst::Label(self.label("begin", c)));
+                if let Some(cond) = cond {
+                    let cond = self.gen_expr(cond);
+                    self.emit(Inst::JumpIfZero {
+                        cond,
+                        target: self.label("end", c),
+                    });
                 }
                 self.gen_stmt(then.as_deref());
-                self.gen_expr(inc.as_deref());
-                println!("  jmp .L.begin.{}", c);
-                println!(".L.end.{}:", c);
+                if let Some(inc) = inc {
+                    self.gen_expr(inc);
+                }
+                self.emit(Inst::Jump(self.label("begin", c)));
+                self.emit(Inst::Label(self.label("end", c)));
             }
-            Node::Block { nodes } => {
+            Node::Block { nodes, .. } => {
                 for node in nodes {
                     self.gen_stmt(Some(node));
                 }
             }
+            Node::Asm { text, .. } => {
+                self.emit(Inst::Asm(text.clone()));
+            }
 
             _ => {
                 panic!("invalid statement")
@@ -213,3 +517,112 @@ impl CodeGenerator {
         }
     }
 }
+
+// The line of `source` that `offset` is on, without the indentation.
+fn source_line(source: &str, offset: usize) -> &str {
+    let offset = offset.min(source.len());
+    let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    source[start..end].trim()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn generator(src: &str) -> (CodeGenerator, Vec<Node>) {
+        let (parser, program) = crate::parse(src);
+        (
+            CodeGenerator::new(parser, CompileOptions::default()),
+            program.nodes,
+        )
+    }
+
+    #[test]
+    fn test_generate_to() {
+        let (mut a, nodes) = generator("{ return 1+2; }");
+        let mut out = Vec::new();
+        a.generate_to(nodes, &mut out).expect("write error");
+        let (mut b, nodes) = generator("{ return 1+2; }");
+        assert_eq!(String::from_utf8(out).unwrap(), b.generate(nodes));
+    }
+
+    #[test]
+    fn test_asm_comments() {
+        let src = "{ int x; x = 3;\n  if (x) return x; }";
+        let (generator, nodes) = generator(src);
+        let mut generator = CodeGenerator::new(
+            generator.parser,
+            CompileOptions {
+                asm_comments: true,
+                ..CompileOptions::default()
+            },
+        );
+        generator.source = src.to_string();
+        let asm = generator.generate(nodes);
+        assert!(
+            asm.contains("  # :1: { int x; x = 3; (ExprStmt)\n"),
+            "{}",
+            asm
+        );
+        assert!(asm.contains("  # :2: if (x) return x; } (If)\n"), "{}", asm);
+        assert!(
+            asm.contains("  # :2: if (x) return x; } (Return)\n"),
+            "{}",
+            asm
+        );
+        assert!(!asm.contains("(Block)"), "{}", asm);
+    }
+
+    #[test]
+    fn test_source_map() {
+        let src = "{ int x; x = 3;\n  if (x) return x; }";
+        let (mut generator, nodes) = generator(src);
+        let asm = generator.generate(nodes);
+        let lines: Vec<&str> = asm.lines().collect();
+        assert_eq!(lines.len(), generator.source_map.len());
+        let text = |line: &str| {
+            let i = lines.iter().position(|l| *l == line).expect("line missing");
+            generator.source_map[i].clone().map(|span| &src[span])
+        };
+        assert_eq!(text("  push %rbp"), None);
+        assert_eq!(text("  mov $3, %r10"), Some("x = 3;"));
+        assert_eq!(text("  je .L.else.1.main"), Some("if (x) return x;"));
+        assert_eq!(text("  jmp .L.return.main"), Some("return x;"));
+        // back in the if after the return
+        assert_eq!(text("  jmp .L.end.1.main"), Some("if (x) return x;"));
+        assert_eq!(text("  ret"), None);
+    }
+
+    #[test]
+    fn test_function_labels() {
+        let (mut generator, nodes) = generator("{ if (1) return 2; return 3; }");
+        let ir = generator.lower(nodes).to_string();
+        assert!(ir.contains("  jump_if_zero 1, .L.else.1.main\n"), "{}", ir);
+        assert!(ir.contains("\n.L.end.1.main:\n"), "{}", ir);
+    }
+
+    #[test]
+    fn test_lower() {
+        let (mut generator, nodes) = generator("{ char c; c = 300; return c*4 + 1; }");
+        assert_eq!(
+            generator.lower(nodes).to_string(),
+            "\
+function main (frame 16) {
+  span 10..18
+  %0 = local 1
+  %1 = extend i8 300
+  store i8 %0, %1
+  span 19..34
+  %2 = local 1
+  %3 = load i8 %2
+  %4 = shl i32 %3, 2
+  %5 = add i32 %4, 1
+  ret %5
+}
+"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+// Rough cost of one instruction class on a backend.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cost {
+    pub latency: u32, // cycles until the result is available
+    pub size: u32,    // encoded bytes
+}
+
+// Per-backend cost table consulted by code generation decisions such as
+// strength reduction, so each target can make its own trade-offs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CostModel {
+    pub add: Cost,   // add/sub/mov between registers
+    pub shift: Cost, // shift by an immediate
+    pub mul: Cost,
+    pub div: Cost,    // signed division including sign extension
+    pub branch: Cost, // conditional branch, latency weighted by misprediction
+    pub cmov: Cost,
+}
+
+impl CostModel {
+    pub fn x86_64() -> Self {
+        Self {
+            add: Cost {
+                latency: 1,
+                size: 3,
+            },
+            shift: Cost {
+                latency: 1,
+                size: 4,
+            },
+            mul: Cost {
+                latency: 3,
+                size: 4,
+            },
+            div: Cost {
+                latency: 40,
+                size: 5,
+            },
+            branch: Cost {
+                latency: 8,
+                size: 6,
+            },
+            cmov: Cost {
+                latency: 1,
+                size: 4,
+            },
+        }
+    }
+
+    // `x * 2^k` as one shift.
+    pub fn mul_by_shift(&self) -> bool {
+        self.shift.latency < self.mul.latency
+    }
+
+    // `x / 2^k` as a rounding bias (three shifts and an add on the critical
+    // path) followed by an arithmetic shift.
+    pub fn div_by_shift(&self) -> bool {
+        3 * self.shift.latency + self.add.latency < self.div.latency
+    }
+}
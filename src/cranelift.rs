@@ -0,0 +1,526 @@
+use crate::parser::Type;
+use crate::{CompileOptions, MyError, Node, Os, Parser, PassManager};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::types::{I32, I64, I8};
+use cranelift_codegen::ir::{
+    AbiParam, AtomicRmwOp, Function, InstBuilder, MemFlags, StackSlotData, StackSlotKind,
+    UserFuncName, Value,
+};
+use cranelift_codegen::isa::{self, OwnedTargetIsa};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::collections::HashMap;
+
+// Lowers the AST to Cranelift IR, which Cranelift compiles to an object file
+// or into memory to run. Every value is an i64, with int arithmetic wrapped
+// to 32 bits as the assembly backend does it, and locals live in one frame
+// laid out like the assembly backend's stack.
+// There is no inline assembly or stack protector, and volatile objects are
+// accessed like any other.
+pub struct CraneliftGenerator {
+    parser: Parser,
+    options: CompileOptions,
+    pub passes: PassManager, // the AST passes named in options.passes are run
+}
+
+fn error(e: impl std::fmt::Display) -> MyError {
+    MyError {
+        info: format!("cranelift: {}", e),
+    }
+}
+
+// An error for `what`, at `node` if it is about a part of the source.
+fn unsupported<T>(what: &str, node: Option<(&Parser, &Node)>) -> Result<T, MyError> {
+    let info = format!("{} not supported by the Cranelift backend", what);
+    Err(match node {
+        Some((parser, node)) => parser.error_at(node.span(), info),
+        None => MyError { info },
+    })
+}
+
+impl CraneliftGenerator {
+    pub fn new(parser: Parser, options: CompileOptions) -> Self {
+        Self {
+            parser,
+            options,
+            passes: PassManager::default(),
+        }
+    }
+
+    // Returns an object file for the target defining `main`. The code is
+    // position-independent, so it links into PIE and static executables.
+    pub fn generate(&mut self, nodes: Vec<Node>) -> Result<Vec<u8>, MyError> {
+        let triple = match self.options.target.os {
+            Os::Linux => "x86_64-unknown-linux-gnu",
+            Os::MacOs => "x86_64-apple-darwin",
+        };
+        let builder = isa::lookup_by_name(triple).map_err(error)?;
+        let isa = self.isa(builder, true)?;
+        let builder = ObjectBuilder::new(isa, "main", default_libcall_names()).map_err(error)?;
+        let mut module = ObjectModule::new(builder);
+        self.define(&mut module, nodes)?;
+        module.finish().emit().map_err(error)
+    }
+
+    // Compiles the program for the host into memory and runs its `main`,
+    // returning what it returns.
+    pub fn run(&mut self, nodes: Vec<Node>) -> Result<i32, MyError> {
+        let builder = cranelift_native::builder().map_err(error)?;
+        let isa = self.isa(builder, false)?;
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+        let main = self.define(&mut module, nodes)?;
+        module.finalize_definitions().map_err(error)?;
+        // SAFETY: the finalized code is `main` as defined above, which takes
+        // nothing and returns an i32 by the platform calling convention.
+        let main: extern "C" fn() -> i32 =
+            unsafe { std::mem::transmute(module.get_finalized_function(main)) };
+        let rv = main();
+        // SAFETY: nothing refers to the code any more.
+        unsafe { module.free_memory() };
+        Ok(rv)
+    }
+
+    fn isa(&self, builder: isa::Builder, pic: bool) -> Result<OwnedTargetIsa, MyError> {
+        let mut flags = settings::builder();
+        let opt_level = if self.options.opt_level >= 1 {
+            "speed"
+        } else {
+            "none"
+        };
+        flags.set("opt_level", opt_level).map_err(error)?;
+        flags.set("is_pic", &pic.to_string()).map_err(error)?;
+        builder.finish(settings::Flags::new(flags)).map_err(error)
+    }
+
+    // Declare and define `main` in `module`.
+    fn define<M: Module>(&mut self, module: &mut M, nodes: Vec<Node>) -> Result<FuncId, MyError> {
+        if self.options.stack_protector {
+            return unsupported("-fstack-protector is", None);
+        }
+        let nodes = self
+            .passes
+            .run_ast(&self.options.pipeline(), nodes, &self.parser);
+        let mut signature = module.make_signature();
+        signature.returns.push(AbiParam::new(I32));
+        let main = module
+            .declare_function("main", Linkage::Export, &signature)
+            .map_err(error)?;
+        let mut ctx = module.make_context();
+        ctx.func = Function::with_name_signature(UserFuncName::user(0, main.as_u32()), signature);
+        let mut builder_ctx = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+        let entry = builder.create_block();
+        builder.switch_to_block(entry);
+        // %fp stands in for %rbp, at the top of one frame for all locals
+        let size = self.parser.stack_size as u32;
+        let frame = builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            size,
+            4,
+        ));
+        let start = builder.ins().stack_addr(I64, frame, 0);
+        let fp = builder.ins().iadd_imm(start, size as i64);
+        let mut lowering = Lowering {
+            builder,
+            module,
+            parser: &self.parser,
+            fp,
+            functions: HashMap::new(),
+        };
+        for node in &nodes {
+            lowering.gen_stmt(Some(node))?;
+        }
+        let zero = lowering.builder.ins().iconst(I32, 0);
+        lowering.builder.ins().return_(&[zero]);
+        lowering.builder.seal_all_blocks();
+        lowering.builder.finalize();
+        module.define_function(main, &mut ctx).map_err(error)?;
+        Ok(main)
+    }
+}
+
+struct Lowering<'a, M: Module> {
+    builder: FunctionBuilder<'a>,
+    module: &'a mut M,
+    parser: &'a Parser,
+    fp: Value,
+    functions: HashMap<String, cranelift_codegen::ir::FuncRef>,
+}
+
+impl<M: Module> Lowering<'_, M> {
+    // The integer type an object of `r#type` occupies in memory.
+    fn mem_type(&self, r#type: &Type) -> cranelift_codegen::ir::Type {
+        match r#type.size(&self.parser.target) {
+            1 => I8,
+            4 => I32,
+            _ => I64,
+        }
+    }
+
+    // How a value of `r#type` is passed to and returned from functions,
+    // extended by the caller or callee as the ABI asks.
+    fn abi_param(r#type: &Type) -> AbiParam {
+        match r#type.unqualified() {
+            Type::UChar | Type::Bool => AbiParam::new(I8).uext(),
+            Type::Char | Type::SChar => AbiParam::new(I8).sext(),
+            Type::I32 => AbiParam::new(I32).sext(),
+            _ => AbiParam::new(I64),
+        }
+    }
+
+    // Widen `val` of an object of `r#type` to i64, sign- or zero-extending
+    // as a load would.
+    fn extend(&mut self, val: Value, r#type: &Type) -> Value {
+        if self.builder.func.dfg.value_type(val) == I64 {
+            return val;
+        }
+        if let Type::UChar | Type::Bool = r#type.unqualified() {
+            self.builder.ins().uextend(I64, val)
+        } else {
+            self.builder.ins().sextend(I64, val)
+        }
+    }
+
+    // The result of arithmetic on values of `r#type`: anything narrower
+    // than a long is promoted to an int, which wraps.
+    fn wrap(&mut self, val: Value, r#type: &Type) -> Value {
+        if let Type::Long | Type::I64 | Type::Ptr { .. } | Type::Array { .. } | Type::Func { .. } =
+            r#type.unqualified()
+        {
+            return val;
+        }
+        let val = self.builder.ins().ireduce(I32, val);
+        self.builder.ins().sextend(I64, val)
+    }
+
+    fn truncate(&mut self, val: Value, ty: cranelift_codegen::ir::Type) -> Value {
+        if ty == I64 {
+            return val;
+        }
+        self.builder.ins().ireduce(ty, val)
+    }
+
+    fn is_atomic(&self, node: &Node) -> bool {
+        let r#type = match node {
+            Node::Var { name, .. } => Some(self.parser.locals[name].r#type.clone()),
+            Node::Deref { lhs, .. } => lhs.get_type().and_then(|t| t.base().cloned()),
+            _ => None,
+        };
+        r#type.is_some_and(|t| t.is_atomic())
+    }
+
+    // The address of an lvalue.
+    fn gen_addr(&mut self, node: &Node) -> Result<Value, MyError> {
+        match node {
+            Node::Var { name, .. } => {
+                let offset = self.parser.locals[name].offset as i64;
+                Ok(self.builder.ins().iadd_imm(self.fp, -offset))
+            }
+            Node::Deref { lhs, .. } => self.gen_expr(lhs),
+            _ => panic!("not a lvalue: {:?}", node),
+        }
+    }
+
+    // Load the object of `r#type` at `addr` that `lvalue` designates. An
+    // array is not loaded: its address is its value.
+    fn load(&mut self, addr: Value, r#type: &Type, lvalue: &Node) -> Value {
+        if let Type::Array { .. } = r#type {
+            return addr;
+        }
+        let ty = self.mem_type(r#type);
+        let flags = MemFlags::new();
+        let val = if self.is_atomic(lvalue) {
+            self.builder.ins().atomic_load(ty, flags, addr)
+        } else {
+            self.builder.ins().load(ty, flags, addr, 0)
+        };
+        self.extend(val, r#type)
+    }
+
+    // Store `val` to the object of `r#type` at `addr` that `lvalue`
+    // designates. Returns the value as stored, truncated and widened again.
+    fn store(&mut self, addr: Value, val: Value, r#type: &Type, lvalue: &Node) -> Value {
+        let ty = self.mem_type(r#type);
+        let narrow = self.truncate(val, ty);
+        if self.is_atomic(lvalue) {
+            self.builder
+                .ins()
+                .atomic_store(MemFlags::new(), narrow, addr);
+        } else {
+            self.builder.ins().store(MemFlags::new(), narrow, addr, 0);
+        }
+        self.extend(narrow, r#type)
+    }
+
+    fn gen_expr(&mut self, node: &Node) -> Result<Value, MyError> {
+        match node {
+            Node::Num { val, .. } => Ok(self.builder.ins().iconst(I64, *val)),
+            Node::Neg { lhs, r#type, .. } => {
+                let val = self.gen_expr(lhs)?;
+                let val = self.builder.ins().ineg(val);
+                Ok(self.wrap(val, r#type))
+            }
+            Node::Var { r#type, .. } => {
+                let addr = self.gen_addr(node)?;
+                Ok(self.load(addr, r#type, node))
+            }
+            Node::Deref { lhs, r#type, .. } => {
+                let addr = self.gen_expr(lhs)?;
+                Ok(self.load(addr, r#type, node))
+            }
+            Node::Addr { lhs, .. } => self.gen_addr(lhs),
+            Node::FuncCall {
+                name, args, r#type, ..
+            } => {
+                let params = match &self.parser.functions[name].r#type {
+                    Type::Func { params, .. } => params.clone(),
+                    _ => unreachable!("functions are declared with function types"),
+                };
+                let mut operands = Vec::new();
+                for (arg, param) in args.iter().zip(&params) {
+                    let val = self.gen_expr(arg)?;
+                    let ty = Self::abi_param(param).value_type;
+                    operands.push(self.truncate(val, ty));
+                }
+                let callee = match self.functions.get(name) {
+                    Some(callee) => *callee,
+                    None => {
+                        let mut signature = self.module.make_signature();
+                        signature.params = params.iter().map(Self::abi_param).collect();
+                        signature.returns.push(Self::abi_param(r#type));
+                        let id = self
+                            .module
+                            .declare_function(name, Linkage::Import, &signature)
+                            .map_err(error)?;
+                        let callee = self.module.declare_func_in_func(id, self.builder.func);
+                        self.functions.insert(name.clone(), callee);
+                        callee
+                    }
+                };
+                let call = self.builder.ins().call(callee, &operands);
+                let val = self.builder.inst_results(call)[0];
+                Ok(self.extend(val, r#type))
+            }
+            Node::Assign {
+                lhs, rhs, r#type, ..
+            } => {
+                let addr = self.gen_addr(lhs)?;
+                let val = self.gen_expr(rhs)?;
+                Ok(self.store(addr, val, r#type, lhs))
+            }
+            Node::Exchange {
+                lhs, rhs, r#type, ..
+            }
+            | Node::FetchAdd {
+                lhs, rhs, r#type, ..
+            } => {
+                let addr = self.gen_expr(lhs)?;
+                let val = self.gen_expr(rhs)?;
+                let ty = self.mem_type(r#type);
+                let val = self.truncate(val, ty);
+                let op = if let Node::Exchange { .. } = node {
+                    AtomicRmwOp::Xchg
+                } else {
+                    AtomicRmwOp::Add
+                };
+                let old = self
+                    .builder
+                    .ins()
+                    .atomic_rmw(ty, MemFlags::new(), op, addr, val);
+                Ok(self.extend(old, r#type))
+            }
+            Node::CompareSwap {
+                lhs,
+                old,
+                new,
+                r#type,
+                ..
+            } => {
+                let addr = self.gen_expr(lhs)?;
+                let old = self.gen_expr(old)?;
+                let new = self.gen_expr(new)?;
+                let ty = self.mem_type(r#type);
+                let old = self.truncate(old, ty);
+                let new = self.truncate(new, ty);
+                let val = self
+                    .builder
+                    .ins()
+                    .atomic_cas(MemFlags::new(), addr, old, new);
+                Ok(self.extend(val, r#type))
+            }
+            Node::Add {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Sub {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Mul {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Div {
+                lhs, rhs, r#type, ..
+            } => {
+                // rhs first, as the assembly backend evaluates it
+                let r = self.gen_expr(rhs)?;
+                let l = self.gen_expr(lhs)?;
+                let ins = self.builder.ins();
+                let val = match node {
+                    Node::Add { .. } => ins.iadd(l, r),
+                    Node::Sub { .. } => ins.isub(l, r),
+                    Node::Mul { .. } => ins.imul(l, r),
+                    _ => ins.sdiv(l, r),
+                };
+                Ok(self.wrap(val, r#type))
+            }
+            Node::Eq { lhs, rhs, .. }
+            | Node::Ne { lhs, rhs, .. }
+            | Node::Lt { lhs, rhs, .. }
+            | Node::Le { lhs, rhs, .. } => {
+                // rhs first, as the assembly backend evaluates it
+                let r = self.gen_expr(rhs)?;
+                let l = self.gen_expr(lhs)?;
+                let cc = match node {
+                    Node::Eq { .. } => IntCC::Equal,
+                    Node::Ne { .. } => IntCC::NotEqual,
+                    Node::Lt { .. } => IntCC::SignedLessThan,
+                    _ => IntCC::SignedLessThanOrEqual,
+                };
+                let t = self.builder.ins().icmp(cc, l, r);
+                Ok(self.builder.ins().uextend(I64, t))
+            }
+            _ => panic!("invalid expression, {:?}", node),
+        }
+    }
+
+    fn gen_stmt(&mut self, node: Option<&Node>) -> Result<(), MyError> {
+        let Some(node) = node else {
+            return Ok(());
+        };
+        match node {
+            Node::Return { lhs, .. } => {
+                let val = match lhs {
+                    Some(lhs) => {
+                        let val = self.gen_expr(lhs)?;
+                        self.builder.ins().ireduce(I32, val)
+                    }
+                    None => self.builder.ins().iconst(I32, 0),
+                };
+                self.builder.ins().return_(&[val]);
+                // Anything after the return goes in a block of its own.
+                let dead = self.builder.create_block();
+                self.builder.switch_to_block(dead);
+            }
+            Node::ExprStmt { expr, .. } => {
+                self.gen_expr(expr)?;
+            }
+            Node::If {
+                cond, then, els, ..
+            } => {
+                let then_block = self.builder.create_block();
+                let else_block = self.builder.create_block();
+                let end = self.builder.create_block();
+                let val = self.gen_expr(cond)?;
+                self.builder
+                    .ins()
+                    .brif(val, then_block, &[], else_block, &[]);
+                self.builder.switch_to_block(then_block);
+                self.gen_stmt(then.as_deref())?;
+                self.builder.ins().jump(end, &[]);
+                self.builder.switch_to_block(else_block);
+                self.gen_stmt(els.as_deref())?;
+                self.builder.ins().jump(end, &[]);
+                self.builder.switch_to_block(end);
+            }
+            Node::For {
+                init,
+                cond,
+                inc,
+                then,
+                ..
+            } => {
+                let begin = self.builder.create_block();
+                let body = self.builder.create_block();
+                let end = self.builder.create_block();
+                self.gen_stmt(init.as_deref())?;
+                self.builder.ins().jump(begin, &[]);
+                self.builder.switch_to_block(begin);
+                if let Some(cond) = cond {
+                    let val = self.gen_expr(cond)?;
+                    self.builder.ins().brif(val, body, &[], end, &[]);
+                } else {
+                    self.builder.ins().jump(body, &[]);
+                }
+                self.builder.switch_to_block(body);
+                self.gen_stmt(then.as_deref())?;
+                if let Some(inc) = inc {
+                    self.gen_expr(inc)?;
+                }
+                self.builder.ins().jump(begin, &[]);
+                self.builder.switch_to_block(end);
+            }
+            Node::Block { nodes, .. } => {
+                for node in nodes {
+                    self.gen_stmt(Some(node))?;
+                }
+            }
+            Node::Asm { .. } => {
+                return unsupported("inline assembly is", Some((self.parser, node)))
+            }
+            _ => panic!("invalid statement"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn generator(src: &str, options: CompileOptions) -> (CraneliftGenerator, Vec<Node>) {
+        let (parser, program) = crate::parse(src);
+        (CraneliftGenerator::new(parser, options), program.nodes)
+    }
+
+    fn run(src: &str, options: CompileOptions) -> Result<i32, MyError> {
+        let (mut generator, nodes) = generator(src, options);
+        generator.run(nodes)
+    }
+
+    #[test]
+    fn test_run() {
+        let options = CompileOptions::default();
+        let src = "{ int i=0; int j=0; for (i=0; i<=10; i=i+1) j=i+j; return j; }";
+        assert_eq!(run(src, options.clone()).ok(), Some(55));
+        let src = "int abs(int); { char c=255; int x=3; return abs(-x) + (c==-1); }";
+        assert_eq!(run(src, options.clone()).ok(), Some(4));
+        let src = "{ int x=2147483647; return x+1 < 0; }";
+        assert_eq!(run(src, options.clone()).ok(), Some(1));
+        let src = "{ int x=10; int old=__atomic_fetch_add(&x, 3, 5); return old+x; }";
+        assert_eq!(run(src, options.clone()).ok(), Some(23));
+        let optimized = CompileOptions {
+            opt_level: 2,
+            ..options.clone()
+        };
+        let src = "{ int x=2; if (x<3) __sync_val_compare_and_swap(&x, 2, 12); return x; }";
+        assert_eq!(run(src, optimized).ok(), Some(12));
+        assert!(run("{ asm(\"nop\"); return 0; }", options.clone()).is_err());
+    }
+
+    #[test]
+    fn test_generate() {
+        let (mut generator, nodes) = generator(
+            "int f(int); { return f(1); }",
+            CompileOptions {
+                target: crate::Target::x86_64(),
+                ..CompileOptions::default()
+            },
+        );
+        let object = generator.generate(nodes).expect("cranelift error");
+        assert!(object.starts_with(b"\x7fELF"));
+        let contains = |name: &[u8]| object.windows(name.len()).any(|w| w == name);
+        assert!(contains(b"main\0") && contains(b"f\0"));
+    }
+}
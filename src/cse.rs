@@ -0,0 +1,162 @@
+use crate::cfg::Cfg;
+use crate::ir::{BinOp, Function, Inst, Operand, Reg, Ty};
+use std::collections::HashMap;
+
+// Local common subexpression elimination, run on the IR at -O1 and above.
+// Within a basic block, an instruction computing what an earlier one already
+// has is dropped and its register replaced by the earlier one. Loads count
+// until the next instruction that may write memory.
+
+// What an instruction computes, regardless of where the result goes.
+#[derive(PartialEq, Eq, Hash)]
+enum Expr {
+    LocalAddr(usize),
+    Load(Operand, Ty),
+    Extend(Operand, Ty),
+    Neg(Operand, Ty),
+    Binary(BinOp, Ty, Operand, Operand),
+    Select(Operand, Operand, Operand),
+}
+
+pub(crate) fn eliminate(function: &mut Function) {
+    let cfg = Cfg::new(function);
+    // Registers are used after they are assigned, in instruction order, so
+    // one pass sees every use of a replaced register.
+    let mut replaced: HashMap<Reg, Reg> = HashMap::new();
+    let mut dead = vec![false; function.insts.len()];
+    for block in &cfg.blocks {
+        let mut available: HashMap<Expr, Reg> = HashMap::new();
+        for i in block.insts.clone() {
+            let inst = &mut function.insts[i];
+            for operand in inst.operands_mut() {
+                if let Operand::Reg(reg) = operand {
+                    if let Some(&earlier) = replaced.get(reg) {
+                        *reg = earlier;
+                    }
+                }
+            }
+            if writes_memory(inst) {
+                available.retain(|expr, _| !matches!(expr, Expr::Load(..)));
+            }
+            let (Some(expr), Some(dst)) = (expr(inst), inst.def()) else {
+                continue;
+            };
+            match available.get(&expr) {
+                Some(&earlier) => {
+                    replaced.insert(dst, earlier);
+                    dead[i] = true;
+                }
+                None => {
+                    available.insert(expr, dst);
+                }
+            }
+        }
+    }
+    let mut dead = dead.into_iter();
+    function
+        .insts
+        .retain(|_| !dead.next().expect("one for each instruction"));
+}
+
+fn expr(inst: &Inst) -> Option<Expr> {
+    Some(match *inst {
+        Inst::LocalAddr { offset, .. } => Expr::LocalAddr(offset),
+        Inst::Load {
+            addr,
+            ty,
+            volatile: false,
+            ..
+        } => Expr::Load(addr, ty),
+        Inst::Extend { src, ty, .. } => Expr::Extend(src, ty),
+        Inst::Neg { src, ty, .. } => Expr::Neg(src, ty),
+        Inst::Binary {
+            op, ty, lhs, rhs, ..
+        } => {
+            // a + b is b + a, with the register first
+            let commutes = matches!(op, BinOp::Add | BinOp::Mul | BinOp::Eq | BinOp::Ne);
+            let order = |operand: &Operand| match *operand {
+                Operand::Reg(reg) => (0, reg as i64),
+                Operand::Imm(val) => (1, val),
+            };
+            if commutes && order(&rhs) < order(&lhs) {
+                Expr::Binary(op, ty, rhs, lhs)
+            } else {
+                Expr::Binary(op, ty, lhs, rhs)
+            }
+        }
+        Inst::Select {
+            cond, then, els, ..
+        } => Expr::Select(cond, then, els),
+        _ => return None,
+    })
+}
+
+// Whether `inst` may change memory a load has read, through any address.
+fn writes_memory(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Store { .. }
+            | Inst::Call { .. }
+            | Inst::Exchange { .. }
+            | Inst::FetchAdd { .. }
+            | Inst::CompareSwap { .. }
+            | Inst::Asm(_)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{interpret, CodeGenerator, CompileOptions};
+
+    fn lower(src: &str, opt_level: u8) -> Function {
+        let (parser, program) = crate::parse(src);
+        let options = CompileOptions {
+            opt_level,
+            ..CompileOptions::default()
+        };
+        CodeGenerator::new(parser, options).lower(program.nodes)
+    }
+
+    // Run `function` with f() returning each of `results` in turn.
+    fn run(function: &Function, results: &[i64]) -> Option<i32> {
+        let mut results = results.iter();
+        let mut f = |name: &str, _: &[i64]| match name {
+            "f" => results.next().copied(),
+            _ => None,
+        };
+        interpret(function, &mut f).ok()
+    }
+
+    fn count(function: &Function, text: &str) -> usize {
+        let ir = function.to_string();
+        ir.lines().filter(|line| line.contains(text)).count()
+    }
+
+    #[test]
+    fn test_eliminate() {
+        let src = "int f(); { int x=f(); int y=f(); return (x+y)*(y+x); }";
+        let function = lower(src, 1);
+        assert_eq!(count(&function, " = add "), 1, "{}", function);
+        assert_eq!(count(&function, " = load "), 2, "{}", function);
+        assert_eq!(count(&function, " = local "), 2, "{}", function);
+        assert_eq!(run(&function, &[3, 4]), Some(49));
+        assert_eq!(count(&lower(src, 0), " = add "), 2);
+
+        // x is loaded again after the store, and v every time
+        let src = "int f(); { int x=f(); volatile int v=2; int a=x+v; x=f(); return a+x+v+v; }";
+        let function = lower(src, 1);
+        assert_eq!(count(&function, " = load i32 "), 3, "{}", function);
+        assert_eq!(count(&function, " = load volatile "), 3, "{}", function);
+        assert_eq!(run(&function, &[1, 5]), Some(12));
+    }
+
+    #[test]
+    fn test_blocks() {
+        // a block may be reached without the one before it having run
+        let src = "int f(); { int x=f(); int y=0; if (x) y=x*3; return x*3+y; }";
+        let function = lower(src, 1);
+        assert_eq!(count(&function, " = mul "), 2, "{}", function);
+        assert_eq!(run(&function, &[2]), Some(12));
+    }
+}
@@ -0,0 +1,305 @@
+use std::ops::Range;
+
+// ANSI escapes for --color output.
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const GREEN: &str = "\x1b[1;32m";
+const MAGENTA: &str = "\x1b[1;35m";
+const CYAN: &str = "\x1b[1;36m";
+const RESET: &str = "\x1b[0m";
+
+// A secondary location attached to a diagnostic, such as the previous
+// declaration of a redefined variable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Note {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+// Warnings that can be turned on and off one by one with -W<name> and
+// -Wno-<name>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Warning {
+    NullDereference, // dereference of a pointer known to be null
+    UnusedVariable,  // local that is never referenced
+    UnusedValue,     // expression statement without side effects
+}
+
+impl Warning {
+    pub const ALL: [Warning; 3] = [
+        Warning::NullDereference,
+        Warning::UnusedVariable,
+        Warning::UnusedValue,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Warning::NullDereference => "null-dereference",
+            Warning::UnusedVariable => "unused-variable",
+            Warning::UnusedValue => "unused-value",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Warning> {
+        Self::ALL.into_iter().find(|warning| warning.name() == name)
+    }
+
+    // Whether the warning is on without any -W flag. -Wall turns on the rest.
+    pub fn default_enabled(self) -> bool {
+        matches!(self, Warning::NullDereference)
+    }
+}
+
+// An error, or a warning if `warning` is set, at a primary span of the
+// source, with any number of notes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub span: Range<usize>,
+    pub message: String,
+    pub notes: Vec<Note>,
+    pub warning: Option<Warning>,
+}
+
+impl Diagnostic {
+    pub fn new(span: Range<usize>, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            message: message.into(),
+            notes: Vec::new(),
+            warning: None,
+        }
+    }
+
+    pub fn warning(warning: Warning, span: Range<usize>, message: impl Into<String>) -> Self {
+        Self {
+            warning: Some(warning),
+            ..Self::new(span, message)
+        }
+    }
+
+    pub fn with_note(mut self, span: Range<usize>, message: impl Into<String>) -> Self {
+        self.notes.push(Note {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    // `file:line:col: error: message` (or `warning: message [-W<name>]`) and
+    // the source line with a caret under the column, then the same for each
+    // note. Positions are 1-based, resolved against the preprocessed `source`
+    // and mapped back to the original files with `map`. With `color`
+    // the parts are highlighted with ANSI escapes.
+    pub fn render(&self, map: &LineMap, source: &str, color: bool) -> String {
+        match self.warning {
+            Some(warning) => self.render_as(
+                map,
+                source,
+                ("warning", MAGENTA),
+                &format!(" [-W{}]", warning.name()),
+                color,
+            ),
+            None => self.render_as(map, source, ("error", RED), "", color),
+        }
+    }
+
+    // A warning turned into an error by -Werror, rendered as
+    // `file:line:col: error: message [-Werror=<name>]`.
+    pub fn render_promoted(&self, map: &LineMap, source: &str, color: bool) -> String {
+        let suffix = self.warning.map_or(String::new(), |warning| {
+            format!(" [-Werror={}]", warning.name())
+        });
+        self.render_as(map, source, ("error", RED), &suffix, color)
+    }
+
+    fn render_as(
+        &self,
+        map: &LineMap,
+        source: &str,
+        label: (&str, &str),
+        suffix: &str,
+        color: bool,
+    ) -> String {
+        let mut rv = render_line(
+            map,
+            source,
+            self.span.start,
+            label,
+            &format!("{}{}", self.message, suffix),
+            color,
+        );
+        for note in &self.notes {
+            rv.push('\n');
+            rv.push_str(&render_line(
+                map,
+                source,
+                note.span.start,
+                ("note", CYAN),
+                &note.message,
+                color,
+            ));
+        }
+        rv
+    }
+}
+
+// One `file:line:col: label: message` line followed by the source line and a
+// caret under `offset`.
+fn render_line(
+    map: &LineMap,
+    source: &str,
+    offset: usize,
+    (label, label_style): (&str, &str),
+    message: &str,
+    color: bool,
+) -> String {
+    let paint = |style: &str, text: &str| {
+        if color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    };
+    let (file, line, col) = map.locate(source, offset);
+    let start = offset.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    // keep tabs so the caret lines up however they are displayed
+    let pad: String = source[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "{} {} {}\n{}\n{}{}",
+        paint(BOLD, &format!("{}:{}:{}:", file, line, col)),
+        paint(label_style, &format!("{}:", label)),
+        paint(BOLD, message),
+        &source[line_start..line_end],
+        pad,
+        paint(GREEN, "^")
+    )
+}
+
+// Where each line of preprocessed source came from. The preprocessor adds a
+// marker wherever an #include or a #line directive moves to another file or
+// line; lines before the first marker are lines of `file` as they are.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineMap {
+    pub file: String,
+    markers: Vec<LineMarker>,
+}
+
+// Line `line` of the preprocessed source, and those after it up to the next
+// marker, are lines `source_line` and on of `file`.
+#[derive(Clone, Debug, PartialEq)]
+struct LineMarker {
+    line: usize,
+    file: String,
+    source_line: usize,
+}
+
+impl LineMap {
+    pub fn new(file: impl Into<String>) -> Self {
+        Self {
+            file: file.into(),
+            markers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn mark(&mut self, line: usize, file: &str, source_line: usize) {
+        self.markers.push(LineMarker {
+            line,
+            file: file.to_string(),
+            source_line,
+        });
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.markers.len()
+    }
+
+    // Move the markers from the `from`th on down by `lines` lines, once the
+    // text they were made for is placed after `lines` other lines.
+    pub(crate) fn shift(&mut self, from: usize, lines: usize) {
+        for marker in &mut self.markers[from..] {
+            marker.line += lines;
+        }
+    }
+
+    // The file, 1-based line and column that `offset` into the preprocessed
+    // `source` comes from.
+    pub fn locate(&self, source: &str, offset: usize) -> (&str, usize, usize) {
+        let (line, col) = line_col(source, offset);
+        match self.markers.iter().rev().find(|marker| marker.line <= line) {
+            Some(marker) => (&marker.file, marker.source_line + line - marker.line, col),
+            None => (&self.file, line, col),
+        }
+    }
+}
+
+pub(crate) fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, col)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "{ int x;\n  int x; }";
+        let diagnostic = Diagnostic::new(15..16, "redefinition of 'x'")
+            .with_note(6..7, "previous declaration was here");
+        assert_eq!(
+            diagnostic.render(&LineMap::new("a.c"), source, false),
+            "\
+a.c:2:7: error: redefinition of 'x'
+  int x; }
+      ^
+a.c:1:7: note: previous declaration was here
+{ int x;
+      ^"
+        );
+        assert_eq!(line_col(source, 100), (2, 11));
+        let warning = Diagnostic::warning(Warning::UnusedVariable, 6..7, "unused variable 'x'");
+        assert_eq!(
+            warning.render(&LineMap::new("a.c"), source, false),
+            "a.c:1:7: warning: unused variable 'x' [-Wunused-variable]\n{ int x;\n      ^"
+        );
+        assert_eq!(
+            warning.render_promoted(&LineMap::new("a.c"), source, false),
+            "a.c:1:7: error: unused variable 'x' [-Werror=unused-variable]\n{ int x;\n      ^"
+        );
+        assert_eq!(
+            Diagnostic::new(3..4, "oops").render(&LineMap::new("a.c"), "\tx\ty", true),
+            "\x1b[1ma.c:1:4:\x1b[0m \x1b[1;31merror:\x1b[0m \x1b[1moops\x1b[0m\n\tx\ty\n\t \t\x1b[1;32m^\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_line_map() {
+        let source = "a\nb\nc\nd";
+        let mut map = LineMap::new("main.c");
+        assert_eq!(map.locate(source, 2), ("main.c", 2, 1));
+        map.mark(2, "gen.y", 10);
+        map.mark(4, "main.c", 3);
+        assert_eq!(map.locate(source, 0), ("main.c", 1, 1));
+        assert_eq!(map.locate(source, 4), ("gen.y", 11, 1));
+        assert_eq!(map.locate(source, 6), ("main.c", 3, 1));
+        map.shift(1, 1);
+        assert_eq!(map.locate(source, 6), ("gen.y", 12, 1));
+    }
+
+    #[test]
+    fn test_warning_names() {
+        for warning in Warning::ALL {
+            assert_eq!(Warning::from_name(warning.name()), Some(warning));
+        }
+        assert_eq!(Warning::from_name("unused"), None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+const CONTEXT: usize = 3;
+
+// Renumber `.L.<kind>.<n>` labels in order of first appearance, so an extra
+// `if` early in the program does not show up as a change to every later label.
+pub fn normalize_labels(asm: &str) -> String {
+    let mut numbers: HashMap<&str, usize> = HashMap::new();
+    let mut rv = String::new();
+    let mut rest = asm;
+    while let Some(i) = rest.find(".L.") {
+        let (before, label) = rest.split_at(i);
+        rv.push_str(before);
+        let kind_len = label[3..]
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .map_or(label.len() - 3, |n| n);
+        let after_kind = &label[3 + kind_len..];
+        let digits_len = after_kind
+            .strip_prefix('.')
+            .map(|s| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+            .unwrap_or(0);
+        if digits_len == 0 {
+            rv.push_str(&label[..3 + kind_len]);
+            rest = after_kind;
+            continue;
+        }
+        let digits = &after_kind[1..1 + digits_len];
+        let next = numbers.len() + 1;
+        let n = *numbers.entry(digits).or_insert(next);
+        rv.push_str(&format!("{}.{}", &label[..3 + kind_len], n));
+        rest = &after_kind[1 + digits_len..];
+    }
+    rv.push_str(rest);
+    rv
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Edit {
+    Keep,
+    Remove,
+    Insert,
+}
+
+// Line edit script turning `old` into `new`, from a longest common
+// subsequence table.
+fn edits(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut rv = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            rv.push(Edit::Keep);
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            rv.push(Edit::Remove);
+            i += 1;
+        } else {
+            rv.push(Edit::Insert);
+            j += 1;
+        }
+    }
+    rv
+}
+
+// Unified diff of two texts with three lines of context, or an empty string
+// if they are equal.
+pub fn unified_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let edits = edits(&old, &new);
+    if edits.iter().all(|edit| *edit == Edit::Keep) {
+        return String::new();
+    }
+
+    // Group changes whose context overlaps into hunks of edit indices.
+    let changes: Vec<usize> = (0..edits.len())
+        .filter(|i| edits[*i] != Edit::Keep)
+        .collect();
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for i in changes {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut rv = format!("--- {}\n+++ {}\n", old_name, new_name);
+    // line positions at the start of each edit
+    let (mut old_pos, mut new_pos) = (vec![0], vec![0]);
+    for edit in &edits {
+        let (o, n) = (*old_pos.last().unwrap(), *new_pos.last().unwrap());
+        old_pos.push(o + usize::from(*edit != Edit::Insert));
+        new_pos.push(n + usize::from(*edit != Edit::Remove));
+    }
+    for (start, end) in hunks {
+        let old_len = old_pos[end] - old_pos[start];
+        let new_len = new_pos[end] - new_pos[start];
+        rv.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_pos[start] + usize::from(old_len > 0),
+            old_len,
+            new_pos[start] + usize::from(new_len > 0),
+            new_len
+        ));
+        for i in start..end {
+            let line = match edits[i] {
+                Edit::Keep => format!(" {}", old[old_pos[i]]),
+                Edit::Remove => format!("-{}", old[old_pos[i]]),
+                Edit::Insert => format!("+{}", new[new_pos[i]]),
+            };
+            rv.push_str(&line);
+            rv.push('\n');
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_labels() {
+        let asm = "  je .L.else.7\n.L.else.7:\n.L.end.7:\n.L.begin.9:\n  jmp .L.return\n";
+        assert_eq!(
+            normalize_labels(asm),
+            "  je .L.else.1\n.L.else.1:\n.L.end.1:\n.L.begin.2:\n  jmp .L.return\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified_diff("a", "b", "x\ny\n", "x\ny\n"), "");
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n";
+        assert_eq!(
+            unified_diff("a", "b", old, new),
+            "--- a\n+++ b\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+        assert_eq!(
+            unified_diff("a", "b", "", "x\n"),
+            "--- a\n+++ b\n@@ -0,0 +1,1 @@\n+x\n"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use chibicc_rust::{self, MyError};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+// What -v prints on stderr: each subprocess command line, the temporary
+// files handed to it and how long each phase took.
+#[derive(Clone, Copy, Default)]
+pub struct Trace {
+    pub enabled: bool,
+}
+
+impl Trace {
+    pub fn note(self, message: &str) {
+        if self.enabled {
+            eprintln!("chibicc_rust: {}", message);
+        }
+    }
+
+    // Run `f` as the phase called `phase`, noting how long it took.
+    pub fn time<T>(self, phase: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let rv = f();
+        self.note(&format!(
+            "{}: {:.3} ms",
+            phase,
+            start.elapsed().as_secs_f64() * 1000.0
+        ));
+        rv
+    }
+}
+
+// How the system C compiler driver is run to assemble and link.
+#[derive(Clone, Copy, Default)]
+pub struct DriverOptions {
+    pub static_link: bool,   // -static
+    pub save_temps: bool,    // --save-temps
+    pub integrated_as: bool, // -fintegrated-as: generated assembly skips cc
+}
+
+// A file in the system temporary directory, removed when dropped.
+pub struct TempFile {
+    pub path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(suffix: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let name = format!("chibicc_rust-{}-{}{}", process::id(), n, suffix);
+        Self {
+            path: std::env::temp_dir().join(name),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// What goes to the assembler and linker: the assembly or object generated
+// for a C input, or an assembly or object file given on the command line.
+pub enum LinkInput {
+    Asm(String),
+    Code(Vec<u8>),     // an object, from --backend=cranelift
+    Assembly(PathBuf), // .s
+    Object(PathBuf),   // .o
+}
+
+// Assemble and link `inputs` into the executable `output` with the system C
+// compiler driver, which knows where the C runtime and libraries live.
+pub fn link(
+    inputs: &[LinkInput],
+    output: &Path,
+    driver: DriverOptions,
+    trace: Trace,
+) -> Result<(), MyError> {
+    let (_temps, paths) = input_files(inputs, driver, trace)?;
+    let mut args: Vec<&OsStr> = paths.iter().map(|path| path.as_os_str()).collect();
+    if driver.static_link {
+        args.push("-static".as_ref());
+    }
+    args.extend(["-o".as_ref(), output.as_os_str()]);
+    trace.time("link", || run_cc(&args, trace))
+}
+
+// Assemble `input` into the object file `output`.
+pub fn assemble(
+    input: &LinkInput,
+    output: &Path,
+    driver: DriverOptions,
+    trace: Trace,
+) -> Result<(), MyError> {
+    match (input, driver.integrated_as) {
+        (LinkInput::Asm(asm), true) => return write_object(asm, output, trace),
+        (LinkInput::Code(object), _) => return write_file(output, object),
+        _ => {}
+    }
+    let (_temps, paths) = input_files(std::slice::from_ref(input), driver, trace)?;
+    let args = [
+        "-c".as_ref(),
+        paths[0].as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+    ];
+    trace.time("assemble", || run_cc(&args, trace))
+}
+
+// The file to hand to cc for each of `inputs`, in order. Generated assembly
+// is written to temporary files, assembled already with -fintegrated-as,
+// which are deleted when the first value returned is dropped.
+fn input_files(
+    inputs: &[LinkInput],
+    driver: DriverOptions,
+    trace: Trace,
+) -> Result<(Vec<TempFile>, Vec<PathBuf>), MyError> {
+    let mut temps = Vec::new();
+    let mut paths = Vec::new();
+    for input in inputs {
+        match input {
+            LinkInput::Asm(asm) => {
+                let file = if driver.integrated_as {
+                    let file = TempFile::new(".o");
+                    trace.note(&format!("temporary file {}", file.path.display()));
+                    write_object(asm, &file.path, trace)?;
+                    file
+                } else {
+                    temp_asm(asm, trace)?
+                };
+                paths.push(file.path.clone());
+                temps.push(file);
+            }
+            LinkInput::Code(object) => {
+                let file = TempFile::new(".o");
+                trace.note(&format!("temporary file {}", file.path.display()));
+                write_file(&file.path, object)?;
+                paths.push(file.path.clone());
+                temps.push(file);
+            }
+            LinkInput::Assembly(path) | LinkInput::Object(path) => paths.push(path.clone()),
+        }
+    }
+    Ok((temps, paths))
+}
+
+fn temp_asm(asm: &str, trace: Trace) -> Result<TempFile, MyError> {
+    let file = TempFile::new(".s");
+    trace.note(&format!("temporary file {}", file.path.display()));
+    write_file(&file.path, asm.as_bytes())?;
+    Ok(file)
+}
+
+fn write_object(asm: &str, output: &Path, trace: Trace) -> Result<(), MyError> {
+    let object = trace.time("assemble", || chibicc_rust::assemble(asm))?;
+    write_file(output, &object)
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), MyError> {
+    fs::write(path, contents).map_err(|e| MyError {
+        info: format!("{}: {}", path.display(), e),
+    })
+}
+
+fn run_cc(args: &[&OsStr], trace: Trace) -> Result<(), MyError> {
+    let command: Vec<_> = args.iter().map(|arg| arg.to_string_lossy()).collect();
+    trace.note(&format!("cc {}", command.join(" ")));
+    let status = process::Command::new("cc")
+        .args(args)
+        .status()
+        .map_err(|e| MyError {
+            info: format!("cc: {}", e),
+        })?;
+    if !status.success() {
+        return Err(MyError {
+            info: format!("cc failed ({})", status),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_temp_file() {
+        let a = TempFile::new(".s");
+        let b = TempFile::new(".s");
+        assert_ne!(a.path, b.path);
+        fs::write(&a.path, "x").expect("write error");
+        let path = a.path.clone();
+        drop(a);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::parser::Type;
+use crate::x86_64::Line;
+use crate::Target;
+
+// DWARF 4 debug info for -g beside the line table the assembler builds from
+// .loc: the compile unit, the function, its locals and their types, so a
+// debugger can print variables. Written as ELF assembler directives.
+
+// Abbreviation codes, as declared in .debug_abbrev below.
+const COMPILE_UNIT: u8 = 1;
+const SUBPROGRAM: u8 = 2;
+const VARIABLE: u8 = 3;
+const BASE_TYPE: u8 = 4;
+const POINTER_TYPE: u8 = 5;
+const ARRAY_TYPE: u8 = 6;
+const SUBRANGE_TYPE: u8 = 7;
+const SUBROUTINE_TYPE: u8 = 8;
+
+// An abbreviation: its code, the tag, whether it has children, then the
+// attributes and their forms.
+type Abbrev = (u8, u8, bool, &'static [(u8, u8)]);
+
+const ABBREVS: [Abbrev; 8] = [
+    (
+        COMPILE_UNIT,
+        0x11,
+        true,
+        // producer, language, name, low_pc, high_pc, stmt_list
+        &[
+            (0x25, 0x08),
+            (0x13, 0x0b),
+            (0x03, 0x08),
+            (0x11, 0x01),
+            (0x12, 0x07),
+            (0x10, 0x17),
+        ],
+    ),
+    (
+        SUBPROGRAM,
+        0x2e,
+        true,
+        // name, external, type, low_pc, high_pc, frame_base
+        &[
+            (0x03, 0x08),
+            (0x3f, 0x19),
+            (0x49, 0x13),
+            (0x11, 0x01),
+            (0x12, 0x07),
+            (0x40, 0x18),
+        ],
+    ),
+    // name, type, location
+    (
+        VARIABLE,
+        0x34,
+        false,
+        &[(0x03, 0x08), (0x49, 0x13), (0x02, 0x18)],
+    ),
+    // name, encoding, byte_size
+    (
+        BASE_TYPE,
+        0x24,
+        false,
+        &[(0x03, 0x08), (0x3e, 0x0b), (0x0b, 0x0b)],
+    ),
+    // byte_size, type
+    (POINTER_TYPE, 0x0f, false, &[(0x0b, 0x0b), (0x49, 0x13)]),
+    (ARRAY_TYPE, 0x01, true, &[(0x49, 0x13)]),
+    // count
+    (SUBRANGE_TYPE, 0x21, false, &[(0x37, 0x0f)]),
+    (SUBROUTINE_TYPE, 0x15, false, &[(0x49, 0x13)]),
+];
+
+const DW_LANG_C99: u8 = 0x0c;
+const DW_OP_FBREG: u8 = 0x91;
+const DW_OP_CALL_FRAME_CFA: u8 = 0x9c;
+
+// A local as the debugger finds it: `cfa_offset` bytes from the canonical
+// frame address, which is %rsp before the call.
+pub(crate) struct Variable<'a> {
+    pub name: &'a str,
+    pub r#type: &'a Type,
+    pub cfa_offset: i64,
+}
+
+// The debug sections for `function`, an int function running from its
+// symbol to `end_label`, compiled from `file`.
+pub(crate) fn debug_info(
+    function: &str,
+    end_label: &str,
+    file: &str,
+    variables: &[Variable],
+    target: &Target,
+) -> Vec<Line> {
+    let mut types = Vec::new();
+    collect(&mut types, &Type::I32);
+    for var in variables {
+        collect(&mut types, var.r#type);
+    }
+    let type_ref = |r#type: &Type| {
+        let i = types
+            .iter()
+            .position(|t| t == r#type.unqualified())
+            .expect("collected above");
+        format!(".long .L.debug_type.{} - .L.debug_info", i)
+    };
+    let mut out = Writer::default();
+
+    out.section(".debug_abbrev");
+    for (code, tag, children, attrs) in ABBREVS {
+        out.uleb128(code);
+        out.uleb128(tag);
+        out.byte(children as u8);
+        for (name, form) in attrs {
+            out.uleb128(*name);
+            out.uleb128(*form);
+        }
+        out.byte(0);
+        out.byte(0);
+    }
+    out.byte(0);
+
+    out.section(".debug_info");
+    out.directive(".long .L.debug_info_end - .L.debug_info_start");
+    out.label(".L.debug_info_start");
+    out.directive(".value 4");
+    out.directive(".long .L.debug_abbrev");
+    out.byte(8); // address size
+    let range = |out: &mut Writer| {
+        out.directive(&format!(".quad {}", function));
+        out.directive(&format!(".quad {} - {}", end_label, function));
+    };
+
+    out.uleb128(COMPILE_UNIT);
+    out.string("chibicc_rust");
+    out.byte(DW_LANG_C99);
+    out.string(file);
+    range(&mut out);
+    out.directive(".long .L.debug_line");
+
+    out.uleb128(SUBPROGRAM);
+    out.string(function);
+    out.directive(&type_ref(&Type::I32));
+    range(&mut out);
+    out.uleb128(1);
+    out.byte(DW_OP_CALL_FRAME_CFA);
+    for var in variables {
+        out.uleb128(VARIABLE);
+        out.string(var.name);
+        out.directive(&type_ref(var.r#type));
+        out.uleb128(1 + sleb128_len(var.cfa_offset) as u8);
+        out.byte(DW_OP_FBREG);
+        out.directive(&format!(".sleb128 {}", var.cfa_offset));
+    }
+    out.byte(0);
+
+    for (i, r#type) in types.iter().enumerate() {
+        out.label(&format!(".L.debug_type.{}", i));
+        match r#type {
+            Type::Ptr { base } => {
+                out.uleb128(POINTER_TYPE);
+                out.byte(r#type.size(target) as u8);
+                out.directive(&type_ref(base));
+            }
+            Type::Array { base, len } => {
+                out.uleb128(ARRAY_TYPE);
+                out.directive(&type_ref(base));
+                out.uleb128(SUBRANGE_TYPE);
+                out.directive(&format!(".uleb128 {}", len));
+                out.byte(0);
+            }
+            Type::Func { ret, .. } => {
+                out.uleb128(SUBROUTINE_TYPE);
+                out.directive(&type_ref(ret));
+            }
+            _ => {
+                out.uleb128(BASE_TYPE);
+                out.string(&r#type.to_string());
+                // DW_ATE_boolean, signed, signed_char and unsigned_char
+                out.byte(match r#type {
+                    Type::Bool => 0x02,
+                    Type::I32 | Type::Long | Type::I64 => 0x05,
+                    Type::Char | Type::SChar => 0x06,
+                    _ => 0x08,
+                });
+                out.byte(r#type.size(target) as u8);
+            }
+        }
+    }
+    out.byte(0);
+    out.label(".L.debug_info_end");
+
+    // The assembler fills in the line table after the label.
+    out.section(".debug_line");
+    out.lines
+}
+
+// Assembler lines for the debug sections.
+#[derive(Default)]
+struct Writer {
+    lines: Vec<Line>,
+}
+
+impl Writer {
+    // Start `section`, labelled with its name, e.g. .L.debug_info.
+    fn section(&mut self, section: &str) {
+        self.directive(&format!(".section {},\"\",@progbits", section));
+        self.label(&format!(".L{}", section));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.lines.push(Line::Label(label.to_string()));
+    }
+
+    fn directive(&mut self, text: &str) {
+        self.lines.push(Line::Directive(text.to_string()));
+    }
+
+    fn byte(&mut self, val: u8) {
+        self.directive(&format!(".byte {:#x}", val));
+    }
+
+    fn uleb128(&mut self, val: u8) {
+        self.directive(&format!(".uleb128 {:#x}", val));
+    }
+
+    fn string(&mut self, text: &str) {
+        self.directive(&format!(".string {:?}", text));
+    }
+}
+
+// Add `r#type` and the types it is made from to `types`, once each, leaving
+// out qualifiers.
+fn collect(types: &mut Vec<Type>, r#type: &Type) {
+    let r#type = r#type.unqualified();
+    if types.contains(r#type) {
+        return;
+    }
+    types.push(r#type.clone());
+    match r#type {
+        Type::Ptr { base } | Type::Array { base, .. } => collect(types, base),
+        Type::Func { ret, .. } => collect(types, ret),
+        _ => {}
+    }
+}
+
+// Bytes in the signed LEB128 encoding of `val`.
+fn sleb128_len(mut val: i64) -> usize {
+    let mut len = 1;
+    loop {
+        let sign = val & 0x40 != 0;
+        val >>= 7;
+        if (val == 0 && !sign) || (val == -1 && sign) {
+            return len;
+        }
+        len += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_debug_info() {
+        let array = Type::Array {
+            base: Box::new(Type::Char),
+            len: 4,
+        };
+        let variables = [
+            Variable {
+                name: "x",
+                r#type: &Type::I32,
+                cfa_offset: -20,
+            },
+            Variable {
+                name: "s",
+                r#type: &array,
+                cfa_offset: -200,
+            },
+        ];
+        let target = Target::host();
+        let lines = debug_info("main", ".L.func_end.main", "a.c", &variables, &target);
+        let asm = crate::x86_64::print(&lines);
+        // x is an int, the type main returns, so they share a DIE
+        assert!(
+            asm.contains(
+                "  .string \"x\"\n  .long .L.debug_type.0 - .L.debug_info\n  .uleb128 0x2\n  .byte 0x91\n  .sleb128 -20\n"
+            ),
+            "{}",
+            asm
+        );
+        assert!(
+            asm.contains("  .uleb128 0x3\n  .byte 0x91\n  .sleb128 -200\n"),
+            "{}",
+            asm
+        );
+        assert!(asm.contains(".L.debug_type.1:\n  .uleb128 0x6\n  .long .L.debug_type.2 - .L.debug_info\n  .uleb128 0x7\n  .uleb128 4\n"), "{}", asm);
+        assert!(asm.contains("  .quad .L.func_end.main - main\n"), "{}", asm);
+        assert!(asm.ends_with("  .section .debug_line,\"\",@progbits\n.L.debug_line:\n"));
+    }
+
+    #[test]
+    fn test_sleb128_len() {
+        assert_eq!(sleb128_len(0), 1);
+        assert_eq!(sleb128_len(63), 1);
+        assert_eq!(sleb128_len(64), 2);
+        assert_eq!(sleb128_len(-64), 1);
+        assert_eq!(sleb128_len(-65), 2);
+        assert_eq!(sleb128_len(-8192), 2);
+        assert_eq!(sleb128_len(-8193), 3);
+    }
+}
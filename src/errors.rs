@@ -3,7 +3,6 @@ pub struct MyError {
     pub info: String,
 }
 
-
 impl std::fmt::Display for MyError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "MyError: {}", self.info)
@@ -0,0 +1,236 @@
+use crate::ir::{BinOp, Function, Inst, Operand, Reg, Ty};
+use crate::CostModel;
+use std::collections::HashMap;
+
+// If-conversion, run on the IR at -O2. `if (c) x = a; else x = b;`, or the
+// same without the else, is lowered to a branch around stores to one local.
+// When both values are cheap and cannot trap, so evaluating them
+// unconditionally is safe and beats a branch, both are computed and the one
+// to store is picked with a select, which becomes a conditional move.
+
+// One side of the branch: the value it stores, where, and the number of
+// simple operations computing it takes.
+struct Arm {
+    store: Option<(Operand, usize, Ty, Operand)>, // address, local, width, value
+    cost: u32,
+}
+
+pub(crate) fn convert(function: &mut Function, cost_model: &CostModel) {
+    let locals: HashMap<Reg, usize> = function
+        .insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::LocalAddr { dst, offset } => Some((*dst, *offset)),
+            _ => None,
+        })
+        .collect();
+    let mut i = 0;
+    while i < function.insts.len() {
+        match diamond(function, i, &locals, cost_model) {
+            Some((end, insts)) => {
+                let len = insts.len();
+                function.insts.splice(i..=end, insts);
+                i += len;
+            }
+            None => i += 1,
+        }
+    }
+}
+
+// If the branch at `i` can be converted, the index of the label it ends at
+// and the instructions to replace it with.
+fn diamond(
+    function: &mut Function,
+    i: usize,
+    locals: &HashMap<Reg, usize>,
+    cost_model: &CostModel,
+) -> Option<(usize, Vec<Inst>)> {
+    let insts = &function.insts;
+    let Inst::JumpIfZero { cond, target: els } = &insts[i] else {
+        return None;
+    };
+    let jump = i + insts[i..]
+        .iter()
+        .position(|inst| matches!(inst, Inst::Jump(_)))?;
+    let Inst::Jump(end) = &insts[jump] else {
+        unreachable!()
+    };
+    if insts.get(jump + 1) != Some(&Inst::Label(els.clone())) {
+        return None;
+    }
+    let label = jump
+        + 1
+        + insts[jump + 1..]
+            .iter()
+            .position(|inst| *inst == Inst::Label(end.clone()))?;
+    // nothing else may jump into the middle
+    if jumps_to(function, els) != 1 || jumps_to(function, end) != 1 {
+        return None;
+    }
+    let then = arm(&insts[i + 1..jump], locals)?;
+    let els = arm(&insts[jump + 2..label], locals)?;
+    let (addr, local, ty, then_val) = then.store?;
+    let els_cost = match els.store {
+        Some((_, els_local, els_ty, _)) if (els_local, els_ty) != (local, ty) => return None,
+        Some(_) => els.cost,
+        None if els.cost > 0 => return None,
+        None => 1, // x is loaded to store it back
+    };
+    let add = cost_model.add.latency;
+    let branchless = (then.cost + els_cost) * add + cost_model.cmov.latency;
+    let branchy = then.cost.max(els_cost) * add + cost_model.branch.latency;
+    if branchless > branchy {
+        return None;
+    }
+
+    let cond = *cond;
+    let mut rv: Vec<Inst> = insts[i + 1..jump]
+        .iter()
+        .chain(&insts[jump + 2..label])
+        .filter(|inst| !matches!(inst, Inst::Store { .. }))
+        .cloned()
+        .collect();
+    let els_val = match els.store {
+        Some((_, _, _, val)) => val,
+        None => {
+            let dst = function.regs;
+            function.regs += 1;
+            rv.push(Inst::Load {
+                dst,
+                addr,
+                ty,
+                volatile: false,
+            });
+            Operand::Reg(dst)
+        }
+    };
+    let dst = function.regs;
+    function.regs += 1;
+    rv.push(Inst::Select {
+        dst,
+        cond,
+        then: then_val,
+        els: els_val,
+    });
+    rv.push(Inst::Store {
+        addr,
+        src: Operand::Reg(dst),
+        ty,
+        atomic: false,
+        volatile: false,
+    });
+    Some((label, rv))
+}
+
+// The number of jumps to `label`.
+fn jumps_to(function: &Function, label: &str) -> usize {
+    function
+        .insts
+        .iter()
+        .filter(|inst| match inst {
+            Inst::Jump(target) | Inst::JumpIfZero { target, .. } => target == label,
+            _ => false,
+        })
+        .count()
+}
+
+// `insts` as an arm, or None if it does anything but compute a value and
+// store it to a plain local last: a call, a division, a load through a
+// pointer or of a volatile, all of which may trap or be seen.
+fn arm(insts: &[Inst], locals: &HashMap<Reg, usize>) -> Option<Arm> {
+    let mut arm = Arm {
+        store: None,
+        cost: 0,
+    };
+    for inst in insts {
+        if arm.store.is_some()
+            && !matches!(inst, Inst::Loc { .. } | Inst::Comment(_) | Inst::Span(_))
+        {
+            return None;
+        }
+        match inst {
+            Inst::Loc { .. } | Inst::Comment(_) | Inst::Span(_) | Inst::LocalAddr { .. } => {}
+            Inst::Load {
+                addr: Operand::Reg(addr),
+                volatile: false,
+                ..
+            } if locals.contains_key(addr) => arm.cost += 1,
+            Inst::Copy { .. } | Inst::Extend { .. } | Inst::Neg { .. } => arm.cost += 1,
+            Inst::Binary { op, .. } if *op != BinOp::Div => arm.cost += 1,
+            Inst::Store {
+                addr: addr @ Operand::Reg(reg),
+                src,
+                ty,
+                atomic: false,
+                volatile: false,
+            } => arm.store = Some((*addr, *locals.get(reg)?, *ty, *src)),
+            _ => return None,
+        }
+    }
+    Some(arm)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{interpret, CodeGenerator, CompileOptions};
+
+    fn lower(src: &str, passes: &[&str]) -> Function {
+        let (parser, program) = crate::parse(src);
+        let options = CompileOptions {
+            passes: Some(passes.iter().map(|name| name.to_string()).collect()),
+            ..CompileOptions::default()
+        };
+        CodeGenerator::new(parser, options).lower(program.nodes)
+    }
+
+    // Run `function` with f() returning `result`.
+    fn run(function: &Function, result: i64) -> Option<i32> {
+        let mut f = |name: &str, _: &[i64]| (name == "f").then_some(result);
+        interpret(function, &mut f).ok()
+    }
+
+    fn selects(function: &Function) -> usize {
+        let ir = function.to_string();
+        ir.lines()
+            .filter(|line| line.contains(" = select "))
+            .count()
+    }
+
+    #[test]
+    fn test_convert() {
+        for src in [
+            "int f(); { int x; int y=f(); if (y<2) x=y; else x=y+1; return x; }",
+            "int f(); { int x=5; int y=f(); if (y<2) { x=y*2; } return x; }",
+            "int f(); { char x=5; int y=f(); if (y<2) x=-y; else x=300; return x; }",
+        ] {
+            let function = lower(src, &["ifcvt"]);
+            assert_eq!(selects(&function), 1, "{}\n{}", src, function);
+            assert!(!function.to_string().contains("jump"), "{}", function);
+            let plain = lower(src, &[]);
+            for y in [0, 1, 2, 3] {
+                assert_eq!(run(&function, y), run(&plain, y), "{} with y={}", src, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_not_converted() {
+        for src in [
+            // volatile reads and writes, and loads through pointers, are seen
+            "int f(); { volatile int v=1; int x; int y=f(); if (y<2) x=v; else x=y; return x; }",
+            "int f(); { volatile int x=1; int y=f(); if (y<2) x=y; return x; }",
+            "int f(); { int a=1; int *p=&a; int x; int y=f(); if (y) x=*p; else x=y; return x; }",
+            // division may trap, and calls have side effects
+            "int f(); { int x; int y=f(); if (y) x=6/y; else x=0; return x; }",
+            "int f(); { int x; int y=f(); if (y) x=f(); else x=0; return x; }",
+            // different locals
+            "int f(); { int x=0; int z=0; int y=f(); if (y) x=1; else z=1; return x+z; }",
+            // too much work for a conditional move
+            "int f(); { int x; int y=f(); if (y) x=y*y*y*y*y; else x=y+y+y+y+y; return x; }",
+        ] {
+            let function = lower(src, &["ifcvt"]);
+            assert_eq!(selects(&function), 0, "{}\n{}", src, function);
+        }
+    }
+}
@@ -0,0 +1,3 @@
+#ifndef __STDARG_H
+#define __STDARG_H
+#endif
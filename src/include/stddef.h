@@ -0,0 +1,6 @@
+#ifndef __STDDEF_H
+#define __STDDEF_H
+#define NULL 0
+#define size_t long
+#define ptrdiff_t long
+#endif
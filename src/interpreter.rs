@@ -0,0 +1,316 @@
+use crate::ir::{BinOp, Function, Inst, Operand, Ty};
+use crate::MyError;
+use std::collections::HashMap;
+
+// Running the IR directly, with no assembler or C compiler, so programs can
+// be checked end to end on any host. The frame is a byte array below a
+// made-up frame pointer; calls go to `externs`, which is given the argument
+// values and returns the result, or None for a function it doesn't know.
+
+// Where the frame pointer seems to be. Addresses are this minus an offset.
+const FRAME_POINTER: i64 = 0x7fff_0000;
+
+// Run `function` and return what it returns; a function that falls off its
+// end returns 0, as main does.
+pub fn interpret(
+    function: &Function,
+    externs: &mut dyn FnMut(&str, &[i64]) -> Option<i64>,
+) -> Result<i32, MyError> {
+    let labels: HashMap<&str, usize> = function
+        .insts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, inst)| match inst {
+            Inst::Label(label) => Some((label.as_str(), i)),
+            _ => None,
+        })
+        .collect();
+    let jump = |label: &str| {
+        labels.get(label).copied().ok_or_else(|| MyError {
+            info: format!("interpreter: no label {}", label),
+        })
+    };
+    let mut frame = Frame {
+        bytes: vec![0; function.frame_size],
+    };
+    let mut regs = vec![0; function.regs];
+    // the labels of the block being run and the one before it, for phis
+    let (mut block, mut from): (Option<&str>, Option<&str>) = (None, None);
+    let mut pc = 0;
+    while let Some(inst) = function.insts.get(pc) {
+        pc += 1;
+        let value = |operand: &Operand| match operand {
+            Operand::Reg(reg) => regs[*reg],
+            Operand::Imm(val) => *val,
+        };
+        let result = match inst {
+            Inst::Copy { src, .. } => value(src),
+            Inst::LocalAddr { offset, .. } => FRAME_POINTER - *offset as i64,
+            Inst::Load { addr, ty, .. } => frame.load(value(addr), *ty)?,
+            Inst::Store { addr, src, ty, .. } => {
+                frame.store(value(addr), value(src), *ty)?;
+                continue;
+            }
+            Inst::Extend { src, ty, .. } => extend(value(src), *ty),
+            Inst::Neg { src, ty, .. } => extend(value(src).wrapping_neg(), *ty),
+            Inst::Binary {
+                op, ty, lhs, rhs, ..
+            } => binary(*op, *ty, value(lhs), value(rhs))?,
+            Inst::Select {
+                cond, then, els, ..
+            } => {
+                if value(cond) != 0 {
+                    value(then)
+                } else {
+                    value(els)
+                }
+            }
+            Inst::Call {
+                name, args, ret, ..
+            } => {
+                let args: Vec<i64> = args.iter().map(value).collect();
+                let val = externs(name, &args).ok_or_else(|| MyError {
+                    info: format!("interpreter: undefined function {}", name),
+                })?;
+                extend(val, *ret)
+            }
+            Inst::Exchange { addr, src, ty, .. } | Inst::FetchAdd { addr, src, ty, .. } => {
+                let (addr, src) = (value(addr), value(src));
+                let old = frame.load(addr, *ty)?;
+                let new = match inst {
+                    Inst::Exchange { .. } => src,
+                    _ => old.wrapping_add(src),
+                };
+                frame.store(addr, new, *ty)?;
+                old
+            }
+            Inst::CompareSwap {
+                addr, old, new, ty, ..
+            } => {
+                let addr = value(addr);
+                let current = frame.load(addr, *ty)?;
+                if current == extend(value(old), *ty) {
+                    frame.store(addr, value(new), *ty)?;
+                }
+                current
+            }
+            Inst::Phi { .. } => {
+                // the phis at the top of a block all take their values at once
+                let mut values = Vec::new();
+                for inst in &function.insts[pc - 1..] {
+                    let Inst::Phi { dst, args } = inst else {
+                        break;
+                    };
+                    let (_, arg) = args
+                        .iter()
+                        .find(|(label, _)| Some(label.as_str()) == from)
+                        .ok_or_else(|| MyError {
+                            info: format!("interpreter: phi %{} has no value for {:?}", dst, from),
+                        })?;
+                    values.push((*dst, value(arg)));
+                }
+                pc += values.len() - 1;
+                for (dst, val) in values {
+                    regs[dst] = val;
+                }
+                continue;
+            }
+            Inst::Label(label) => {
+                (from, block) = (block, Some(label.as_str()));
+                continue;
+            }
+            Inst::Loc { .. } | Inst::Comment(_) | Inst::Span(_) => continue,
+            Inst::Jump(label) => {
+                pc = jump(label)?;
+                continue;
+            }
+            Inst::JumpIfZero { cond, target } => {
+                if value(cond) == 0 {
+                    pc = jump(target)?;
+                }
+                continue;
+            }
+            Inst::Ret(val) => return Ok(val.as_ref().map_or(0, value) as i32),
+            Inst::Asm(_) => {
+                return Err(MyError {
+                    info: "interpreter: inline assembly is not supported".to_string(),
+                })
+            }
+        };
+        let dst = inst
+            .def()
+            .expect("only instructions with a result get here");
+        regs[dst] = result;
+    }
+    Ok(0)
+}
+
+// The low bits of `val` that `ty` covers, sign- or zero-extended.
+fn extend(val: i64, ty: Ty) -> i64 {
+    match ty {
+        Ty::I8 => val as i8 as i64,
+        Ty::U8 => val as u8 as i64,
+        Ty::I32 => val as i32 as i64,
+        Ty::I64 => val,
+    }
+}
+
+// What the x86-64 backend computes for `op`, including how shift counts are
+// masked. Division traps there, so it is an error here.
+fn binary(op: BinOp, ty: Ty, lhs: i64, rhs: i64) -> Result<i64, MyError> {
+    let (lhs, rhs) = (extend(lhs, ty), extend(rhs, ty));
+    let bits = if ty == Ty::I32 { 32 } else { 64 };
+    let count = (rhs & (bits - 1)) as u32;
+    let val = match op {
+        BinOp::Add => lhs.wrapping_add(rhs),
+        BinOp::Sub => lhs.wrapping_sub(rhs),
+        BinOp::Mul => lhs.wrapping_mul(rhs),
+        BinOp::Div => {
+            let min = if bits == 32 {
+                i32::MIN as i64
+            } else {
+                i64::MIN
+            };
+            if rhs == 0 || (lhs == min && rhs == -1) {
+                return Err(MyError {
+                    info: "interpreter: division overflow".to_string(),
+                });
+            }
+            lhs / rhs
+        }
+        BinOp::Shl => lhs << count,
+        BinOp::Shr if bits == 32 => (lhs as u32 >> count) as i64,
+        BinOp::Shr => (lhs as u64 >> count) as i64,
+        BinOp::Sar => lhs >> count,
+        BinOp::Eq => return Ok((lhs == rhs) as i64),
+        BinOp::Ne => return Ok((lhs != rhs) as i64),
+        BinOp::Lt => return Ok((lhs < rhs) as i64),
+        BinOp::Le => return Ok((lhs <= rhs) as i64),
+    };
+    Ok(extend(val, ty))
+}
+
+// The function's locals, the only memory the IR can reach.
+struct Frame {
+    bytes: Vec<u8>,
+}
+
+impl Frame {
+    fn range(&self, addr: i64, ty: Ty) -> Result<std::ops::Range<usize>, MyError> {
+        let len = match ty {
+            Ty::I8 | Ty::U8 => 1,
+            Ty::I32 => 4,
+            Ty::I64 => 8,
+        };
+        let start = addr - (FRAME_POINTER - self.bytes.len() as i64);
+        match usize::try_from(start) {
+            Ok(start) if start + len <= self.bytes.len() => Ok(start..start + len),
+            _ => Err(MyError {
+                info: format!("interpreter: access to {:#x} is outside the frame", addr),
+            }),
+        }
+    }
+
+    fn load(&self, addr: i64, ty: Ty) -> Result<i64, MyError> {
+        let mut bytes = [0; 8];
+        let range = self.range(addr, ty)?;
+        bytes[..range.len()].copy_from_slice(&self.bytes[range]);
+        Ok(extend(i64::from_le_bytes(bytes), ty))
+    }
+
+    fn store(&mut self, addr: i64, val: i64, ty: Ty) -> Result<(), MyError> {
+        let range = self.range(addr, ty)?;
+        let len = range.len();
+        self.bytes[range].copy_from_slice(&val.to_le_bytes()[..len]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CodeGenerator, CompileOptions};
+
+    fn lower(src: &str, opt_level: u8) -> Function {
+        let (parser, program) = crate::parse(src);
+        let options = CompileOptions {
+            opt_level,
+            ..CompileOptions::default()
+        };
+        CodeGenerator::new(parser, options).lower(program.nodes)
+    }
+
+    fn libc(name: &str, args: &[i64]) -> Option<i64> {
+        match (name, args) {
+            ("abs", [x]) => Some((*x as i32).wrapping_abs() as i64),
+            _ => None,
+        }
+    }
+
+    // Each program gives the same result interpreted at every level and,
+    // where the host can run it, as machine code.
+    #[test]
+    fn test_differential() {
+        let programs = [
+            ("{ return 5+6*7; }", 47),
+            ("{ int x=-9; return -(x/4); }", 2),
+            (
+                "{ int i=0; int j=0; for (i=0; i<=10; i=i+1) j=i+j; return j; }",
+                55,
+            ),
+            ("{ int x=2147483647; return x+1<0; }", 1),
+            ("{ char c; c=300; return c*4+1; }", 177),
+            ("{ unsigned char c; c=255; c=c+1; return c; }", 0),
+            ("{ long long x=1099511627776; int y=2; return x*y/x; }", 2),
+            ("{ int a=3; int *p=&a; *p=*p+4; return a; }", 7),
+            (
+                "{ int x=0; if (x) return 2; else if (x==0) return 3; return 4; }",
+                3,
+            ),
+            ("int abs(int); { return abs(-5) + abs(6); }", 11),
+            (
+                "{ int x=10; int old=__atomic_fetch_add(&x, 3, 5); return old+x; }",
+                23,
+            ),
+            (
+                "{ char c=4; int old=__atomic_exchange_n(&c, 9, 5); return old+c; }",
+                13,
+            ),
+        ];
+        for (src, expected) in programs {
+            for opt_level in 0..=2 {
+                let function = lower(src, opt_level);
+                let result = interpret(&function, &mut libc).map_err(|e| e.info);
+                assert_eq!(result, Ok(expected), "{} at -O{}", src, opt_level);
+                if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+                    let options = CompileOptions {
+                        opt_level,
+                        ..CompileOptions::default()
+                    };
+                    let (parser, program) = crate::parse(src);
+                    let asm = CodeGenerator::new(parser, options).generate(program.nodes);
+                    assert_eq!(crate::run(&asm).ok(), Some(expected), "{}", src);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |src: &str| interpret(&lower(src, 0), &mut libc).err().map(|e| e.info);
+        assert_eq!(
+            error("int nowhere(); { return nowhere(); }").as_deref(),
+            Some("interpreter: undefined function nowhere")
+        );
+        assert_eq!(
+            error("{ int x=0; return 1/x; }").as_deref(),
+            Some("interpreter: division overflow")
+        );
+        assert_eq!(
+            error("{ asm(\"nop\"); return 0; }").as_deref(),
+            Some("interpreter: inline assembly is not supported")
+        );
+        assert!(error("{ int a; return *(&a+8); }")
+            .is_some_and(|info| info.ends_with("is outside the frame")));
+    }
+}
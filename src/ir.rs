@@ -0,0 +1,590 @@
+use crate::cfg::Cfg;
+use crate::MyError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+// A three-address IR between the AST and machine code. Values live in an
+// unlimited number of virtual registers, each assigned by one instruction;
+// locals stay in the frame laid out by the parser and are only reached
+// through their addresses, until the ssa module promotes them to registers
+// joined by phis. Control flow is labels and jumps.
+
+// A virtual register, numbered from 0 in each function.
+pub type Reg = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(i64),
+}
+
+// How many bytes a memory access or an extension covers, and whether they
+// are sign- or zero-extended to 64 bits. Arithmetic is done in I32 or I64;
+// an I32 result is sign-extended to 64 bits like a loaded int.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Ty {
+    I8,
+    U8,
+    I32,
+    I64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Shl,
+    Shr, // logical
+    Sar, // arithmetic
+    Eq,
+    Ne,
+    Lt,
+    Le,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inst {
+    Copy {
+        dst: Reg,
+        src: Operand,
+    },
+    LocalAddr {
+        dst: Reg,
+        offset: usize,
+    }, // the address `offset` bytes below the frame pointer
+    Load {
+        dst: Reg,
+        addr: Operand,
+        ty: Ty,
+        volatile: bool,
+    },
+    Store {
+        addr: Operand,
+        src: Operand,
+        ty: Ty,
+        atomic: bool, // sequentially consistent
+        volatile: bool,
+    },
+    Extend {
+        dst: Reg,
+        src: Operand,
+        ty: Ty,
+    }, // the low bits of src, as if stored and loaded back as ty
+    Neg {
+        dst: Reg,
+        src: Operand,
+        ty: Ty,
+    },
+    Binary {
+        op: BinOp,
+        ty: Ty, // of the operands
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Select {
+        dst: Reg,
+        cond: Operand,
+        then: Operand,
+        els: Operand,
+    }, // then if cond is nonzero, else els
+    Call {
+        dst: Reg,
+        name: String,
+        args: Vec<Operand>,
+        ret: Ty,
+    },
+    Exchange {
+        dst: Reg,
+        addr: Operand,
+        src: Operand,
+        ty: Ty,
+    }, // atomically store src to addr, yielding the old value
+    FetchAdd {
+        dst: Reg,
+        addr: Operand,
+        src: Operand,
+        ty: Ty,
+    }, // atomically add src to addr, yielding the old value
+    CompareSwap {
+        dst: Reg,
+        addr: Operand,
+        old: Operand,
+        new: Operand,
+        ty: Ty,
+    }, // atomically store new to addr if it holds old, yielding the old value
+    Phi {
+        dst: Reg,
+        args: Vec<(String, Operand)>,
+    }, // in SSA form, the value for the block, by its label, control came from
+    Label(String),
+    Jump(String),
+    JumpIfZero {
+        cond: Operand,
+        target: String,
+    },
+    Ret(Option<Operand>),
+    Asm(String), // inline assembly, emitted verbatim
+    Loc {
+        file: String,
+        line: usize,
+        col: usize,
+    }, // where the following instructions came from, for debug info
+    Comment(String), // shown above the following instructions, for --asm-comments
+    Span(Range<usize>), // of the source the following instructions came from
+}
+
+impl Inst {
+    // The register the instruction assigns, if any.
+    pub fn def(&self) -> Option<Reg> {
+        match self {
+            Inst::Copy { dst, .. }
+            | Inst::LocalAddr { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Extend { dst, .. }
+            | Inst::Neg { dst, .. }
+            | Inst::Binary { dst, .. }
+            | Inst::Select { dst, .. }
+            | Inst::Call { dst, .. }
+            | Inst::Exchange { dst, .. }
+            | Inst::FetchAdd { dst, .. }
+            | Inst::CompareSwap { dst, .. }
+            | Inst::Phi { dst, .. } => Some(*dst),
+            _ => None,
+        }
+    }
+
+    // The operands the instruction reads, in order.
+    pub fn operands(&self) -> Vec<Operand> {
+        match self {
+            Inst::Copy { src, .. } | Inst::Extend { src, .. } | Inst::Neg { src, .. } => {
+                vec![*src]
+            }
+            Inst::Load { addr, .. } => vec![*addr],
+            Inst::Store { addr, src, .. }
+            | Inst::Exchange { addr, src, .. }
+            | Inst::FetchAdd { addr, src, .. } => vec![*addr, *src],
+            Inst::Binary { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Inst::Select {
+                cond, then, els, ..
+            } => vec![*cond, *then, *els],
+            Inst::Call { args, .. } => args.clone(),
+            Inst::CompareSwap { addr, old, new, .. } => vec![*addr, *old, *new],
+            Inst::Phi { args, .. } => args.iter().map(|(_, arg)| *arg).collect(),
+            Inst::JumpIfZero { cond, .. } => vec![*cond],
+            Inst::Ret(val) => val.iter().copied().collect(),
+            Inst::LocalAddr { .. }
+            | Inst::Label(_)
+            | Inst::Jump(_)
+            | Inst::Asm(_)
+            | Inst::Loc { .. }
+            | Inst::Comment(_)
+            | Inst::Span(_) => Vec::new(),
+        }
+    }
+
+    // The operands the instruction reads, for rewriting them in place.
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Inst::Copy { src, .. } | Inst::Extend { src, .. } | Inst::Neg { src, .. } => {
+                vec![src]
+            }
+            Inst::Load { addr, .. } => vec![addr],
+            Inst::Store { addr, src, .. }
+            | Inst::Exchange { addr, src, .. }
+            | Inst::FetchAdd { addr, src, .. } => vec![addr, src],
+            Inst::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Inst::Select {
+                cond, then, els, ..
+            } => vec![cond, then, els],
+            Inst::Call { args, .. } => args.iter_mut().collect(),
+            Inst::CompareSwap { addr, old, new, .. } => vec![addr, old, new],
+            Inst::Phi { args, .. } => args.iter_mut().map(|(_, arg)| arg).collect(),
+            Inst::JumpIfZero { cond, .. } => vec![cond],
+            Inst::Ret(val) => val.iter_mut().collect(),
+            Inst::LocalAddr { .. }
+            | Inst::Label(_)
+            | Inst::Jump(_)
+            | Inst::Asm(_)
+            | Inst::Loc { .. }
+            | Inst::Comment(_)
+            | Inst::Span(_) => Vec::new(),
+        }
+    }
+
+    // The registers the instruction reads.
+    pub fn uses(&self) -> Vec<Reg> {
+        self.operands()
+            .into_iter()
+            .filter_map(|operand| match operand {
+                Operand::Reg(reg) => Some(reg),
+                Operand::Imm(_) => None,
+            })
+            .collect()
+    }
+}
+
+pub struct Function {
+    pub name: String,
+    pub insts: Vec<Inst>,
+    pub regs: usize,       // virtual registers used, numbered from 0
+    pub frame_size: usize, // bytes of locals below the frame pointer
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "%{}", reg),
+            Operand::Imm(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Ty::I8 => "i8",
+            Ty::U8 => "u8",
+            Ty::I32 => "i32",
+            Ty::I64 => "i64",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Shl => "shl",
+            BinOp::Shr => "shr",
+            BinOp::Sar => "sar",
+            BinOp::Eq => "eq",
+            BinOp::Ne => "ne",
+            BinOp::Lt => "lt",
+            BinOp::Le => "le",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inst::Copy { dst, src } => write!(f, "  %{} = {}", dst, src),
+            Inst::LocalAddr { dst, offset } => write!(f, "  %{} = local {}", dst, offset),
+            Inst::Load {
+                dst,
+                addr,
+                ty,
+                volatile,
+            } => {
+                let volatile = if *volatile { " volatile" } else { "" };
+                write!(f, "  %{} = load{} {} {}", dst, volatile, ty, addr)
+            }
+            Inst::Store {
+                addr,
+                src,
+                ty,
+                atomic,
+                volatile,
+            } => {
+                let atomic = if *atomic { " atomic" } else { "" };
+                let volatile = if *volatile { " volatile" } else { "" };
+                write!(f, "  store{}{} {} {}, {}", atomic, volatile, ty, addr, src)
+            }
+            Inst::Extend { dst, src, ty } => write!(f, "  %{} = extend {} {}", dst, ty, src),
+            Inst::Neg { dst, src, ty } => write!(f, "  %{} = neg {} {}", dst, ty, src),
+            Inst::Binary {
+                op,
+                ty,
+                dst,
+                lhs,
+                rhs,
+            } => write!(f, "  %{} = {} {} {}, {}", dst, op, ty, lhs, rhs),
+            Inst::Select {
+                dst,
+                cond,
+                then,
+                els,
+            } => write!(f, "  %{} = select {}, {}, {}", dst, cond, then, els),
+            Inst::Call {
+                dst,
+                name,
+                args,
+                ret,
+            } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "  %{} = call {} {}({})", dst, ret, name, args.join(", "))
+            }
+            Inst::Exchange { dst, addr, src, ty } => {
+                write!(f, "  %{} = exchange {} {}, {}", dst, ty, addr, src)
+            }
+            Inst::FetchAdd { dst, addr, src, ty } => {
+                write!(f, "  %{} = fetch_add {} {}, {}", dst, ty, addr, src)
+            }
+            Inst::CompareSwap {
+                dst,
+                addr,
+                old,
+                new,
+                ty,
+            } => write!(
+                f,
+                "  %{} = compare_swap {} {}, {}, {}",
+                dst, ty, addr, old, new
+            ),
+            Inst::Phi { dst, args } => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(label, arg)| format!("[{}, {}]", label, arg))
+                    .collect();
+                write!(f, "  %{} = phi {}", dst, args.join(", "))
+            }
+            Inst::Label(label) => write!(f, "{}:", label),
+            Inst::Jump(label) => write!(f, "  jump {}", label),
+            Inst::JumpIfZero { cond, target } => write!(f, "  jump_if_zero {}, {}", cond, target),
+            Inst::Ret(Some(val)) => write!(f, "  ret {}", val),
+            Inst::Ret(None) => write!(f, "  ret"),
+            Inst::Asm(text) => write!(f, "  asm {:?}", text),
+            Inst::Loc { file, line, col } => write!(f, "  loc {:?} {} {}", file, line, col),
+            Inst::Comment(text) => write!(f, "  # {}", text),
+            Inst::Span(span) => write!(f, "  span {}..{}", span.start, span.end),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "function {} (frame {}) {{", self.name, self.frame_size)?;
+        for inst in &self.insts {
+            writeln!(f, "{}", inst)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+// Check that `function` is well formed, as the optimization passes must
+// leave it: every jump goes to a label defined once, each register is
+// assigned once and, outside phis, before every use on every path,
+// arithmetic is done in I32 or I64, and addresses are 64-bit values. The
+// error names the offending instruction.
+pub fn verify(function: &Function) -> Result<(), MyError> {
+    let error = |inst: &Inst, info: String| {
+        Err(MyError {
+            info: format!("ir: {} in `{}`", info, inst.to_string().trim()),
+        })
+    };
+    let mut labels = HashSet::new();
+    for inst in &function.insts {
+        if let Inst::Label(label) = inst {
+            if !labels.insert(label) {
+                return error(inst, format!("{} is defined twice", label));
+            }
+        }
+    }
+    let mut defs: HashMap<Reg, usize> = HashMap::new();
+    for (i, inst) in function.insts.iter().enumerate() {
+        let targets: Vec<&String> = match inst {
+            Inst::Jump(target) | Inst::JumpIfZero { target, .. } => vec![target],
+            Inst::Phi { args, .. } => args.iter().map(|(label, _)| label).collect(),
+            _ => Vec::new(),
+        };
+        if let Some(target) = targets.into_iter().find(|target| !labels.contains(target)) {
+            return error(inst, format!("{} is not defined", target));
+        }
+        if let Some(dst) = inst.def() {
+            if dst >= function.regs {
+                return error(
+                    inst,
+                    format!("%{} is not one of the {} registers", dst, function.regs),
+                );
+            }
+            if defs.insert(dst, i).is_some() {
+                return error(inst, format!("%{} is assigned twice", dst));
+            }
+        }
+    }
+
+    let cfg = Cfg::new(function);
+    let idom = cfg.dominators();
+    let mut block_of = vec![0; function.insts.len()];
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for i in block.insts.clone() {
+            block_of[i] = b;
+        }
+    }
+    let dominates = |a: usize, mut b: usize| loop {
+        if a == b {
+            return true;
+        }
+        match idom[b] {
+            Some(parent) if parent != b => b = parent,
+            _ => return false,
+        }
+    };
+    for (i, inst) in function.insts.iter().enumerate() {
+        if idom[block_of[i]].is_none() || matches!(inst, Inst::Phi { .. }) {
+            continue;
+        }
+        for reg in inst.uses() {
+            match defs.get(&reg) {
+                None => return error(inst, format!("%{} is never assigned", reg)),
+                Some(&d)
+                    if (block_of[d] == block_of[i] && d < i)
+                        || (block_of[d] != block_of[i] && dominates(block_of[d], block_of[i])) => {}
+                Some(_) => {
+                    return error(inst, format!("%{} may be used before it is assigned", reg))
+                }
+            }
+        }
+    }
+
+    // the width of the value in each register, where it is known
+    let width = |operand: &Operand| -> Option<Ty> {
+        let mut operand = *operand;
+        loop {
+            let Operand::Reg(reg) = operand else {
+                return None;
+            };
+            return match &function.insts[*defs.get(&reg)?] {
+                Inst::Copy { src, .. } | Inst::Select { then: src, .. } => {
+                    operand = *src;
+                    continue;
+                }
+                Inst::LocalAddr { .. } => Some(Ty::I64),
+                Inst::Binary {
+                    op: BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le,
+                    ..
+                } => Some(Ty::I32),
+                Inst::Load { ty, .. }
+                | Inst::Extend { ty, .. }
+                | Inst::Neg { ty, .. }
+                | Inst::Binary { ty, .. }
+                | Inst::Exchange { ty, .. }
+                | Inst::FetchAdd { ty, .. }
+                | Inst::CompareSwap { ty, .. }
+                | Inst::Call { ret: ty, .. } => Some(*ty),
+                _ => None,
+            };
+        }
+    };
+    for inst in &function.insts {
+        match inst {
+            Inst::Neg { ty, .. } | Inst::Binary { ty, .. } if !matches!(ty, Ty::I32 | Ty::I64) => {
+                return error(inst, format!("arithmetic is done in {}", ty));
+            }
+            Inst::Load { addr, .. }
+            | Inst::Store { addr, .. }
+            | Inst::Exchange { addr, .. }
+            | Inst::FetchAdd { addr, .. }
+            | Inst::CompareSwap { addr, .. } => {
+                if let Some(ty) = width(addr).filter(|ty| *ty != Ty::I64) {
+                    return error(inst, format!("{} holds {}, not an address", addr, ty));
+                }
+            }
+            Inst::Select { then, els, .. } => {
+                if let (Some(then_ty), Some(els_ty)) = (width(then), width(els)) {
+                    if then_ty != els_ty {
+                        return error(
+                            inst,
+                            format!("selects between {} and {} values", then_ty, els_ty),
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_inst() {
+        let inst = Inst::Binary {
+            op: BinOp::Add,
+            ty: Ty::I32,
+            dst: 2,
+            lhs: Operand::Reg(1),
+            rhs: Operand::Imm(3),
+        };
+        assert_eq!(inst.to_string(), "  %2 = add i32 %1, 3");
+        assert_eq!(inst.def(), Some(2));
+        assert_eq!(inst.uses(), vec![1]);
+        let inst = Inst::Store {
+            addr: Operand::Reg(0),
+            src: Operand::Reg(1),
+            ty: Ty::I8,
+            atomic: false,
+            volatile: false,
+        };
+        assert_eq!(inst.to_string(), "  store i8 %0, %1");
+        assert_eq!(inst.def(), None);
+        assert_eq!(inst.uses(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_verify() {
+        let verify = |insts: Vec<Inst>| {
+            let function = Function {
+                name: "main".to_string(),
+                insts,
+                regs: 3,
+                frame_size: 8,
+            };
+            verify(&function).err().map(|e| e.info)
+        };
+        let local = |dst| Inst::LocalAddr { dst, offset: 8 };
+        let load = |dst, addr, ty| Inst::Load {
+            dst,
+            addr: Operand::Reg(addr),
+            ty,
+            volatile: false,
+        };
+        let ret = |reg| Inst::Ret(Some(Operand::Reg(reg)));
+        assert_eq!(verify(vec![local(0), load(1, 0, Ty::I32), ret(1)]), None);
+        assert_eq!(
+            verify(vec![Inst::Jump(".L.end".to_string())]),
+            Some("ir: .L.end is not defined in `jump .L.end`".to_string())
+        );
+        assert_eq!(
+            verify(vec![local(0), local(0)]),
+            Some("ir: %0 is assigned twice in `%0 = local 8`".to_string())
+        );
+        assert_eq!(
+            verify(vec![
+                Inst::JumpIfZero {
+                    cond: Operand::Imm(1),
+                    target: ".L.end".to_string(),
+                },
+                local(0),
+                Inst::Label(".L.end".to_string()),
+                load(1, 0, Ty::I32),
+                ret(1),
+            ]),
+            Some("ir: %0 may be used before it is assigned in `%1 = load i32 %0`".to_string())
+        );
+        assert_eq!(
+            verify(vec![local(0), load(1, 0, Ty::I32), load(2, 1, Ty::I32)]),
+            Some("ir: %1 holds i32, not an address in `%2 = load i32 %1`".to_string())
+        );
+        assert_eq!(
+            verify(vec![Inst::Neg {
+                dst: 0,
+                src: Operand::Imm(1),
+                ty: Ty::I8,
+            }]),
+            Some("ir: arithmetic is done in i8 in `%0 = neg i8 1`".to_string())
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use crate::assembler::Assembler;
+use crate::MyError;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void, CString};
+
+// Running generated code in-process: the integrated assembler's machine code
+// is copied into executable memory and `main` is called directly. Calls to
+// anything outside the program go through a stub holding the address dlsym
+// finds, which may be too far away for a rel32.
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        off: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 0x02;
+const MAP_ANONYMOUS: c_int = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+const RTLD_DEFAULT: *mut c_void = std::ptr::null_mut();
+
+// jmp *0(%rip), followed by the 8-byte address it jumps to
+const STUB: [u8; 6] = [0xFF, 0x25, 0, 0, 0, 0];
+
+// Assemble `asm`, as printed by the native backend, and run its `main`,
+// returning what it returns. Only an x86-64 Linux host can run the code.
+pub fn run(asm: &str) -> Result<i32, MyError> {
+    if !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+        return Err(MyError {
+            info: "jit: only supported on x86_64 Linux hosts".to_string(),
+        });
+    }
+    let mut assembler = Assembler::parse(asm)?;
+    let relocations = assembler.resolve()?;
+    let main = *assembler.labels.get("main").ok_or_else(|| MyError {
+        info: "jit: no main".to_string(),
+    })?;
+
+    let mut code = assembler.code;
+    code.resize(code.len().next_multiple_of(16), 0xCC);
+    let mut stubs = HashMap::new();
+    for fixup in &relocations {
+        let target = match assembler.labels.get(&fixup.target) {
+            Some(&offset) => offset,
+            None => match stubs.get(&fixup.target) {
+                Some(&offset) => offset,
+                None => {
+                    let address = lookup(&fixup.target)?;
+                    let offset = code.len();
+                    code.extend(STUB);
+                    code.extend((address as u64).to_le_bytes());
+                    stubs.insert(fixup.target.clone(), offset);
+                    offset
+                }
+            },
+        };
+        let rel = target as i64 - (fixup.offset as i64 + 4);
+        let rel = i32::try_from(rel).expect("code is under 2GB");
+        code[fixup.offset..fixup.offset + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    let memory = Memory::new(&code)?;
+    // SAFETY: the memory holds the assembled program, which starts `main`
+    // at this offset and follows the C calling convention.
+    let main: extern "C" fn() -> i32 = unsafe { std::mem::transmute(memory.ptr.add(main)) };
+    Ok(main())
+}
+
+fn lookup(name: &str) -> Result<*mut c_void, MyError> {
+    let undefined = || MyError {
+        info: format!("jit: undefined symbol {}", name),
+    };
+    let symbol = CString::new(name).map_err(|_| undefined())?;
+    // SAFETY: `symbol` is a valid C string and RTLD_DEFAULT a valid handle.
+    let address = unsafe { dlsym(RTLD_DEFAULT, symbol.as_ptr()) };
+    if address.is_null() {
+        return Err(undefined());
+    }
+    Ok(address)
+}
+
+// A private mapping holding a copy of some code, made executable and no
+// longer writable once the code is in.
+struct Memory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Memory {
+    fn new(code: &[u8]) -> Result<Self, MyError> {
+        let error = |call: &str| MyError {
+            info: format!("jit: {}: {}", call, std::io::Error::last_os_error()),
+        };
+        let len = code.len().max(1);
+        // SAFETY: an anonymous mapping aliases nothing.
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == MAP_FAILED {
+            return Err(error("mmap"));
+        }
+        let memory = Self {
+            ptr: ptr as *mut u8,
+            len,
+        };
+        // SAFETY: the mapping is writable and at least `code.len()` long.
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), memory.ptr, code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                return Err(error("mprotect"));
+            }
+        }
+        Ok(memory)
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is ours and nothing points into it any more.
+        unsafe {
+            munmap(self.ptr as *mut c_void, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CodeGenerator, CompileOptions};
+
+    fn jit(src: &str, options: CompileOptions) -> Result<i32, MyError> {
+        let (parser, program) = crate::parse(src);
+        let asm = CodeGenerator::new(parser, options).generate(program.nodes);
+        run(&asm)
+    }
+
+    #[test]
+    fn test_run() {
+        let options = CompileOptions::default();
+        let src = "{ int i=0; int j=0; for (i=0; i<=10; i=i+1) j=i+j; return j; }";
+        assert_eq!(jit(src, options.clone()).ok(), Some(55));
+        assert_eq!(jit("{ return -7; }", options.clone()).ok(), Some(-7));
+        // through a stub into libc
+        let src = "int abs(int); { return abs(-5) + abs(-6); }";
+        assert_eq!(jit(src, options.clone()).ok(), Some(11));
+        let Err(e) = jit("int nowhere(); { return nowhere(); }", options.clone()) else {
+            panic!("undefined symbol ran");
+        };
+        assert_eq!(e.info, "jit: undefined symbol nowhere");
+        let protected = CompileOptions {
+            stack_protector: true,
+            opt_level: 2,
+            ..options
+        };
+        assert_eq!(
+            jit("{ char a[8]; *a=3; return *a; }", protected).ok(),
+            Some(3)
+        );
+    }
+}
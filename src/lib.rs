@@ -1,11 +1,56 @@
+mod analysis;
+mod assembler;
+pub mod cfg;
+mod code_generator;
+mod cost_model;
+#[cfg(feature = "cranelift")]
+mod cranelift;
+mod cse;
+mod diagnostics;
+mod dwarf;
 mod errors;
+mod ifcvt;
+mod interpreter;
+pub mod ir;
+mod jit;
+mod llvm_ir;
+mod optimizer;
+mod options;
 mod parser;
+mod passes;
+mod preprocessor;
+mod qbe;
+pub mod ssa;
+mod target;
 mod tokenizer;
-mod code_generator;
-
+mod x86_64;
 
-pub use errors::MyError;
-pub use tokenizer::{Token, TokenQueue};
-pub use parser::{Node, Parser};
+pub use analysis::null_deref_warnings;
+pub use assembler::assemble;
 pub use code_generator::CodeGenerator;
+pub use cost_model::{Cost, CostModel};
+#[cfg(feature = "cranelift")]
+pub use cranelift::CraneliftGenerator;
+pub use diagnostics::{Diagnostic, LineMap, Note, Warning};
+pub use errors::MyError;
+pub use interpreter::interpret;
+pub use jit::run;
+pub use llvm_ir::LlvmIrGenerator;
+pub use options::{Backend, CompileOptions, Std};
+pub use parser::{Node, Parser, Program, ProgramStats};
+pub use passes::{AsmPass, AstPass, IrPass, Pass, PassManager};
+pub use preprocessor::Preprocessor;
+pub use qbe::QbeGenerator;
+pub use target::{Arch, Os, Target};
+pub use tokenizer::{Location, SourceFile, Token, TokenQueue};
+pub use x86_64::Line;
 
+// Parse `src` as a whole program for a test, which fails if it doesn't. The
+// parser comes back too, for the locals the backends need.
+#[cfg(test)]
+pub(crate) fn parse(src: &str) -> (Parser, Program) {
+    let tokens = TokenQueue::tokenizer(src).expect("tokenizer error");
+    let mut parser = Parser::new(tokens);
+    let program = parser.program().expect("parse error");
+    (parser, program)
+}
@@ -0,0 +1,605 @@
+use crate::parser::Type;
+use crate::{CompileOptions, Node, Parser, PassManager};
+use std::fmt::Write;
+
+macro_rules! emit {
+    ($self:ident, $($arg:tt)*) => {
+        writeln!($self.ir, $($arg)*).expect("writing to a String cannot fail")
+    };
+}
+
+// Lowers the AST to textual LLVM IR instead of assembly. As in the assembly
+// backend every value is an i64, addresses included, though int arithmetic
+// is done in i32, and objects keep their sizes and stack layout; pointers
+// only appear where memory is touched. The IR uses typed pointers, as
+// LLVM 14 expects.
+pub struct LlvmIrGenerator {
+    ir: String,
+    parser: Parser,
+    counter: usize,
+    options: CompileOptions,
+    pub passes: PassManager, // the AST passes named in options.passes are run
+}
+
+impl LlvmIrGenerator {
+    pub fn new(parser: Parser, options: CompileOptions) -> Self {
+        Self {
+            ir: String::new(),
+            parser,
+            counter: 0,
+            options,
+            passes: PassManager::default(),
+        }
+    }
+
+    fn count(&mut self) -> usize {
+        self.counter += 1;
+        self.counter
+    }
+
+    // A fresh SSA value name.
+    fn temp(&mut self) -> String {
+        format!("%t{}", self.count())
+    }
+
+    // Returns the IR module for the whole program.
+    pub fn generate(&mut self, nodes: Vec<Node>) -> String {
+        let nodes = self
+            .passes
+            .run_ast(&self.options.pipeline(), nodes, &self.parser);
+        let mut functions: Vec<(&String, &Type)> = self
+            .parser
+            .functions
+            .iter()
+            .map(|(name, item)| (name, &item.r#type))
+            .collect();
+        functions.sort_by_key(|(name, _)| *name);
+        let mut declarations = String::new();
+        for (name, r#type) in functions {
+            if let Type::Func { ret, params } = r#type {
+                let params: Vec<&str> = params.iter().map(Self::abi_type).collect();
+                writeln!(
+                    declarations,
+                    "declare {} @{}({})",
+                    Self::abi_type(ret),
+                    name,
+                    params.join(", ")
+                )
+                .expect("writing to a String cannot fail");
+            }
+        }
+        if !declarations.is_empty() {
+            emit!(self, "{}", declarations);
+        }
+
+        emit!(self, "define i32 @main() {{");
+        emit!(self, "entry:");
+        // One frame for all locals, laid out as the assembly backend lays out
+        // its stack, with %fp standing in for %rbp.
+        let size = self.parser.stack_size;
+        emit!(self, "  %frame = alloca [{} x i8], align 16", size);
+        emit!(
+            self,
+            "  %frame.start = ptrtoint [{} x i8]* %frame to i64",
+            size
+        );
+        emit!(self, "  %fp = add i64 %frame.start, {}", size);
+        for node in &nodes {
+            self.gen_stmt(Some(node));
+        }
+        emit!(self, "  ret i32 0");
+        emit!(self, "}}");
+        std::mem::take(&mut self.ir)
+    }
+
+    // How a value of `r#type` is passed to and returned from functions.
+    fn abi_type(r#type: &Type) -> &'static str {
+        match r#type.unqualified() {
+            Type::Char | Type::SChar | Type::UChar | Type::Bool => "i8",
+            Type::I32 => "i32",
+            Type::Long | Type::I64 => "i64",
+            Type::Ptr { .. } | Type::Array { .. } | Type::Func { .. } => "i8*",
+            Type::Qualified { .. } => unreachable!("unqualified above"),
+        }
+    }
+
+    // The integer type arithmetic on values of `r#type` is done in: an int
+    // wraps at 32 bits, and is kept sign-extended in its i64 in between.
+    fn arith_type(r#type: &Type) -> &'static str {
+        match r#type.unqualified() {
+            Type::Long | Type::I64 | Type::Ptr { .. } | Type::Array { .. } | Type::Func { .. } => {
+                "i64"
+            }
+            _ => "i32",
+        }
+    }
+
+    // The integer type an object of `r#type` occupies in memory.
+    fn mem_type(&self, r#type: &Type) -> String {
+        format!("i{}", r#type.size(&self.parser.target) * 8)
+    }
+
+    // Widen the `from`-typed value `val` of an object of `r#type` to i64,
+    // sign- or zero-extending as a load would.
+    fn extend(&mut self, val: &str, from: &str, r#type: &Type) -> String {
+        if from == "i64" {
+            return val.to_string();
+        }
+        let op = if let Type::UChar | Type::Bool = r#type.unqualified() {
+            "zext"
+        } else {
+            "sext"
+        };
+        let t = self.temp();
+        emit!(self, "  {} = {} {} {} to i64", t, op, from, val);
+        t
+    }
+
+    fn is_volatile(&self, node: &Node) -> bool {
+        self.lvalue_type(node).is_some_and(|t| t.is_volatile())
+    }
+
+    fn is_atomic(&self, node: &Node) -> bool {
+        self.lvalue_type(node).is_some_and(|t| t.is_atomic())
+    }
+
+    // The declared type of the object `node` designates, qualifiers included.
+    fn lvalue_type(&self, node: &Node) -> Option<Type> {
+        match node {
+            Node::Var { name, .. } => Some(self.parser.locals[name].r#type.clone()),
+            Node::Deref { lhs, .. } => lhs.get_type().and_then(|t| t.base().cloned()),
+            _ => None,
+        }
+    }
+
+    // An iN* for the i64 address `addr`.
+    fn pointer(&mut self, addr: &str, ty: &str) -> String {
+        let p = self.temp();
+        emit!(self, "  {} = inttoptr i64 {} to {}*", p, addr, ty);
+        p
+    }
+
+    // The address of an lvalue, as an i64.
+    fn gen_addr(&mut self, node: &Node) -> String {
+        match node {
+            Node::Var { name, .. } => {
+                let offset = self.parser.locals[name].offset;
+                let t = self.temp();
+                emit!(self, "  {} = sub i64 %fp, {}", t, offset);
+                t
+            }
+            Node::Deref { lhs, .. } => self.gen_expr(lhs),
+            _ => panic!("not a lvalue: {:?}", node),
+        }
+    }
+
+    // Load the object of `r#type` at `addr` that `lvalue` designates. An
+    // array is not loaded: its address is its value.
+    fn load(&mut self, addr: String, r#type: &Type, lvalue: &Node) -> String {
+        if let Type::Array { .. } = r#type {
+            return addr;
+        }
+        let ty = self.mem_type(r#type);
+        let p = self.pointer(&addr, &ty);
+        let t = self.temp();
+        if self.is_atomic(lvalue) {
+            let align = r#type.size(&self.parser.target);
+            emit!(
+                self,
+                "  {} = load atomic {}, {}* {} seq_cst, align {}",
+                t,
+                ty,
+                ty,
+                p,
+                align
+            );
+        } else {
+            let volatile = if self.is_volatile(lvalue) {
+                "volatile "
+            } else {
+                ""
+            };
+            emit!(
+                self,
+                "  {} = load {}{}, {}* {}, align 1",
+                t,
+                volatile,
+                ty,
+                ty,
+                p
+            );
+        }
+        self.extend(&t, &ty, r#type)
+    }
+
+    // Store `val` to the object of `r#type` at `addr` that `lvalue`
+    // designates. Returns the value as stored, truncated and widened again.
+    fn store(&mut self, addr: &str, val: String, r#type: &Type, lvalue: &Node) -> String {
+        let ty = self.mem_type(r#type);
+        let narrow = if ty == "i64" {
+            val
+        } else {
+            let t = self.temp();
+            emit!(self, "  {} = trunc i64 {} to {}", t, val, ty);
+            t
+        };
+        let p = self.pointer(addr, &ty);
+        if self.is_atomic(lvalue) {
+            let align = r#type.size(&self.parser.target);
+            emit!(
+                self,
+                "  store atomic {} {}, {}* {} seq_cst, align {}",
+                ty,
+                narrow,
+                ty,
+                p,
+                align
+            );
+        } else {
+            let volatile = if self.is_volatile(lvalue) {
+                "volatile "
+            } else {
+                ""
+            };
+            emit!(
+                self,
+                "  store {}{} {}, {}* {}, align 1",
+                volatile,
+                ty,
+                narrow,
+                ty,
+                p
+            );
+        }
+        self.extend(&narrow, &ty, r#type)
+    }
+
+    // Convert the i64 `val` to what a parameter of `r#type` is passed as.
+    fn abi_arg(&mut self, val: String, r#type: &Type) -> String {
+        let ty = Self::abi_type(r#type);
+        let op = match ty {
+            "i64" => return val,
+            "i8*" => "inttoptr",
+            _ => "trunc",
+        };
+        let t = self.temp();
+        emit!(self, "  {} = {} i64 {} to {}", t, op, val, ty);
+        t
+    }
+
+    pub fn gen_expr(&mut self, node: &Node) -> String {
+        match node {
+            Node::Num { val, .. } => val.to_string(),
+            Node::Neg { lhs, r#type, .. } => {
+                let val = self.gen_expr(lhs);
+                let ty = Self::arith_type(r#type);
+                let val = self.truncate(val, ty);
+                let t = self.temp();
+                emit!(self, "  {} = sub {} 0, {}", t, ty, val);
+                self.extend(&t, ty, &Type::I32)
+            }
+            Node::Var { r#type, .. } => {
+                let addr = self.gen_addr(node);
+                self.load(addr, r#type, node)
+            }
+            Node::Deref { lhs, r#type, .. } => {
+                let addr = self.gen_expr(lhs);
+                self.load(addr, r#type, node)
+            }
+            Node::Addr { lhs, .. } => self.gen_addr(lhs),
+            Node::FuncCall {
+                name, args, r#type, ..
+            } => {
+                let params = match &self.parser.functions[name].r#type {
+                    Type::Func { params, .. } => params.clone(),
+                    _ => unreachable!("functions are declared with function types"),
+                };
+                let mut operands = Vec::new();
+                for (arg, param) in args.iter().zip(&params) {
+                    let val = self.gen_expr(arg);
+                    let val = self.abi_arg(val, param);
+                    operands.push(format!("{} {}", Self::abi_type(param), val));
+                }
+                let ty = Self::abi_type(r#type);
+                let t = self.temp();
+                emit!(
+                    self,
+                    "  {} = call {} @{}({})",
+                    t,
+                    ty,
+                    name,
+                    operands.join(", ")
+                );
+                match ty {
+                    "i8*" => {
+                        let i = self.temp();
+                        emit!(self, "  {} = ptrtoint i8* {} to i64", i, t);
+                        i
+                    }
+                    _ => self.extend(&t, ty, r#type),
+                }
+            }
+            Node::Assign {
+                lhs, rhs, r#type, ..
+            } => {
+                let addr = self.gen_addr(lhs);
+                let val = self.gen_expr(rhs);
+                self.store(&addr, val, r#type, lhs)
+            }
+            Node::Exchange {
+                lhs, rhs, r#type, ..
+            }
+            | Node::FetchAdd {
+                lhs, rhs, r#type, ..
+            } => {
+                let addr = self.gen_expr(lhs);
+                let val = self.gen_expr(rhs);
+                let ty = self.mem_type(r#type);
+                let val = self.truncate(val, &ty);
+                let p = self.pointer(&addr, &ty);
+                let op = if let Node::Exchange { .. } = node {
+                    "xchg"
+                } else {
+                    "add"
+                };
+                let t = self.temp();
+                emit!(
+                    self,
+                    "  {} = atomicrmw {} {}* {}, {} {} seq_cst",
+                    t,
+                    op,
+                    ty,
+                    p,
+                    ty,
+                    val
+                );
+                self.extend(&t, &ty, r#type)
+            }
+            Node::CompareSwap {
+                lhs,
+                old,
+                new,
+                r#type,
+                ..
+            } => {
+                let addr = self.gen_expr(lhs);
+                let old = self.gen_expr(old);
+                let new = self.gen_expr(new);
+                let ty = self.mem_type(r#type);
+                let old = self.truncate(old, &ty);
+                let new = self.truncate(new, &ty);
+                let p = self.pointer(&addr, &ty);
+                let pair = self.temp();
+                emit!(
+                    self,
+                    "  {} = cmpxchg {}* {}, {} {}, {} {} seq_cst seq_cst",
+                    pair,
+                    ty,
+                    p,
+                    ty,
+                    old,
+                    ty,
+                    new
+                );
+                let t = self.temp();
+                emit!(self, "  {} = extractvalue {{ {}, i1 }} {}, 0", t, ty, pair);
+                self.extend(&t, &ty, r#type)
+            }
+            Node::Add {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Sub {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Mul {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Div {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Eq {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Ne {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Lt {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Le {
+                lhs, rhs, r#type, ..
+            } => {
+                // a comparison is as wide as its operands, not its result
+                let ty = match node {
+                    Node::Eq { .. } | Node::Ne { .. } | Node::Lt { .. } | Node::Le { .. } => {
+                        Self::arith_type(&Type::common(
+                            &lhs.get_type().expect("should have a type"),
+                            &rhs.get_type().expect("should have a type"),
+                        ))
+                    }
+                    _ => Self::arith_type(r#type),
+                };
+                // rhs first, as the assembly backend evaluates it
+                let r = self.gen_expr(rhs);
+                let l = self.gen_expr(lhs);
+                let r = self.truncate(r, ty);
+                let l = self.truncate(l, ty);
+                let t = self.temp();
+                let op = match node {
+                    Node::Add { .. } => "add",
+                    Node::Sub { .. } => "sub",
+                    Node::Mul { .. } => "mul",
+                    Node::Div { .. } => "sdiv",
+                    Node::Eq { .. } => "icmp eq",
+                    Node::Ne { .. } => "icmp ne",
+                    Node::Lt { .. } => "icmp slt",
+                    _ => "icmp sle",
+                };
+                emit!(self, "  {} = {} {} {}, {}", t, op, ty, l, r);
+                if !op.starts_with("icmp") {
+                    return self.extend(&t, ty, &Type::I32);
+                }
+                let i = self.temp();
+                emit!(self, "  {} = zext i1 {} to i64", i, t);
+                i
+            }
+            _ => panic!("invalid expression, {:?}", node),
+        }
+    }
+
+    fn truncate(&mut self, val: String, ty: &str) -> String {
+        match (ty, val.parse::<i64>()) {
+            ("i64", _) => return val,
+            ("i32", Ok(n)) => return (n as i32).to_string(),
+            ("i8", Ok(n)) => return (n as i8).to_string(),
+            _ => {}
+        }
+        let t = self.temp();
+        emit!(self, "  {} = trunc i64 {} to {}", t, val, ty);
+        t
+    }
+
+    // Branch on whether the i64 `cond` is nonzero.
+    fn branch(&mut self, cond: &str, then: &str, els: &str) {
+        let t = self.temp();
+        emit!(self, "  {} = icmp ne i64 {}, 0", t, cond);
+        emit!(self, "  br i1 {}, label %{}, label %{}", t, then, els);
+    }
+
+    fn gen_stmt(&mut self, node: Option<&Node>) {
+        let Some(node) = node else {
+            return;
+        };
+        match node {
+            Node::Return { lhs, .. } => {
+                let val = match lhs {
+                    Some(lhs) => self.gen_expr(lhs),
+                    None => "0".to_string(),
+                };
+                let t = self.temp();
+                emit!(self, "  {} = trunc i64 {} to i32", t, val);
+                emit!(self, "  ret i32 {}", t);
+                // Anything after the return goes in a block of its own.
+                let c = self.count();
+                emit!(self, "dead.{}:", c);
+            }
+            Node::ExprStmt { expr, .. } => {
+                self.gen_expr(expr);
+            }
+            Node::If {
+                cond, then, els, ..
+            } => {
+                let c = self.count();
+                let val = self.gen_expr(cond);
+                self.branch(&val, &format!("then.{}", c), &format!("else.{}", c));
+                emit!(self, "then.{}:", c);
+                self.gen_stmt(then.as_deref());
+                emit!(self, "  br label %end.{}", c);
+                emit!(self, "else.{}:", c);
+                self.gen_stmt(els.as_deref());
+                emit!(self, "  br label %end.{}", c);
+                emit!(self, "end.{}:", c);
+            }
+            Node::For {
+                init,
+                cond,
+                inc,
+                then,
+                ..
+            } => {
+                let c = self.count();
+                self.gen_stmt(init.as_deref());
+                emit!(self, "  br label %begin.{}", c);
+                emit!(self, "begin.{}:", c);
+                if let Some(cond) = cond {
+                    let val = self.gen_expr(cond);
+                    self.branch(&val, &format!("body.{}", c), &format!("end.{}", c));
+                } else {
+                    emit!(self, "  br label %body.{}", c);
+                }
+                emit!(self, "body.{}:", c);
+                self.gen_stmt(then.as_deref());
+                if let Some(inc) = inc {
+                    self.gen_expr(inc);
+                }
+                emit!(self, "  br label %begin.{}", c);
+                emit!(self, "end.{}:", c);
+            }
+            Node::Block { nodes, .. } => {
+                for node in nodes {
+                    self.gen_stmt(Some(node));
+                }
+            }
+            Node::Asm { text, .. } => {
+                emit!(
+                    self,
+                    "  call void asm sideeffect \"{}\", \"\"()",
+                    escape_asm(text)
+                );
+            }
+            _ => panic!("invalid statement"),
+        }
+    }
+}
+
+// Inline assembly text as an LLVM string: `$` starts an operand reference,
+// and quotes, backslashes and control characters are written as hex escapes.
+fn escape_asm(text: &str) -> String {
+    let mut rv = String::new();
+    for b in text.bytes() {
+        match b {
+            b'$' => rv.push_str("$$"),
+            b'"' | b'\\' | 0..=0x1f | 0x7f.. => {
+                write!(rv, "\\{:02X}", b).expect("writing to a String cannot fail")
+            }
+            _ => rv.push(b as char),
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ir(src: &str) -> String {
+        let (parser, program) = crate::parse(src);
+        LlvmIrGenerator::new(parser, CompileOptions::default()).generate(program.nodes)
+    }
+
+    #[test]
+    fn test_llvm_ir() {
+        let ir = ir("int f(int, char*); { char c=1; return f(c+2, &c); }");
+        assert!(ir.starts_with("declare i32 @f(i32, i8*)\n"), "{}", ir);
+        assert!(
+            ir.contains("  %frame = alloca [16 x i8], align 16\n"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("store i8 "), "{}", ir);
+        assert!(ir.contains(" = call i32 @f(i32 "), "{}", ir);
+        assert!(ir.ends_with("  ret i32 0\n}\n"), "{}", ir);
+    }
+
+    #[test]
+    fn test_int_overflow() {
+        // int arithmetic wraps at 32 bits, so the sum is negative
+        let module = ir("{ int x=2147483647; return x+1 < 0; }");
+        assert!(module.contains(" = add i32 "), "{}", module);
+        assert!(module.contains(" = icmp slt i32 "), "{}", module);
+        assert!(!module.contains(" i64 2147483648"), "{}", module);
+        let module = ir("{ return 2147483647+1 < 0; }");
+        assert!(module.contains(" = add i32 2147483647, 1\n"), "{}", module);
+        let module = ir("{ long x=65536; int *p=0; return x*x + (p+1 == 0); }");
+        assert!(module.contains(" = mul i64 "), "{}", module);
+        assert!(module.contains(" = icmp eq i64 "), "{}", module);
+    }
+
+    #[test]
+    fn test_escape_asm() {
+        assert_eq!(
+            escape_asm("mov $1, %rax\t# \"x\""),
+            "mov $$1, %rax\\09# \\22x\\22"
+        );
+    }
+}
@@ -1,24 +1,551 @@
+mod cli;
+mod diff;
+mod driver;
+mod server;
+
+use chibicc_rust::null_deref_warnings;
+use chibicc_rust::Backend;
 use chibicc_rust::CodeGenerator;
+use chibicc_rust::CompileOptions;
+#[cfg(feature = "cranelift")]
+use chibicc_rust::CraneliftGenerator;
+use chibicc_rust::Diagnostic;
+use chibicc_rust::LineMap;
+use chibicc_rust::LlvmIrGenerator;
 use chibicc_rust::MyError;
+use chibicc_rust::Node;
 use chibicc_rust::Parser;
+use chibicc_rust::Preprocessor;
+use chibicc_rust::Program;
+use chibicc_rust::QbeGenerator;
+use chibicc_rust::SourceFile;
 use chibicc_rust::TokenQueue;
+use cli::{Args, ArgsError, Command, Emit, Input};
+use driver::{DriverOptions, LinkInput, Trace};
 use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+pub struct Output {
+    pub asm: String,
+    pub object: Option<Vec<u8>>, // from a backend writing machine code, instead of asm
+    pub warnings: Vec<String>,
+    pub preprocessed: String,
+}
+
+// A parsed input, with the warnings to report about it.
+struct Parsed {
+    parser: Parser,
+    program: Program,
+    source: String, // preprocessed
+    line_map: LineMap,
+    warnings: Vec<String>,
+}
+
+// `name` is the file name used in diagnostics and for __FILE__, and `dir` is
+// where quoted #includes are looked up first.
+pub fn compile(
+    source: &str,
+    name: &str,
+    dir: &Path,
+    include_paths: Vec<PathBuf>,
+    options: CompileOptions,
+    trace: Trace,
+) -> Result<Output, MyError> {
+    let Parsed {
+        parser,
+        program,
+        source,
+        line_map,
+        warnings,
+    } = front_end(source, name, dir, include_paths, &options, trace)?;
+    // Traverse the AST to emit assembly, or IR for another backend
+    let (asm, object) = trace.time(&format!("generate {}", name), || {
+        Ok(match options.backend {
+            Backend::Native => {
+                let mut generator = CodeGenerator::new(parser, options);
+                generator.line_map = line_map;
+                generator.source = source.clone();
+                (generator.generate(program.nodes), None)
+            }
+            Backend::LlvmIr => (
+                LlvmIrGenerator::new(parser, options).generate(program.nodes),
+                None,
+            ),
+            Backend::Qbe => (
+                QbeGenerator::new(parser, options).generate(program.nodes)?,
+                None,
+            ),
+            Backend::Cranelift => (
+                String::new(),
+                Some(cranelift_object(parser, options, program.nodes)?),
+            ),
+        })
+    })?;
+    Ok(Output {
+        asm,
+        object,
+        warnings,
+        preprocessed: source,
+    })
+}
 
-fn main() -> Result<(), MyError> {
-    let args: Vec<String> = env::args().skip(1).collect();
-    if args.len() != 1 {
-        Err(MyError {
-            info: format!("args error {:?}", args),
-        })?;
-    }
-    let arg = &args[0];
-    // Tokenize
-    let tokens = TokenQueue::tokenizer(&arg)?;
-    // Parse
+// Preprocess and parse `source`, failing on errors and on warnings promoted
+// to errors.
+fn front_end(
+    source: &str,
+    name: &str,
+    dir: &Path,
+    include_paths: Vec<PathBuf>,
+    options: &CompileOptions,
+    trace: Trace,
+) -> Result<Parsed, MyError> {
+    let (source, line_map) = trace.time(&format!("preprocess {}", name), || {
+        preprocess(source, name, dir, include_paths)
+    })?;
+    let (parser, program) = trace.time(&format!("parse {}", name), || {
+        parse(&source, &line_map, options)
+    })?;
+    let mut warnings: Vec<Diagnostic> = parser
+        .warnings
+        .iter()
+        .cloned()
+        .chain(null_deref_warnings(&program.nodes))
+        .filter(|diagnostic| {
+            diagnostic
+                .warning
+                .is_some_and(|warning| options.warnings.contains(&warning))
+        })
+        .collect();
+    warnings.sort_by_key(|diagnostic| diagnostic.span.start);
+    let promoted = |diagnostic: &Diagnostic| {
+        diagnostic
+            .warning
+            .is_some_and(|warning| options.werror.contains(&warning))
+    };
+    if warnings.iter().any(promoted) {
+        let info: Vec<String> = warnings
+            .iter()
+            .map(|diagnostic| {
+                if promoted(diagnostic) {
+                    diagnostic.render_promoted(&line_map, &source, options.color)
+                } else {
+                    diagnostic.render(&line_map, &source, options.color)
+                }
+            })
+            .collect();
+        return Err(MyError {
+            info: info.join("\n"),
+        });
+    }
+    let warnings = warnings
+        .iter()
+        .map(|diagnostic| diagnostic.render(&line_map, &source, options.color))
+        .collect();
+    Ok(Parsed {
+        parser,
+        program,
+        source,
+        line_map,
+        warnings,
+    })
+}
+
+#[cfg(feature = "cranelift")]
+fn cranelift_object(
+    parser: Parser,
+    options: CompileOptions,
+    nodes: Vec<Node>,
+) -> Result<Vec<u8>, MyError> {
+    CraneliftGenerator::new(parser, options).generate(nodes)
+}
+
+#[cfg(feature = "cranelift")]
+fn cranelift_run(
+    parser: Parser,
+    options: CompileOptions,
+    nodes: Vec<Node>,
+) -> Result<i32, MyError> {
+    CraneliftGenerator::new(parser, options).run(nodes)
+}
+
+#[cfg(not(feature = "cranelift"))]
+fn cranelift_object(_: Parser, _: CompileOptions, _: Vec<Node>) -> Result<Vec<u8>, MyError> {
+    unreachable!("--backend=cranelift is rejected by parse_args without the feature")
+}
+
+#[cfg(not(feature = "cranelift"))]
+fn cranelift_run(_: Parser, _: CompileOptions, _: Vec<Node>) -> Result<i32, MyError> {
+    unreachable!("--backend=cranelift is rejected by parse_args without the feature")
+}
+
+fn preprocess(
+    source: &str,
+    name: &str,
+    dir: &Path,
+    include_paths: Vec<PathBuf>,
+) -> Result<(String, LineMap), MyError> {
+    let mut preprocessor = Preprocessor::new(include_paths);
+    preprocessor.file = name.to_string();
+    let source = preprocessor.preprocess(source, dir)?;
+    Ok((source, preprocessor.line_map))
+}
+
+// Tokenize and parse the preprocessed `source`, rendering any diagnostics
+// against it and locating them with `line_map`.
+fn parse(
+    source: &str,
+    line_map: &LineMap,
+    options: &CompileOptions,
+) -> Result<(Parser, Program), MyError> {
+    let tokens = TokenQueue::tokenize_file(SourceFile::new(&line_map.file, source))?;
     let mut parser = Parser::new(tokens);
-    let nodes = parser.program()?;
-    // Traverse the AST to emit assembly
-    let mut generator = CodeGenerator::new(parser);
-    generator.generate(nodes);
-    Ok(())
+    parser.error_limit = options.error_limit;
+    parser.target = options.target.clone();
+    parser.std = options.std;
+    let program = parser.program().map_err(|e| {
+        let mut info: Vec<String> = parser
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render(line_map, source, options.color))
+            .collect();
+        info.push(e.info);
+        MyError {
+            info: info.join("\n"),
+        }
+    })?;
+    Ok((parser, program))
+}
+
+// The tokens or the AST of `input`, as printed by --dump-tokens and
+// --dump-ast. The JSON form of the AST is one object per line.
+fn dump(
+    input: &Input,
+    emit: &Emit,
+    include_paths: Vec<PathBuf>,
+    options: &CompileOptions,
+) -> Result<String, MyError> {
+    let (source, name, dir) = read_input(input)?;
+    let (source, line_map) = preprocess(&source, &name, &dir, include_paths)?;
+    match emit {
+        Emit::Tokens => {
+            Ok(TokenQueue::tokenize_file(SourceFile::new(&line_map.file, source))?.dump())
+        }
+        Emit::AstJson => {
+            let program = parse(&source, &line_map, options)?.1;
+            let json = serde_json::to_string(&program).map_err(|e| MyError {
+                info: e.to_string(),
+            })?;
+            Ok(json + "\n")
+        }
+        _ => Ok(parse(&source, &line_map, options)?.1.dump()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match cli::parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(ArgsError::Help) => {
+            print!("{}", cli::help());
+            return ExitCode::SUCCESS;
+        }
+        Err(ArgsError::Usage(info)) => {
+            eprintln!("error: {}", info);
+            eprintln!("{}", cli::USAGE);
+            return ExitCode::from(cli::EXIT_USAGE_ERROR);
+        }
+    };
+    let Args {
+        include_paths,
+        options,
+        command,
+        driver,
+        verbose,
+    } = args;
+    let trace = Trace { enabled: verbose };
+    let result = match command {
+        Command::Compile {
+            inputs,
+            emit: Emit::Jit,
+            ..
+        } => match jit(&inputs[0], include_paths, options, trace) {
+            // the low byte, as the exit status of an executable would be
+            Ok(value) => return ExitCode::from(value as u8),
+            Err(e) => Err(e),
+        },
+        Command::Compile {
+            inputs,
+            output,
+            emit,
+        } => build(&inputs, output, emit, include_paths, options, driver, trace),
+        Command::Diff { old, new } => {
+            diff_files(&old, &new, include_paths, options, trace).map(|diff| print!("{}", diff))
+        }
+        Command::Server => {
+            server::serve(io::stdin().lock(), io::stdout().lock()).map_err(|e| MyError {
+                info: e.to_string(),
+            })
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(cli::EXIT_COMPILE_ERROR)
+        }
+    }
+}
+
+// Compile each input independently, then write what `emit` asks for: one
+// assembly or object file per input, or a single executable linking them all.
+fn build(
+    inputs: &[Input],
+    output: Option<PathBuf>,
+    emit: Emit,
+    include_paths: Vec<PathBuf>,
+    options: CompileOptions,
+    driver: DriverOptions,
+    trace: Trace,
+) -> Result<(), MyError> {
+    let unused = |path: &Path| {
+        eprintln!(
+            "warning: {}: linker input file unused because linking not done",
+            path.display()
+        )
+    };
+    if let Emit::Tokens | Emit::Ast | Emit::AstJson = emit {
+        let mut text = String::new();
+        for input in inputs {
+            match prebuilt(input) {
+                Some(LinkInput::Assembly(path) | LinkInput::Object(path)) => unused(&path),
+                _ => text.push_str(&dump(input, &emit, include_paths.clone(), &options)?),
+            }
+        }
+        return write_output(output.as_deref(), &text);
+    }
+    let mut units = Vec::new();
+    let mut errors = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        if let Some(unit) = prebuilt(input) {
+            units.push(unit);
+            continue;
+        }
+        match compile_input(input, include_paths.clone(), options.clone(), trace) {
+            Ok(compiled) => {
+                for warning in compiled.warnings {
+                    eprintln!("{}", warning);
+                }
+                let unit = match compiled.object {
+                    Some(object) => LinkInput::Code(object),
+                    None => LinkInput::Asm(compiled.asm),
+                };
+                if !driver.save_temps {
+                    units.push(unit);
+                    continue;
+                }
+                let path = input.temp_path(index, "i");
+                write_output(Some(&path), &compiled.preprocessed)?;
+                match unit {
+                    LinkInput::Asm(asm) if !matches!(emit, Emit::Asm) => {
+                        let path = input.temp_path(index, "s");
+                        write_output(Some(&path), &asm)?;
+                        units.push(LinkInput::Assembly(path));
+                    }
+                    // the assembly is the output itself, or there is none
+                    unit => units.push(unit),
+                }
+            }
+            Err(e) => errors.push(e.info),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(MyError {
+            info: errors.join("\n"),
+        });
+    }
+    let ext = options.backend.extension();
+    match emit {
+        Emit::Asm => inputs
+            .iter()
+            .zip(&units)
+            .try_for_each(|(input, unit)| match unit {
+                LinkInput::Asm(asm) => {
+                    let output = output.clone().or_else(|| input.default_output(ext));
+                    write_output(output.as_deref(), asm)
+                }
+                LinkInput::Assembly(path) | LinkInput::Object(path) => {
+                    unused(path);
+                    Ok(())
+                }
+                LinkInput::Code(_) => unreachable!("-S is rejected with --backend=cranelift"),
+            }),
+        Emit::Object => inputs
+            .iter()
+            .zip(&units)
+            .try_for_each(|(input, unit)| match unit {
+                LinkInput::Object(path) => {
+                    unused(path);
+                    Ok(())
+                }
+                _ => {
+                    let output = output.clone().or_else(|| input.default_output("o"));
+                    driver::assemble(unit, &output.expect("checked by parse_args"), driver, trace)
+                }
+            }),
+        Emit::Executable => {
+            // With --save-temps, the object of each input is kept too.
+            let units = if driver.save_temps {
+                let objects = inputs
+                    .iter()
+                    .zip(units)
+                    .enumerate()
+                    .map(|(index, (input, unit))| {
+                        if let LinkInput::Object(_) = unit {
+                            return Ok(unit);
+                        }
+                        let path = input.temp_path(index, "o");
+                        driver::assemble(&unit, &path, driver, trace)?;
+                        Ok(LinkInput::Object(path))
+                    });
+                objects.collect::<Result<Vec<_>, MyError>>()?
+            } else {
+                units
+            };
+            driver::link(
+                &units,
+                output.as_deref().unwrap_or(Path::new("a.out")),
+                driver,
+                trace,
+            )
+        }
+        Emit::Tokens | Emit::Ast | Emit::AstJson | Emit::Jit => unreachable!(),
+    }
+}
+
+// Compile `input`, or take it as the assembly it is, and run it in-process,
+// returning what its main returns.
+fn jit(
+    input: &Input,
+    include_paths: Vec<PathBuf>,
+    options: CompileOptions,
+    trace: Trace,
+) -> Result<i32, MyError> {
+    let asm = match prebuilt(input) {
+        Some(LinkInput::Assembly(path)) => read_source(&path)?,
+        Some(LinkInput::Object(path)) => {
+            return Err(MyError {
+                info: format!("{}: --jit cannot run object files", path.display()),
+            })
+        }
+        _ if options.backend == Backend::Cranelift => {
+            // Cranelift compiles straight into memory
+            let (source, name, dir) = read_input(input)?;
+            let parsed = front_end(&source, &name, &dir, include_paths, &options, trace)?;
+            for warning in parsed.warnings {
+                eprintln!("{}", warning);
+            }
+            let nodes = parsed.program.nodes;
+            return trace.time("jit", || cranelift_run(parsed.parser, options, nodes));
+        }
+        _ => {
+            let compiled = compile_input(input, include_paths, options, trace)?;
+            for warning in compiled.warnings {
+                eprintln!("{}", warning);
+            }
+            compiled.asm
+        }
+    };
+    trace.time("jit", || chibicc_rust::run(&asm))
+}
+
+// An assembly or object file input, which goes to cc as it is instead of
+// being compiled.
+fn prebuilt(input: &Input) -> Option<LinkInput> {
+    let Input::File(path) = input else {
+        return None;
+    };
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("s") => Some(LinkInput::Assembly(path.clone())),
+        Some("o") => Some(LinkInput::Object(path.clone())),
+        _ => None,
+    }
+}
+
+fn compile_input(
+    input: &Input,
+    include_paths: Vec<PathBuf>,
+    options: CompileOptions,
+    trace: Trace,
+) -> Result<Output, MyError> {
+    let (source, name, dir) = read_input(input)?;
+    compile(&source, &name, &dir, include_paths, options, trace)
+}
+
+// The text of `input`, the name it goes by in diagnostics and the directory
+// its quoted #includes are relative to.
+fn read_input(input: &Input) -> Result<(String, String, PathBuf), MyError> {
+    match input {
+        Input::File(path) => {
+            let source = read_source(path)?;
+            let dir = path.parent().unwrap_or(Path::new("."));
+            Ok((source, path.display().to_string(), dir.to_path_buf()))
+        }
+        Input::Text(source) => Ok((
+            source.clone(),
+            "<command line>".to_string(),
+            PathBuf::from("."),
+        )),
+        Input::Stdin => {
+            let source = io::read_to_string(io::stdin()).map_err(|e| MyError {
+                info: format!("<stdin>: {}", e),
+            })?;
+            Ok((source, "<stdin>".to_string(), PathBuf::from(".")))
+        }
+    }
+}
+
+// Write `text` to `path`, or to stdout if there is none or it is `-`.
+fn write_output(path: Option<&Path>, text: &str) -> Result<(), MyError> {
+    match path.filter(|path| *path != Path::new("-")) {
+        Some(path) => fs::write(path, text).map_err(|e| MyError {
+            info: format!("{}: {}", path.display(), e),
+        }),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+fn read_source(path: &Path) -> Result<String, MyError> {
+    fs::read_to_string(path).map_err(|e| MyError {
+        info: format!("{}: {}", path.display(), e),
+    })
+}
+
+// Label-normalized unified diff of the assembly generated for two files.
+fn diff_files(
+    old: &Path,
+    new: &Path,
+    include_paths: Vec<PathBuf>,
+    options: CompileOptions,
+    trace: Trace,
+) -> Result<String, MyError> {
+    let asm = |path: &Path| -> Result<String, MyError> {
+        let output = compile_input(
+            &Input::File(path.to_path_buf()),
+            include_paths.clone(),
+            options.clone(),
+            trace,
+        )?;
+        Ok(diff::normalize_labels(&output.asm))
+    };
+    Ok(diff::unified_diff(
+        &old.display().to_string(),
+        &new.display().to_string(),
+        &asm(old)?,
+        &asm(new)?,
+    ))
 }
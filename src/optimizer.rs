@@ -0,0 +1,552 @@
+use crate::parser::Type;
+use crate::{Node, Parser};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+// The AST passes: constant folding and propagation with algebraic
+// simplification, and removal of code that can never run.
+
+// Fold constants and propagate the values of locals through the program.
+pub fn propagate(nodes: Vec<Node>, parser: &Parser) -> Vec<Node> {
+    let mut propagator = Propagator::new(&nodes, parser);
+    nodes
+        .into_iter()
+        .map(|node| propagator.node(node))
+        .collect()
+}
+
+// Drop the code that can never run.
+pub fn eliminate_dead_code(nodes: Vec<Node>) -> Vec<Node> {
+    nodes.into_iter().map(eliminate_dead).collect()
+}
+
+// Fold integer arithmetic and comparisons on literals, bottom up. Results
+// wrap at the width of their type like the instructions the code generator
+// would emit, and division that would trap is left for run time.
+fn fold(node: Node) -> Node {
+    fold_node(node.map_children(&mut fold))
+}
+
+// Fold `node` itself, its children already folded.
+fn fold_node(node: Node) -> Node {
+    let folded = match &node {
+        Node::Neg { lhs, r#type, .. } => {
+            num(lhs).map(|val| (wrap(val.wrapping_neg(), r#type), r#type))
+        }
+        Node::Add {
+            lhs, rhs, r#type, ..
+        }
+        | Node::Sub {
+            lhs, rhs, r#type, ..
+        }
+        | Node::Mul {
+            lhs, rhs, r#type, ..
+        }
+        | Node::Div {
+            lhs, rhs, r#type, ..
+        }
+        | Node::Eq {
+            lhs, rhs, r#type, ..
+        }
+        | Node::Ne {
+            lhs, rhs, r#type, ..
+        }
+        | Node::Lt {
+            lhs, rhs, r#type, ..
+        }
+        | Node::Le {
+            lhs, rhs, r#type, ..
+        } => match (num(lhs), num(rhs)) {
+            (Some(l), Some(r)) => match &node {
+                Node::Add { .. } => Some(l.wrapping_add(r)),
+                Node::Sub { .. } => Some(l.wrapping_sub(r)),
+                Node::Mul { .. } => Some(l.wrapping_mul(r)),
+                Node::Div { .. } if *r#type == Type::I32 => {
+                    (l as i32).checked_div(r as i32).map(i64::from)
+                }
+                Node::Div { .. } => l.checked_div(r),
+                Node::Eq { .. } => Some((l == r) as i64),
+                Node::Ne { .. } => Some((l != r) as i64),
+                Node::Lt { .. } => Some((l < r) as i64),
+                _ => Some((l <= r) as i64),
+            }
+            .map(|val| (wrap(val, r#type), r#type)),
+            _ => None,
+        },
+        _ => None,
+    };
+    match folded {
+        Some((val, r#type)) if r#type.base().is_none() => Node::Num {
+            val,
+            r#type: r#type.clone(),
+            span: node.span(),
+        },
+        _ => node,
+    }
+}
+
+// `val` truncated to an int and sign-extended back, if `r#type` is int.
+fn wrap(val: i64, r#type: &Type) -> i64 {
+    if *r#type == Type::I32 {
+        val as i32 as i64
+    } else {
+        val
+    }
+}
+
+// `val` as it reads back from an object of `r#type` it was stored to.
+fn stored(val: i64, r#type: &Type) -> i64 {
+    match r#type.unqualified() {
+        Type::Char | Type::SChar => val as i8 as i64,
+        Type::UChar | Type::Bool => val as u8 as i64,
+        Type::I32 => val as i32 as i64,
+        _ => val,
+    }
+}
+
+// Rewrite identities that hold whatever the operands are: x+0, x-0, x*1,
+// x/1 and -(-x) are x, and x*0 and x-x are 0 when x may be left out, as
+// `pure` says. The result has the value of `node`, if not always its type.
+fn simplify(node: Node, pure: &dyn Fn(&Node) -> bool) -> Node {
+    let zero = |r#type, span| Node::Num {
+        val: 0,
+        r#type,
+        span,
+    };
+    match node {
+        Node::Add { lhs, rhs, .. } if num(&rhs) == Some(0) => *lhs,
+        Node::Add { lhs, rhs, .. } if num(&lhs) == Some(0) => *rhs,
+        Node::Sub { lhs, rhs, .. } if num(&rhs) == Some(0) => *lhs,
+        Node::Mul { lhs, rhs, .. } | Node::Div { lhs, rhs, .. } if num(&rhs) == Some(1) => *lhs,
+        Node::Mul { lhs, rhs, .. } if num(&lhs) == Some(1) => *rhs,
+        Node::Mul {
+            lhs,
+            rhs,
+            r#type,
+            span,
+        } if (num(&rhs) == Some(0) && pure(&lhs)) || (num(&lhs) == Some(0) && pure(&rhs)) => {
+            zero(r#type, span)
+        }
+        Node::Sub {
+            lhs,
+            rhs,
+            r#type,
+            span,
+        } if lhs.same(&rhs) && pure(&lhs) && r#type.base().is_none() => zero(r#type, span),
+        Node::Neg { lhs, r#type, span } => match *lhs {
+            Node::Neg { lhs, .. } => *lhs,
+            lhs => Node::Neg {
+                lhs: Box::new(lhs),
+                r#type,
+                span,
+            },
+        },
+        node => node,
+    }
+}
+
+// The value of `node` if it folds to an integer constant.
+pub fn constant(node: &Node) -> Option<i64> {
+    num(&fold(node.clone()))
+}
+
+fn num(node: &Node) -> Option<i64> {
+    match node {
+        Node::Num { val, r#type, .. } if r#type.base().is_none() => Some(*val),
+        _ => None,
+    }
+}
+
+// Carries the values of locals known to hold a constant through the
+// statements, in the order they run, replacing reads of them and folding and
+// simplifying as it goes. Integer locals are only tracked while no local's
+// address is taken, so that nothing but an assignment to one by name can
+// change it (a pointer to one local may be moved to its neighbours), and
+// while there is no inline assembly, which might change any of them.
+struct Propagator {
+    tracked: HashMap<String, Type>,
+    known: HashMap<String, i64>,
+    volatile: HashSet<String>, // locals whose reads can't be left out, atomic ones too
+}
+
+impl Propagator {
+    fn new(nodes: &[Node], parser: &Parser) -> Propagator {
+        let mut escapes = false;
+        let mut work: Vec<&Node> = nodes.iter().collect();
+        while let Some(node) = work.pop() {
+            escapes |= match node {
+                Node::Addr { lhs, .. } => lhs.is_var(),
+                Node::Var { r#type, .. } => matches!(r#type, Type::Array { .. }),
+                Node::Asm { .. } => true,
+                _ => false,
+            };
+            work.extend(node.children());
+        }
+        let tracked = parser
+            .locals
+            .iter()
+            .filter(|(_, item)| {
+                !escapes
+                    && !item.r#type.is_volatile()
+                    && !item.r#type.is_atomic()
+                    && matches!(
+                        item.r#type.unqualified(),
+                        Type::Char
+                            | Type::SChar
+                            | Type::UChar
+                            | Type::Bool
+                            | Type::I32
+                            | Type::Long
+                            | Type::I64
+                    )
+            })
+            .map(|(name, item)| (name.clone(), item.r#type.clone()))
+            .collect();
+        let volatile = parser
+            .locals
+            .iter()
+            .filter(|(_, item)| item.r#type.is_volatile() || item.r#type.is_atomic())
+            .map(|(name, _)| name.clone())
+            .collect();
+        Propagator {
+            tracked,
+            known: HashMap::new(),
+            volatile,
+        }
+    }
+
+    fn node(&mut self, node: Node) -> Node {
+        match node {
+            Node::Var { name, r#type, span } => match self.known.get(&name) {
+                Some(&val) => Node::Num { val, r#type, span },
+                None => Node::Var { name, r#type, span },
+            },
+            Node::Assign {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => {
+                let lhs = self.lvalue(*lhs);
+                let rhs = self.node(*rhs);
+                if let Node::Var { name, .. } = &lhs {
+                    if let Some(var_type) = self.tracked.get(name) {
+                        match num(&rhs) {
+                            Some(val) => self.known.insert(name.clone(), stored(val, var_type)),
+                            None => self.known.remove(name),
+                        };
+                    }
+                }
+                Node::Assign {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                    r#type,
+                    span,
+                }
+            }
+            Node::Addr { lhs, r#type, span } => Node::Addr {
+                lhs: Box::new(self.lvalue(*lhs)),
+                r#type,
+                span,
+            },
+            Node::If {
+                cond,
+                then,
+                els,
+                span,
+            } => {
+                let cond = self.node(*cond);
+                // A branch that can't run is left for dead code elimination.
+                let (then, els) = match num(&cond) {
+                    Some(0) => (then, self.branch(els)),
+                    Some(_) => (self.branch(then), els),
+                    None => {
+                        let before = self.known.clone();
+                        let then = self.branch(then);
+                        let after_then = std::mem::replace(&mut self.known, before);
+                        let els = self.branch(els);
+                        // a branch that returns doesn't reach the code after
+                        if els.as_deref().is_some_and(always_returns) {
+                            self.known = after_then;
+                        } else if !then.as_deref().is_some_and(always_returns) {
+                            self.known
+                                .retain(|name, val| after_then.get(name) == Some(val));
+                        }
+                        (then, els)
+                    }
+                };
+                Node::If {
+                    cond: Box::new(cond),
+                    then,
+                    els,
+                    span,
+                }
+            }
+            Node::For {
+                init,
+                cond,
+                inc,
+                then,
+                span,
+            } => {
+                let init = self.branch(init);
+                // a loop that never runs is left for dead code elimination
+                if cond.as_deref().and_then(constant) == Some(0) {
+                    return Node::For {
+                        init,
+                        cond,
+                        inc,
+                        then,
+                        span,
+                    };
+                }
+                // Coming around again, what the loop assigns may have changed.
+                let mut work: Vec<&Node> = [&cond, &inc, &then]
+                    .into_iter()
+                    .flatten()
+                    .map(Box::as_ref)
+                    .collect();
+                while let Some(node) = work.pop() {
+                    if let Node::Assign { lhs, .. } = node {
+                        if let Node::Var { name, .. } = lhs.as_ref() {
+                            self.known.remove(name);
+                        }
+                    }
+                    work.extend(node.children());
+                }
+                let cond = self.branch(cond);
+                // the loop is only left when the condition is false
+                let exit = self.known.clone();
+                let then = self.branch(then);
+                let inc = self.branch(inc);
+                self.known = exit;
+                Node::For {
+                    init,
+                    cond,
+                    inc,
+                    then,
+                    span,
+                }
+            }
+            node => {
+                let node = fold_node(node.map_children(&mut |node| self.node(node)));
+                simplify(node, &|node| self.pure(node))
+            }
+        }
+    }
+
+    // Whether `node` has no effect and can't trap, so it may be left out.
+    fn pure(&self, node: &Node) -> bool {
+        match node {
+            Node::Num { .. } => true,
+            Node::Var { name, .. } => !self.volatile.contains(name),
+            Node::Addr { lhs, .. } => lhs.is_var(),
+            Node::Neg { lhs, .. } => self.pure(lhs),
+            Node::Add { lhs, rhs, .. }
+            | Node::Sub { lhs, rhs, .. }
+            | Node::Mul { lhs, rhs, .. }
+            | Node::Eq { lhs, rhs, .. }
+            | Node::Ne { lhs, rhs, .. }
+            | Node::Lt { lhs, rhs, .. }
+            | Node::Le { lhs, rhs, .. } => self.pure(lhs) && self.pure(rhs),
+            _ => false,
+        }
+    }
+
+    fn branch(&mut self, node: Option<Box<Node>>) -> Option<Box<Node>> {
+        node.map(|node| Box::new(self.node(*node)))
+    }
+
+    // `node` as an object assigned to or whose address is taken: a local
+    // is not replaced by its value, but a pointer dereferenced is.
+    fn lvalue(&mut self, node: Node) -> Node {
+        match node {
+            Node::Var { .. } => node,
+            Node::Deref { lhs, r#type, span } => Node::Deref {
+                lhs: Box::new(self.node(*lhs)),
+                r#type,
+                span,
+            },
+            node => self.node(node),
+        }
+    }
+}
+
+// Drop statements after a `return` in the same block, branches of an `if`
+// whose condition is a constant and loops whose condition is constant false.
+// There are no labels, so nothing can jump into the removed code.
+fn eliminate_dead(node: Node) -> Node {
+    let node = node.map_children(&mut eliminate_dead);
+    match node {
+        Node::Block { nodes, span } => {
+            let mut live = Vec::new();
+            for node in nodes {
+                let returns = always_returns(&node);
+                live.push(node);
+                if returns {
+                    break;
+                }
+            }
+            Node::Block { nodes: live, span }
+        }
+        Node::If {
+            cond,
+            then,
+            els,
+            span,
+        } => match num(&cond) {
+            Some(0) => els.map_or_else(|| empty_block(span), |els| *els),
+            Some(_) => then.map_or_else(|| empty_block(span), |then| *then),
+            None => Node::If {
+                cond,
+                then,
+                els,
+                span,
+            },
+        },
+        Node::For {
+            init,
+            cond: Some(cond),
+            span,
+            ..
+        } if num(&cond) == Some(0) => init.map_or_else(|| empty_block(span), |init| *init),
+        node => node,
+    }
+}
+
+fn always_returns(node: &Node) -> bool {
+    match node {
+        Node::Return { .. } => true,
+        Node::Block { nodes, .. } => nodes.iter().any(always_returns),
+        Node::If {
+            then: Some(then),
+            els: Some(els),
+            ..
+        } => always_returns(then) && always_returns(els),
+        _ => false,
+    }
+}
+
+fn empty_block(span: Range<usize>) -> Node {
+    Node::Block {
+        nodes: Vec::new(),
+        span,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn optimized(src: &str) -> String {
+        let (parser, mut program) = crate::parse(src);
+        let nodes = propagate(program.nodes, &parser);
+        program.nodes = eliminate_dead_code(nodes);
+        program.dump()
+    }
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(
+            optimized("{ return (1+2)*3 - 8/4 == 7; }"),
+            "Block\n  Return\n    Num 1 <int>\n"
+        );
+        // division by zero is left to trap at run time
+        assert_eq!(
+            optimized("{ return 1/0; }"),
+            "Block\n  Return\n    Div <int>\n      Num 1 <int>\n      Num 0 <int>\n"
+        );
+    }
+
+    #[test]
+    fn test_dead_code() {
+        assert_eq!(
+            optimized("{ int x; if (1) x=1; else x=2; return x; x=3; }"),
+            "\
+Block
+  Block
+  ExprStmt
+    Assign <int>
+      Var x <int>
+      Num 1 <int>
+  Return
+    Num 1 <int>
+"
+        );
+        assert_eq!(
+            optimized("{ int x; while (0) x=1; if (0) return 1; return 2; }"),
+            "Block\n  Block\n  Block\n  Block\n  Return\n    Num 2 <int>\n"
+        );
+    }
+
+    #[test]
+    fn test_propagate() {
+        assert!(optimized("{ int x=3; int y=x*2; return y+x; }")
+            .ends_with("  Return\n    Num 9 <int>\n"));
+        // stored to a char, 300 reads back as 44
+        assert!(optimized("{ char c=300; return c; }").ends_with("  Return\n    Num 44 <char>\n"));
+        // the same value on both branches, or the only branch that is left
+        let src = "int f(); { int x; int c=f(); if (c) x=4; else x=4; return x; }";
+        assert!(optimized(src).ends_with("  Return\n    Num 4 <int>\n"));
+        let src = "int f(); { int x=1; if (f()) return 0; else x=2; return x; }";
+        assert!(optimized(src).ends_with("  Return\n    Num 2 <int>\n"));
+        let src = "int f(); { int x=1; if (f()) x=3; return x; }";
+        assert!(optimized(src).ends_with("  Return\n    Var x <int>\n"));
+        let src = "{ int x=0; int y; if (x) y=5; else y=6; return y; }";
+        assert!(optimized(src).ends_with("  Return\n    Num 6 <int>\n"));
+        // with an address taken, any local may change through a pointer
+        let src = "{ int x=1; int *p=&x; *p=2; return x; }";
+        assert!(optimized(src).ends_with("  Return\n    Var x <int>\n"));
+        let src = "{ int x=3; int y=5; *(&x+1)=7; return y; }";
+        assert!(optimized(src).ends_with("  Return\n    Var y <int>\n"));
+        let src = "{ int a[1]; int y=5; *(a+1)=7; return y; }";
+        assert!(optimized(src).ends_with("  Return\n    Var y <int>\n"));
+        let src = "{ volatile int v=1; return v; }";
+        assert!(optimized(src).ends_with("  Return\n    Var v <int>\n"));
+        let src = "{ int x=1; asm(\"nop\"); return x; }";
+        assert!(optimized(src).ends_with("  Return\n    Var x <int>\n"));
+    }
+
+    #[test]
+    fn test_propagate_loop() {
+        // n is the same each time around; s and i are not
+        let src = "{ int i; int n=5; int s=0; for (i=0; i<n; i=i+1) s=s+n; return s+n; }";
+        let dump = optimized(src);
+        assert!(
+            dump.ends_with("  Return\n    Add <int>\n      Var s <int>\n      Num 5 <int>\n"),
+            "{}",
+            dump
+        );
+        assert!(
+            dump.contains("    cond: Lt <int>\n      Var i <int>\n      Num 5 <int>\n"),
+            "{}",
+            dump
+        );
+        // the loop is left with the condition false, so x is 1 after it
+        let src = "{ int x=0; while (x==0) x=1; return x; }";
+        assert!(optimized(src).ends_with("  Return\n    Var x <int>\n"));
+        let src = "{ int x=3; while (0) x=1; return x; }";
+        assert!(optimized(src).ends_with("  Return\n    Num 3 <int>\n"));
+    }
+
+    #[test]
+    fn test_simplify() {
+        let src = "int f(); { int x=f(); return -(-(x*1+0)) - 0; }";
+        assert!(optimized(src).ends_with("  Return\n    Var x <int>\n"));
+        let src = "int f(); { int x=f(); return x*0 + (x-x) + 0*x; }";
+        assert!(optimized(src).ends_with("  Return\n    Num 0 <int>\n"));
+        let src = "int f(); { int x=f(); return 2*(x/1) + 0; }";
+        let dump = optimized(src);
+        assert!(
+            dump.ends_with("  Return\n    Mul <int>\n      Num 2 <int>\n      Var x <int>\n"),
+            "{}",
+            dump
+        );
+        // the call, the volatile reads and the load through p stay
+        let src = "int f(); { volatile int v=1; int *p=&v; return f()*0 + (v-v) + *p*0; }";
+        let dump = optimized(src);
+        assert!(dump.contains("FuncCall f"), "{}", dump);
+        assert!(dump.contains("Sub <int>\n"), "{}", dump);
+        assert!(dump.contains("Deref <int>\n"), "{}", dump);
+    }
+}
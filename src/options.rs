@@ -0,0 +1,107 @@
+use crate::{PassManager, Target, Warning};
+use std::collections::BTreeSet;
+
+// Settings that change what the compiler emits, shared by the driver and
+// library users.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileOptions {
+    // -O<level>; 1 folds constants, drops dead code and tidies the assembly,
+    // 2 also converts branches to conditional moves
+    pub opt_level: u8,
+    // -fpasses=<list>; the optimization passes to run, in order, instead of
+    // those for opt_level
+    pub passes: Option<Vec<String>>,
+    pub error_limit: usize,    // -ferror-limit=N; 0 reports every error
+    pub debug_info: bool,      // -g; emit .file/.loc for DWARF line tables, and DIEs for the locals
+    pub pic: bool,             // -fpic; call functions through the PLT, for shared libraries
+    pub stack_protector: bool, // -fstack-protector; check a canary before returning
+    pub asm_comments: bool, // --asm-comments; annotate the assembly with the statements it came from
+    pub target: Target,
+    pub warnings: BTreeSet<Warning>, // enabled warnings; -Wall, -W<name>, -Wno-<name>
+    pub werror: BTreeSet<Warning>,   // enabled warnings that fail the compile; -Werror[=<name>]
+    pub backend: Backend,
+    pub color: bool, // highlight diagnostics with ANSI escapes; --color
+    pub std: Std,
+}
+
+// The language level set with -std. Constructs from later standards are
+// errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Std {
+    C89,
+    C99,
+    C11,
+}
+
+impl Std {
+    pub fn name(self) -> &'static str {
+        match self {
+            Std::C89 => "c89",
+            Std::C99 => "c99",
+            Std::C11 => "c11",
+        }
+    }
+
+    // c90 is the same language as c89.
+    pub fn from_name(name: &str) -> Option<Std> {
+        match name {
+            "c89" | "c90" => Some(Std::C89),
+            "c99" => Some(Std::C99),
+            "c11" => Some(Std::C11),
+            _ => None,
+        }
+    }
+}
+
+// What the program is lowered to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Native,    // assembly for the target
+    LlvmIr,    // textual LLVM IR; --emit=llvm-ir
+    Qbe,       // QBE IL; --emit=qbe
+    Cranelift, // machine code from Cranelift; --backend=cranelift
+}
+
+impl Backend {
+    // The extension of the text file the backend writes.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Backend::Native => "s",
+            Backend::LlvmIr => "ll",
+            Backend::Qbe => "ssa",
+            Backend::Cranelift => "o",
+        }
+    }
+}
+
+impl CompileOptions {
+    // The names of the optimization passes to run.
+    pub fn pipeline(&self) -> Vec<String> {
+        self.passes
+            .clone()
+            .unwrap_or_else(|| PassManager::default_pipeline(self.opt_level))
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            opt_level: 0,
+            passes: None,
+            error_limit: 20,
+            debug_info: false,
+            pic: false,
+            stack_protector: false,
+            asm_comments: false,
+            target: Target::host(),
+            warnings: Warning::ALL
+                .into_iter()
+                .filter(|warning| warning.default_enabled())
+                .collect(),
+            werror: BTreeSet::new(),
+            backend: Backend::Native,
+            color: false,
+            std: Std::C11,
+        }
+    }
+}
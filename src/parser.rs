@@ -1,95 +1,152 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
 
-use crate::{MyError, TokenQueue};
+use serde::Serialize;
+use std::ops::Range;
 
-#[derive(PartialEq, Debug, Clone)]
+use crate::optimizer;
+use crate::{Diagnostic, MyError, Std, Target, Token, TokenQueue, Warning};
+
+// Every node carries the byte range of the source text it was parsed from;
+// nodes the parser or the optimizer make up take the range of the one they
+// stand for.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+#[serde(tag = "kind")]
 pub enum Node {
     Add {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // +
 
     Sub {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // -
     Mul {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // *
     Div {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // /
     Neg {
         lhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // unary -
     Eq {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // ==
     Ne {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // !=
     Lt {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // <
     Le {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // <=
     Assign {
         lhs: Box<Node>,
         rhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // =
     Addr {
         lhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // unary &
     Deref {
         lhs: Box<Node>,
         r#type: Type,
+        span: Range<usize>,
     }, // unary *
     Return {
         lhs: Option<Box<Node>>,
+        span: Range<usize>,
     }, // "return"
     If {
         cond: Box<Node>,
         then: Option<Box<Node>>,
         els: Option<Box<Node>>,
+        span: Range<usize>,
     }, // "if"
     For {
         init: Option<Box<Node>>,
         cond: Option<Box<Node>>,
         inc: Option<Box<Node>>,
         then: Option<Box<Node>>,
+        span: Range<usize>,
     }, // "for" and "while"
     Block {
         nodes: Vec<Node>,
+        span: Range<usize>,
     }, // { ... }
     ExprStmt {
         expr: Box<Node>,
+        span: Range<usize>,
     }, // Expression statement
+    Asm {
+        text: String,
+        span: Range<usize>,
+    }, // "asm", emitted verbatim
     Var {
         name: String,
         r#type: Type,
+        span: Range<usize>,
     }, // Local variable
     Num {
-        val: i32,
+        val: i64,
         r#type: Type,
+        span: Range<usize>,
     }, // Integer
+    FuncCall {
+        name: String,
+        args: Vec<Node>,
+        r#type: Type,
+        span: Range<usize>,
+    }, // Function call
+    Exchange {
+        lhs: Box<Node>,
+        rhs: Box<Node>,
+        r#type: Type,
+        span: Range<usize>,
+    }, // atomically store rhs to *lhs, yielding the old value
+    FetchAdd {
+        lhs: Box<Node>,
+        rhs: Box<Node>,
+        r#type: Type,
+        span: Range<usize>,
+    }, // atomically add rhs to *lhs, yielding the old value
+    CompareSwap {
+        lhs: Box<Node>,
+        old: Box<Node>,
+        new: Box<Node>,
+        r#type: Type,
+        span: Range<usize>,
+    }, // atomically store new to *lhs if it holds old, yielding the old value
 }
 
 impl Node {
@@ -112,7 +169,11 @@ impl Node {
             | Node::Le { r#type, .. }
             | Node::Num { r#type, .. }
             | Node::Addr { r#type, .. }
-            | Node::Deref { r#type, .. } => Some(r#type.clone()),
+            | Node::Deref { r#type, .. }
+            | Node::FuncCall { r#type, .. }
+            | Node::Exchange { r#type, .. }
+            | Node::FetchAdd { r#type, .. }
+            | Node::CompareSwap { r#type, .. } => Some(r#type.clone()),
             _ => None,
         }
     }
@@ -132,10 +193,11 @@ impl Node {
             | Node::Le { r#type, .. }
             | Node::Num { r#type, .. }
             | Node::Addr { r#type, .. }
-            | Node::Deref { r#type, .. } => match r#type {
-                Type::I32 => false,
-                Type::Ptr { .. } => true,
-            },
+            | Node::Deref { r#type, .. }
+            | Node::FuncCall { r#type, .. }
+            | Node::Exchange { r#type, .. }
+            | Node::FetchAdd { r#type, .. }
+            | Node::CompareSwap { r#type, .. } => r#type.base().is_some(),
             _ => false,
         }
     }
@@ -143,17 +205,648 @@ impl Node {
         matches!(self, Self::Var { .. })
     }
 
+    // Whether the node designates an object, which the code generators can
+    // take the address of.
+    pub fn is_lvalue(&self) -> bool {
+        matches!(self, Self::Var { .. } | Self::Deref { .. })
+    }
+
     pub fn is_num(&self) -> bool {
         matches!(self, Self::Num { .. })
     }
 
     pub fn assign_type(&mut self) {}
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Node::Add { .. } => "Add",
+            Node::Sub { .. } => "Sub",
+            Node::Mul { .. } => "Mul",
+            Node::Div { .. } => "Div",
+            Node::Neg { .. } => "Neg",
+            Node::Eq { .. } => "Eq",
+            Node::Ne { .. } => "Ne",
+            Node::Lt { .. } => "Lt",
+            Node::Le { .. } => "Le",
+            Node::Assign { .. } => "Assign",
+            Node::Addr { .. } => "Addr",
+            Node::Deref { .. } => "Deref",
+            Node::Return { .. } => "Return",
+            Node::If { .. } => "If",
+            Node::For { .. } => "For",
+            Node::Block { .. } => "Block",
+            Node::ExprStmt { .. } => "ExprStmt",
+            Node::Asm { .. } => "Asm",
+            Node::Var { .. } => "Var",
+            Node::Num { .. } => "Num",
+            Node::FuncCall { .. } => "FuncCall",
+            Node::Exchange { .. } => "Exchange",
+            Node::FetchAdd { .. } => "FetchAdd",
+            Node::CompareSwap { .. } => "CompareSwap",
+        }
+    }
+
+    pub fn is_stmt(&self) -> bool {
+        matches!(
+            self,
+            Node::Return { .. }
+                | Node::If { .. }
+                | Node::For { .. }
+                | Node::Block { .. }
+                | Node::ExprStmt { .. }
+                | Node::Asm { .. }
+        )
+    }
+
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Node::Add { span, .. }
+            | Node::Sub { span, .. }
+            | Node::Mul { span, .. }
+            | Node::Div { span, .. }
+            | Node::Neg { span, .. }
+            | Node::Eq { span, .. }
+            | Node::Ne { span, .. }
+            | Node::Lt { span, .. }
+            | Node::Le { span, .. }
+            | Node::Assign { span, .. }
+            | Node::Addr { span, .. }
+            | Node::Deref { span, .. }
+            | Node::Return { span, .. }
+            | Node::If { span, .. }
+            | Node::For { span, .. }
+            | Node::Block { span, .. }
+            | Node::ExprStmt { span, .. }
+            | Node::Asm { span, .. }
+            | Node::Var { span, .. }
+            | Node::Num { span, .. }
+            | Node::FuncCall { span, .. }
+            | Node::Exchange { span, .. }
+            | Node::FetchAdd { span, .. }
+            | Node::CompareSwap { span, .. } => span.clone(),
+        }
+    }
+
+    // Source range of a statement other than a block, the unit debug info
+    // and assembly comments give a line to.
+    pub fn stmt_span(&self) -> Option<Range<usize>> {
+        match self {
+            Node::Block { .. } => None,
+            node if node.is_stmt() => Some(node.span()),
+            _ => None,
+        }
+    }
+
+    // The children of the node, in the order they run.
+    pub fn children(&self) -> Vec<&Node> {
+        match self {
+            Node::Add { lhs, rhs, .. }
+            | Node::Sub { lhs, rhs, .. }
+            | Node::Mul { lhs, rhs, .. }
+            | Node::Div { lhs, rhs, .. }
+            | Node::Eq { lhs, rhs, .. }
+            | Node::Ne { lhs, rhs, .. }
+            | Node::Lt { lhs, rhs, .. }
+            | Node::Le { lhs, rhs, .. }
+            | Node::Assign { lhs, rhs, .. }
+            | Node::Exchange { lhs, rhs, .. }
+            | Node::FetchAdd { lhs, rhs, .. } => vec![lhs, rhs],
+            Node::CompareSwap { lhs, old, new, .. } => vec![lhs, old, new],
+            Node::Neg { lhs, .. } | Node::Addr { lhs, .. } | Node::Deref { lhs, .. } => vec![lhs],
+            Node::Return { lhs, .. } => lhs.as_deref().into_iter().collect(),
+            Node::If {
+                cond, then, els, ..
+            } => std::iter::once(cond.as_ref())
+                .chain(then.as_deref())
+                .chain(els.as_deref())
+                .collect(),
+            Node::For {
+                init,
+                cond,
+                inc,
+                then,
+                ..
+            } => [init, cond, then, inc]
+                .into_iter()
+                .filter_map(|node| node.as_deref())
+                .collect(),
+            Node::Block { nodes, .. } => nodes.iter().collect(),
+            Node::ExprStmt { expr, .. } => vec![expr],
+            Node::FuncCall { args, .. } => args.iter().collect(),
+            Node::Var { .. } | Node::Num { .. } | Node::Asm { .. } => Vec::new(),
+        }
+    }
+
+    // Rebuild the node with `f` applied to each of its children, in the order
+    // they run.
+    pub fn map_children(self, f: &mut dyn FnMut(Node) -> Node) -> Node {
+        let mut b = |node: Box<Node>| Box::new(f(*node));
+        match self {
+            Node::Add {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Add {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Sub {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Sub {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Mul {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Mul {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Div {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Div {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Eq {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Eq {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Ne {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Ne {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Lt {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Lt {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Le {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Le {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Assign {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Assign {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::Exchange {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::Exchange {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::FetchAdd {
+                lhs,
+                rhs,
+                r#type,
+                span,
+            } => Node::FetchAdd {
+                lhs: b(lhs),
+                rhs: b(rhs),
+                r#type,
+                span,
+            },
+            Node::CompareSwap {
+                lhs,
+                old,
+                new,
+                r#type,
+                span,
+            } => Node::CompareSwap {
+                lhs: b(lhs),
+                old: b(old),
+                new: b(new),
+                r#type,
+                span,
+            },
+            Node::Neg { lhs, r#type, span } => Node::Neg {
+                lhs: b(lhs),
+                r#type,
+                span,
+            },
+            Node::Addr { lhs, r#type, span } => Node::Addr {
+                lhs: b(lhs),
+                r#type,
+                span,
+            },
+            Node::Deref { lhs, r#type, span } => Node::Deref {
+                lhs: b(lhs),
+                r#type,
+                span,
+            },
+            Node::Return { lhs, span } => Node::Return {
+                lhs: lhs.map(&mut b),
+                span,
+            },
+            Node::If {
+                cond,
+                then,
+                els,
+                span,
+            } => Node::If {
+                cond: b(cond),
+                then: then.map(&mut b),
+                els: els.map(&mut b),
+                span,
+            },
+            Node::For {
+                init,
+                cond,
+                inc,
+                then,
+                span,
+            } => Node::For {
+                init: init.map(&mut b),
+                cond: cond.map(&mut b),
+                then: then.map(&mut b),
+                inc: inc.map(&mut b),
+                span,
+            },
+            Node::Block { nodes, span } => Node::Block {
+                nodes: nodes.into_iter().map(&mut *f).collect(),
+                span,
+            },
+            Node::ExprStmt { expr, span } => Node::ExprStmt {
+                expr: b(expr),
+                span,
+            },
+            Node::FuncCall {
+                name,
+                args,
+                r#type,
+                span,
+            } => Node::FuncCall {
+                name,
+                args: args.into_iter().map(&mut *f).collect(),
+                r#type,
+                span,
+            },
+            node @ (Node::Var { .. } | Node::Num { .. } | Node::Asm { .. }) => node,
+        }
+    }
+
+    // Whether two expressions are the same but for where they are in the
+    // source.
+    pub fn same(&self, other: &Node) -> bool {
+        let leaves = match (self, other) {
+            (Node::Var { name: a, .. }, Node::Var { name: b, .. })
+            | (Node::FuncCall { name: a, .. }, Node::FuncCall { name: b, .. })
+            | (Node::Asm { text: a, .. }, Node::Asm { text: b, .. }) => a == b,
+            (Node::Num { val: a, .. }, Node::Num { val: b, .. }) => a == b,
+            _ => true,
+        };
+        let (lhs, rhs) = (self.children(), other.children());
+        leaves
+            && self.kind() == other.kind()
+            && self.get_type() == other.get_type()
+            && lhs.len() == rhs.len()
+            && lhs.iter().zip(&rhs).all(|(a, b)| a.same(b))
+    }
+}
+
+// A parsed translation unit. The whole input is the body of `main`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Program {
+    pub nodes: Vec<Node>,
+    pub stack_size: usize,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ProgramStats {
+    pub functions: usize,
+    pub statements: usize,
+    pub expressions: BTreeMap<&'static str, usize>, // node kind -> count
+    pub max_depth: usize,                           // deepest statement nesting
+    pub frame_bytes: usize,
 }
 
-#[derive(PartialEq, Debug, Clone)]
+impl Program {
+    pub fn stats(&self) -> ProgramStats {
+        let mut stats = ProgramStats {
+            functions: 1,
+            frame_bytes: self.stack_size,
+            ..Default::default()
+        };
+        for node in &self.nodes {
+            Self::count(node, 1, &mut stats);
+        }
+        stats
+    }
+
+    fn count(node: &Node, depth: usize, stats: &mut ProgramStats) {
+        let depth = if node.is_stmt() {
+            stats.statements += 1;
+            stats.max_depth = stats.max_depth.max(depth);
+            depth + 1
+        } else {
+            *stats.expressions.entry(node.kind()).or_default() += 1;
+            depth
+        };
+        for child in node.children() {
+            Self::count(child, depth, stats);
+        }
+    }
+
+    // The AST as an indented tree, one node per line with its kind, the
+    // name or value it carries and its type.
+    pub fn dump(&self) -> String {
+        let mut rv = String::new();
+        for node in &self.nodes {
+            Self::dump_node(node, None, 0, &mut rv);
+        }
+        rv
+    }
+
+    fn dump_node(node: &Node, label: Option<&str>, depth: usize, out: &mut String) {
+        out.push_str(&"  ".repeat(depth));
+        if let Some(label) = label {
+            out.push_str(&format!("{}: ", label));
+        }
+        out.push_str(node.kind());
+        match node {
+            Node::Var { name, .. } | Node::FuncCall { name, .. } => {
+                out.push_str(&format!(" {}", name))
+            }
+            Node::Num { val, .. } => out.push_str(&format!(" {}", val)),
+            Node::Asm { text, .. } => out.push_str(&format!(" {:?}", text)),
+            _ => {}
+        }
+        if let Some(r#type) = node.get_type() {
+            out.push_str(&format!(" <{}>", r#type));
+        }
+        out.push('\n');
+        // The optional parts of `if` and `for` are labeled, since any of them
+        // may be missing.
+        let labeled: Vec<(&str, &Option<Box<Node>>)> = match node {
+            Node::If {
+                cond, then, els, ..
+            } => {
+                Self::dump_node(cond, Some("cond"), depth + 1, out);
+                vec![("then", then), ("else", els)]
+            }
+            Node::For {
+                init,
+                cond,
+                inc,
+                then,
+                ..
+            } => vec![("init", init), ("cond", cond), ("inc", inc), ("body", then)],
+            _ => {
+                for child in node.children() {
+                    Self::dump_node(child, None, depth + 1, out);
+                }
+                return;
+            }
+        };
+        for (label, child) in labeled {
+            if let Some(child) = child {
+                Self::dump_node(child, Some(label), depth + 1, out);
+            }
+        }
+    }
+}
+
+// C-like spelling of a type for diagnostics and dumps, e.g. `int*[3]`.
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Char => write!(f, "char"),
+            Type::SChar => write!(f, "signed char"),
+            Type::UChar => write!(f, "unsigned char"),
+            Type::Bool => write!(f, "_Bool"),
+            Type::I32 => write!(f, "int"),
+            Type::Long => write!(f, "long"),
+            Type::I64 => write!(f, "long long"),
+            Type::Ptr { base } => write!(f, "{}*", base),
+            Type::Array { base, len } => write!(f, "{}[{}]", base, len),
+            Type::Func { ret, params } => {
+                let params: Vec<String> = params.iter().map(|param| param.to_string()).collect();
+                write!(f, "{}({})", ret, params.join(", "))
+            }
+            Type::Qualified { base, quals } => {
+                for (set, name) in [
+                    (quals.volatile, "volatile"),
+                    (quals.restrict, "restrict"),
+                    (quals.atomic, "_Atomic"),
+                ] {
+                    if set {
+                        write!(f, "{} ", name)?;
+                    }
+                }
+                write!(f, "{}", base)
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+#[serde(tag = "kind")]
 pub enum Type {
+    Char, // plain char, signed on x86-64
+    SChar,
+    UChar,
+    Bool, // stored as 0 or 1; converted with `!= 0`
     I32,
+    Long, // sized by the target
+    I64,  // long long
     Ptr { base: Box<Type> },
+    Array { base: Box<Type>, len: usize },
+    Func { ret: Box<Type>, params: Vec<Type> },
+    Qualified { base: Box<Type>, quals: Qualifiers },
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Default, Serialize)]
+pub struct Qualifiers {
+    pub volatile: bool,
+    pub restrict: bool,
+    pub atomic: bool,
+}
+
+impl Type {
+    // Bytes an object of this type occupies in memory on `target`.
+    pub fn size(&self, target: &Target) -> usize {
+        match self {
+            Type::Char | Type::SChar | Type::UChar | Type::Bool => 1,
+            Type::Ptr { .. } => target.pointer_size,
+            Type::I32 => 4,
+            Type::Long => target.long_size,
+            Type::I64 => 8,
+            Type::Array { base, len } => base.size(target) * len,
+            Type::Func { .. } => 1, // as GCC does for arithmetic on function pointers
+            Type::Qualified { base, .. } => base.size(target),
+        }
+    }
+
+    // The alignment an object of this type needs on `target`: that of its
+    // elements for an array, its size for a scalar.
+    pub fn align(&self, target: &Target) -> usize {
+        match self {
+            Type::Array { base, .. } | Type::Qualified { base, .. } => base.align(target),
+            _ => self.size(target),
+        }
+    }
+
+    fn qualify(self, quals: Qualifiers) -> Type {
+        if quals == Qualifiers::default() {
+            return self;
+        }
+        match self {
+            Type::Qualified { base, quals: old } => Type::Qualified {
+                base,
+                quals: Qualifiers {
+                    volatile: quals.volatile || old.volatile,
+                    restrict: quals.restrict || old.restrict,
+                    atomic: quals.atomic || old.atomic,
+                },
+            },
+            _ => Type::Qualified {
+                base: Box::new(self),
+                quals,
+            },
+        }
+    }
+
+    // The type with top-level qualifiers removed, as for the value of an
+    // lvalue.
+    pub fn unqualified(&self) -> &Type {
+        match self {
+            Type::Qualified { base, .. } => base,
+            _ => self,
+        }
+    }
+
+    pub fn is_volatile(&self) -> bool {
+        matches!(self, Type::Qualified { quals, .. } if quals.volatile)
+    }
+
+    pub fn is_atomic(&self) -> bool {
+        matches!(self, Type::Qualified { quals, .. } if quals.atomic)
+    }
+
+    // Whether a value of type `from` may be passed where `self` is expected:
+    // integers convert to each other, pointers must point to the same type up
+    // to qualifiers.
+    fn accepts(&self, from: &Type) -> bool {
+        let from = from.decay();
+        match (self.unqualified(), &from) {
+            (Type::Bool, _) => true,
+            (Type::Ptr { base }, Type::Ptr { base: from }) => {
+                base.unqualified() == from.unqualified()
+            }
+            (Type::Ptr { .. } | Type::Func { .. }, _)
+            | (_, Type::Ptr { .. } | Type::Func { .. }) => false,
+            _ => true,
+        }
+    }
+
+    // Element type of a pointer or array.
+    pub fn base(&self) -> Option<&Type> {
+        match self.unqualified() {
+            Type::Ptr { base } | Type::Array { base, .. } => Some(base),
+            _ => None,
+        }
+    }
+
+    // Replace the innermost type reached through pointers, arrays and return
+    // types with `base`.
+    fn rebase(self, base: Type) -> Type {
+        match self {
+            Type::Ptr { base: inner } => Type::Ptr {
+                base: Box::new(inner.rebase(base)),
+            },
+            Type::Array { base: inner, len } => Type::Array {
+                base: Box::new(inner.rebase(base)),
+                len,
+            },
+            Type::Func { ret, params } => Type::Func {
+                ret: Box::new(ret.rebase(base)),
+                params,
+            },
+            Type::Qualified { base: inner, quals } => inner.rebase(base).qualify(quals),
+            _ => base,
+        }
+    }
+
+    // An array used as a value is a pointer to its first element.
+    pub fn decay(&self) -> Type {
+        match self {
+            Type::Array { base, .. } => Type::Ptr { base: base.clone() },
+            _ => self.clone(),
+        }
+    }
+
+    fn pointee_size(&self, target: &Target) -> i64 {
+        self.base().map_or(1, |base| base.size(target) as i64)
+    }
+
+    // Usual arithmetic conversion: the wider integer type wins, and a pointer
+    // operand keeps its type.
+    pub fn common(lhs: &Type, rhs: &Type) -> Type {
+        match (lhs, rhs) {
+            (Type::Ptr { .. } | Type::Array { .. }, _) => lhs.decay(),
+            (_, Type::Ptr { .. } | Type::Array { .. }) => rhs.decay(),
+            (Type::I64, _) | (_, Type::I64) => Type::I64,
+            (Type::Long, _) | (_, Type::Long) => Type::Long,
+            _ => Type::I32,
+        }
+    }
 }
 
 type ParseResult = Result<Node, MyError>;
@@ -162,84 +855,453 @@ type ParseResult = Result<Node, MyError>;
 pub struct VarTableItem {
     pub offset: usize,
     pub r#type: Type,
+    pub decl: Range<usize>, // span of the declaring identifier
+    pub align: usize,       // minimum alignment of the stack address
+    pub used: bool,         // referenced after its declaration
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Attributes {
+    aligned: Option<usize>,
+}
+
+impl Attributes {
+    // Attributes written later override earlier ones.
+    fn merge(self, later: Attributes) -> Attributes {
+        Attributes {
+            aligned: later.aligned.or(self.aligned),
+        }
+    }
 }
 
 type VarTable = HashMap<String, VarTableItem>; // variable name offset hashtable
 
+// A declared function: its Type::Func signature and where it was declared.
+#[derive(Clone)]
+pub struct FuncItem {
+    pub r#type: Type,
+    pub decl: Range<usize>,
+}
+
 pub struct Parser {
     pub locals: VarTable,
+    pub functions: HashMap<String, FuncItem>, // global scope
     pub locals_dequeue: VecDeque<String>,
     pub stack_size: usize,
     pub nodes: Vec<Node>,
     pub token_queue: TokenQueue,
+    pub diagnostics: Vec<Diagnostic>,
+    pub warnings: Vec<Diagnostic>, // every warning found; the driver picks the enabled ones
+    pub error_limit: usize,        // stop after this many errors, 0 for no limit
+    pub target: Target,
+    pub std: Std,
 }
 
 impl Parser {
     pub fn new(token_queue: TokenQueue) -> Self {
         Self {
             locals: HashMap::new(),
+            functions: HashMap::new(),
             locals_dequeue: VecDeque::new(),
             stack_size: 0,
             nodes: Vec::new(),
             token_queue,
+            diagnostics: Vec::new(),
+            warnings: Vec::new(),
+            error_limit: 0,
+            target: Target::x86_64(),
+            std: Std::C11,
         }
     }
+
+    // Record an error and keep parsing, unless the error limit is reached.
+    fn report(&mut self, diagnostic: Diagnostic) -> Result<(), MyError> {
+        self.diagnostics.push(diagnostic);
+        if self.error_limit_reached() {
+            return Err(MyError {
+                info: "too many errors emitted, stopping now".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    // An error at `span` of the source, for the code generators to return.
+    pub fn error_at(&self, span: Range<usize>, message: String) -> MyError {
+        self.token_queue.source().error(span.start, message)
+    }
+
+    // Report `what` at `span` unless the -std level is `since` or later.
+    fn require_std(&mut self, since: Std, what: &str, span: Range<usize>) -> Result<(), MyError> {
+        if self.std < since {
+            self.report(Diagnostic::new(
+                span,
+                format!("{} requires -std={} or later", what, since.name()),
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn error_limit_reached(&self) -> bool {
+        self.error_limit != 0 && self.diagnostics.len() >= self.error_limit
+    }
+
+    // Skip to the end of the broken statement: past the next ";", or up to the
+    // "}" closing the enclosing block.
+    fn synchronize(&mut self) {
+        while !self.token_queue.at_eof() && !self.token_queue.is_reserve("}") {
+            let semicolon = self.token_queue.is_reserve(";");
+            self.token_queue.skip();
+            if semicolon {
+                break;
+            }
+        }
+    }
+    // In declaration order.
+    fn warn_unused_variables(&mut self) {
+        for name in self.locals_dequeue.iter().rev() {
+            let item = &self.locals[name];
+            if !item.used {
+                self.warnings.push(Diagnostic::warning(
+                    Warning::UnusedVariable,
+                    item.decl.clone(),
+                    format!("unused variable '{}'", name),
+                ));
+            }
+        }
+    }
+
+    // Whether evaluating `node` does more than compute a value: assignments,
+    // calls, atomics and volatile accesses.
+    fn has_side_effects(&self, node: &Node) -> bool {
+        match node {
+            Node::Assign { .. }
+            | Node::FuncCall { .. }
+            | Node::Exchange { .. }
+            | Node::FetchAdd { .. }
+            | Node::CompareSwap { .. } => true,
+            Node::Var { name, .. } => self.locals[name].r#type.is_volatile(),
+            Node::Deref { lhs, .. }
+                if lhs
+                    .get_type()
+                    .and_then(|r#type| r#type.base().map(Type::is_volatile))
+                    == Some(true) =>
+            {
+                true
+            }
+            _ => node
+                .children()
+                .into_iter()
+                .any(|child| self.has_side_effects(child)),
+        }
+    }
+
     fn find_var(&self, name: &String) -> Option<VarTableItem> {
         self.locals.get(name).cloned()
     }
 
-    fn push_var(&mut self, name: String, r#type: Type) -> usize {
-        match self.locals.get(&name) {
-            None => {
-                self.locals_dequeue.push_front(name.clone());
-                let item = VarTableItem {
-                    offset: self.locals_dequeue.len() * 8,
-                    r#type,
-                };
-                self.locals.insert(name, item);
+    fn push_var(&mut self, name: String, r#type: Type, decl: Range<usize>, align: usize) {
+        if !self.locals.contains_key(&name) {
+            self.locals_dequeue.push_front(name.clone());
+            let item = VarTableItem {
+                offset: 0, // set by assign_lvar_offset
+                r#type,
+                decl,
+                align,
+                used: false,
+            };
+            self.locals.insert(name, item);
+        }
+    }
+
+    fn is_typename(&self) -> bool {
+        [
+            "__attribute__",
+            "volatile",
+            "restrict",
+            "_Atomic",
+            "_Bool",
+            "_Static_assert",
+            "char",
+            "signed",
+            "unsigned",
+            "int",
+            "long",
+        ]
+        .iter()
+        .any(|name| self.token_queue.is_reserve(name))
+    }
+
+    // qualifiers = ("volatile" | "restrict" | "_Atomic")*
+    fn qualifiers(&mut self) -> Result<Qualifiers, MyError> {
+        let mut quals = Qualifiers::default();
+        loop {
+            if self.token_queue.consume_reserve("volatile")? {
+                quals.volatile = true;
+            } else if self.token_queue.consume_reserve("restrict")? {
+                quals.restrict = true;
+            } else if self.token_queue.is_reserve("_Atomic") {
+                let span = self.token_queue.span(0);
+                self.token_queue.skip();
+                self.require_std(Std::C11, "'_Atomic'", span)?;
+                quals.atomic = true;
+            } else {
+                return Ok(quals);
             }
-            _ => {}
-        };
-        self.locals_dequeue.len() * 8
+        }
     }
 
-    // declspec = "int"
+    // declspec = qualifiers base-type qualifiers
     fn declspec(&mut self) -> Result<Type, MyError> {
+        let quals = self.qualifiers()?;
+        let r#type = self.base_type()?.qualify(quals);
+        Ok(r#type.qualify(self.qualifiers()?))
+    }
+
+    // pointers = ("*" qualifiers)*
+    fn pointers(&mut self, mut r#type: Type) -> Result<Type, MyError> {
+        while self.token_queue.consume_reserve("*")? {
+            r#type = Type::Ptr {
+                base: Box::new(r#type),
+            }
+            .qualify(self.qualifiers()?);
+        }
+        Ok(r#type)
+    }
+
+    // base-type = "char" | ("signed" | "unsigned") "char" | "_Bool" | "int"
+    //           | "long" "long"? "int"?
+    fn base_type(&mut self) -> Result<Type, MyError> {
+        if self.token_queue.is_reserve("_Bool") {
+            let span = self.token_queue.span(0);
+            self.token_queue.skip();
+            self.require_std(Std::C99, "'_Bool'", span)?;
+            return Ok(Type::Bool);
+        }
+        if self.token_queue.consume_reserve("char")? {
+            return Ok(Type::Char);
+        }
+        if self.token_queue.consume_reserve("signed")? {
+            self.token_queue.expect_reserve("char")?;
+            return Ok(Type::SChar);
+        }
+        if self.token_queue.consume_reserve("unsigned")? {
+            self.token_queue.expect_reserve("char")?;
+            return Ok(Type::UChar);
+        }
+        if self.token_queue.consume_reserve("long")? {
+            let long_long = self.token_queue.consume_reserve("long")?;
+            self.token_queue.consume_reserve("int")?;
+            return Ok(if long_long { Type::I64 } else { Type::Long });
+        }
         self.token_queue.expect_reserve("int")?;
         Ok(Type::I32)
     }
 
-    // declarator = "*"* ident
-    fn declarator(&mut self, base_type: Type) -> ParseResult {
-        let mut num = 0;
-        while self.token_queue.consume_reserve("*")? {
-            num += 1;
+    // declarator = pointers ("(" declarator ")" | ident) type-suffix
+    fn declarator(&mut self, r#type: Type) -> Result<(String, Range<usize>, Type), MyError> {
+        let r#type = self.pointers(r#type)?;
+        if self.token_queue.consume_reserve("(")? {
+            // In `int (*p)[3]` the suffix binds before the parenthesized part:
+            // build the inner declarator on a stand-in base, then swap in the
+            // suffixed type.
+            let (name, span, inner) = self.declarator(Type::I32)?;
+            self.token_queue.expect_reserve(")")?;
+            let r#type = self.type_suffix(r#type)?;
+            return Ok((name, span, inner.rebase(r#type)));
+        }
+        let span = self.token_queue.span(0);
+        let Some(name) = self.token_queue.consume_ident()? else {
+            return Err(MyError {
+                info: "expect a variable name".to_string(),
+            });
+        };
+        let r#type = self.type_suffix(r#type)?;
+        Ok((name, span, r#type))
+    }
+
+    // type-suffix = "(" func-params | "[" num? "]" type-suffix | ε
+    // func-params = (param ("," param)*)? ")"
+    fn type_suffix(&mut self, base: Type) -> Result<Type, MyError> {
+        if self.token_queue.consume_reserve("(")? {
+            let mut params = Vec::new();
+            if !self.token_queue.consume_reserve(")")? {
+                loop {
+                    params.push(self.param()?);
+                    if self.token_queue.consume_reserve(")")? {
+                        break;
+                    }
+                    self.token_queue.expect_reserve(",")?;
+                }
+            }
+            return Ok(Type::Func {
+                ret: Box::new(base),
+                params,
+            });
+        }
+        if !self.token_queue.consume_reserve("[")? {
+            return Ok(base);
+        }
+        // `[]` is only useful for parameters, which decay to pointers anyway
+        let len = if self.token_queue.consume_reserve("]")? {
+            0
+        } else {
+            let len = self.token_queue.expect_num()?;
+            self.token_queue.expect_reserve("]")?;
+            usize::try_from(len).map_err(|_| MyError {
+                info: format!("invalid array length: {}", len),
+            })?
+        };
+        let base = self.type_suffix(base)?;
+        Ok(Type::Array {
+            base: Box::new(base),
+            len,
+        })
+    }
+
+    // param = declspec pointers ident? type-suffix
+    // Array parameters are pointers.
+    fn param(&mut self) -> Result<Type, MyError> {
+        let declspec = self.declspec()?;
+        let r#type = self.pointers(declspec)?;
+        self.token_queue.consume_ident()?;
+        Ok(self.type_suffix(r#type)?.decay())
+    }
+
+    fn declare_var(
+        &mut self,
+        name: String,
+        span: Range<usize>,
+        r#type: Type,
+        attrs: Attributes,
+    ) -> ParseResult {
+        if let Type::Qualified { base, quals } = &r#type {
+            if quals.restrict && !matches!(base.as_ref(), Type::Ptr { .. }) {
+                return Err(MyError {
+                    info: format!("restrict requires a pointer type: '{}'", name),
+                });
+            }
+        }
+        if let Some(prev) = self.find_var(&name) {
+            self.report(
+                Diagnostic::new(span.clone(), format!("redefinition of '{}'", name))
+                    .with_note(prev.decl, "previous declaration was here"),
+            )?;
+        }
+        // `aligned` can raise the alignment of a variable but not lower it
+        let align = attrs.aligned.unwrap_or(1).max(r#type.align(&self.target));
+        self.push_var(name.clone(), r#type.clone(), span.clone(), align);
+        Ok(Node::Var {
+            name,
+            r#type: r#type.unqualified().clone(),
+            span,
+        })
+    }
+
+    // Function declarations go to the global scope wherever they appear, and
+    // may be repeated with the same signature.
+    fn declare_function(
+        &mut self,
+        name: String,
+        span: Range<usize>,
+        r#type: Type,
+    ) -> Result<(), MyError> {
+        match self.functions.get(&name) {
+            Some(prev) if prev.r#type != r#type => {
+                let decl = prev.decl.clone();
+                self.report(
+                    Diagnostic::new(span, format!("conflicting types for '{}'", name))
+                        .with_note(decl, "previous declaration was here"),
+                )
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.functions.insert(name, FuncItem { r#type, decl: span });
+                Ok(())
+            }
         }
-        if let Some(name) = self.token_queue.consume_ident()? {
-            let r#type = if num > 0 {
-                let mut t = Type::Ptr {
-                    base: Box::new(base_type),
+    }
+
+    // attributes = ("__attribute__" "(" "(" (attribute ("," attribute)*)? ")" ")")*
+    // attribute = ident ("(" tokens ")")?
+    // Only `aligned` has an effect; unknown attributes and their arguments
+    // are skipped. (`packed` only matters for aggregates, which do not exist
+    // yet.)
+    fn attributes(&mut self) -> Result<Attributes, MyError> {
+        let mut attrs = Attributes::default();
+        while self.token_queue.consume_reserve("__attribute__")? {
+            self.token_queue.expect_reserve("(")?;
+            self.token_queue.expect_reserve("(")?;
+            while !self.token_queue.consume_reserve(")")? {
+                let Some(name) = self.token_queue.consume_ident()? else {
+                    return Err(MyError {
+                        info: format!("expected an attribute name {}", self.token_queue.here()),
+                    });
                 };
-                for _ in 0..num - 1 {
-                    t = Type::Ptr { base: Box::new(t) }
+                match name.trim_matches('_') {
+                    "aligned" => attrs.aligned = Some(self.attribute_alignment()?),
+                    _ => self.skip_parens()?,
                 }
-                t
-            } else {
-                Type::I32
-            };
+                if !self.token_queue.consume_reserve(",")? {
+                    self.token_queue.expect_reserve(")")?;
+                    break;
+                }
+            }
+            self.token_queue.expect_reserve(")")?;
+        }
+        Ok(attrs)
+    }
 
-            self.push_var(name.clone(), r#type.clone());
-            Ok(Node::Var { name, r#type })
+    // `aligned` without an argument means the largest useful alignment. The
+    // frame pointer is only 16-byte aligned, so that is also the limit.
+    fn attribute_alignment(&mut self) -> Result<usize, MyError> {
+        let align = if self.token_queue.consume_reserve("(")? {
+            let align = self.token_queue.expect_num()?;
+            self.token_queue.expect_reserve(")")?;
+            align
         } else {
-            Err(MyError {
-                info: "expect a variable name".to_string(),
-            })
+            16
+        };
+        match usize::try_from(align) {
+            Ok(align @ 1..=16) if align.is_power_of_two() => Ok(align),
+            _ => Err(MyError {
+                info: format!("invalid or unsupported alignment: {}", align),
+            }),
+        }
+    }
+
+    // Skip a parenthesized token sequence, if there is one.
+    fn skip_parens(&mut self) -> Result<(), MyError> {
+        if !self.token_queue.consume_reserve("(")? {
+            return Ok(());
+        }
+        let mut depth = 1;
+        while depth > 0 {
+            if self.token_queue.at_eof() {
+                return Err(MyError {
+                    info: "unterminated attribute arguments".to_string(),
+                });
+            }
+            if self.token_queue.is_reserve("(") {
+                depth += 1;
+            } else if self.token_queue.is_reserve(")") {
+                depth -= 1;
+            }
+            self.token_queue.skip();
         }
+        Ok(())
     }
 
-    //declaration = declspec (declarator ("=" expr)? ("," declarator ("=" expr)?)*)? ";"
+    // declaration = static-assert
+    //             | attributes declspec attributes
+    //               (declarator attributes ("=" expr)? ("," declarator attributes ("=" expr)?)*)? ";"
     fn declaration(&mut self) -> ParseResult {
+        let decl_start = self.token_queue.span(0).start;
+        if self.token_queue.is_reserve("_Static_assert") {
+            return self.static_assert();
+        }
+        let mut common = self.attributes()?;
         let base_type = self.declspec()?;
+        common = common.merge(self.attributes()?);
         let mut head = true;
         let mut nodes = Vec::new();
         while !self.token_queue.consume_reserve(";")? {
@@ -250,31 +1312,110 @@ impl Parser {
                 head = false;
             }
 
-            let declarator = self.declarator(base_type.clone())?;
+            let (name, span, r#type) = self.declarator(base_type.clone())?;
+            let start = span.start;
+            let attrs = common.merge(self.attributes()?);
+            if let Type::Func { .. } = r#type {
+                self.declare_function(name, span, r#type)?;
+                continue;
+            }
+            let declarator = self.declare_var(name, span, r#type, attrs)?;
             if !self.token_queue.consume_reserve("=")? {
                 // TODO: support initialization variable use empty value
                 continue;
             }
+            if let Some(Type::Array { .. }) = declarator.get_type() {
+                self.report(Diagnostic::new(
+                    declarator.span(),
+                    "array initializers are not supported",
+                ))?;
+                self.expr()?;
+                continue;
+            }
+            let r#type = declarator.get_type().expect("should have a type");
+            let rhs = Self::convert(self.expr()?, &r#type);
+            let span = start..self.token_queue.prev_end();
             let assign_node = Node::Assign {
                 lhs: Box::new(declarator),
-                rhs: Box::new(self.expr()?),
-                r#type: base_type.clone(),
+                rhs: Box::new(rhs),
+                r#type,
+                span: span.clone(),
             };
             let node = Node::ExprStmt {
                 expr: Box::new(assign_node),
+                span,
             };
             nodes.push(node);
         }
-        return Ok(Node::Block { nodes });
+        Ok(Node::Block {
+            nodes,
+            span: decl_start..self.token_queue.prev_end(),
+        })
     }
 
-    // program = stmt*
-    pub fn program(&mut self) -> Result<Vec<Node>, MyError> {
+    // static-assert = "_Static_assert" "(" expr "," str ")" ";"
+    fn static_assert(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
+        self.token_queue.expect_reserve("_Static_assert")?;
+        self.require_std(
+            Std::C11,
+            "'_Static_assert'",
+            start..self.token_queue.prev_end(),
+        )?;
+        self.token_queue.expect_reserve("(")?;
+        let cond = self.expr()?;
+        self.token_queue.expect_reserve(",")?;
+        let message = self.token_queue.expect_str()?;
+        self.token_queue.expect_reserve(")")?;
+        self.token_queue.expect_reserve(";")?;
+        match optimizer::constant(&cond) {
+            Some(0) => self.report(Diagnostic::new(
+                start..self.token_queue.prev_end(),
+                format!("static assertion failed: {:?}", message),
+            ))?,
+            Some(_) => {}
+            None => self.report(Diagnostic::new(
+                cond.span(),
+                "static assertion expression is not an integer constant",
+            ))?,
+        }
+        Ok(Node::Block {
+            nodes: Vec::new(),
+            span: start..self.token_queue.prev_end(),
+        })
+    }
+
+    // program = (declaration | stmt)*
+    // Errors not reported inside a block are reported at the token the parser
+    // stopped at, and end the parse.
+    pub fn program(&mut self) -> Result<Program, MyError> {
         let mut nodes = Vec::new();
         while !self.token_queue.at_eof() {
-            nodes.push(self.stmt()?);
+            let node = if self.is_typename() {
+                self.declaration()
+            } else {
+                self.stmt()
+            };
+            match node {
+                Ok(node) => nodes.push(node),
+                Err(e) if self.error_limit_reached() => return Err(e),
+                Err(e) => {
+                    self.report(Diagnostic::new(self.token_queue.span(0), e.info))?;
+                    break;
+                }
+            }
         }
-        Ok(nodes)
+        if !self.diagnostics.is_empty() {
+            return Err(MyError {
+                info: format!("{} error(s) generated", self.diagnostics.len()),
+            });
+        }
+        self.warn_unused_variables();
+        self.assign_lvar_offset();
+        Ok(Program {
+            nodes,
+            stack_size: self.stack_size,
+        })
     }
 
     // stmt = "return" expr ";"
@@ -282,15 +1423,31 @@ impl Parser {
     //      | "for" "(" expr-stmt expr? ";" expr? ")" stmt
     //      | "while" "(" expr ")" stmt
     //      | "{" compound-stmt
+    //      | ("asm" | "__asm__") "(" str ")" ";"
     //      | expr-stmt
     fn stmt(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
+        if self.token_queue.consume_reserve("asm")?
+            || self.token_queue.consume_reserve("__asm__")?
+        {
+            self.token_queue.expect_reserve("(")?;
+            let text = self.token_queue.expect_str()?;
+            self.token_queue.expect_reserve(")")?;
+            self.token_queue.expect_reserve(";")?;
+            return Ok(Node::Asm {
+                text,
+                span: start..self.token_queue.prev_end(),
+            });
+        }
+
         // RETURN NODE
         if self.token_queue.consume_reserve("return")? {
-            let node = Node::Return {
-                lhs: Some(Box::new(self.expr()?)),
-            };
+            let lhs = Some(Box::new(self.expr()?));
             self.token_queue.expect_reserve(";")?;
-            return Ok(node);
+            return Ok(Node::Return {
+                lhs,
+                span: start..self.token_queue.prev_end(),
+            });
         }
 
         //      | "if" "(" expr ")" stmt ("else" stmt)?
@@ -308,6 +1465,7 @@ impl Parser {
                 cond: Box::new(cond),
                 then: Some(Box::new(then)),
                 els,
+                span: start..self.token_queue.prev_end(),
             });
         }
 
@@ -336,6 +1494,7 @@ impl Parser {
                 cond,
                 inc,
                 then: Some(Box::new(then)),
+                span: start..self.token_queue.prev_end(),
             });
         }
 
@@ -351,40 +1510,75 @@ impl Parser {
                 inc: None,
                 cond: Some(Box::new(cond)),
                 then: Some(Box::new(then)),
+                span: start..self.token_queue.prev_end(),
             });
         }
 
         // block node
         if self.token_queue.consume_reserve("{")? {
-            return self.compound_stmt();
+            return self.compound_stmt(start);
         }
-        return self.expr_stmt();
+        self.expr_stmt()
     }
 
     // compound-stmt = (declaration | stmt)* "}"
-    fn compound_stmt(&mut self) -> ParseResult {
+    // Before C99 the declarations must come first. `start` is where the "{"
+    // is.
+    fn compound_stmt(&mut self, start: usize) -> ParseResult {
         let mut nodes = Vec::new();
+        let mut statements = false;
         while !self.token_queue.consume_reserve("}")? {
-            let node = if self.token_queue.is_reserve("int") {
-                self.declaration()?
+            if self.token_queue.at_eof() {
+                self.token_queue.expect_reserve("}")?;
+            }
+            let node = if self.is_typename() {
+                if statements {
+                    let span = self.token_queue.span(0);
+                    self.require_std(Std::C99, "declaration after statement", span)?;
+                }
+                self.declaration()
             } else {
-                self.stmt()?
+                statements = true;
+                self.stmt()
             };
-            nodes.push(node);
+            match node {
+                Ok(node) => nodes.push(node),
+                Err(e) if self.error_limit_reached() || self.token_queue.at_eof() => return Err(e),
+                Err(e) => {
+                    self.report(Diagnostic::new(self.token_queue.span(0), e.info))?;
+                    self.synchronize();
+                }
+            }
         }
-        Ok(Node::Block { nodes })
+        Ok(Node::Block {
+            nodes,
+            span: start..self.token_queue.prev_end(),
+        })
     }
 
     // expr-stmt = expr? ";"
     fn expr_stmt(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
         if self.token_queue.consume_reserve(";")? {
-            return Ok(Node::Block { nodes: Vec::new() });
+            return Ok(Node::Block {
+                nodes: Vec::new(),
+                span: start..self.token_queue.prev_end(),
+            });
         };
         let node = self.expr()?;
         self.token_queue.expect_reserve(";")?;
-        return Ok(Node::ExprStmt {
+        let span = start..self.token_queue.prev_end();
+        if !self.has_side_effects(&node) {
+            self.warnings.push(Diagnostic::warning(
+                Warning::UnusedValue,
+                node.span(),
+                "expression result unused",
+            ));
+        }
+        Ok(Node::ExprStmt {
             expr: Box::new(node),
-        });
+            span,
+        })
     }
     // expr = assign
     fn expr(&mut self) -> ParseResult {
@@ -393,19 +1587,50 @@ impl Parser {
 
     // assign = equality ("=" assign)?
     fn assign(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
         let mut node = self.equality()?;
         if self.token_queue.consume_reserve("=")? {
+            if let Some(Type::Array { .. }) = node.get_type() {
+                self.report(Diagnostic::new(node.span(), "array is not assignable"))?;
+            } else if !node.is_lvalue() {
+                self.report(Diagnostic::new(
+                    node.span(),
+                    "lvalue required as left operand of assignment",
+                ))?;
+            }
+            let r#type = node.get_type().expect("should have a type");
+            let rhs = Self::convert(self.assign()?, &r#type);
             node = Node::Assign {
                 lhs: Box::new(node),
-                rhs: Box::new(self.assign()?),
-                r#type: node.get_type().expect("should have a type"),
+                rhs: Box::new(rhs),
+                r#type,
+                span: start..self.token_queue.prev_end(),
             };
         }
         Ok(node)
     }
 
+    // `node` converted for assignment to an object of `r#type`. Only _Bool
+    // needs anything done: every nonzero value becomes 1.
+    fn convert(node: Node, r#type: &Type) -> Node {
+        match r#type.unqualified() {
+            Type::Bool => Node::Ne {
+                rhs: Box::new(Node::Num {
+                    val: 0,
+                    r#type: Type::I32,
+                    span: node.span(),
+                }),
+                r#type: Type::I32,
+                span: node.span(),
+                lhs: Box::new(node),
+            },
+            _ => node,
+        }
+    }
+
     // equality = relational ("==" relational | "!=" relational)*
     fn equality(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
         let mut node = self.relational()?;
         loop {
             if self.token_queue.consume_reserve("==")? {
@@ -413,12 +1638,14 @@ impl Parser {
                     lhs: Box::new(node),
                     rhs: Box::new(self.relational()?),
                     r#type: Type::I32,
+                    span: start..self.token_queue.prev_end(),
                 };
             } else if self.token_queue.consume_reserve("!=")? {
                 node = Node::Ne {
                     lhs: Box::new(node),
                     rhs: Box::new(self.relational()?),
                     r#type: Type::I32,
+                    span: start..self.token_queue.prev_end(),
                 };
             } else {
                 return Ok(node);
@@ -428,6 +1655,7 @@ impl Parser {
 
     // relational = add ("<" add | "<=" add | ">" add | ">=" add)*
     fn relational(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
         let mut node = self.add()?;
         loop {
             if self.token_queue.consume_reserve("<")? {
@@ -435,24 +1663,28 @@ impl Parser {
                     lhs: Box::new(node),
                     rhs: Box::new(self.add()?),
                     r#type: Type::I32,
+                    span: start..self.token_queue.prev_end(),
                 };
             } else if self.token_queue.consume_reserve("<=")? {
                 node = Node::Le {
                     lhs: Box::new(node),
                     rhs: Box::new(self.add()?),
                     r#type: Type::I32,
+                    span: start..self.token_queue.prev_end(),
                 };
             } else if self.token_queue.consume_reserve(">")? {
                 node = Node::Lt {
                     lhs: Box::new(self.add()?),
                     rhs: Box::new(node),
                     r#type: Type::I32,
+                    span: start..self.token_queue.prev_end(),
                 };
             } else if self.token_queue.consume_reserve(">=")? {
                 node = Node::Le {
                     lhs: Box::new(self.add()?),
                     rhs: Box::new(node),
                     r#type: Type::I32,
+                    span: start..self.token_queue.prev_end(),
                 };
             } else {
                 return Ok(node);
@@ -461,31 +1693,25 @@ impl Parser {
     }
 
     // Canonicalize `num + ptr` to `ptr + num`.
-    fn new_add(&self, mut node: Node) -> Result<Node, MyError> {
+    fn new_add(&mut self, mut node: Node) -> Result<Node, MyError> {
         let Node::Add {
             ref mut lhs,
             ref mut rhs,
-            ..
+            ref mut r#type,
+            ref span,
         } = node
         else {
             return Err(MyError {
-                info: format!(
-                    "not a add node, current node: {:?}, current token: {:?}",
-                    node, self.token_queue
-                ),
+                info: format!("not a add node, current node: {:?}", node),
             });
         };
         if lhs.is_ptr_node() && rhs.is_ptr_node() {
-            return Err(MyError {
-                info: format!(
-                    "two pointer add error, current node: {:?}, current token: {:?}",
-                    node, self.token_queue
-                ),
-            });
+            let diagnostic = Self::invalid_operands("+", lhs, rhs, span.clone());
+            self.report(diagnostic)?;
+            return Ok(node);
         }
         if (lhs.is_num() && rhs.is_var()) || rhs.is_ptr_node() {
             std::mem::swap(lhs, rhs);
-            return Ok(node);
         }
 
         // ptr + num
@@ -493,48 +1719,58 @@ impl Parser {
             let new_rhs = Box::new(Node::Mul {
                 lhs: Box::new(*rhs.clone()),
                 rhs: Box::new(Node::Num {
-                    val: 8,
+                    val: lhs
+                        .get_type()
+                        .expect("should have a type")
+                        .pointee_size(&self.target),
                     r#type: Type::I32,
+                    span: rhs.span(),
                 }),
                 r#type: Type::I32,
+                span: rhs.span(),
             });
             let _ = std::mem::replace(rhs, new_rhs);
+            *r#type = lhs.get_type().expect("should have a type").decay();
             return Ok(node);
         }
 
-        return Ok(node);
+        Ok(node)
     }
 
     // for support pointer - pointer and pointer - number
-    fn new_sub(&self, node: Node) -> Result<Node, MyError> {
+    fn new_sub(&mut self, node: Node) -> Result<Node, MyError> {
         let Node::Sub {
-            ref lhs, ref rhs, ..
+            ref lhs,
+            ref rhs,
+            ref span,
+            ..
         } = node
         else {
             return Err(MyError {
-                info: format!(
-                    "not a sub node, current node: {:?}, current token: {:?}",
-                    node, self.token_queue
-                ),
+                info: format!("not a sub node, current node: {:?}", node),
             });
         };
         if rhs.is_ptr_node() && !lhs.is_ptr_node() {
-            return Err(MyError {
-                info: format!(
-                    "minus pointer error, current node: {:?}, current token : {:?}",
-                    node, self.token_queue
-                ),
-            });
+            let diagnostic = Self::invalid_operands("-", lhs, rhs, span.clone());
+            self.report(diagnostic)?;
+            return Ok(node);
         }
 
         if lhs.is_ptr_node() && rhs.is_ptr_node() {
+            let size = lhs
+                .get_type()
+                .expect("should have a type")
+                .pointee_size(&self.target);
+            let span = span.clone();
             let new_node = Node::Div {
                 lhs: Box::new(node),
                 rhs: Box::new(Node::Num {
-                    val: 8,
+                    val: size,
                     r#type: Type::I32,
+                    span: span.clone(),
                 }),
                 r#type: Type::I32,
+                span,
             };
             return Ok(new_node);
         }
@@ -542,36 +1778,262 @@ impl Parser {
             let new_rhs = Box::new(Node::Mul {
                 lhs: Box::new(*rhs.clone()),
                 rhs: Box::new(Node::Num {
-                    val: 8,
+                    val: lhs
+                        .get_type()
+                        .expect("should have a type")
+                        .pointee_size(&self.target),
                     r#type: Type::I32,
+                    span: rhs.span(),
                 }),
                 r#type: Type::I32,
+                span: rhs.span(),
             });
             return Ok(Node::Sub {
                 lhs: Box::new(*lhs.clone()),
                 rhs: new_rhs,
-                r#type: lhs.get_type().expect("should have a type"),
+                r#type: lhs.get_type().expect("should have a type").decay(),
+                span: span.clone(),
             });
         }
-        return Ok(node);
+        Ok(node)
+    }
+
+    // `invalid operands to binary + (have 'int*' and 'int*')`
+    fn invalid_operands(op: &str, lhs: &Node, rhs: &Node, span: Range<usize>) -> Diagnostic {
+        let r#type = |node: &Node| node.get_type().expect("should have a type");
+        Diagnostic::new(
+            span,
+            format!(
+                "invalid operands to binary {} (have '{}' and '{}')",
+                op,
+                r#type(lhs),
+                r#type(rhs)
+            ),
+        )
+    }
+
+    // type-name = declspec pointers type-suffix
+    fn type_name(&mut self) -> Result<Type, MyError> {
+        let declspec = self.declspec()?;
+        let r#type = self.pointers(declspec)?;
+        self.type_suffix(r#type)
+    }
+
+    // Compiler builtins are folded or lowered here and never emitted as calls.
+    // builtin = "__builtin_expect" "(" assign "," assign ")"
+    //         | "__builtin_constant_p" "(" assign ")"
+    //         | "__builtin_types_compatible_p" "(" type-name "," type-name ")"
+    //         | ("__atomic_exchange_n" | "__atomic_fetch_add") "(" assign "," assign "," assign ")"
+    //         | "__sync_val_compare_and_swap" "(" assign "," assign "," assign ")"
+    // `start` is where the name is.
+    fn builtin(&mut self, name: &str, start: usize) -> ParseResult {
+        let node = match name {
+            // the hint is dropped; the value is the first argument
+            "__builtin_expect" => {
+                let node = self.assign()?;
+                self.token_queue.expect_reserve(",")?;
+                self.assign()?;
+                self.token_queue.expect_reserve(")")?;
+                node
+            }
+            "__builtin_constant_p" => {
+                let node = self.assign()?;
+                self.token_queue.expect_reserve(")")?;
+                Node::Num {
+                    val: i64::from(node.is_num()),
+                    r#type: Type::I32,
+                    span: start..self.token_queue.prev_end(),
+                }
+            }
+            "__builtin_types_compatible_p" => {
+                let lhs = self.type_name()?;
+                self.token_queue.expect_reserve(",")?;
+                let rhs = self.type_name()?;
+                self.token_queue.expect_reserve(")")?;
+                Node::Num {
+                    // top-level qualifiers are ignored
+                    val: i64::from(lhs.unqualified() == rhs.unqualified()),
+                    r#type: Type::I32,
+                    span: start..self.token_queue.prev_end(),
+                }
+            }
+            // Locked instructions are sequentially consistent on x86, so the
+            // memory order argument is evaluated and otherwise ignored.
+            "__atomic_exchange_n" | "__atomic_fetch_add" => {
+                let (lhs, r#type) = self.atomic_pointer(name)?;
+                self.token_queue.expect_reserve(",")?;
+                let rhs = Box::new(self.assign()?);
+                self.token_queue.expect_reserve(",")?;
+                self.assign()?;
+                self.token_queue.expect_reserve(")")?;
+                let span = start..self.token_queue.prev_end();
+                if name == "__atomic_exchange_n" {
+                    Node::Exchange {
+                        lhs,
+                        rhs,
+                        r#type,
+                        span,
+                    }
+                } else {
+                    Node::FetchAdd {
+                        lhs,
+                        rhs,
+                        r#type,
+                        span,
+                    }
+                }
+            }
+            "__sync_val_compare_and_swap" => {
+                let (lhs, r#type) = self.atomic_pointer(name)?;
+                self.token_queue.expect_reserve(",")?;
+                let old = Box::new(self.assign()?);
+                self.token_queue.expect_reserve(",")?;
+                let new = Box::new(self.assign()?);
+                self.token_queue.expect_reserve(")")?;
+                Node::CompareSwap {
+                    lhs,
+                    old,
+                    new,
+                    r#type,
+                    span: start..self.token_queue.prev_end(),
+                }
+            }
+            _ => {
+                return Err(MyError {
+                    info: format!("unknown builtin '{}'", name),
+                })
+            }
+        };
+        Ok(node)
+    }
+
+    // The pointer operand of an atomic builtin and the integer type it points
+    // to.
+    fn atomic_pointer(&mut self, name: &str) -> Result<(Box<Node>, Type), MyError> {
+        let node = self.assign()?;
+        let r#type = node.get_type().and_then(|t| t.decay().base().cloned());
+        match r#type.as_ref().map(Type::unqualified) {
+            Some(
+                r#type @ (Type::Char
+                | Type::SChar
+                | Type::UChar
+                | Type::I32
+                | Type::Long
+                | Type::I64),
+            ) => Ok((Box::new(node), r#type.clone())),
+            _ => {
+                self.report(Diagnostic::new(
+                    node.span(),
+                    format!("'{}' requires a pointer to an integer", name),
+                ))?;
+                Ok((Box::new(node), Type::I32))
+            }
+        }
+    }
+
+    // func-args = (assign ("," assign)*)? ")"
+    // Calls are checked against the declared prototype. `start` is where the
+    // name is.
+    fn funcall(&mut self, name: String, start: usize) -> ParseResult {
+        let mut args = Vec::new();
+        if !self.token_queue.consume_reserve(")")? {
+            loop {
+                args.push(self.assign()?);
+                if self.token_queue.consume_reserve(")")? {
+                    break;
+                }
+                self.token_queue.expect_reserve(",")?;
+            }
+        }
+        let span = start..self.token_queue.prev_end();
+        let Some(Type::Func { ret, params }) = self.functions.get(&name).map(|f| f.r#type.clone())
+        else {
+            self.report(Diagnostic::new(
+                span.clone(),
+                format!("implicit declaration of function '{}'", name),
+            ))?;
+            return Ok(Node::FuncCall {
+                name,
+                args,
+                r#type: Type::I32,
+                span,
+            });
+        };
+        if args.len() != params.len() {
+            self.report(Diagnostic::new(
+                span.clone(),
+                format!(
+                    "wrong number of arguments to function '{}': expected {}, have {}",
+                    name,
+                    params.len(),
+                    args.len()
+                ),
+            ))?;
+        }
+        for (i, (arg, param)) in args.iter().zip(&params).enumerate() {
+            // a literal 0 is also a null pointer
+            let null = matches!(arg, Node::Num { val: 0, .. }) && matches!(param, Type::Ptr { .. });
+            let r#type = arg.get_type().expect("should have a type");
+            if !(param.accepts(&r#type) || null) {
+                self.report(Diagnostic::new(
+                    arg.span(),
+                    format!(
+                        "incompatible type for argument {} of '{}': expected '{}', have '{}'",
+                        i + 1,
+                        name,
+                        param,
+                        r#type
+                    ),
+                ))?;
+            }
+        }
+        if args.len() > 6 {
+            self.report(Diagnostic::new(
+                span.clone(),
+                format!("more than 6 arguments to function '{}'", name),
+            ))?;
+        }
+        let args = args
+            .into_iter()
+            .zip(&params)
+            .map(|(arg, param)| Self::convert(arg, param))
+            .collect();
+        Ok(Node::FuncCall {
+            name,
+            args,
+            r#type: ret.unqualified().clone(),
+            span,
+        })
+    }
+
+    fn common_type(lhs: &Node, rhs: &Node) -> Type {
+        Type::common(
+            &lhs.get_type().expect("should have a type"),
+            &rhs.get_type().expect("should have a type"),
+        )
     }
 
     // add = mul ("+" mul | "-" mul)*
     fn add(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
         let mut node = self.mul()?;
         loop {
             if self.token_queue.consume_reserve("+")? {
+                let rhs = self.mul()?;
                 node = Node::Add {
+                    r#type: Self::common_type(&node, &rhs),
                     lhs: Box::new(node),
-                    rhs: Box::new(self.mul()?),
-                    r#type: node.get_type().expect("should have a type"),
+                    rhs: Box::new(rhs),
+                    span: start..self.token_queue.prev_end(),
                 };
                 node = self.new_add(node)?;
             } else if self.token_queue.consume_reserve("-")? {
+                let rhs = self.mul()?;
                 node = Node::Sub {
+                    r#type: Self::common_type(&node, &rhs),
                     lhs: Box::new(node),
-                    rhs: Box::new(self.mul()?),
-                    r#type: node.get_type().expect("should have a type"),
+                    rhs: Box::new(rhs),
+                    span: start..self.token_queue.prev_end(),
                 };
                 node = self.new_sub(node)?;
             } else {
@@ -581,19 +2043,24 @@ impl Parser {
     }
     // mul = unary ("*" unary | "/" unary)*
     fn mul(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
         let mut node = self.unary()?;
         loop {
             if self.token_queue.consume_reserve("*")? {
+                let rhs = self.unary()?;
                 node = Node::Mul {
+                    r#type: Self::common_type(&node, &rhs),
                     lhs: Box::new(node),
-                    rhs: Box::new(self.unary()?),
-                    r#type: node.get_type().expect("should have a type"),
+                    rhs: Box::new(rhs),
+                    span: start..self.token_queue.prev_end(),
                 };
             } else if self.token_queue.consume_reserve("/")? {
+                let rhs = self.unary()?;
                 node = Node::Div {
+                    r#type: Self::common_type(&node, &rhs),
                     lhs: Box::new(node),
-                    rhs: Box::new(self.unary()?),
-                    r#type: node.get_type().expect("should have a type"),
+                    rhs: Box::new(rhs),
+                    span: start..self.token_queue.prev_end(),
                 };
             } else {
                 return Ok(node);
@@ -604,68 +2071,706 @@ impl Parser {
     // unary = ("+" | "-" | "*" | "&") unary
     //       | primary
     fn unary(&mut self) -> ParseResult {
+        let start = self.token_queue.span(0).start;
         if self.token_queue.consume_reserve("+")? {
             return self.unary();
         }
         if self.token_queue.consume_reserve("-")? {
             let lhs = self.unary()?;
             let node = Node::Neg {
-                lhs: Box::new(lhs),
                 r#type: lhs.get_type().expect("should have a type"),
+                lhs: Box::new(lhs),
+                span: start..self.token_queue.prev_end(),
             };
             return Ok(node);
         }
         if self.token_queue.consume_reserve("*")? {
             let lhs = self.unary()?;
+            let r#type = lhs.get_type().expect("should have a type");
+            let base = match r#type.base() {
+                Some(base) => base.unqualified().clone(),
+                None => {
+                    self.report(Diagnostic::new(
+                        lhs.span(),
+                        format!("invalid type argument of unary '*' (have '{}')", r#type),
+                    ))?;
+                    Type::I32
+                }
+            };
             let node = Node::Deref {
-                lhs: Box::new(self.unary()?),
-                r#type: todo!("complete this")
+                lhs: Box::new(lhs),
+                r#type: base,
+                span: start..self.token_queue.prev_end(),
             };
             return Ok(node);
         }
         if self.token_queue.consume_reserve("&")? {
+            let lhs = self.unary()?;
+            if !lhs.is_lvalue() {
+                self.report(Diagnostic::new(
+                    lhs.span(),
+                    "lvalue required as unary '&' operand",
+                ))?;
+            }
             let node = Node::Addr {
-                lhs: Box::new(self.unary()?),
-                r#type: todo!("complete this")
+                r#type: Type::Ptr {
+                    base: Box::new(lhs.get_type().expect("should have a type")),
+                },
+                lhs: Box::new(lhs),
+                span: start..self.token_queue.prev_end(),
             };
             return Ok(node);
         }
-        return self.primary();
+        self.primary()
     }
 
-    // primary = "(" expr ")" | ident | num
+    // primary = "(" expr ")" | ident ("(" func-args)? | num
     fn primary(&mut self) -> ParseResult {
         if self.token_queue.consume_reserve("(")? {
             let node = self.expr()?;
             self.token_queue.expect_reserve(")")?;
             return Ok(node);
         }
+        let span = self.token_queue.span(0);
         if let Ok(Some(name)) = self.token_queue.consume_ident() {
-            let item = self.find_var(&name).ok_or(MyError {
-                info: format!("undefined variable: {}", name),
-            })?;
+            if self.token_queue.consume_reserve("(")? {
+                if ["__builtin_", "__atomic_", "__sync_"]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+                {
+                    return self.builtin(&name, span.start);
+                }
+                return self.funcall(name, span.start);
+            }
+            let Some(item) = self.find_var(&name) else {
+                self.report(Diagnostic::new(
+                    span.clone(),
+                    format!("undefined variable: {}", name),
+                ))?;
+                return Ok(Node::Num {
+                    val: 0,
+                    r#type: Type::I32,
+                    span,
+                });
+            };
+            self.locals.get_mut(&name).expect("found above").used = true;
             Ok(Node::Var {
                 name,
-                r#type: item.r#type,
+                r#type: item.r#type.unqualified().clone(),
+                span,
             })
         } else {
-            Ok(Node::Num {
-                val: self.token_queue.expect_num()?,
-                r#type: Type::I32
-            })
+            if let Token::Float { raw, .. } = &self.token_queue[0] {
+                return Err(MyError {
+                    info: format!("floating-point literals are not supported yet: {}", raw),
+                });
+            }
+            if !matches!(self.token_queue[0], Token::Num { .. }) {
+                return Err(MyError {
+                    info: format!("expected an expression {}", self.token_queue.here()),
+                });
+            }
+            let (val, suffix) = self.token_queue.expect_int()?;
+            // A literal too large for int, or with an `L` suffix, has type
+            // long, or long long if too large for that too or with `LL`.
+            // Unsigned types are not modelled, so `U` leaves it alone.
+            let fits_int = i32::try_from(val).is_ok();
+            let r#type = if suffix.long_long {
+                Type::I64
+            } else if !suffix.long && fits_int {
+                Type::I32
+            } else if fits_int || self.target.long_size == 8 {
+                Type::Long
+            } else {
+                Type::I64
+            };
+            Ok(Node::Num { val, r#type, span })
         }
     }
 
+    // Each local takes only its own size. They are packed most aligned
+    // first below the frame pointer, so the small ones share what would
+    // otherwise be padding; among equals the last declared comes first.
     pub fn assign_lvar_offset(&mut self) {
-        let offset = self.locals_dequeue.len() * 8;
-        self.stack_size = Self::align_to(offset, 16);
-        for (i, name) in self.locals_dequeue.iter().enumerate() {
-            let v = self.locals.get_mut(name).expect("local variable get error");
-            v.offset = (i + 1) * 8;
+        let mut names: Vec<String> = self.locals_dequeue.iter().cloned().collect();
+        names.sort_by_key(|name| std::cmp::Reverse(self.locals[name].align));
+        let mut offset = 0;
+        for name in names {
+            let v = self
+                .locals
+                .get_mut(&name)
+                .expect("local variable get error");
+            offset = Self::align_to(offset + v.r#type.size(&self.target), v.align);
+            v.offset = offset;
         }
+        self.stack_size = Self::align_to(offset, 16);
     }
 
     fn align_to(n: usize, align: usize) -> usize {
-        (n + align - 1) / align * align
+        n.div_ceil(align) * align
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_program_stats() {
+        let stats = crate::parse("{ int a=1; int *p=&a; if (a) { a=a+1; } return *p; }")
+            .1
+            .stats();
+        assert_eq!(stats.functions, 1);
+        // outer block, two declaration blocks holding one init statement
+        // each, the if with its block and statement, return
+        assert_eq!(stats.statements, 9);
+        // { if (a) { a=a+1; } }
+        assert_eq!(stats.max_depth, 4);
+        assert_eq!(stats.frame_bytes, 16);
+        assert_eq!(
+            stats.expressions,
+            BTreeMap::from([
+                ("Add", 1),
+                ("Addr", 1),
+                ("Assign", 3),
+                ("Deref", 1),
+                ("Num", 2),
+                ("Var", 7),
+            ])
+        );
+    }
+
+    fn diagnostics(src: &str, error_limit: usize) -> Vec<Diagnostic> {
+        let tokens = TokenQueue::tokenizer(src).expect("tokenizer error");
+        let mut parser = Parser::new(tokens);
+        parser.error_limit = error_limit;
+        assert!(parser.program().is_err());
+        parser.diagnostics
+    }
+
+    #[test]
+    fn test_redefinition_note() {
+        let diagnostics = diagnostics("{ int x; long x; return x; }", 0);
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::new(14..15, "redefinition of 'x'")
+                .with_note(6..7, "previous declaration was here")]
+        );
+    }
+
+    #[test]
+    fn test_error_recovery_and_limit() {
+        let src = "{ return a; return b; int c; int c; return 0; }";
+        assert_eq!(diagnostics(src, 0).len(), 3);
+        assert_eq!(diagnostics(src, 2).len(), 2);
+    }
+
+    #[test]
+    fn test_syntax_error_location() {
+        assert_eq!(
+            diagnostics("{ return 1 }", 0),
+            vec![Diagnostic::new(11..12, "expected ';' before '}'")]
+        );
+        // outside any block, and at the end of the input
+        assert_eq!(
+            diagnostics("return 1 2;", 0),
+            vec![Diagnostic::new(9..10, "expected ';' before '2'")]
+        );
+        assert_eq!(
+            diagnostics("{ return ; }", 0),
+            vec![Diagnostic::new(9..10, "expected an expression before ';'")]
+        );
+        assert_eq!(
+            diagnostics("{ return 1;", 0),
+            vec![Diagnostic::new(11..11, "expected '}' at end of input")]
+        );
+    }
+
+    fn parse_for(src: &str, target: Target) -> Program {
+        let tokens = TokenQueue::tokenizer(src).expect("tokenizer error");
+        let mut parser = Parser::new(tokens);
+        parser.target = target;
+        parser.program().expect("parse error")
+    }
+
+    // The value of the trailing `return` of the block.
+    fn return_value(program: &Program) -> Node {
+        let Some(Node::Return { lhs: Some(lhs), .. }) = program.nodes.iter().find_map(|node| {
+            let Node::Block { nodes, .. } = node else {
+                return None;
+            };
+            nodes.last().cloned()
+        }) else {
+            panic!("expected a trailing return");
+        };
+        *lhs
+    }
+
+    // The scale factor in `return q+1;`, `q` being the last declared pointer.
+    fn return_scale(program: &Program) -> i64 {
+        let Node::Add { rhs, .. } = return_value(program) else {
+            panic!("expected an add");
+        };
+        let Node::Mul { rhs, .. } = *rhs else {
+            panic!("expected a scaled offset");
+        };
+        let Node::Num { val, .. } = *rhs else {
+            panic!("expected a constant scale");
+        };
+        val
+    }
+
+    #[test]
+    fn test_32bit_target() {
+        // only the data layout matters to the parser
+        let ilp32 = Target {
+            name: "i686",
+            pointer_size: 4,
+            long_size: 4,
+            ..Target::x86_64()
+        };
+        let src = "{ int *p; int **q; return q+1; }";
+        assert_eq!(return_scale(&parse_for(src, Target::x86_64())), 8);
+        assert_eq!(return_scale(&parse_for(src, ilp32.clone())), 4);
+        let src = "{ char *p; char **q; return *q+1; }";
+        assert_eq!(return_scale(&parse_for(src, ilp32.clone())), 1);
+
+        // longs and pointers shrink with the target, ints and long longs do not
+        let src = "{ long a; long b; int c; int *e; return 0; }";
+        assert_eq!(parse_for(src, Target::x86_64()).stack_size, 32);
+        assert_eq!(parse_for(src, ilp32.clone()).stack_size, 16);
+        let src = "{ long long a; long long int b; long c; return 0; }";
+        assert_eq!(parse_for(src, Target::x86_64()).stack_size, 32);
+        assert_eq!(parse_for(src, ilp32.clone()).stack_size, 32);
+
+        // a literal too large for a 32-bit long is a long long
+        let literal = |src: &str, target: Target| match return_value(&parse_for(src, target)) {
+            Node::Num { r#type, .. } => r#type,
+            node => panic!("not a literal: {:?}", node),
+        };
+        assert_eq!(
+            literal("{ return 4294967296; }", Target::x86_64()),
+            Type::Long
+        );
+        assert_eq!(literal("{ return 4294967296; }", ilp32.clone()), Type::I64);
+        assert_eq!(literal("{ return 1L; }", ilp32.clone()), Type::Long);
+        assert_eq!(literal("{ return 1LL; }", ilp32), Type::I64);
+    }
+
+    #[test]
+    fn test_mixed_declarators() {
+        let (parser, _) = crate::parse("{ int x, *p, a[3][2]; return 0; }");
+        let int = || Box::new(Type::I32);
+        assert_eq!(parser.locals["x"].r#type, Type::I32);
+        assert_eq!(parser.locals["p"].r#type, Type::Ptr { base: int() });
+        assert_eq!(
+            parser.locals["a"].r#type,
+            Type::Array {
+                base: Box::new(Type::Array {
+                    base: int(),
+                    len: 2
+                }),
+                len: 3
+            }
+        );
+        // p is the most aligned, so it comes first below %rbp, then a and x
+        assert_eq!(parser.locals["p"].offset, 8);
+        assert_eq!(parser.locals["a"].offset, 32);
+        assert_eq!(parser.locals["x"].offset, 36);
+        assert_eq!(parser.stack_size, 48);
+    }
+
+    #[test]
+    fn test_slot_packing() {
+        let (parser, _) = crate::parse("{ char a; int b; char c; char d[3]; return 0; }");
+        // b first for its alignment, then the chars in the bytes below it
+        assert_eq!(parser.locals["b"].offset, 4);
+        assert_eq!(parser.locals["d"].offset, 7);
+        assert_eq!(parser.locals["c"].offset, 8);
+        assert_eq!(parser.locals["a"].offset, 9);
+        assert_eq!(parser.stack_size, 16);
+    }
+
+    #[test]
+    fn test_prototype_checks() {
+        let program =
+            crate::parse("int f(int, char *p, long a[]); { char c; return f(1, &c, 0); }").1;
+        let Node::Block { nodes, .. } = &program.nodes[1] else {
+            panic!("expected a block");
+        };
+        let Node::Return {
+            lhs: Some(call), ..
+        } = &nodes[1]
+        else {
+            panic!("expected a return");
+        };
+        assert_eq!(call.kind(), "FuncCall");
+        assert_eq!(call.get_type(), Some(Type::I32));
+
+        let messages = |src| -> Vec<String> {
+            diagnostics(src, 0)
+                .into_iter()
+                .map(|diagnostic| diagnostic.message)
+                .collect()
+        };
+        assert_eq!(
+            messages("int f(int); { return f(); }"),
+            vec!["wrong number of arguments to function 'f': expected 1, have 0"]
+        );
+        assert!(messages("int f(int *); { int x; return f(x); }")[0]
+            .starts_with("incompatible type for argument 1 of 'f'"));
+        assert_eq!(
+            messages("{ return g(); }"),
+            vec!["implicit declaration of function 'g'"]
+        );
+        assert_eq!(
+            messages("{ int f(int); char f(int); return 0; }"),
+            vec!["conflicting types for 'f'"]
+        );
+    }
+
+    #[test]
+    fn test_complex_declarators() {
+        let (parser, _) = crate::parse("{ int (*p)[3], *a[4], (**q), *(*r)[2][5]; return 0; }");
+        let ptr = |base| Type::Ptr {
+            base: Box::new(base),
+        };
+        let array = |base, len| Type::Array {
+            base: Box::new(base),
+            len,
+        };
+        assert_eq!(parser.locals["p"].r#type, ptr(array(Type::I32, 3)));
+        assert_eq!(parser.locals["a"].r#type, array(ptr(Type::I32), 4));
+        assert_eq!(parser.locals["q"].r#type, ptr(ptr(Type::I32)));
+        assert_eq!(
+            parser.locals["r"].r#type,
+            ptr(array(array(ptr(Type::I32), 5), 2))
+        );
+    }
+
+    #[test]
+    fn test_qualifiers() {
+        let (parser, _) = crate::parse(
+            "{ volatile int x; int volatile *restrict p; int f(volatile int *); return f(&x); }",
+        );
+        let volatile = Qualifiers {
+            volatile: true,
+            restrict: false,
+            atomic: false,
+        };
+        let volatile_int = Type::Qualified {
+            base: Box::new(Type::I32),
+            quals: volatile,
+        };
+        assert_eq!(parser.locals["x"].r#type, volatile_int);
+        assert!(parser.locals["x"].r#type.is_volatile());
+        assert_eq!(
+            parser.locals["p"].r#type,
+            Type::Qualified {
+                base: Box::new(Type::Ptr {
+                    base: Box::new(volatile_int)
+                }),
+                quals: Qualifiers {
+                    volatile: false,
+                    restrict: true,
+                    atomic: false,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_attributes() {
+        let (parser, _) = crate::parse(
+            "{ char a; __attribute__((unused)) char b __attribute__((aligned(16), foo(1, (2)))); return 0; }",
+        );
+        assert_eq!(parser.locals["a"].align, 1);
+        assert_eq!(parser.locals["b"].align, 16);
+        assert_eq!(parser.locals["b"].offset % 16, 0);
+
+        let tokens = TokenQueue::tokenizer("{ int x __attribute__((aligned(32))); return 0; }")
+            .expect("tokenizer error");
+        assert!(Parser::new(tokens).program().is_err());
+    }
+
+    #[test]
+    fn test_atomic() {
+        let (parser, program) = crate::parse("{ _Atomic int x; char c; return __atomic_fetch_add(&x, 1, 5) + __sync_val_compare_and_swap(&c, 0, 1); }");
+        assert!(parser.locals["x"].r#type.is_atomic());
+        assert!(!parser.locals["c"].r#type.is_atomic());
+        let stats = program.stats();
+        assert_eq!(stats.expressions["FetchAdd"], 1);
+        assert_eq!(stats.expressions["CompareSwap"], 1);
+
+        let tokens = TokenQueue::tokenizer("{ int x; return __atomic_exchange_n(x, 1, 5); }")
+            .expect("tokenizer error");
+        assert!(Parser::new(tokens).program().is_err());
+    }
+
+    #[test]
+    fn test_dump() {
+        let dump = crate::parse(
+            "int f(int); { int a[2]; int *p=a; for (;;) if (*p) return 1; return f(3); }",
+        )
+        .1
+        .dump();
+        assert_eq!(
+            dump,
+            "\
+Block
+Block
+  Block
+  Block
+    ExprStmt
+      Assign <int*>
+        Var p <int*>
+        Var a <int[2]>
+  For
+    init: Block
+    body: If
+      cond: Deref <int>
+        Var p <int*>
+      then: Return
+        Num 1 <int>
+  Return
+    FuncCall f <int>
+      Num 3 <int>
+"
+        );
+        let volatile = Type::I32.qualify(Qualifiers {
+            volatile: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            Type::Ptr {
+                base: Box::new(volatile)
+            }
+            .to_string(),
+            "volatile int*"
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let json =
+            serde_json::to_value(crate::parse("{ char *p; return *p; }").1).expect("json error");
+        assert_eq!(json["stack_size"], 16);
+        let ret = &json["nodes"][0]["nodes"][1];
+        assert_eq!(ret["kind"], "Return");
+        assert_eq!(ret["lhs"]["kind"], "Deref");
+        assert_eq!(ret["lhs"]["type"], serde_json::json!({"kind": "Char"}));
+        assert_eq!(
+            ret["lhs"]["lhs"],
+            serde_json::json!({
+                "kind": "Var",
+                "name": "p",
+                "type": {"kind": "Ptr", "base": {"kind": "Char"}},
+                "span": {"start": 19, "end": 20},
+            })
+        );
+    }
+
+    #[test]
+    fn test_literal_types() {
+        let dump = crate::parse("{ return 1U + 2L + 2147483648u + 3ull; }")
+            .1
+            .dump();
+        for num in [
+            "Num 1 <int>",
+            "Num 2 <long>",
+            "Num 2147483648 <long>",
+            "Num 3 <long long>",
+        ] {
+            assert!(dump.contains(num), "{}", dump);
+        }
+    }
+
+    #[test]
+    fn test_statement_spans() {
+        let src = "{ int x=1; if (x) x=2; return x; }";
+        let program = crate::parse(src).1;
+        let Node::Block { nodes, .. } = &program.nodes[0] else {
+            panic!("expected a block");
+        };
+        let spans: Vec<&str> = nodes
+            .iter()
+            .flat_map(|node| match node {
+                Node::Block { nodes, .. } => nodes.iter().collect(),
+                _ => vec![node],
+            })
+            .map(|node| &src[node.stmt_span().expect("statement without span")])
+            .collect();
+        assert_eq!(spans, vec!["x=1", "if (x) x=2;", "return x;"]);
+    }
+
+    #[test]
+    fn test_expression_spans() {
+        let src = "int f(int); { int x=1; int *p=&x; return (x+2)*-*p - f(x); }";
+        let program = crate::parse(src).1;
+        let mut spans = Vec::new();
+        let mut work: Vec<&Node> = program.nodes.iter().collect();
+        while let Some(node) = work.pop() {
+            if !node.is_stmt() {
+                spans.push(format!("{} {}", node.kind(), &src[node.span()]));
+            }
+            work.extend(node.children());
+        }
+        spans.sort();
+        assert_eq!(
+            spans,
+            vec![
+                "Add x+2",
+                "Addr &x",
+                "Assign p=&x",
+                "Assign x=1",
+                "Deref *p",
+                "FuncCall f(x)",
+                "Mul (x+2)*-*p",
+                "Neg -*p",
+                "Num 1",
+                "Num 2",
+                "Sub (x+2)*-*p - f(x)",
+                "Var p",
+                "Var p",
+                "Var x",
+                "Var x",
+                "Var x",
+                "Var x",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_error_spans() {
+        let src = "int f(int*); { int x; int a[2]; a = *x + f(x); 1 = &2; return &x + &x; }";
+        let errors: Vec<(&str, String)> = diagnostics(src, 0)
+            .into_iter()
+            .map(|diagnostic| (&src[diagnostic.span], diagnostic.message))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("a", "array is not assignable".to_string()),
+                (
+                    "x",
+                    "invalid type argument of unary '*' (have 'int')".to_string()
+                ),
+                (
+                    "x",
+                    "incompatible type for argument 1 of 'f': expected 'int*', have 'int'"
+                        .to_string()
+                ),
+                (
+                    "1",
+                    "lvalue required as left operand of assignment".to_string()
+                ),
+                ("2", "lvalue required as unary '&' operand".to_string()),
+                (
+                    "&x + &x",
+                    "invalid operands to binary + (have 'int*' and 'int*')".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_warnings() {
+        let warnings = |src: &str| -> Vec<(Warning, String)> {
+            let (parser, _) = crate::parse(src);
+            parser
+                .warnings
+                .into_iter()
+                .map(|diagnostic| {
+                    (
+                        diagnostic.warning.expect("not a warning"),
+                        diagnostic.message,
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            warnings("{ int a; int b=1; int c; c=2; return c; }"),
+            vec![
+                (Warning::UnusedVariable, "unused variable 'a'".to_string()),
+                (Warning::UnusedVariable, "unused variable 'b'".to_string()),
+            ]
+        );
+        assert_eq!(
+            warnings("{ int x=1; x+1; x=2; return x; }"),
+            vec![(Warning::UnusedValue, "expression result unused".to_string())]
+        );
+        let unused_value = |src: &str| {
+            warnings(src)
+                .iter()
+                .any(|(warning, _)| *warning == Warning::UnusedValue)
+        };
+        assert!(unused_value("{ int *p=0; *p; return 0; }"));
+        assert!(!unused_value("{ volatile int v=1; v; return 0; }"));
+        assert!(!unused_value("{ volatile int *q=0; *q; return 0; }"));
+    }
+
+    #[test]
+    fn test_std() {
+        let errors = |src: &str, std: Std| -> Vec<String> {
+            let tokens = TokenQueue::tokenizer(src).expect("tokenizer error");
+            let mut parser = Parser::new(tokens);
+            parser.std = std;
+            let _ = parser.program();
+            parser
+                .diagnostics
+                .into_iter()
+                .map(|diagnostic| diagnostic.message)
+                .collect()
+        };
+        let src = "_Static_assert(1, \"x\"); { int x; x=1; _Bool b; _Atomic int a; return x; }";
+        assert_eq!(
+            errors(src, Std::C89),
+            [
+                "'_Static_assert' requires -std=c11 or later",
+                "declaration after statement requires -std=c99 or later",
+                "'_Bool' requires -std=c99 or later",
+                "declaration after statement requires -std=c99 or later",
+                "'_Atomic' requires -std=c11 or later",
+            ]
+        );
+        assert_eq!(
+            errors(src, Std::C99),
+            [
+                "'_Static_assert' requires -std=c11 or later",
+                "'_Atomic' requires -std=c11 or later",
+            ]
+        );
+        assert!(errors(src, Std::C11).is_empty());
+        assert_eq!(
+            errors("_Static_assert(1+1==3, \"math\"); { return 0; }", Std::C11),
+            ["static assertion failed: \"math\""]
+        );
+    }
+
+    #[test]
+    fn test_bool_conversion() {
+        let program = parse_for(
+            "int f(_Bool); { _Bool b=2; return f(b); }",
+            Target::x86_64(),
+        );
+        assert_eq!(
+            program.dump(),
+            "\
+Block
+Block
+  Block
+    ExprStmt
+      Assign <_Bool>
+        Var b <_Bool>
+        Ne <int>
+          Num 2 <int>
+          Num 0 <int>
+  Return
+    FuncCall f <int>
+      Ne <int>
+        Var b <_Bool>
+        Num 0 <int>
+"
+        );
     }
 }
@@ -0,0 +1,222 @@
+use crate::ir::{self, Function};
+use crate::x86_64::{self, Line};
+use crate::{cse, ifcvt, optimizer};
+use crate::{CostModel, MyError, Node, Parser};
+
+// The optimization passes, by name, and the pipelines that run them. The
+// AST passes of a pipeline run before lowering, in the order given, then the
+// IR passes on the function, then the assembly passes on the native
+// backend's output; backends that don't lower to the IR only run the AST
+// ones. Library users can register passes of their own and name them in
+// CompileOptions::passes.
+
+// rewrites the statements of the program, given the parser for its locals
+pub type AstPass = dyn Fn(Vec<Node>, &Parser) -> Vec<Node>;
+pub type IrPass = dyn Fn(&mut Function);
+pub type AsmPass = dyn Fn(Vec<Line>) -> Vec<Line>;
+
+pub enum Pass {
+    Ast(Box<AstPass>),
+    Ir(Box<IrPass>),
+    Asm(Box<AsmPass>),
+}
+
+pub struct PassManager {
+    passes: Vec<(String, Pass)>, // in the order they were registered
+}
+
+impl Default for PassManager {
+    // The built-in passes: fold (constant folding and propagation, and
+    // algebraic simplification), dce (dead code elimination), ifcvt
+    // (if-conversion to conditional moves), cse (common subexpression
+    // elimination within basic blocks) and peephole (tidying the assembly).
+    fn default() -> Self {
+        let mut passes = PassManager { passes: Vec::new() };
+        passes.register("fold", Pass::Ast(Box::new(optimizer::propagate)));
+        passes.register(
+            "dce",
+            Pass::Ast(Box::new(|nodes, _| optimizer::eliminate_dead_code(nodes))),
+        );
+        // the IR is only lowered to x86-64
+        let cost_model = CostModel::x86_64();
+        passes.register(
+            "ifcvt",
+            Pass::Ir(Box::new(move |function| {
+                ifcvt::convert(function, &cost_model)
+            })),
+        );
+        passes.register("cse", Pass::Ir(Box::new(cse::eliminate)));
+        passes.register("peephole", Pass::Asm(Box::new(x86_64::peephole)));
+        passes
+    }
+}
+
+impl PassManager {
+    // The pipeline for -O<level>.
+    pub fn default_pipeline(opt_level: u8) -> Vec<String> {
+        let names: &[&str] = match opt_level {
+            0 => &[],
+            1 => &["fold", "dce", "cse", "peephole"],
+            _ => &["fold", "dce", "ifcvt", "cse", "peephole"],
+        };
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    // Add `pass` as `name`, replacing a pass of that name.
+    pub fn register(&mut self, name: &str, pass: Pass) {
+        match self.passes.iter_mut().find(|(n, _)| n == name) {
+            Some((_, old)) => *old = pass,
+            None => self.passes.push((name.to_string(), pass)),
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.passes.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    // Check that every pass `pipeline` names is registered.
+    pub fn check(&self, pipeline: &[String]) -> Result<(), MyError> {
+        match pipeline.iter().find(|name| self.get(name).is_none()) {
+            Some(name) => Err(MyError {
+                info: format!(
+                    "unknown pass '{}', expected one of {}",
+                    name,
+                    self.names().join(", ")
+                ),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&Pass> {
+        self.passes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, pass)| pass)
+    }
+
+    // Run the AST passes of `pipeline` on `nodes`. The names must have
+    // passed `check`.
+    pub fn run_ast(&self, pipeline: &[String], nodes: Vec<Node>, parser: &Parser) -> Vec<Node> {
+        pipeline.iter().fold(nodes, |nodes, name| {
+            match self.get(name).expect("pipeline checked") {
+                Pass::Ast(pass) => pass(nodes, parser),
+                _ => nodes,
+            }
+        })
+    }
+
+    // Run the IR passes of `pipeline` on `function`. In debug builds the
+    // function is verified after each one, and a pass that breaks it is an
+    // internal compiler error.
+    pub fn run_ir(&self, pipeline: &[String], function: &mut Function) {
+        for name in pipeline {
+            if let Pass::Ir(pass) = self.get(name).expect("pipeline checked") {
+                pass(function);
+                if cfg!(debug_assertions) {
+                    if let Err(e) = ir::verify(function) {
+                        panic!(
+                            "internal compiler error after pass '{}': {}\n{}",
+                            name, e.info, function
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Run the assembly passes of `pipeline` on `lines`.
+    pub fn run_asm(&self, pipeline: &[String], lines: Vec<Line>) -> Vec<Line> {
+        pipeline.iter().fold(lines, |lines, name| {
+            match self.get(name).expect("pipeline checked") {
+                Pass::Asm(pass) => pass(lines),
+                _ => lines,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Inst, Operand};
+    use crate::{interpret, CodeGenerator, CompileOptions};
+
+    fn pipeline(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_order() {
+        let passes = PassManager::default();
+        assert_eq!(passes.names(), ["fold", "dce", "ifcvt", "cse", "peephole"]);
+        assert_eq!(PassManager::default_pipeline(0), pipeline(&[]));
+        assert_eq!(
+            PassManager::default_pipeline(1),
+            pipeline(&["fold", "dce", "cse", "peephole"])
+        );
+        assert_eq!(
+            PassManager::default_pipeline(2),
+            pipeline(&["fold", "dce", "ifcvt", "cse", "peephole"])
+        );
+        // the branch is only dropped once its condition is folded
+        let src = "{ if (1-1) return 2; return 3; }";
+        let run = |names: &[&str]| {
+            let (parser, program) = crate::parse(src);
+            let nodes = passes.run_ast(&pipeline(names), program.nodes, &parser);
+            crate::Program {
+                nodes,
+                stack_size: 0,
+            }
+            .dump()
+        };
+        assert_eq!(
+            run(&["fold", "dce"]),
+            "Block\n  Block\n  Return\n    Num 3 <int>\n"
+        );
+        assert!(run(&["dce", "fold"]).contains("  If\n"));
+        assert_eq!(
+            passes
+                .check(&pipeline(&["fold", "licm"]))
+                .err()
+                .map(|e| e.info),
+            Some(
+                "unknown pass 'licm', expected one of fold, dce, ifcvt, cse, peephole".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_register() {
+        let mut passes = PassManager::default();
+        // every return returns 42
+        passes.register(
+            "answer",
+            Pass::Ir(Box::new(|function: &mut Function| {
+                for inst in &mut function.insts {
+                    if let Inst::Ret(Some(val)) = inst {
+                        *val = Operand::Imm(42);
+                    }
+                }
+            })),
+        );
+        // a pass replaced keeps its place
+        passes.register(
+            "dce",
+            Pass::Ast(Box::new(|nodes, _| optimizer::eliminate_dead_code(nodes))),
+        );
+        assert_eq!(
+            passes.names(),
+            ["fold", "dce", "ifcvt", "cse", "peephole", "answer"]
+        );
+        let (parser, program) = crate::parse("{ int x=1; return x; }");
+        let options = CompileOptions {
+            passes: Some(pipeline(&["fold", "answer"])),
+            ..CompileOptions::default()
+        };
+        let mut generator = CodeGenerator::new(parser, options);
+        generator.passes = passes;
+        let function = generator.lower(program.nodes);
+        assert_eq!(interpret(&function, &mut |_, _| None).ok(), Some(42));
+    }
+}
@@ -0,0 +1,1172 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{LineMap, MyError};
+
+// Freestanding headers built into the compiler, found after the include
+// paths. Without typedefs, the types they define are macros. Variadic
+// functions cannot be defined yet, so <stdarg.h> is empty.
+const BUILTIN_HEADERS: &[(&str, &str)] = &[
+    ("stdarg.h", include_str!("include/stdarg.h")),
+    ("stdbool.h", include_str!("include/stdbool.h")),
+    ("stddef.h", include_str!("include/stddef.h")),
+];
+
+enum Header {
+    File(PathBuf),
+    Builtin(&'static str, &'static str), // name and text
+}
+
+pub struct Preprocessor {
+    include_paths: Vec<PathBuf>,
+    include_stack: Vec<PathBuf>, // files currently being included, for cycle detection
+    macros: HashMap<String, Macro>,
+    pragma_once: HashSet<PathBuf>, // files marked with #pragma once
+    include_guards: HashMap<PathBuf, String>, // file -> macro guarding its whole body
+    pub file: String,              // name of the file being preprocessed, for __FILE__
+    line: usize,                   // 1-based line number in `file`, for __LINE__
+    line_delta: isize,             // what #line added to the physical line number
+    pub line_map: LineMap,         // where the lines of the output came from
+}
+
+impl Preprocessor {
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        Self {
+            include_paths,
+            include_stack: Vec::new(),
+            macros: HashMap::new(),
+            pragma_once: HashSet::new(),
+            include_guards: HashMap::new(),
+            file: "<command line>".to_string(),
+            line: 0,
+            line_delta: 0,
+            line_map: LineMap::default(),
+        }
+    }
+
+    // Expand directives in `source`. Quoted includes are looked up relative to
+    // `dir` first, then in the include paths. Directive lines and lines skipped
+    // by conditional inclusion are replaced by empty lines so the line count of
+    // the main file is preserved. Lines ending in a backslash are joined with
+    // the next one first, and the joined lines are made up for after it.
+    // `line_map` records where included files start and end and the lines
+    // renumbered by #line.
+    pub fn preprocess(&mut self, source: &str, dir: &Path) -> Result<String, MyError> {
+        if self.include_stack.is_empty() {
+            self.line_map = LineMap::new(self.file.clone());
+            self.line_delta = 0;
+        } else {
+            self.line_map.mark(1, &self.file, 1);
+        }
+        let mut rv = String::new();
+        let mut conds: Vec<CondIncl> = Vec::new();
+        let mut lines = source.lines().enumerate();
+        while let Some((i, first)) = lines.next() {
+            self.line = (i as isize + 1 + self.line_delta) as usize;
+            let mut line = first.to_string();
+            let mut spliced = 0;
+            while line.ends_with('\\') {
+                line.pop();
+                let Some((_, next)) = lines.next() else {
+                    break;
+                };
+                line.push_str(next);
+                spliced += 1;
+            }
+            let line = line.as_str();
+            let active = conds.last().is_none_or(|c| c.active);
+            // whether the next line starts somewhere the line map must be told
+            let mut resync = false;
+            if let Some(directive) = line.trim_start().strip_prefix('#') {
+                let directive = directive.trim_start();
+                let name: String = directive
+                    .chars()
+                    .take_while(|c| Self::is_ident_char(*c))
+                    .collect();
+                let rest = &directive[name.len()..];
+                if !self.conditional(&name, rest, &mut conds)? && active {
+                    let marker = !name.is_empty() && name.chars().all(|c| c.is_ascii_digit());
+                    if name == "line" || marker {
+                        // `#line N "file"`, or `# N "file" flags` as cpp writes it
+                        let (line, file) =
+                            self.line_directive(if marker { directive } else { rest })?;
+                        self.line_delta = line as isize - (i + spliced + 2) as isize;
+                        if let Some(file) = file {
+                            self.file = file;
+                        }
+                        resync = true;
+                    } else {
+                        self.directive(&name, rest, dir, &mut rv)?;
+                        resync = name == "include";
+                    }
+                }
+            } else if active {
+                rv.push_str(&self.expand(line, &mut Vec::new())?);
+            }
+            rv.push('\n');
+            rv.push_str(&"\n".repeat(spliced));
+            if resync {
+                let next = (i + spliced + 2) as isize + self.line_delta;
+                self.line_map
+                    .mark(rv.matches('\n').count() + 1, &self.file, next as usize);
+            }
+        }
+        if !conds.is_empty() {
+            return Err(MyError {
+                info: "unterminated conditional directive".to_string(),
+            });
+        }
+        Ok(rv)
+    }
+
+    fn directive(
+        &mut self,
+        name: &str,
+        rest: &str,
+        dir: &Path,
+        rv: &mut String,
+    ) -> Result<(), MyError> {
+        match name {
+            // null directive
+            "" if rest.trim().is_empty() => Ok(()),
+            "include" => {
+                let (name, angled) = Self::include_name(rest.trim())?;
+                let header = self.search_include(name, (!angled).then_some(dir))?;
+                self.include_file(header, rv)
+            }
+            "define" => self.define(rest),
+            "undef" => {
+                let name = Self::macro_name(rest)?;
+                if name.len() != rest.trim().len() {
+                    return Err(MyError {
+                        info: format!("extra tokens at end of #undef: {}", rest.trim()),
+                    });
+                }
+                self.macros.remove(name);
+                Ok(())
+            }
+            "pragma" => {
+                // Unknown pragmas are ignored. #pragma once in the main input
+                // has nothing to guard against.
+                if rest.trim() == "once" {
+                    if let Some(file) = self.include_stack.last() {
+                        self.pragma_once.insert(file.clone());
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(MyError {
+                info: format!("invalid preprocessor directive: #{}{}", name, rest),
+            }),
+        }
+    }
+
+    // The line number and optional file name of a #line directive, after
+    // macro expansion.
+    fn line_directive(&self, rest: &str) -> Result<(usize, Option<String>), MyError> {
+        let invalid = || MyError {
+            info: format!("invalid #line directive: {}", rest.trim()),
+        };
+        let text = self.expand(rest, &mut Vec::new())?;
+        let text = text.trim_start();
+        let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let line = text[..digits].parse().map_err(|_| invalid())?;
+        let Some(quoted) = text[digits..].trim_start().strip_prefix('"') else {
+            return Ok((line, None));
+        };
+        let mut file = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next().ok_or_else(invalid)? {
+                '"' => return Ok((line, Some(file))),
+                '\\' => file.push(chars.next().ok_or_else(invalid)?),
+                c => file.push(c),
+            }
+        }
+    }
+
+    // Handle #if, #ifdef, #ifndef, #elif, #else and #endif. These are processed
+    // even inside skipped groups so nesting is tracked; conditions of nested
+    // groups are not evaluated there. Returns false for any other directive.
+    fn conditional(
+        &self,
+        name: &str,
+        rest: &str,
+        conds: &mut Vec<CondIncl>,
+    ) -> Result<bool, MyError> {
+        let active = conds.last().is_none_or(|c| c.active);
+        match name {
+            "if" | "ifdef" | "ifndef" => {
+                let cond = active
+                    && match name {
+                        "if" => self.eval(rest)? != 0,
+                        "ifdef" => self.is_defined(Self::macro_name(rest)?),
+                        _ => !self.is_defined(Self::macro_name(rest)?),
+                    };
+                conds.push(CondIncl {
+                    active: cond,
+                    taken: cond,
+                    parent_active: active,
+                    seen_else: false,
+                });
+            }
+            "elif" | "else" => {
+                let Some(c) = conds.last_mut() else {
+                    return Err(MyError {
+                        info: format!("#{} without #if", name),
+                    });
+                };
+                if c.seen_else {
+                    return Err(MyError {
+                        info: format!("#{} after #else", name),
+                    });
+                }
+                let cond = c.parent_active && !c.taken && (name == "else" || self.eval(rest)? != 0);
+                c.active = cond;
+                c.taken |= cond;
+                c.seen_else = name == "else";
+            }
+            "endif" => {
+                if conds.pop().is_none() {
+                    return Err(MyError {
+                        info: "#endif without #if".to_string(),
+                    });
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Evaluate the controlling expression of #if / #elif. `defined` operators
+    // are resolved before macro expansion, and identifiers left over after
+    // expansion evaluate to 0.
+    fn eval(&self, expr: &str) -> Result<i64, MyError> {
+        let mut text = String::new();
+        let mut tokens = Self::pp_tokens(expr)
+            .into_iter()
+            .filter(|t| !t.trim().is_empty());
+        while let Some(token) = tokens.next() {
+            if token == "defined" {
+                let mut name = tokens.next().unwrap_or_default();
+                let paren = name == "(";
+                if paren {
+                    name = tokens.next().unwrap_or_default();
+                }
+                if Self::macro_name(name).is_err() || (paren && tokens.next() != Some(")")) {
+                    return Err(MyError {
+                        info: format!("invalid use of defined: {}", expr.trim()),
+                    });
+                }
+                text.push_str(if self.is_defined(name) { "1" } else { "0" });
+            } else {
+                text.push_str(token);
+            }
+            text.push(' ');
+        }
+        let expanded = self.expand(&text, &mut Vec::new())?;
+        CondExpr::new(&expanded).eval()
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name) || matches!(name, "__FILE__" | "__LINE__")
+    }
+
+    fn macro_name(s: &str) -> Result<&str, MyError> {
+        let s = s.trim();
+        let name = &s[..s.find(|c| !Self::is_ident_char(c)).unwrap_or(s.len())];
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(MyError {
+                info: format!("macro name must be an identifier: {}", s),
+            });
+        }
+        Ok(name)
+    }
+
+    // include-name = "\"" file "\""
+    // The name of the header in `#include "name"` or `#include <name>`, and
+    // whether it was in angle brackets.
+    fn include_name(s: &str) -> Result<(&str, bool), MyError> {
+        let quoted = |open, close| {
+            s.strip_prefix(open)
+                .and_then(|s: &str| s.split_once(close))
+                .filter(|(name, rest)| !name.is_empty() && rest.trim().is_empty())
+                .map(|(name, _)| name)
+        };
+        if let Some(name) = quoted('"', '"') {
+            return Ok((name, false));
+        }
+        quoted('<', '>').map(|name| (name, true)).ok_or(MyError {
+            info: format!("expected \"FILENAME\" or <FILENAME>, got: {}", s),
+        })
+    }
+
+    // define = ident ("(" params? ")")? replacement-list
+    // params = ident ("," ident)* ("," "...")? | "..."
+    // The parameter list must follow the name without whitespace; otherwise
+    // the parenthesis starts the replacement list of an object-like macro.
+    fn define(&mut self, rest: &str) -> Result<(), MyError> {
+        let name = Self::macro_name(rest)?;
+        let mut body = &rest.trim_start()[name.len()..];
+        let mut params = None;
+        let mut variadic = false;
+        if let Some(list) = body.strip_prefix('(') {
+            let Some((list, rest)) = list.split_once(')') else {
+                return Err(MyError {
+                    info: format!("missing ')' in macro parameter list: {}", name),
+                });
+            };
+            let mut names: Vec<String> = list.split(',').map(|p| p.trim().to_string()).collect();
+            if names == [""] {
+                names.clear();
+            }
+            if names.last().is_some_and(|p| p == "...") {
+                names.pop();
+                variadic = true;
+            }
+            if let Some(bad) = names
+                .iter()
+                .find(|p| Self::macro_name(p).ok() != Some(p.as_str()))
+            {
+                return Err(MyError {
+                    info: format!("invalid macro parameter '{}' in: {}", bad, name),
+                });
+            }
+            params = Some(names);
+            body = rest;
+        }
+        self.macros.insert(
+            name.to_string(),
+            Macro {
+                params,
+                variadic,
+                body: body.trim().to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    // Replace macro names in `text`. A macro is never expanded again while its
+    // own replacement list is being rescanned, which stops self-referential
+    // definitions like `#define foo foo + 1` from recursing forever. A
+    // function-like macro name is only expanded when followed by an argument
+    // list on the same line.
+    fn expand(&self, text: &str, hidden: &mut Vec<String>) -> Result<String, MyError> {
+        let tokens = Self::pp_tokens(text);
+        let mut rv = String::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = tokens[i];
+            i += 1;
+            let Some(m) = self
+                .macros
+                .get(token)
+                .filter(|_| !hidden.iter().any(|name| name == token))
+            else {
+                match token {
+                    "__LINE__" => rv.push_str(&self.line.to_string()),
+                    "__FILE__" => {
+                        let name = self.file.replace('\\', "\\\\").replace('"', "\\\"");
+                        rv.push_str(&format!("\"{}\"", name));
+                    }
+                    _ => rv.push_str(token),
+                }
+                continue;
+            };
+            let body = match &m.params {
+                None => m.body.clone(),
+                Some(params) => {
+                    let Some((args, next)) = Self::macro_args(&tokens, i, token)? else {
+                        rv.push_str(token);
+                        continue;
+                    };
+                    i = next;
+                    self.substitute(token, m, params, args, hidden)?
+                }
+            };
+            hidden.push(token.to_string());
+            rv.push_str(&self.expand(&body, hidden)?);
+            hidden.pop();
+        }
+        Ok(rv)
+    }
+
+    // The arguments of a function-like macro invocation whose name ends
+    // before `tokens[i]`, and the index just past the closing parenthesis.
+    // Returns None if no argument list follows.
+    fn macro_args(
+        tokens: &[&str],
+        mut i: usize,
+        name: &str,
+    ) -> Result<Option<(Vec<String>, usize)>, MyError> {
+        while tokens.get(i).is_some_and(|t| t.trim().is_empty()) {
+            i += 1;
+        }
+        if tokens.get(i) != Some(&"(") {
+            return Ok(None);
+        }
+        let mut args = vec![String::new()];
+        let mut depth = 0;
+        for (j, token) in tokens.iter().enumerate().skip(i + 1) {
+            match *token {
+                ")" if depth == 0 => return Ok(Some((args, j + 1))),
+                "," if depth == 0 => args.push(String::new()),
+                _ => {
+                    match *token {
+                        "(" => depth += 1,
+                        ")" => depth -= 1,
+                        _ => {}
+                    }
+                    args.last_mut().expect("never empty").push_str(token);
+                }
+            }
+        }
+        Err(MyError {
+            info: format!("unterminated argument list invoking macro '{}'", name),
+        })
+    }
+
+    // The replacement list of `m` with parameters replaced by the
+    // fully expanded arguments. Extra arguments of a variadic macro become
+    // __VA_ARGS__, and __VA_OPT__(x) is x only if there are any.
+    fn substitute(
+        &self,
+        name: &str,
+        m: &Macro,
+        params: &[String],
+        mut args: Vec<String>,
+        hidden: &mut Vec<String>,
+    ) -> Result<String, MyError> {
+        // `f()` passes one empty argument, which is no arguments for `f`.
+        if params.is_empty() && args.len() == 1 && args[0].trim().is_empty() {
+            args.clear();
+        }
+        if args.len() < params.len() || (!m.variadic && args.len() > params.len()) {
+            return Err(MyError {
+                info: format!(
+                    "macro '{}' requires {} arguments, but {} given",
+                    name,
+                    params.len(),
+                    args.len()
+                ),
+            });
+        }
+        let va_args = args.split_off(params.len()).join(",");
+        let mut values: HashMap<&str, String> = HashMap::new();
+        for (param, arg) in params.iter().zip(&args) {
+            values.insert(param, self.expand(arg.trim(), hidden)?);
+        }
+        if m.variadic {
+            values.insert("__VA_ARGS__", self.expand(va_args.trim(), hidden)?);
+        }
+        let va_opt = m.variadic && !va_args.trim().is_empty();
+        Ok(Self::replace_params(&m.body, &values, va_opt))
+    }
+
+    fn replace_params(body: &str, values: &HashMap<&str, String>, va_opt: bool) -> String {
+        let tokens = Self::pp_tokens(body);
+        let mut rv = String::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = tokens[i];
+            i += 1;
+            if token == "__VA_OPT__" && values.contains_key("__VA_ARGS__") {
+                if let Ok(Some((content, next))) = Self::macro_args(&tokens, i, token) {
+                    i = next;
+                    if va_opt {
+                        rv.push_str(&Self::replace_params(&content.join(","), values, va_opt));
+                    }
+                    continue;
+                }
+            }
+            match values.get(token) {
+                Some(value) => rv.push_str(value),
+                None => rv.push_str(token),
+            }
+        }
+        rv
+    }
+
+    // Split a line into preprocessing tokens. Identifiers, pp-numbers and
+    // quoted literals are kept whole; everything else is one char per token.
+    fn pp_tokens(text: &str) -> Vec<&str> {
+        let mut rv = Vec::new();
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let mut end = start + c.len_utf8();
+            if Self::is_ident_char(c) {
+                let is_number = c.is_ascii_digit();
+                while let Some(&(i, c)) = chars.peek() {
+                    if !(Self::is_ident_char(c) || (is_number && c == '.')) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+            } else if c == '"' || c == '\'' {
+                let mut escaped = false;
+                for (i, next) in chars.by_ref() {
+                    end = i + next.len_utf8();
+                    if escaped {
+                        escaped = false;
+                    } else if next == '\\' {
+                        escaped = true;
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+            rv.push(&text[start..end]);
+        }
+        rv
+    }
+
+    fn is_ident_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '_'
+    }
+
+    // Look for `name` in `dir`, which is None for angle-bracket includes,
+    // then in the include paths, then among the built-in headers.
+    fn search_include(&self, name: &str, dir: Option<&Path>) -> Result<Header, MyError> {
+        let candidates = dir
+            .into_iter()
+            .chain(self.include_paths.iter().map(|p| p.as_path()));
+        for base in candidates {
+            let path = base.join(name);
+            if path.is_file() {
+                return Ok(Header::File(path));
+            }
+        }
+        if let Some((name, text)) = BUILTIN_HEADERS.iter().find(|(header, _)| *header == name) {
+            return Ok(Header::Builtin(name, text));
+        }
+        Err(MyError {
+            info: format!("{}: file not found", name),
+        })
+    }
+
+    fn include_file(&mut self, header: Header, rv: &mut String) -> Result<(), MyError> {
+        // Built-in headers are known by a path no file can have.
+        let (path, canonical) = match &header {
+            Header::File(path) => {
+                let canonical = path.canonicalize().map_err(|e| MyError {
+                    info: format!("{}: {}", path.display(), e),
+                })?;
+                (path.clone(), canonical)
+            }
+            Header::Builtin(name, _) => {
+                let path = Path::new("<built-in>").join(name);
+                (path.clone(), path)
+            }
+        };
+        if self.pragma_once.contains(&canonical) || self.is_guarded(&canonical) {
+            return Ok(());
+        }
+        if self.include_stack.contains(&canonical) {
+            return Err(MyError {
+                info: format!("#include cycle detected: {}", path.display()),
+            });
+        }
+        let source = match header {
+            Header::File(_) => fs::read_to_string(&path).map_err(|e| MyError {
+                info: format!("{}: {}", path.display(), e),
+            })?,
+            Header::Builtin(_, text) => text.to_string(),
+        };
+        if let Some(guard) = Self::include_guard(&source) {
+            self.include_guards.insert(canonical.clone(), guard);
+        }
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+
+        self.include_stack.push(canonical);
+        let file = std::mem::replace(&mut self.file, path.display().to_string());
+        let line = self.line;
+        let delta = std::mem::replace(&mut self.line_delta, 0);
+        let first = self.line_map.len();
+        let expanded = self.preprocess(&source, &dir);
+        (self.file, self.line, self.line_delta) = (file, line, delta);
+        self.include_stack.pop();
+        let expanded = expanded?;
+        // the included lines come after those already output
+        self.line_map.shift(first, rv.matches('\n').count());
+        rv.push_str(&expanded);
+        Ok(())
+    }
+
+    fn is_guarded(&self, file: &Path) -> bool {
+        self.include_guards
+            .get(file)
+            .is_some_and(|guard| self.macros.contains_key(guard))
+    }
+
+    // Detect the classic include guard
+    //
+    //   #ifndef NAME
+    //   #define NAME
+    //   ...
+    //   #endif
+    //
+    // where nothing but blank lines is outside the #ifndef group and the
+    // group has no #else or #elif. Once NAME is defined, including the file
+    // again cannot produce anything, so it is not even read.
+    fn include_guard(source: &str) -> Option<String> {
+        let lines: Vec<&str> = source
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect();
+        let directive = |line: &str| -> Option<(String, String)> {
+            let directive = line.strip_prefix('#')?.trim_start();
+            let name: String = directive
+                .chars()
+                .take_while(|c| Self::is_ident_char(*c))
+                .collect();
+            let rest = directive[name.len()..].trim().to_string();
+            Some((name, rest))
+        };
+
+        let (ifndef, guard) = directive(lines.first()?)?;
+        let (define, defined) = directive(lines.get(1)?)?;
+        if ifndef != "ifndef"
+            || define != "define"
+            || Self::macro_name(&guard).ok()? != guard
+            || Self::macro_name(&defined).ok()? != guard
+        {
+            return None;
+        }
+        // The #endif closing the guard must be the last line.
+        let mut depth = 0;
+        for (i, line) in lines.iter().enumerate() {
+            match directive(line).map(|(name, _)| name).as_deref() {
+                Some("if" | "ifdef" | "ifndef") => depth += 1,
+                Some("else" | "elif") if depth == 1 => return None,
+                Some("endif") => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return (i == lines.len() - 1).then_some(guard);
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+// A macro definition. Function-like macros have a parameter list, possibly
+// empty, and may also take variable arguments.
+struct Macro {
+    params: Option<Vec<String>>,
+    variadic: bool,
+    body: String, // replacement list
+}
+
+// State of one #if ... #endif group.
+struct CondIncl {
+    active: bool,        // lines of the current branch are kept
+    taken: bool,         // some branch of the group has been kept already
+    parent_active: bool, // the group itself is inside a kept region
+    seen_else: bool,
+}
+
+// Constant expression evaluator for #if over macro-expanded pp tokens.
+struct CondExpr {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+// Binary operators from lowest to highest precedence.
+const BINARY_OPS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl CondExpr {
+    fn new(text: &str) -> Self {
+        let mut tokens: Vec<String> = Vec::new();
+        for token in Preprocessor::pp_tokens(text) {
+            if token.trim().is_empty() {
+                continue;
+            }
+            if let Some(last) = tokens.last_mut() {
+                let joined = format!("{}{}", last, token);
+                if matches!(
+                    joined.as_str(),
+                    "||" | "&&" | "==" | "!=" | "<=" | ">=" | "<<" | ">>"
+                ) {
+                    *last = joined;
+                    continue;
+                }
+            }
+            tokens.push(token.to_string());
+        }
+        Self { tokens, pos: 0 }
+    }
+
+    fn eval(mut self) -> Result<i64, MyError> {
+        let val = self.cond()?;
+        if let Some(token) = self.tokens.get(self.pos) {
+            return Err(MyError {
+                info: format!("extra token in #if expression: {}", token),
+            });
+        }
+        Ok(val)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn consume(&mut self, op: &str) -> bool {
+        if self.peek() == Some(op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), MyError> {
+        if self.consume(op) {
+            Ok(())
+        } else {
+            Err(MyError {
+                info: format!("expected '{}' in #if expression, got {:?}", op, self.peek()),
+            })
+        }
+    }
+
+    // cond = binary ("?" cond ":" cond)?
+    fn cond(&mut self) -> Result<i64, MyError> {
+        let val = self.binary(0)?;
+        if !self.consume("?") {
+            return Ok(val);
+        }
+        let then = self.cond()?;
+        self.expect(":")?;
+        let els = self.cond()?;
+        Ok(if val != 0 { then } else { els })
+    }
+
+    // binary = next-level (op next-level)*, one level per entry of BINARY_OPS
+    fn binary(&mut self, level: usize) -> Result<i64, MyError> {
+        if level == BINARY_OPS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek().filter(|t| BINARY_OPS[level].contains(t)) {
+            let op = op.to_string();
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = match op.as_str() {
+                "||" => (lhs != 0 || rhs != 0) as i64,
+                "&&" => (lhs != 0 && rhs != 0) as i64,
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "==" => (lhs == rhs) as i64,
+                "!=" => (lhs != rhs) as i64,
+                "<" => (lhs < rhs) as i64,
+                "<=" => (lhs <= rhs) as i64,
+                ">" => (lhs > rhs) as i64,
+                ">=" => (lhs >= rhs) as i64,
+                "<<" => lhs.wrapping_shl(rhs as u32),
+                ">>" => lhs.wrapping_shr(rhs as u32),
+                "+" => lhs.wrapping_add(rhs),
+                "-" => lhs.wrapping_sub(rhs),
+                "*" => lhs.wrapping_mul(rhs),
+                "/" | "%" if rhs == 0 => {
+                    return Err(MyError {
+                        info: "division by zero in #if expression".to_string(),
+                    })
+                }
+                "/" => lhs.wrapping_div(rhs),
+                _ => lhs.wrapping_rem(rhs),
+            };
+        }
+        Ok(lhs)
+    }
+
+    // unary = ("+" | "-" | "!" | "~") unary | primary
+    fn unary(&mut self) -> Result<i64, MyError> {
+        if self.consume("+") {
+            return self.unary();
+        }
+        if self.consume("-") {
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        if self.consume("!") {
+            return Ok((self.unary()? == 0) as i64);
+        }
+        if self.consume("~") {
+            return Ok(!self.unary()?);
+        }
+        self.primary()
+    }
+
+    // primary = "(" cond ")" | number | ident
+    fn primary(&mut self) -> Result<i64, MyError> {
+        if self.consume("(") {
+            let val = self.cond()?;
+            self.expect(")")?;
+            return Ok(val);
+        }
+        let Some(token) = self.tokens.get(self.pos).cloned() else {
+            return Err(MyError {
+                info: "unexpected end of #if expression".to_string(),
+            });
+        };
+        self.pos += 1;
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            let digits = token.trim_end_matches(['u', 'U', 'l', 'L']);
+            let val = if let Some(hex) = digits
+                .strip_prefix("0x")
+                .or_else(|| digits.strip_prefix("0X"))
+            {
+                u64::from_str_radix(hex, 16)
+            } else if digits.len() > 1 && digits.starts_with('0') {
+                u64::from_str_radix(&digits[1..], 8)
+            } else {
+                digits.parse::<u64>()
+            };
+            return val.map(|v| v as i64).map_err(|_| MyError {
+                info: format!("invalid integer in #if expression: {}", token),
+            });
+        }
+        if token.starts_with(Preprocessor::is_ident_char) {
+            // identifiers that are not macros evaluate to 0
+            return Ok(0);
+        }
+        Err(MyError {
+            info: format!("unexpected token in #if expression: {}", token),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chibicc_pp_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        dir
+    }
+
+    #[test]
+    fn test_include_relative_and_search_path() {
+        let dir = temp_dir("include");
+        let inc = dir.join("inc");
+        fs::create_dir_all(&inc).unwrap();
+        fs::write(inc.join("b.h"), "#include \"a.h\"\nint b;\n").unwrap();
+        fs::write(inc.join("a.h"), "int inner_a;\n").unwrap();
+
+        let mut pp = Preprocessor::new(vec![inc]);
+        let out = pp
+            .preprocess("#include \"b.h\"\n{ return 0; }", &dir)
+            .expect("preprocess error");
+        // b.h includes a.h relative to its own directory first
+        assert_eq!(out, "int inner_a;\n\nint b;\n\n{ return 0; }\n");
+    }
+
+    #[test]
+    fn test_define() {
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(
+                "#define N 10\n#define M N+N\n{ return M*NN; }",
+                Path::new("."),
+            )
+            .expect("preprocess error");
+        assert_eq!(out, "\n\n{ return 10+10*NN; }\n");
+    }
+
+    #[test]
+    fn test_define_recursion() {
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(
+                "#define foo foo+1\n#define a b\n#define b a\nfoo; a; b;",
+                Path::new("."),
+            )
+            .expect("preprocess error");
+        assert_eq!(out, "\n\n\nfoo+1; a; b;\n");
+    }
+
+    #[test]
+    fn test_file_and_line() {
+        let dir = temp_dir("file_line");
+        fs::write(dir.join("a.h"), "\n__FILE__ __LINE__\n").unwrap();
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(
+                "__LINE__\n#include \"a.h\"\n__FILE__ __LINE__\n#ifdef __LINE__\n1\n#endif",
+                &dir,
+            )
+            .expect("preprocess error");
+        let header = dir.join("a.h").display().to_string();
+        assert_eq!(
+            out,
+            format!("1\n\n\"{}\" 2\n\n\"<command line>\" 3\n\n1\n\n", header)
+        );
+    }
+
+    #[test]
+    fn test_line_directive() {
+        let dir = temp_dir("line_directive");
+        fs::write(dir.join("a.h"), "x\ny\n").unwrap();
+        let mut pp = Preprocessor::new(Vec::new());
+        pp.file = "main.c".to_string();
+        let out = pp
+            .preprocess(
+                "#line 10 \"gen.y\"\n__LINE__ __FILE__\n# 20 \"lex.l\" 1\n#include \"a.h\"\nz",
+                &dir,
+            )
+            .expect("preprocess error");
+        assert_eq!(out, "\n10 \"gen.y\"\n\nx\ny\n\nz\n");
+        let locate = |line: usize| {
+            let offset = out.split_inclusive('\n').take(line - 1).map(str::len).sum();
+            let (file, line, _) = pp.line_map.locate(&out, offset);
+            (file.to_string(), line)
+        };
+        let header = dir.join("a.h").display().to_string();
+        assert_eq!(locate(2), ("gen.y".to_string(), 10));
+        assert_eq!(locate(4), (header.clone(), 1));
+        assert_eq!(locate(5), (header, 2));
+        assert_eq!(locate(7), ("lex.l".to_string(), 21));
+        assert!(pp.preprocess("#line x", &dir).is_err());
+        assert!(pp.preprocess("#line 1 \"a", &dir).is_err());
+    }
+
+    #[test]
+    fn test_function_like_macros() {
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(
+                "#define ADD(a, b) ((a)+(b))\n#define ZERO() 0\nADD(ADD(1, 2), (3, 4)) ZERO() ADD",
+                Path::new("."),
+            )
+            .expect("preprocess error");
+        assert_eq!(out, "\n\n((((1)+(2)))+((3, 4))) 0 ADD\n");
+        assert!(pp.preprocess("ADD(1)", Path::new(".")).is_err());
+        assert!(pp.preprocess("ADD(1, 2", Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn test_variadic_macros() {
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(
+                "#define F(x, ...) f(x __VA_OPT__(,) __VA_ARGS__)\nF(1) F(1, 2, (3, 4))",
+                Path::new("."),
+            )
+            .expect("preprocess error");
+        assert_eq!(out, "\nf(1  ) f(1 , 2, (3, 4))\n");
+    }
+
+    #[test]
+    fn test_line_splicing() {
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(
+                "#define ADD(a, b) \\\n  ((a)+(b))\nADD(1,\\\n 2) re\\\nturn __LINE__\\",
+                Path::new("."),
+            )
+            .expect("preprocess error");
+        assert_eq!(out, "\n\n((1)+(2)) return 3\n\n\n");
+    }
+
+    #[test]
+    fn test_undef() {
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(
+                "#define N 1\nN\n#undef N\nN\n#ifdef N\nbad\n#endif\n#define N 2\nN\n#undef M",
+                Path::new("."),
+            )
+            .expect("preprocess error");
+        assert_eq!(out, "\n1\n\nN\n\n\n\n\n2\n\n");
+        assert!(pp.preprocess("#undef N 1", Path::new(".")).is_err());
+    }
+
+    fn eval(expr: &str) -> i64 {
+        CondExpr::new(expr).eval().expect("eval error")
+    }
+
+    #[test]
+    fn test_cond_expr() {
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3 == 9"), 1);
+        assert_eq!(eval("1 || 0 && 0"), 1);
+        assert_eq!(eval("-1 < 0 ? 0x10 : 010"), 16);
+        assert_eq!(eval("!0 + ~0 + (1 << 4) % 5 + 10UL / 3"), 4);
+        assert_eq!(eval("FOO"), 0);
+        assert!(CondExpr::new("1 / 0").eval().is_err());
+        assert!(CondExpr::new("(1").eval().is_err());
+        assert!(CondExpr::new("1 2").eval().is_err());
+    }
+
+    #[test]
+    fn test_conditional() {
+        let src = "#define A 2
+#if A == 2
+a;
+#elif defined(B)
+b;
+#else
+c;
+#endif
+#ifdef B
+#if 1/0
+#endif
+#elif !defined A
+d;
+#else
+e;
+#endif
+#ifndef B
+f;
+#endif";
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(src, Path::new("."))
+            .expect("preprocess error");
+        let kept: Vec<&str> = out.lines().filter(|l| !l.is_empty()).collect();
+        assert_eq!(kept, vec!["a;", "e;", "f;"]);
+        assert_eq!(out.lines().count(), src.lines().count());
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        let mut pp = Preprocessor::new(Vec::new());
+        for src in [
+            "#if 1\n",
+            "#endif\n",
+            "#else\n",
+            "#if 1\n#else\n#elif 1\n#endif\n",
+            "#ifdef 1\n#endif\n",
+        ] {
+            assert!(pp.preprocess(src, Path::new(".")).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_include_guard_detection() {
+        let guarded = "\n#ifndef FOO_H\n#define FOO_H\n#if 1\nint a;\n#endif\n#endif\n\n";
+        assert_eq!(
+            Preprocessor::include_guard(guarded),
+            Some("FOO_H".to_string())
+        );
+        let nested_else = "#ifndef FOO_H\n#define FOO_H\n#if 0\n#else\nint a;\n#endif\n#endif\n";
+        assert_eq!(
+            Preprocessor::include_guard(nested_else),
+            Some("FOO_H".to_string())
+        );
+        for src in [
+            "#ifndef FOO_H\n#define FOO_H\n#else\nint b;\n#endif\n",
+            "#ifndef FOO_H\n#define FOO_H\n#elif 1\nint b;\n#endif\n",
+            "#ifndef FOO_H\n#define FOO_H\n#endif\nint a;\n",
+            "int a;\n#ifndef FOO_H\n#define FOO_H\n#endif\n",
+            "#ifndef FOO_H\n#define BAR_H\n#endif\n",
+            "#ifdef FOO_H\n#define FOO_H\n#endif\n",
+        ] {
+            assert_eq!(Preprocessor::include_guard(src), None, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_include_once() {
+        let dir = temp_dir("once");
+        fs::write(
+            dir.join("guard.h"),
+            "#ifndef GUARD_H\n#define GUARD_H\nint g;\n#include \"guard.h\"\n#endif\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("once.h"),
+            "#pragma once\nint o;\n#include \"once.h\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("plain.h"), "int p;\n").unwrap();
+        fs::write(
+            dir.join("else.h"),
+            "#ifndef ELSE_H\n#define ELSE_H\nint e;\n#else\nint b;\n#endif\n",
+        )
+        .unwrap();
+
+        let mut pp = Preprocessor::new(Vec::new());
+        let src = "#include \"guard.h\"\n#include \"guard.h\"\n\
+#include \"once.h\"\n#include \"once.h\"\n\
+#include \"plain.h\"\n#include \"plain.h\"\n\
+#include \"else.h\"\n#include \"else.h\"\n";
+        let out = pp.preprocess(src, &dir).expect("preprocess error");
+        let kept: Vec<&str> = out.lines().filter(|l| !l.is_empty()).collect();
+        assert_eq!(
+            kept,
+            vec!["int g;", "int o;", "int p;", "int p;", "int e;", "int b;"]
+        );
+        assert!(pp.is_guarded(&dir.join("guard.h").canonicalize().unwrap()));
+        assert!(!pp.is_guarded(&dir.join("else.h").canonicalize().unwrap()));
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = temp_dir("cycle");
+        fs::write(dir.join("x.h"), "#include \"y.h\"\n").unwrap();
+        fs::write(dir.join("y.h"), "#include \"x.h\"\n").unwrap();
+
+        let mut pp = Preprocessor::new(Vec::new());
+        let err = pp.preprocess("#include \"x.h\"\n", &dir).unwrap_err();
+        assert!(err.info.contains("cycle"), "{}", err.info);
+    }
+
+    #[test]
+    fn test_include_not_found() {
+        let mut pp = Preprocessor::new(Vec::new());
+        let err = pp
+            .preprocess("#include \"no_such_header.h\"\n", &temp_dir("missing"))
+            .unwrap_err();
+        assert!(err.info.contains("file not found"), "{}", err.info);
+    }
+
+    #[test]
+    fn test_builtin_headers() {
+        let dir = temp_dir("builtin");
+        fs::write(
+            dir.join("stdbool.h"),
+            "#define bool int
+",
+        )
+        .unwrap();
+        let mut pp = Preprocessor::new(Vec::new());
+        let out = pp
+            .preprocess(
+                "#include <stddef.h>\n#include <stddef.h>\nsize_t n = NULL;",
+                &dir,
+            )
+            .expect("preprocess error");
+        assert_eq!(out.trim(), "long n = 0;");
+        // angle brackets skip the directory of the including file
+        let out = pp
+            .preprocess("#include <stdbool.h>\nbool b = true;", &dir)
+            .expect("preprocess error");
+        assert_eq!(out.trim(), "_Bool b = 1;");
+        let out = pp
+            .preprocess("#include \"stdbool.h\"\nbool b;", &dir)
+            .expect("preprocess error");
+        assert_eq!(out.trim(), "int b;");
+        let err = pp
+            .preprocess("#include <stdio.h>\n", &dir)
+            .expect_err("stdio.h is not built in");
+        assert!(err.info.contains("file not found"), "{}", err.info);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::parser::Type;
+use crate::{CompileOptions, MyError, Node, Parser, PassManager};
+use std::fmt::Write;
+
+macro_rules! emit {
+    ($self:ident, $($arg:tt)*) => {
+        writeln!($self.il, $($arg)*).expect("writing to a String cannot fail")
+    };
+}
+
+// Lowers the AST to QBE IL. Every value is held in a long, as in the
+// assembly backend's registers: int arithmetic is done on words and the
+// result sign-extended, and locals live in one frame laid out like its
+// stack. QBE has no inline assembly, atomics or volatile accesses: the first
+// two are errors, and volatile objects are accessed like any other.
+pub struct QbeGenerator {
+    il: String,
+    parser: Parser,
+    counter: usize,
+    options: CompileOptions,
+    pub passes: PassManager, // the AST passes named in options.passes are run
+}
+
+impl QbeGenerator {
+    pub fn new(parser: Parser, options: CompileOptions) -> Self {
+        Self {
+            il: String::new(),
+            parser,
+            counter: 0,
+            options,
+            passes: PassManager::default(),
+        }
+    }
+
+    fn count(&mut self) -> usize {
+        self.counter += 1;
+        self.counter
+    }
+
+    // A fresh temporary.
+    fn temp(&mut self) -> String {
+        format!("%t{}", self.count())
+    }
+
+    // Returns the IL for the whole program.
+    pub fn generate(&mut self, nodes: Vec<Node>) -> Result<String, MyError> {
+        let nodes = self
+            .passes
+            .run_ast(&self.options.pipeline(), nodes, &self.parser);
+        emit!(self, "export function w $main() {{");
+        emit!(self, "@start");
+        // %fp stands in for %rbp
+        let size = self.parser.stack_size;
+        emit!(self, "  %frame =l alloc16 {}", size);
+        emit!(self, "  %fp =l add %frame, {}", size);
+        for node in &nodes {
+            self.gen_stmt(Some(node))?;
+        }
+        emit!(self, "  ret 0");
+        emit!(self, "}}");
+        Ok(std::mem::take(&mut self.il))
+    }
+
+    // The suffix of the load and store instructions for an object of
+    // `r#type`: b, h, w or l.
+    fn mem_class(&self, r#type: &Type) -> &'static str {
+        match r#type.size(&self.parser.target) {
+            1 => "b",
+            2 => "h",
+            4 => "w",
+            _ => "l",
+        }
+    }
+
+    // Sign- or zero-extend the low `class` bits of `val` to a long, as a
+    // load of an object of `r#type` would.
+    fn extend(&mut self, val: &str, class: &str, r#type: &Type) -> String {
+        if class == "l" {
+            return val.to_string();
+        }
+        let sign = if let Type::UChar | Type::Bool = r#type.unqualified() {
+            "u"
+        } else {
+            "s"
+        };
+        let t = self.temp();
+        emit!(self, "  {} =l ext{}{} {}", t, sign, class, val);
+        t
+    }
+
+    // The address of an lvalue.
+    fn gen_addr(&mut self, node: &Node) -> Result<String, MyError> {
+        match node {
+            Node::Var { name, .. } => {
+                let offset = self.parser.locals[name].offset;
+                let t = self.temp();
+                emit!(self, "  {} =l sub %fp, {}", t, offset);
+                Ok(t)
+            }
+            Node::Deref { lhs, .. } => self.gen_expr(lhs),
+            _ => panic!("not a lvalue: {:?}", node),
+        }
+    }
+
+    // Load the object of `r#type` at `addr`. An array is not loaded: its
+    // address is its value.
+    fn load(&mut self, addr: String, r#type: &Type) -> String {
+        if let Type::Array { .. } = r#type {
+            return addr;
+        }
+        let class = self.mem_class(r#type);
+        let op = match (class, r#type.unqualified()) {
+            ("l", _) => "loadl".to_string(),
+            (_, Type::UChar | Type::Bool) => format!("loadu{}", class),
+            _ => format!("loads{}", class),
+        };
+        let t = self.temp();
+        emit!(self, "  {} =l {} {}", t, op, addr);
+        t
+    }
+
+    // The class arithmetic on values of `r#type` is done in: w for an int,
+    // or anything narrower, which is promoted to one.
+    fn arith_class(r#type: &Type) -> &'static str {
+        match r#type.unqualified() {
+            Type::Long | Type::I64 | Type::Ptr { .. } | Type::Array { .. } | Type::Func { .. } => {
+                "l"
+            }
+            _ => "w",
+        }
+    }
+
+    // `op` on `operands` in `class`, the result as a long.
+    fn arith(&mut self, op: &str, class: &str, operands: &str) -> String {
+        let t = self.temp();
+        emit!(self, "  {} ={} {} {}", t, class, op, operands);
+        if class == "l" {
+            return t;
+        }
+        let long = self.temp();
+        emit!(self, "  {} =l extsw {}", long, t);
+        long
+    }
+
+    // How a value of `r#type` is passed to and returned from functions.
+    fn abi_class(r#type: &Type) -> &'static str {
+        match r#type.unqualified() {
+            Type::Char | Type::SChar | Type::UChar | Type::Bool | Type::I32 => "w",
+            _ => "l",
+        }
+    }
+
+    fn unsupported(&self, what: &str, node: &Node) -> Result<String, MyError> {
+        Err(self.parser.error_at(
+            node.span(),
+            format!("{} not supported by the QBE backend", what),
+        ))
+    }
+
+    pub fn gen_expr(&mut self, node: &Node) -> Result<String, MyError> {
+        match node {
+            Node::Num { val, .. } => Ok(val.to_string()),
+            Node::Neg { lhs, r#type, .. } => {
+                let val = self.gen_expr(lhs)?;
+                Ok(self.arith("neg", Self::arith_class(r#type), &val))
+            }
+            Node::Var { r#type, .. } => {
+                let addr = self.gen_addr(node)?;
+                Ok(self.load(addr, r#type))
+            }
+            Node::Deref { lhs, r#type, .. } => {
+                let addr = self.gen_expr(lhs)?;
+                Ok(self.load(addr, r#type))
+            }
+            Node::Addr { lhs, .. } => self.gen_addr(lhs),
+            Node::FuncCall {
+                name, args, r#type, ..
+            } => {
+                let params = match &self.parser.functions[name].r#type {
+                    Type::Func { params, .. } => params.clone(),
+                    _ => unreachable!("functions are declared with function types"),
+                };
+                let mut operands = Vec::new();
+                for (arg, param) in args.iter().zip(&params) {
+                    let val = self.gen_expr(arg)?;
+                    // a long can be passed where a word is expected
+                    operands.push(format!("{} {}", Self::abi_class(param), val));
+                }
+                let class = Self::abi_class(r#type);
+                let t = self.temp();
+                emit!(
+                    self,
+                    "  {} ={} call ${}({})",
+                    t,
+                    class,
+                    name,
+                    operands.join(", ")
+                );
+                // the callee only defines the low bits of a char result
+                let class = match r#type.unqualified() {
+                    Type::Char | Type::SChar | Type::UChar | Type::Bool => "b",
+                    _ => class,
+                };
+                Ok(self.extend(&t, class, r#type))
+            }
+            Node::Assign {
+                lhs, rhs, r#type, ..
+            } => {
+                let addr = self.gen_addr(lhs)?;
+                let val = self.gen_expr(rhs)?;
+                let class = self.mem_class(r#type);
+                emit!(self, "  store{} {}, {}", class, val, addr);
+                // the value as stored
+                Ok(self.extend(&val, class, r#type))
+            }
+            Node::Exchange { .. } | Node::FetchAdd { .. } | Node::CompareSwap { .. } => {
+                self.unsupported("atomic operations are", node)
+            }
+            Node::Add {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Sub {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Mul {
+                lhs, rhs, r#type, ..
+            }
+            | Node::Div {
+                lhs, rhs, r#type, ..
+            } => {
+                // rhs first, as the assembly backend evaluates it
+                let r = self.gen_expr(rhs)?;
+                let l = self.gen_expr(lhs)?;
+                let op = match node {
+                    Node::Add { .. } => "add",
+                    Node::Sub { .. } => "sub",
+                    Node::Mul { .. } => "mul",
+                    _ => "div",
+                };
+                let operands = format!("{}, {}", l, r);
+                Ok(self.arith(op, Self::arith_class(r#type), &operands))
+            }
+            Node::Eq { lhs, rhs, .. }
+            | Node::Ne { lhs, rhs, .. }
+            | Node::Lt { lhs, rhs, .. }
+            | Node::Le { lhs, rhs, .. } => {
+                // compared in the class both operands are converted to
+                let class = Self::arith_class(&Type::common(
+                    &lhs.get_type().expect("should have a type"),
+                    &rhs.get_type().expect("should have a type"),
+                ));
+                let r = self.gen_expr(rhs)?;
+                let l = self.gen_expr(lhs)?;
+                let op = match node {
+                    Node::Eq { .. } => "ceq",
+                    Node::Ne { .. } => "cne",
+                    Node::Lt { .. } => "cslt",
+                    _ => "csle",
+                };
+                let t = self.temp();
+                emit!(self, "  {} =l {}{} {}, {}", t, op, class, l, r);
+                Ok(t)
+            }
+            _ => panic!("invalid expression, {:?}", node),
+        }
+    }
+
+    // jnz only tests the low word, so compare the whole long first.
+    fn truth(&mut self, val: &str) -> String {
+        let t = self.temp();
+        emit!(self, "  {} =w cnel {}, 0", t, val);
+        t
+    }
+
+    fn gen_stmt(&mut self, node: Option<&Node>) -> Result<(), MyError> {
+        let Some(node) = node else {
+            return Ok(());
+        };
+        match node {
+            Node::Return { lhs, .. } => {
+                let val = match lhs {
+                    Some(lhs) => self.gen_expr(lhs)?,
+                    None => "0".to_string(),
+                };
+                emit!(self, "  ret {}", val);
+                // Anything after the return goes in a block of its own.
+                let c = self.count();
+                emit!(self, "@dead.{}", c);
+            }
+            Node::ExprStmt { expr, .. } => {
+                self.gen_expr(expr)?;
+            }
+            Node::If {
+                cond, then, els, ..
+            } => {
+                let c = self.count();
+                let val = self.gen_expr(cond)?;
+                let val = self.truth(&val);
+                emit!(self, "  jnz {}, @then.{}, @else.{}", val, c, c);
+                emit!(self, "@then.{}", c);
+                self.gen_stmt(then.as_deref())?;
+                emit!(self, "  jmp @end.{}", c);
+                emit!(self, "@else.{}", c);
+                self.gen_stmt(els.as_deref())?;
+                emit!(self, "@end.{}", c);
+            }
+            Node::For {
+                init,
+                cond,
+                inc,
+                then,
+                ..
+            } => {
+                let c = self.count();
+                self.gen_stmt(init.as_deref())?;
+                emit!(self, "@begin.{}", c);
+                if let Some(cond) = cond {
+                    let val = self.gen_expr(cond)?;
+                    let val = self.truth(&val);
+                    emit!(self, "  jnz {}, @body.{}, @end.{}", val, c, c);
+                }
+                emit!(self, "@body.{}", c);
+                self.gen_stmt(then.as_deref())?;
+                if let Some(inc) = inc {
+                    self.gen_expr(inc)?;
+                }
+                emit!(self, "  jmp @begin.{}", c);
+                emit!(self, "@end.{}", c);
+            }
+            Node::Block { nodes, .. } => {
+                for node in nodes {
+                    self.gen_stmt(Some(node))?;
+                }
+            }
+            Node::Asm { .. } => {
+                self.unsupported("inline assembly is", node)?;
+            }
+            _ => panic!("invalid statement"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn il(src: &str) -> Result<String, MyError> {
+        let (parser, program) = crate::parse(src);
+        QbeGenerator::new(parser, CompileOptions::default()).generate(program.nodes)
+    }
+
+    #[test]
+    fn test_qbe() {
+        let text = il("int f(int, char*); { char c=1; return f(c+2, &c); }").expect("qbe error");
+        assert!(
+            text.starts_with("export function w $main() {\n@start\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("  storeb 1, %t"), "{}", text);
+        assert!(text.contains(" =w call $f(w %t"), "{}", text);
+        assert!(text.ends_with("  ret 0\n}\n"), "{}", text);
+        // int arithmetic wraps at 32 bits
+        let text = il("{ int x=2147483647; long y=x; return x+1 < y-x; }").expect("qbe error");
+        assert!(text.contains(" =w add %t"), "{}", text);
+        assert!(text.contains(" =l extsw %t"), "{}", text);
+        assert!(text.contains(" =l sub %t"), "{}", text);
+        assert!(text.contains(" =l csltl %t"), "{}", text);
+        let text = il("{ int x=3; return -x == 2; }").expect("qbe error");
+        assert!(text.contains(" =w neg %t"), "{}", text);
+        assert!(text.contains(" =l ceqw %t"), "{}", text);
+        assert!(il("{ asm(\"nop\"); return 0; }").is_err());
+        assert_eq!(
+            il("{ int x;\n  return __atomic_fetch_add(&x, 1, 5); }")
+                .err()
+                .map(|e| e.info),
+            Some(
+                "<string>:2:10: error: atomic operations are not supported by the QBE backend"
+                    .to_string()
+            )
+        );
+    }
+}
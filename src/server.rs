@@ -0,0 +1,191 @@
+use crate::compile;
+use crate::driver::Trace;
+use chibicc_rust::{CompileOptions, MyError};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+// One compile request per input line:
+//   {"id": 1, "source": "{ return 0; }", "options": {"opt_level": 2}}
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: serde_json::Value,
+    source: String,
+    #[serde(default)]
+    options: RequestOptions,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RequestOptions {
+    opt_level: u8,
+    error_limit: Option<usize>,
+    include_paths: Vec<PathBuf>,
+}
+
+// One response per request line, echoing its id.
+#[derive(Debug, PartialEq, Serialize)]
+struct Response {
+    id: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    warnings: Vec<String>,
+}
+
+fn handle(line: &str) -> Response {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => {
+            return Response {
+                id: serde_json::Value::Null,
+                asm: None,
+                error: Some(format!("invalid request: {}", e)),
+                warnings: Vec::new(),
+            }
+        }
+    };
+    let defaults = CompileOptions::default();
+    let options = CompileOptions {
+        opt_level: request.options.opt_level,
+        error_limit: request.options.error_limit.unwrap_or(defaults.error_limit),
+        ..defaults
+    };
+    // A bug in the compiler must not take the server, and every later
+    // client, down with it.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        compile(
+            &request.source,
+            "<request>",
+            Path::new("."),
+            request.options.include_paths,
+            options,
+            Trace::default(),
+        )
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(MyError {
+            info: format!("internal compiler error: {}", message),
+        })
+    });
+    match result {
+        Ok(output) => Response {
+            id: request.id,
+            asm: Some(output.asm),
+            error: None,
+            warnings: output.warnings,
+        },
+        Err(e) => Response {
+            id: request.id,
+            asm: None,
+            error: Some(e.info),
+            warnings: Vec::new(),
+        },
+    }
+}
+
+// Answer requests until stdin is closed. Blank lines are ignored.
+pub fn serve(input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = serde_json::to_string(&handle(&line)).map_err(io::Error::other)?;
+        writeln!(output, "{}", response)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn serve_str(input: &str) -> Vec<serde_json::Value> {
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output).expect("io error");
+        String::from_utf8(output)
+            .expect("utf8")
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("invalid response"))
+            .collect()
+    }
+
+    #[test]
+    fn test_serve() {
+        let responses = serve_str(concat!(
+            r#"{"id": 1, "source": "{ return 42; }"}"#,
+            "\n\n",
+            r#"{"id": "b", "source": "{ return x; }"}"#,
+            "\n",
+            "not json\n",
+        ));
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["id"], 1);
+        assert!(responses[0]["asm"]
+            .as_str()
+            .expect("asm")
+            .contains("mov $42, %rax"));
+        assert!(responses[0].get("error").is_none());
+        assert_eq!(responses[1]["id"], "b");
+        assert!(responses[1].get("asm").is_none());
+        assert!(responses[1]["error"].is_string());
+        assert!(responses[2]["error"]
+            .as_str()
+            .expect("error")
+            .starts_with("invalid request"));
+    }
+
+    #[test]
+    fn test_serve_options() {
+        let responses = serve_str(concat!(
+            r#"{"source": "int f(); { int x; int y=f(); if (y<2) x=y; else x=y+1; return x; }","#,
+            r#" "options": {"opt_level": 2}}"#,
+            "\n",
+            r#"{"source": "{ int *p=0; return *p; }"}"#,
+        ));
+        assert!(responses[0]["asm"].as_str().expect("asm").contains("cmove"));
+        assert_eq!(
+            responses[1]["warnings"][0],
+            concat!(
+                "<request>:1:20: warning: dereference of null pointer 'p' [-Wnull-dereference]\n",
+                "{ int *p=0; return *p; }\n",
+                "                   ^"
+            )
+        );
+    }
+
+    #[test]
+    fn test_serve_after_bad_request() {
+        let responses = serve_str(concat!(
+            r#"{"id": 1, "source": "{ 1=2; return 0; }"}"#,
+            "\n",
+            r#"{"id": 2, "source": "{ int x; &x=0; return &1; }"}"#,
+            "\n",
+            r#"{"id": 3, "source": "{ return 7; }"}"#,
+            "\n",
+        ));
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0]["error"]
+            .as_str()
+            .expect("error")
+            .contains("lvalue required as left operand of assignment"));
+        assert!(responses[1]["error"]
+            .as_str()
+            .expect("error")
+            .contains("lvalue required as unary '&' operand"));
+        assert!(responses[2]["asm"]
+            .as_str()
+            .expect("asm")
+            .contains("mov $7, %rax"));
+    }
+}
@@ -0,0 +1,562 @@
+use crate::cfg::Cfg;
+use crate::ir::{Function, Inst, Operand, Reg, Ty};
+use crate::MyError;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+// Static single assignment form. Registers are assigned once already, so
+// to_ssa turns the locals whose address never escapes into registers too:
+// each store makes a new register, and where stores on different paths meet
+// a phi picks between them. Phis go at the dominance frontiers of the
+// stores, as in Cytron et al., and are named by walking the dominator tree.
+// No backend lowers phis yet, so nothing is emitted from this form; the
+// native backend keeps the same locals in registers with plain copies.
+
+// Rewrite `function` into SSA form. Unreachable blocks are dropped and every
+// block gets a label, which phis name their predecessors by. A function with
+// inline assembly, which may use any local, is left alone.
+pub fn to_ssa(function: &mut Function) {
+    if function
+        .insts
+        .iter()
+        .any(|inst| matches!(inst, Inst::Asm(_)))
+    {
+        return;
+    }
+    prune_unreachable(function);
+    label_blocks(function);
+    let cfg = Cfg::new(function);
+    let slots = promotable(function);
+    let idom: Vec<usize> = cfg
+        .dominators()
+        .into_iter()
+        .map(|idom| idom.expect("unreachable blocks were dropped"))
+        .collect();
+    let frontiers = frontiers(&cfg, &idom);
+    // the registers holding the address of each promoted local
+    let addrs: HashMap<Reg, usize> = function
+        .insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::LocalAddr { dst, offset } if slots.contains_key(offset) => Some((*dst, *offset)),
+            _ => None,
+        })
+        .collect();
+
+    // A block where stores to a local from different paths meet needs a phi
+    // for it, which is a store of its own.
+    let mut phis: Vec<Vec<(usize, Reg)>> = vec![Vec::new(); cfg.blocks.len()];
+    for &slot in slots.keys() {
+        let mut work: Vec<usize> = (0..cfg.blocks.len())
+            .filter(|&b| {
+                function.insts[cfg.blocks[b].insts.clone()]
+                    .iter()
+                    .any(|inst| matches!(inst, Inst::Store { addr: Operand::Reg(addr), .. } if addrs.get(addr) == Some(&slot)))
+            })
+            .collect();
+        let mut placed = HashSet::new();
+        while let Some(b) = work.pop() {
+            for &f in &frontiers[b] {
+                if placed.insert(f) {
+                    phis[f].push((slot, function.regs));
+                    function.regs += 1;
+                    work.push(f);
+                }
+            }
+        }
+    }
+
+    let mut children = vec![Vec::new(); cfg.blocks.len()];
+    for (b, &parent) in idom.iter().enumerate().skip(1) {
+        children[parent].push(b);
+    }
+    let mut renamer = Renamer {
+        blocks: cfg
+            .blocks
+            .iter()
+            .map(|block| function.insts[block.insts.clone()].to_vec())
+            .collect(),
+        args: phis
+            .iter()
+            .map(|phis| vec![Vec::new(); phis.len()])
+            .collect(),
+        stacks: HashMap::new(),
+        regs: function.regs,
+        cfg: &cfg,
+        phis: &phis,
+        children: &children,
+        addrs: &addrs,
+    };
+    renamer.block(0);
+
+    let mut insts = Vec::with_capacity(function.insts.len());
+    for (b, block) in renamer.blocks.into_iter().enumerate() {
+        let mut block = block.into_iter();
+        insts.extend(block.next()); // the label
+        for ((_, dst), args) in phis[b].iter().zip(&renamer.args[b]) {
+            let mut args = args.clone();
+            args.sort_by_key(|(label, _)| {
+                cfg.blocks[b]
+                    .preds
+                    .iter()
+                    .position(|&p| cfg.blocks[p].label.as_ref() == Some(label))
+            });
+            insts.push(Inst::Phi { dst: *dst, args });
+        }
+        insts.extend(block);
+    }
+    function.insts = insts;
+    function.regs = renamer.regs;
+    if cfg!(debug_assertions) {
+        if let Err(e) = verify(function) {
+            panic!("{}\n{}", e.info, function);
+        }
+    }
+}
+
+// Check that `function` is in SSA form: each register is assigned once,
+// before every use on every path, and the phis come first in their blocks
+// with one value for each predecessor.
+pub fn verify(function: &Function) -> Result<(), MyError> {
+    let error = |info: String| {
+        Err(MyError {
+            info: format!("ssa: {}", info),
+        })
+    };
+    let cfg = Cfg::new(function);
+    let idom = cfg.dominators();
+    let mut defs: HashMap<Reg, (usize, usize)> = HashMap::new();
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for i in block.insts.clone() {
+            if let Some(dst) = function.insts[i].def() {
+                if defs.insert(dst, (b, i)).is_some() {
+                    return error(format!("%{} is assigned twice", dst));
+                }
+            }
+        }
+    }
+    let dominates = |a: usize, mut b: usize| loop {
+        if a == b {
+            return true;
+        }
+        match idom[b] {
+            Some(parent) if parent != b => b = parent,
+            _ => return false,
+        }
+    };
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        if idom[b].is_none() {
+            continue;
+        }
+        for i in block.insts.clone() {
+            let inst = &function.insts[i];
+            let Inst::Phi { dst, args } = inst else {
+                for reg in inst.uses() {
+                    match defs.get(&reg) {
+                        None => return error(format!("%{} is used but never assigned", reg)),
+                        Some(&(db, di)) if (db == b && di < i) || (db != b && dominates(db, b)) => {
+                        }
+                        Some(_) => {
+                            return error(format!("%{} is used where it may not be assigned", reg))
+                        }
+                    }
+                }
+                continue;
+            };
+            let at_top = function.insts[block.insts.start..i]
+                .iter()
+                .all(|inst| matches!(inst, Inst::Label(_) | Inst::Phi { .. }));
+            if !at_top {
+                return error(format!("phi %{} is not at the top of its block", dst));
+            }
+            let mut preds: Vec<Option<&String>> = block
+                .preds
+                .iter()
+                .map(|&p| cfg.blocks[p].label.as_ref())
+                .collect();
+            let mut labels: Vec<Option<&String>> =
+                args.iter().map(|(label, _)| Some(label)).collect();
+            preds.sort();
+            labels.sort();
+            if preds != labels {
+                return error(format!(
+                    "phi %{} doesn't have one value for each predecessor",
+                    dst
+                ));
+            }
+            for (label, arg) in args {
+                let Operand::Reg(reg) = arg else {
+                    continue;
+                };
+                let pred = block
+                    .preds
+                    .iter()
+                    .copied()
+                    .find(|&p| cfg.blocks[p].label.as_ref() == Some(label))
+                    .expect("checked above");
+                match defs.get(reg) {
+                    None => return error(format!("%{} is used but never assigned", reg)),
+                    Some(&(db, _)) if dominates(db, pred) => {}
+                    Some(_) => {
+                        return error(format!(
+                            "%{} may not be assigned at the end of {}",
+                            reg, label
+                        ))
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Names the values of the promoted locals, visiting the blocks down the
+// dominator tree so the value of each local is the one on top of its stack.
+struct Renamer<'a> {
+    blocks: Vec<Vec<Inst>>,
+    args: Vec<Vec<Vec<(String, Operand)>>>, // of each phi of each block
+    stacks: HashMap<usize, Vec<Operand>>,
+    regs: usize,
+    cfg: &'a Cfg,
+    phis: &'a [Vec<(usize, Reg)>],
+    children: &'a [Vec<usize>],
+    addrs: &'a HashMap<Reg, usize>,
+}
+
+impl Renamer<'_> {
+    // A local read before any store reads 0, as the interpreter's frame does.
+    fn value(&self, slot: usize) -> Operand {
+        self.stacks
+            .get(&slot)
+            .and_then(|stack| stack.last().copied())
+            .unwrap_or(Operand::Imm(0))
+    }
+
+    fn block(&mut self, b: usize) {
+        let mut pushed = Vec::new();
+        for &(slot, dst) in &self.phis[b] {
+            self.stacks.entry(slot).or_default().push(Operand::Reg(dst));
+            pushed.push(slot);
+        }
+        let insts = std::mem::take(&mut self.blocks[b]);
+        for inst in insts {
+            let slot = |addr: &Operand| match addr {
+                Operand::Reg(reg) => self.addrs.get(reg).copied(),
+                Operand::Imm(_) => None,
+            };
+            match inst {
+                Inst::LocalAddr { dst, .. } if self.addrs.contains_key(&dst) => {}
+                Inst::Load { dst, addr, .. } if slot(&addr).is_some() => {
+                    let src = self.value(slot(&addr).expect("matched above"));
+                    self.blocks[b].push(Inst::Copy { dst, src });
+                }
+                Inst::Store { addr, src, ty, .. } if slot(&addr).is_some() => {
+                    let slot = slot(&addr).expect("matched above");
+                    // the low bits stored, as they would be loaded back
+                    let dst = self.regs;
+                    self.regs += 1;
+                    self.blocks[b].push(Inst::Extend { dst, src, ty });
+                    self.stacks.entry(slot).or_default().push(Operand::Reg(dst));
+                    pushed.push(slot);
+                }
+                inst => self.blocks[b].push(inst),
+            }
+        }
+        let label = self.cfg.blocks[b]
+            .label
+            .clone()
+            .expect("blocks are labelled");
+        for &succ in &self.cfg.blocks[b].succs {
+            for (i, &(slot, _)) in self.phis[succ].iter().enumerate() {
+                let value = self.value(slot);
+                self.args[succ][i].push((label.clone(), value));
+            }
+        }
+        for &child in &self.children[b] {
+            self.block(child);
+        }
+        for slot in pushed {
+            self.stacks.get_mut(&slot).map(Vec::pop);
+        }
+    }
+}
+
+// The locals that are only ever loaded and stored, all in one width, and
+// not volatile or atomic, by their offset. Once any local's address is used
+// otherwise, none is: a pointer to one local may be moved to its
+// neighbours, as the optimizer also assumes.
+pub(crate) fn promotable(function: &Function) -> BTreeMap<usize, Ty> {
+    let offsets: HashMap<Reg, usize> = function
+        .insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::LocalAddr { dst, offset } => Some((*dst, *offset)),
+            _ => None,
+        })
+        .collect();
+    let mut tys: BTreeMap<usize, Ty> = BTreeMap::new();
+    let mut kept = HashSet::new();
+    let mut escapes = false;
+    for inst in &function.insts {
+        let (addr, ty, plain, src) = match inst {
+            Inst::Load {
+                addr: Operand::Reg(addr),
+                ty,
+                volatile,
+                ..
+            } => (addr, *ty, !volatile, None),
+            Inst::Store {
+                addr: Operand::Reg(addr),
+                src,
+                ty,
+                atomic,
+                volatile,
+            } => (addr, *ty, !atomic && !volatile, Some(src)),
+            _ => {
+                escapes |= inst.uses().iter().any(|reg| offsets.contains_key(reg));
+                continue;
+            }
+        };
+        if let Some(Operand::Reg(src)) = src {
+            escapes |= offsets.contains_key(src);
+        }
+        let Some(&offset) = offsets.get(addr) else {
+            continue;
+        };
+        if !plain || *tys.entry(offset).or_insert(ty) != ty {
+            kept.insert(offset);
+        }
+    }
+    if escapes {
+        return BTreeMap::new();
+    }
+    tys.retain(|offset, _| !kept.contains(offset));
+    tys
+}
+
+fn prune_unreachable(function: &mut Function) {
+    let cfg = Cfg::new(function);
+    let reachable = cfg.reachable();
+    if reachable.iter().all(|&r| r) {
+        return;
+    }
+    function.insts = cfg
+        .blocks
+        .iter()
+        .zip(reachable)
+        .filter(|(_, reachable)| *reachable)
+        .flat_map(|(block, _)| function.insts[block.insts.clone()].to_vec())
+        .collect();
+}
+
+// Give every block a label. The entry gets a new one, so no jump goes
+// there and it has no predecessors.
+fn label_blocks(function: &mut Function) {
+    let entry = Inst::Label(format!(".L.entry.{}", function.name));
+    if function.insts.first() != Some(&entry) {
+        function.insts.insert(0, entry);
+    }
+    let cfg = Cfg::new(function);
+    let mut insts = Vec::with_capacity(function.insts.len() + cfg.blocks.len());
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        if block.label.is_none() {
+            insts.push(Inst::Label(format!(".L.block.{}.{}", b, function.name)));
+        }
+        insts.extend_from_slice(&function.insts[block.insts.clone()]);
+    }
+    function.insts = insts;
+}
+
+// The blocks where each block's dominance ends: those it doesn't strictly
+// dominate but dominates a predecessor of.
+fn frontiers(cfg: &Cfg, idom: &[usize]) -> Vec<BTreeSet<usize>> {
+    let mut frontiers = vec![BTreeSet::new(); cfg.blocks.len()];
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        if block.preds.len() < 2 {
+            continue;
+        }
+        for &p in &block.preds {
+            let mut runner = p;
+            while runner != idom[b] {
+                frontiers[runner].insert(b);
+                runner = idom[runner];
+            }
+        }
+    }
+    frontiers
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{interpret, CodeGenerator, CompileOptions};
+
+    fn lower(src: &str, opt_level: u8) -> Function {
+        let (parser, program) = crate::parse(src);
+        let options = CompileOptions {
+            opt_level,
+            ..CompileOptions::default()
+        };
+        CodeGenerator::new(parser, options).lower(program.nodes)
+    }
+
+    fn run(function: &Function) -> Result<i32, String> {
+        let mut libc = |name: &str, args: &[i64]| match (name, args) {
+            ("abs", [x]) => Some((*x as i32).wrapping_abs() as i64),
+            _ => None,
+        };
+        interpret(function, &mut libc).map_err(|e| e.info)
+    }
+
+    #[test]
+    fn test_to_ssa() {
+        let mut function = lower(
+            "{ int i=0; int j=0; for (i=0; i<=10; i=i+1) j=i+j; return j; }",
+            0,
+        );
+        to_ssa(&mut function);
+        assert_eq!(verify(&function).map_err(|e| e.info), Ok(()));
+        assert!(
+            !function.insts.iter().any(|inst| matches!(
+                inst,
+                Inst::Load { .. } | Inst::Store { .. } | Inst::LocalAddr { .. }
+            )),
+            "{}",
+            function
+        );
+        let ir = function.to_string();
+        // i and j meet at the top of the loop
+        let phis = ir.lines().filter(|line| line.contains(" = phi ")).count();
+        assert_eq!(phis, 2, "{}", ir);
+        assert!(ir.contains("[.L.entry.main, "), "{}", ir);
+        assert_eq!(run(&function), Ok(55));
+    }
+
+    // Programs give the same result before and after.
+    #[test]
+    fn test_same_result() {
+        let programs = [
+            "{ int x=-9; return -(x/4); }",
+            "{ char c; c=300; return c*4+1; }",
+            "{ unsigned char c; c=255; c=c+1; return c; }",
+            "{ int a=3; int *p=&a; *p=*p+4; return a; }",
+            "{ int x=0; if (x) return 2; else if (x==0) return 3; return 4; }",
+            "{ int x; int y=1; if (y) x=5; else x=6; return x+y; }",
+            "{ int i; int j=1; int k=0; for (i=0; i<5; i=i+1) { if (i==2) k=k+j; j=j*2; } return j+k; }",
+            "{ int a[2]; int i=1; *(a+i)=8; *a=i; return *a+*(a+1); }",
+            "int abs(int); { int x=-5; return abs(x) + abs(6); }",
+            "{ int x=10; int old=__atomic_fetch_add(&x, 3, 5); return old+x; }",
+            "{ volatile int v=3; int w=v; return w+v; }",
+            "{ return 1; int x=2; return x; }",
+            "{ int i=0; for (;;) { i=i+1; if (i==4) return i; } }",
+            "{ int x=3; int y=5; return *(&x+1); }",
+            "{ int x=3; int y=5; *(&x+1)=7; return y; }",
+        ];
+        for src in programs {
+            for opt_level in [0, 2] {
+                let before = lower(src, opt_level);
+                let mut after = lower(src, opt_level);
+                to_ssa(&mut after);
+                assert_eq!(verify(&after).map_err(|e| e.info), Ok(()), "{}", after);
+                assert_eq!(
+                    run(&after),
+                    run(&before),
+                    "{} at -O{}\n{}",
+                    src,
+                    opt_level,
+                    after
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_stays_in_memory() {
+        // v is volatile
+        let function = lower("{ int x=3; volatile int v=1; return x+v; }", 0);
+        let slots = promotable(&function);
+        assert_eq!(slots.len(), 1, "{:?}", slots);
+        // a's address is taken, so p may point at any local
+        let mut function = lower(
+            "{ int a=3; int *p=&a; volatile int v=1; *p=*p+v; return a; }",
+            0,
+        );
+        assert!(promotable(&function).is_empty());
+        to_ssa(&mut function);
+        assert_eq!(run(&function), Ok(4));
+        // inline assembly might use any local
+        let mut function = lower("{ int x=1; asm(\"nop\"); return x; }", 0);
+        let before = function.to_string();
+        to_ssa(&mut function);
+        assert_eq!(function.to_string(), before);
+    }
+
+    #[test]
+    fn test_verify() {
+        let function = |insts| Function {
+            name: "main".to_string(),
+            insts,
+            regs: 3,
+            frame_size: 0,
+        };
+        let error = |insts| verify(&function(insts)).err().map(|e| e.info);
+        let label = |name: &str| Inst::Label(name.to_string());
+        let copy = |dst, src| Inst::Copy {
+            dst,
+            src: Operand::Imm(src),
+        };
+        assert_eq!(
+            error(vec![copy(0, 1), copy(0, 2)]).as_deref(),
+            Some("ssa: %0 is assigned twice")
+        );
+        assert_eq!(
+            error(vec![Inst::Ret(Some(Operand::Reg(1)))]).as_deref(),
+            Some("ssa: %1 is used but never assigned")
+        );
+        // %0 is only assigned on the way that skips .L.a
+        let branch = |tail: Vec<Inst>| {
+            let mut insts = vec![
+                label(".L.entry"),
+                Inst::JumpIfZero {
+                    cond: Operand::Imm(1),
+                    target: ".L.b".to_string(),
+                },
+                label(".L.a"),
+                Inst::Jump(".L.c".to_string()),
+                label(".L.b"),
+                copy(0, 7),
+                label(".L.c"),
+            ];
+            insts.extend(tail);
+            insts
+        };
+        assert_eq!(
+            error(branch(vec![Inst::Ret(Some(Operand::Reg(0)))])).as_deref(),
+            Some("ssa: %0 is used where it may not be assigned")
+        );
+        let phi = |args: &[(&str, Operand)]| Inst::Phi {
+            dst: 1,
+            args: args
+                .iter()
+                .map(|(label, arg)| (label.to_string(), *arg))
+                .collect(),
+        };
+        let good = phi(&[(".L.a", Operand::Imm(0)), (".L.b", Operand::Reg(0))]);
+        assert_eq!(error(branch(vec![good.clone()])), None);
+        assert_eq!(
+            error(branch(vec![phi(&[(".L.b", Operand::Reg(0))])])).as_deref(),
+            Some("ssa: phi %1 doesn't have one value for each predecessor")
+        );
+        assert_eq!(
+            error(branch(vec![phi(&[
+                (".L.a", Operand::Reg(0)),
+                (".L.b", Operand::Reg(0))
+            ])]))
+            .as_deref(),
+            Some("ssa: %0 may not be assigned at the end of .L.a")
+        );
+        assert_eq!(
+            error(branch(vec![copy(2, 0), good])).as_deref(),
+            Some("ssa: phi %1 is not at the top of its block")
+        );
+    }
+}
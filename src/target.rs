@@ -0,0 +1,89 @@
+// Data layout of the machine being compiled for. The parser sizes pointers
+// and longs from here instead of assuming 8 bytes, so a target with
+// 4-byte pointers only needs a new description (and a backend).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub name: &'static str,
+    pub arch: Arch, // picks the backend
+    pub os: Os,     // picks the object format the assembly is written for
+    pub pointer_size: usize,
+    pub long_size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arch {
+    X86_64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Os {
+    Linux, // ELF
+    MacOs, // Mach-O: symbols take a leading underscore, sections are named
+           // by segment
+}
+
+impl Target {
+    // Names accepted by `lookup`, canonical first.
+    pub const NAMES: &'static [&'static str] = &[
+        "x86_64-linux",
+        "x86_64-unknown-linux-gnu",
+        "x86_64-pc-linux-gnu",
+        "x86_64",
+        "x86_64-apple-darwin",
+        "x86_64-macos",
+    ];
+
+    pub fn x86_64() -> Self {
+        Self {
+            name: "x86_64-linux",
+            arch: Arch::X86_64,
+            os: Os::Linux,
+            pointer_size: 8,
+            long_size: 8,
+        }
+    }
+
+    pub fn x86_64_macos() -> Self {
+        Self {
+            name: "x86_64-apple-darwin",
+            os: Os::MacOs,
+            ..Self::x86_64()
+        }
+    }
+
+    // The machine the compiler itself runs on, the default target.
+    pub fn host() -> Self {
+        if cfg!(target_os = "macos") {
+            Self::x86_64_macos()
+        } else {
+            Self::x86_64()
+        }
+    }
+
+    // The target named by `name`, a triple as given to --target.
+    pub fn lookup(name: &str) -> Option<Self> {
+        match name {
+            "x86_64-linux" | "x86_64-unknown-linux-gnu" | "x86_64-pc-linux-gnu" | "x86_64" => {
+                Some(Self::x86_64())
+            }
+            "x86_64-apple-darwin" | "x86_64-macos" => Some(Self::x86_64_macos()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        for name in &Target::NAMES[..4] {
+            assert_eq!(Target::lookup(name), Some(Target::x86_64()));
+        }
+        for name in &Target::NAMES[4..] {
+            assert_eq!(Target::lookup(name).map(|t| t.os), Some(Os::MacOs));
+        }
+        assert_eq!(Target::lookup("riscv64-linux"), None);
+    }
+}
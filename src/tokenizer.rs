@@ -1,32 +1,253 @@
 use crate::MyError;
 use std::collections::VecDeque;
-use std::ops::Index;
+use std::fmt;
+use std::iter;
+use std::ops::{Index, Range};
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
-    Reserved { keyword: String },  // Keywords or punctuators
-    Num { raw: String, val: i32 }, // Integer literals
-    Ident { name: String },        // Identifiers
-    Eof,                           // End-of-file markers
+    Reserved { keyword: String },     // Keywords or punctuators
+    Num { raw: String, val: i64 },    // Integer literals
+    Float { raw: String, val: f64 },  // Floating-point literals
+    Str { raw: String, val: String }, // String literals
+    Ident { name: String },           // Identifiers
+    Eof,                              // End-of-file markers
 }
 
-#[derive(Debug)]
-pub struct TokenQueue(VecDeque<Token>);
+impl Token {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Token::Reserved { .. } => "reserved",
+            Token::Num { .. } => "num",
+            Token::Float { .. } => "float",
+            Token::Str { .. } => "str",
+            Token::Ident { .. } => "ident",
+            Token::Eof => "eof",
+        }
+    }
+
+    // The token as spelled in the source.
+    pub fn text(&self) -> &str {
+        match self {
+            Token::Reserved { keyword } => keyword,
+            Token::Num { raw, .. } | Token::Float { raw, .. } | Token::Str { raw, .. } => raw,
+            Token::Ident { name } => name,
+            Token::Eof => "",
+        }
+    }
+}
+
+// Suffix of an integer literal: `u`, `l` or `ll`, or `u` with one of the
+// others, in any case and order.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IntSuffix {
+    pub unsigned: bool,
+    pub long: bool,      // `l` or `ll`
+    pub long_long: bool, // `ll`
+}
+
+impl IntSuffix {
+    fn parse(s: &str) -> Option<IntSuffix> {
+        let (unsigned, rest) = match s.strip_prefix(['u', 'U']) {
+            Some(rest) => (true, rest),
+            None => match s.strip_suffix(['u', 'U']) {
+                Some(rest) => (true, rest),
+                None => (false, s),
+            },
+        };
+        match rest {
+            "" | "l" | "L" | "ll" | "LL" => Some(IntSuffix {
+                unsigned,
+                long: !rest.is_empty(),
+                long_long: rest.len() == 2,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// The text a token queue is lexed from, with where each of its lines starts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+    line_starts: Vec<usize>,
+}
+
+// A byte offset into a source file, with its 1-based line and column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub offset: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl SourceFile {
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        let text = text.into();
+        let line_starts = iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            name: name.into(),
+            text,
+            line_starts,
+        }
+    }
+
+    // Offsets past the end are taken as the end.
+    pub fn location(&self, offset: usize) -> Location {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        Location {
+            offset,
+            line,
+            col: offset - self.line_starts[line - 1] + 1,
+        }
+    }
+
+    // `name:line:col: error: message`, for errors that don't go through the
+    // parser's diagnostics: those found while lexing or after parsing.
+    pub fn error(&self, offset: usize, message: String) -> MyError {
+        let location = self.location(offset);
+        MyError {
+            info: format!(
+                "{}:{}:{}: error: {}",
+                self.name, location.line, location.col, message
+            ),
+        }
+    }
+}
+
+pub struct TokenQueue {
+    tokens: VecDeque<Token>,
+    spans: VecDeque<Range<usize>>, // byte range of each token in the source
+    source: SourceFile,
+    prev_end: usize, // end of the last token taken off the queue
+}
 
 impl Index<usize> for TokenQueue {
     type Output = Token;
-    fn index<'a>(&'a self, i: usize) -> &'a Token {
-        &self.0[i]
+    fn index(&self, i: usize) -> &Token {
+        &self.tokens[i]
+    }
+}
+
+impl fmt::Debug for TokenQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("TokenQueue").field(&self.tokens).finish()
     }
 }
 
 impl TokenQueue {
-    pub fn expect_num(&mut self) -> Result<i32, MyError> {
-        match self.0.pop_front() {
-            Some(Token::Num { val, .. }) => Ok(val),
-            _ => Err(MyError {
-                info: format!("expected Num, current tokens: {:?}", self.0),
-            })?,
+    fn pop(&mut self) -> Option<Token> {
+        if let Some(span) = self.spans.pop_front() {
+            self.prev_end = span.end;
+        }
+        self.tokens.pop_front()
+    }
+
+    // Drop the current token, e.g. while recovering from a syntax error.
+    pub fn skip(&mut self) {
+        if !self.at_eof() {
+            self.pop();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    // Byte range of the i-th remaining token in the source it was lexed from.
+    pub fn span(&self, i: usize) -> Range<usize> {
+        self.spans[i].clone()
+    }
+
+    // Where the i-th remaining token starts.
+    pub fn location(&self, i: usize) -> Location {
+        self.source.location(self.spans[i].start)
+    }
+
+    pub fn source(&self) -> &SourceFile {
+        &self.source
+    }
+
+    // The current token, for an error message: `before 'x'`, or `at end of
+    // input`.
+    pub fn here(&self) -> String {
+        match &self[0] {
+            Token::Eof => "at end of input".to_string(),
+            token => format!("before '{}'", token.text()),
+        }
+    }
+
+    // One `line:col kind text` line per remaining token.
+    pub fn dump(&self) -> String {
+        let mut rv = String::new();
+        for (i, token) in self.tokens.iter().enumerate() {
+            let location = self.location(i);
+            let line = format!(
+                "{}:{} {} {}",
+                location.line,
+                location.col,
+                token.kind(),
+                token.text()
+            );
+            rv.push_str(line.trim_end());
+            rv.push('\n');
+        }
+        rv
+    }
+
+    // Where the last consumed token ends, to close the span of a construct
+    // that has just been parsed.
+    pub fn prev_end(&self) -> usize {
+        self.prev_end
+    }
+
+    // An error about the current token, which is left on the queue.
+    fn expected(&self, what: &str) -> MyError {
+        MyError {
+            info: format!("expected {} {}", what, self.here()),
+        }
+    }
+
+    pub fn expect_num(&mut self) -> Result<i64, MyError> {
+        match self.tokens.front() {
+            Some(Token::Num { val, .. }) => {
+                let val = *val;
+                self.pop();
+                Ok(val)
+            }
+            _ => Err(self.expected("a number")),
+        }
+    }
+
+    // An integer literal and its suffix.
+    pub fn expect_int(&mut self) -> Result<(i64, IntSuffix), MyError> {
+        match self.tokens.front() {
+            Some(Token::Num { raw, val }) => {
+                let suffix = raw.trim_start_matches(|c: char| c.is_ascii_digit());
+                let rv = (*val, IntSuffix::parse(suffix).unwrap_or_default());
+                self.pop();
+                Ok(rv)
+            }
+            _ => Err(self.expected("a number")),
+        }
+    }
+
+    pub fn expect_str(&mut self) -> Result<String, MyError> {
+        match self.tokens.front() {
+            Some(Token::Str { val, .. }) => {
+                let val = val.clone();
+                self.pop();
+                Ok(val)
+            }
+            _ => Err(self.expected("a string literal")),
         }
     }
 
@@ -34,9 +255,7 @@ impl TokenQueue {
         if self.consume_reserve(op)? {
             Ok(())
         } else {
-            Err(MyError {
-                info: format!("expected '{}', current tokens: {:?}", op, self.0),
-            })
+            Err(self.expected(&format!("'{}'", op)))
         }
     }
 
@@ -49,12 +268,12 @@ impl TokenQueue {
     }
 
     pub fn consume_reserve(&mut self, op: &str) -> Result<bool, MyError> {
-        match self.0.front() {
+        match self.tokens.front() {
             None => Err(MyError {
                 info: format!("need {}, but no token left", op),
             }),
             Some(Token::Reserved { keyword: raw }) if raw == op => {
-                self.0.pop_front();
+                self.pop();
                 Ok(true)
             }
             _ => Ok(false),
@@ -62,14 +281,14 @@ impl TokenQueue {
     }
 
     pub fn consume_ident(&mut self) -> Result<Option<String>, MyError> {
-        if self.0.is_empty() {
+        if self.tokens.is_empty() {
             return Err(MyError {
                 info: "no token left".to_string(),
             });
         }
-        let found = matches!(self.0.front(), Some(Token::Ident { .. }));
+        let found = matches!(self.tokens.front(), Some(Token::Ident { .. }));
         if found {
-            let Some(Token::Ident { name }) = self.0.pop_front() else {
+            let Some(Token::Ident { name }) = self.pop() else {
                 Err(MyError {
                     info: "pop token error".to_string(),
                 })?
@@ -85,30 +304,29 @@ impl TokenQueue {
     }
 
     fn is_alpha_num(c: char) -> bool {
-        Self::is_alpha(c) || c.is_digit(10)
+        Self::is_alpha(c) || c.is_ascii_digit()
     }
 
     fn skip_whitespace(&self, s: &str, i: &mut usize) {
         if *i >= s.len() {
             return;
         }
-        let mut chars = s.chars().skip(*i);
-        while let Some(c) = chars.next() {
-            if c != ' ' {
+        for c in s.chars().skip(*i) {
+            if !c.is_whitespace() {
                 break;
             }
             *i += 1;
         }
     }
 
+    // Digits and any suffix letters after them, e.g. `10UL`.
     fn extract_digit(&self, s: &str, i: &mut usize) -> Option<String> {
         if *i >= s.len() {
             return None;
         }
         let mut rv = String::new();
-        let mut chars = s.chars().skip(*i);
-        while let Some(c) = chars.next() {
-            if c.is_digit(10) {
+        for c in s.chars().skip(*i) {
+            if c.is_ascii_digit() || (!rv.is_empty() && Self::is_alpha_num(c)) {
                 rv.push(c);
                 *i += 1;
             } else {
@@ -121,6 +339,97 @@ impl TokenQueue {
         Some(rv)
     }
 
+    // A decimal (`1.5`, `.5f`, `1e9`) or hexadecimal (`0x1.8p3`) floating
+    // literal with an optional `f` or `l` suffix. Leaves `i` alone if the
+    // number at `i` is an integer.
+    fn extract_float(&self, s: &str, i: &mut usize) -> Result<Option<Token>, MyError> {
+        let bytes = s.as_bytes();
+        let starts_number = bytes.get(*i).is_some_and(|c| c.is_ascii_digit())
+            || (bytes.get(*i) == Some(&b'.')
+                && bytes.get(*i + 1).is_some_and(|c| c.is_ascii_digit()));
+        if !starts_number {
+            return Ok(None);
+        }
+        // a preprocessing number: digits, letters, dots and signed exponents
+        let mut end = *i;
+        while end < bytes.len() {
+            let c = bytes[end];
+            let exp_sign =
+                matches!(c, b'+' | b'-') && matches!(bytes[end - 1], b'e' | b'E' | b'p' | b'P');
+            if !(exp_sign || c.is_ascii_alphanumeric() || c == b'_' || c == b'.') {
+                break;
+            }
+            end += 1;
+        }
+        let raw = &s[*i..end];
+        let hex = raw.starts_with("0x") || raw.starts_with("0X");
+        let is_float = if hex {
+            raw.contains(['p', 'P'])
+        } else {
+            raw.contains(['.', 'e', 'E'])
+        };
+        if !is_float {
+            return Ok(None);
+        }
+        let digits = raw.strip_suffix(['f', 'F', 'l', 'L']).unwrap_or(raw);
+        let val = if hex {
+            Self::hex_float(&digits[2..])
+        } else {
+            digits.parse::<f64>().ok()
+        };
+        let Some(val) = val else {
+            return Err(MyError {
+                info: format!("invalid floating constant: {}", raw),
+            });
+        };
+        *i = end;
+        Ok(Some(Token::Float {
+            raw: raw.to_string(),
+            val,
+        }))
+    }
+
+    // hex-float = hex-digits? ("." hex-digits?)? ("p" | "P") ("+" | "-")? digits
+    fn hex_float(s: &str) -> Option<f64> {
+        let (mantissa, exp) = s.split_once(['p', 'P'])?;
+        let exp: i32 = exp.parse().ok()?;
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if int.is_empty() && frac.is_empty() {
+            return None;
+        }
+        let mut val = 0.0;
+        for c in int.chars().chain(frac.chars()) {
+            val = val * 16.0 + f64::from(c.to_digit(16)?);
+        }
+        Some(val * 2f64.powi(exp - 4 * frac.len() as i32))
+    }
+
+    // The value of a decimal literal with an optional suffix. Unsigned types
+    // are not modelled, so an unsigned literal must fit in a long too.
+    fn int_literal(raw: &str) -> Result<i64, MyError> {
+        let digits = raw.trim_end_matches(|c: char| !c.is_ascii_digit());
+        if IntSuffix::parse(&raw[digits.len()..]).is_none() {
+            return Err(MyError {
+                info: format!(
+                    "invalid suffix '{}' on integer constant",
+                    &raw[digits.len()..]
+                ),
+            });
+        }
+        match digits.parse::<u64>() {
+            Ok(val) if i64::try_from(val).is_ok() => Ok(val as i64),
+            Ok(_) => Err(MyError {
+                info: format!("integer constant is too large for its type: {}", raw),
+            }),
+            Err(_) => Err(MyError {
+                info: format!(
+                    "integer constant is too large for any integer type: {}",
+                    raw
+                ),
+            }),
+        }
+    }
+
     fn extract_reserve(&self, s: &str, i: &mut usize) -> Option<String> {
         if *i >= s.len() {
             return None;
@@ -139,29 +448,25 @@ impl TokenQueue {
                 return double_rv;
             }
         }
-        let Some(c) = s.chars().nth(*i) else {
-            return None;
-        };
+        let c = s.chars().nth(*i)?;
         match c {
-            '+' | '-' | '*' | '/' | '(' | ')' | '<' | '>' | ';' | '=' | '{' | '}' | '&' => {
+            '+' | '-' | '*' | '/' | '(' | ')' | '<' | '>' | ';' | '=' | '{' | '}' | '&' | ','
+            | '[' | ']' => {
                 *i += 1;
-                return Some(c.to_string());
+                Some(c.to_string())
             }
             _ => None,
         }
     }
 
     fn extract_ident(&self, s: &str, i: &mut usize) -> Option<String> {
-        let Some(c) = s.chars().nth(*i) else {
-            return None;
-        };
+        let c = s.chars().nth(*i)?;
         if !Self::is_alpha(c) {
             return None;
         }
         let mut rv = c.to_string();
         *i += 1;
-        let mut chars = s.chars().skip(*i);
-        while let Some(c) = chars.next() {
+        for c in s.chars().skip(*i) {
             if Self::is_alpha_num(c) {
                 rv.push(c);
                 *i += 1;
@@ -176,66 +481,206 @@ impl TokenQueue {
         }
     }
 
-    fn generate_token(&mut self, s: &str, i: &mut usize) -> Result<(), MyError> {
-        self.skip_whitespace(s, i);
+    // A double-quoted string literal with C escapes resolved.
+    fn extract_str(&self, s: &str, i: &mut usize) -> Result<Option<Token>, MyError> {
+        if !s[*i..].starts_with('"') {
+            return Ok(None);
+        }
+        let start = *i;
+        let mut val = String::new();
+        let mut chars = s[start + 1..].char_indices();
+        while let Some((j, c)) = chars.next() {
+            match c {
+                '"' => {
+                    *i = start + 1 + j + 1;
+                    return Ok(Some(Token::Str {
+                        raw: s[start..*i].to_string(),
+                        val,
+                    }));
+                }
+                '\\' => {
+                    let Some((_, c)) = chars.next() else {
+                        break;
+                    };
+                    val.push(match c {
+                        'a' => '\x07',
+                        'b' => '\x08',
+                        'e' => '\x1b',
+                        'f' => '\x0c',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'v' => '\x0b',
+                        '0' => '\0',
+                        c => c,
+                    });
+                }
+                '\n' => break,
+                c => val.push(c),
+            }
+        }
+        Err(self
+            .source
+            .error(start, "missing terminating '\"' character".to_string()))
+    }
+
+    fn generate_token(&self, s: &str, i: &mut usize) -> Result<Option<Token>, MyError> {
+        if let Some(token) = self.extract_str(s, i)? {
+            return Ok(Some(token));
+        }
+
+        if let Some(token) = self.extract_float(s, i)? {
+            return Ok(Some(token));
+        }
 
         if let Some(num) = self.extract_digit(s, i) {
-            self.0.push_back(Token::Num {
-                val: num.parse::<i32>().map_err(|e| MyError {
-                    info: e.to_string(),
-                })?,
+            return Ok(Some(Token::Num {
+                val: Self::int_literal(&num)?,
                 raw: num,
-            });
-            return Ok(());
+            }));
         }
 
         if let Some(reserve) = self.extract_reserve(s, i) {
-            self.0.push_back(Token::Reserved { keyword: reserve });
-            return Ok(());
+            return Ok(Some(Token::Reserved { keyword: reserve }));
         }
 
         if let Some(ident) = self.extract_ident(s, i) {
-            match ident.as_str() {
-                key @ ("return" | "if" | "else" | "for" | "while" | "int") => {
-                    self.0.push_back(Token::Reserved {
-                        keyword: key.to_string(),
-                    });
-                }
-                _ => {
-                    self.0.push_back(Token::Ident { name: ident });
-                }
-            }
-            return Ok(());
+            let token = match ident.as_str() {
+                key @ ("return" | "if" | "else" | "for" | "while" | "asm" | "__asm__" | "char"
+                | "signed" | "unsigned" | "int" | "long" | "volatile" | "restrict"
+                | "_Atomic" | "_Bool" | "_Static_assert" | "__attribute__") => Token::Reserved {
+                    keyword: key.to_string(),
+                },
+                _ => Token::Ident { name: ident },
+            };
+            return Ok(Some(token));
         }
 
         if *i >= s.len() {
-            Ok(())
+            Ok(None)
         } else {
-            Err(MyError {
-                info: format!(
-                    "unexpected character: {:?}, in {} at {}, token queue: {:?}",
-                    s.chars().nth(*i),
-                    s,
-                    *i,
-                    self.0
-                ),
-            })
+            let c = s[*i..].chars().next().expect("not at the end");
+            Err(self
+                .source
+                .error(*i, format!("unexpected character '{}'", c)))
+        }
+    }
+
+    // Skip whitespace and lex one token, returning it with its byte range.
+    fn next_token(&self, s: &str, i: &mut usize) -> Result<Option<(Token, Range<usize>)>, MyError> {
+        self.skip_whitespace(s, i);
+        let start = *i;
+        Ok(self.generate_token(s, i)?.map(|token| (token, start..*i)))
+    }
+
+    fn new(source: SourceFile) -> Self {
+        Self {
+            tokens: VecDeque::new(),
+            spans: VecDeque::new(),
+            source,
+            prev_end: 0,
         }
     }
 
-    fn new() -> Self {
-        Self(VecDeque::new())
+    fn push(&mut self, token: Token, span: Range<usize>) {
+        self.tokens.push_back(token);
+        self.spans.push_back(span);
     }
 
     pub fn tokenizer(s: &str) -> Result<Self, MyError> {
-        let mut rv = Self::new();
+        Self::tokenize_file(SourceFile::new("<string>", s))
+    }
+
+    pub fn tokenize_file(source: SourceFile) -> Result<Self, MyError> {
+        let text = source.text.clone();
+        let mut rv = Self::new(source);
         let mut i = 0;
-        while i < s.len() {
-            rv.generate_token(s, &mut i)?;
+        while i < text.len() {
+            if let Some((token, span)) = rv.next_token(&text, &mut i)? {
+                rv.push(token, span);
+            }
         }
-        rv.0.push_back(Token::Eof);
+        rv.push(Token::Eof, text.len()..text.len());
         Ok(rv)
     }
+
+    // Re-lex only the part of `source` touched by an edit and splice the fresh
+    // tokens over the stale ones. The queue must have been built from the text
+    // before the edit; `source` is the full text after it and `edit` is the
+    // byte range of `source` holding the replacement text. Lexing restarts at
+    // the end of the last token before the edit and stops as soon as it lines
+    // up with the start of an old token after it. Returns the index range of
+    // the fresh tokens.
+    pub fn retokenize_range(
+        &mut self,
+        source: &str,
+        edit: Range<usize>,
+    ) -> Result<Range<usize>, MyError> {
+        let source_len = self.source.text.len();
+        let delta = source.len() as isize - source_len as isize;
+        let old_end = edit.end as isize - delta;
+        if edit.start > edit.end || edit.end > source.len() || old_end < edit.start as isize {
+            return Err(MyError {
+                info: format!(
+                    "invalid edit {:?} for a source of {} bytes, previously {}",
+                    edit,
+                    source.len(),
+                    source_len
+                ),
+            });
+        }
+        let old_end = old_end as usize;
+        self.source = SourceFile::new(self.source.name.clone(), source);
+        let shift = |span: &Range<usize>| {
+            (span.start as isize + delta) as usize..(span.end as isize + delta) as usize
+        };
+
+        if self.tokens.back() == Some(&Token::Eof) {
+            self.tokens.pop_back();
+            self.spans.pop_back();
+        }
+        // A token ending exactly at the edit may grow, so it is re-lexed too.
+        let lo = self
+            .spans
+            .iter()
+            .take_while(|span| span.end < edit.start)
+            .count();
+        let mut hi = lo
+            + self
+                .spans
+                .iter()
+                .skip(lo)
+                .take_while(|span| span.start <= old_end)
+                .count();
+
+        let mut i = if lo == 0 { 0 } else { self.spans[lo - 1].end };
+        let mut fresh = Vec::new();
+        loop {
+            self.skip_whitespace(source, &mut i);
+            while hi < self.spans.len() && shift(&self.spans[hi]).start < i {
+                hi += 1;
+            }
+            if i >= source.len() || (hi < self.spans.len() && shift(&self.spans[hi]).start == i) {
+                break;
+            }
+            if let Some(token) = self.next_token(source, &mut i)? {
+                fresh.push(token);
+            }
+        }
+
+        let tail_tokens = self.tokens.split_off(hi);
+        let tail_spans = self.spans.split_off(hi);
+        self.tokens.truncate(lo);
+        self.spans.truncate(lo);
+        let fresh_len = fresh.len();
+        for (token, span) in fresh {
+            self.push(token, span);
+        }
+        self.tokens.extend(tail_tokens);
+        self.spans.extend(tail_spans.iter().map(shift));
+        self.push(Token::Eof, source.len()..source.len());
+        Ok(lo..lo + fresh_len)
+    }
 }
 
 #[cfg(test)]
@@ -314,7 +759,7 @@ mod test {
         match token_queue {
             Ok(token_queue) => {
                 assert_eq!(
-                    token_queue.0,
+                    token_queue.tokens,
                     vec![
                         Token::Num {
                             raw: "12".to_string(),
@@ -357,7 +802,7 @@ mod test {
         match token_queue {
             Ok(token_queue) => {
                 assert_eq!(
-                    token_queue.0,
+                    token_queue.tokens,
                     vec![
                         Token::Num {
                             raw: "3".to_string(),
@@ -391,7 +836,7 @@ mod test {
     fn test_tokenizer_double_op() {
         let token_queue = TokenQueue::tokenizer("3+1==2").expect("tokenizer error");
         assert_eq!(
-            token_queue.0,
+            token_queue.tokens,
             vec![
                 Token::Num {
                     raw: "3".to_string(),
@@ -420,7 +865,7 @@ mod test {
         let token_queue =
             TokenQueue::tokenizer("foo123=3; bar=5; return foo123+bar;").expect("tokenizer error");
         assert_eq!(
-            token_queue.0,
+            token_queue.tokens,
             vec![
                 Token::Ident {
                     name: "foo123".to_string()
@@ -467,4 +912,188 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn test_tokenizer_string() {
+        let token_queue = TokenQueue::tokenizer(r#"asm("a\tb\"c\\")"#).expect("tokenizer error");
+        assert_eq!(
+            token_queue.tokens[2],
+            Token::Str {
+                raw: r#""a\tb\"c\\""#.to_string(),
+                val: "a\tb\"c\\".to_string()
+            }
+        );
+        assert_eq!(token_queue.span(2), 4..15);
+        assert!(TokenQueue::tokenizer("\"abc").is_err());
+    }
+
+    #[test]
+    fn test_tokenizer_long_literal() {
+        let token_queue = TokenQueue::tokenizer("long 8589934592").expect("tokenizer error");
+        assert_eq!(
+            token_queue.tokens,
+            vec![
+                Token::Reserved {
+                    keyword: "long".to_string()
+                },
+                Token::Num {
+                    raw: "8589934592".to_string(),
+                    val: 8589934592
+                },
+                Token::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_int_suffixes() {
+        let mut token_queue = TokenQueue::tokenizer("1 2u 3L 4ull 5LLU 9223372036854775807u")
+            .expect("tokenizer error");
+        let suffix = |unsigned, l: &str| IntSuffix {
+            unsigned,
+            long: !l.is_empty(),
+            long_long: l.len() == 2,
+        };
+        assert_eq!(token_queue.expect_int().unwrap(), (1, suffix(false, "")));
+        assert_eq!(token_queue.expect_int().unwrap(), (2, suffix(true, "")));
+        assert_eq!(token_queue.expect_int().unwrap(), (3, suffix(false, "l")));
+        assert_eq!(token_queue.expect_int().unwrap(), (4, suffix(true, "ll")));
+        assert_eq!(token_queue.expect_int().unwrap(), (5, suffix(true, "ll")));
+        assert_eq!(
+            token_queue.expect_int().unwrap(),
+            (i64::MAX, suffix(true, ""))
+        );
+
+        for bad in [
+            "1lL",
+            "2uu",
+            "3x",
+            "9223372036854775808",
+            "9223372036854775808u",
+            "18446744073709551615U",
+            "18446744073709551616u",
+        ] {
+            assert!(TokenQueue::tokenizer(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_float_literals() {
+        let token_queue =
+            TokenQueue::tokenizer("1.5 .5f 1e9 2.E-1L 0x1.8p3 0X.4P+0 3").expect("tokenizer error");
+        let floats: Vec<f64> = token_queue
+            .tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Float { val, .. } => Some(*val),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(floats, vec![1.5, 0.5, 1e9, 0.2, 12.0, 0.25]);
+        assert_eq!(
+            token_queue[6],
+            Token::Num {
+                raw: "3".to_string(),
+                val: 3
+            }
+        );
+
+        for bad in ["1.5x", "1e", "0x1.8", "0xp3", "1.2.3"] {
+            assert!(TokenQueue::tokenizer(bad).is_err(), "{}", bad);
+        }
+    }
+
+    fn check_retokenize(old: &str, new: &str, edit: std::ops::Range<usize>) -> Range<usize> {
+        let mut token_queue = TokenQueue::tokenizer(old).expect("tokenizer error");
+        let fresh = token_queue
+            .retokenize_range(new, edit)
+            .expect("retokenize error");
+        let expected = TokenQueue::tokenizer(new).expect("tokenizer error");
+        assert_eq!(token_queue.tokens, expected.tokens, "{} -> {}", old, new);
+        assert_eq!(token_queue.spans, expected.spans, "{} -> {}", old, new);
+        fresh
+    }
+
+    #[test]
+    fn test_retokenize_range() {
+        // replace a literal
+        assert_eq!(check_retokenize("x=1; y=2;", "x=10; y=2;", 2..4), 1..4);
+        // grow an identifier at its end
+        assert_eq!(check_retokenize("a = 1;", "ab = 1;", 1..2), 0..1);
+        // delete the space between two identifiers, merging them
+        assert_eq!(check_retokenize("a b;", "ab;", 1..1), 0..1);
+        // turn "<" into "<=" by inserting right after it
+        assert_eq!(check_retokenize("1<2", "1<=2", 2..3), 1..3);
+        // insert whole statements in the middle
+        check_retokenize("{ a=1; }", "{ a=1; b=2; c=3; }", 6..16);
+        // delete everything
+        assert_eq!(check_retokenize("return 1;", "", 0..0), 0..0);
+        // edits at the very start and end
+        check_retokenize("b;", "a+b;", 0..2);
+        check_retokenize("a;", "a;b", 2..3);
+    }
+
+    #[test]
+    fn test_retokenize_range_invalid_edit() {
+        let mut token_queue = TokenQueue::tokenizer("a;").expect("tokenizer error");
+        assert!(token_queue.retokenize_range("a;", 1..5).is_err());
+        assert!(token_queue.retokenize_range("abc;", 0..1).is_err());
+    }
+
+    #[test]
+    fn test_dump() {
+        let source = "int x;\n  x = \"a\" + 1.5;";
+        let tokens = TokenQueue::tokenizer(source).expect("tokenize error");
+        assert_eq!(
+            tokens.dump(),
+            "1:1 reserved int\n1:5 ident x\n1:6 reserved ;\n2:3 ident x\n2:5 reserved =\n\
+             2:7 str \"a\"\n2:11 reserved +\n2:13 float 1.5\n2:16 reserved ;\n2:17 eof\n"
+        );
+    }
+
+    #[test]
+    fn test_locations() {
+        let source = SourceFile::new("a.c", "{\n\n  x = 1;\n}");
+        let location = |offset, line, col| Location { offset, line, col };
+        assert_eq!(source.location(0), location(0, 1, 1));
+        assert_eq!(source.location(1), location(1, 1, 2));
+        assert_eq!(source.location(2), location(2, 2, 1));
+        assert_eq!(source.location(5), location(5, 3, 3));
+        assert_eq!(source.location(100), location(13, 4, 2));
+
+        let mut tokens = TokenQueue::tokenize_file(source).expect("tokenize error");
+        assert_eq!(tokens.location(1), location(5, 3, 3));
+        tokens
+            .retokenize_range("{\n  y;\n  x = 1;\n}", 2..6)
+            .unwrap();
+        assert_eq!(tokens.location(3), location(9, 3, 3));
+        assert_eq!(tokens.source().name, "a.c");
+
+        assert_eq!(
+            TokenQueue::tokenize_file(SourceFile::new("a.c", "{\n  x @ 1; }"))
+                .err()
+                .map(|e| e.info),
+            Some("a.c:2:5: error: unexpected character '@'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expect_errors() {
+        let mut tokens = TokenQueue::tokenizer("x 1").expect("tokenize error");
+        assert_eq!(
+            tokens.expect_reserve(";").unwrap_err().info,
+            "expected ';' before 'x'"
+        );
+        assert_eq!(
+            tokens.expect_num().unwrap_err().info,
+            "expected a number before 'x'"
+        );
+        // the token is still there
+        assert_eq!(tokens.consume_ident().unwrap(), Some("x".to_string()));
+        assert_eq!(tokens.expect_num().unwrap(), 1);
+        assert_eq!(
+            tokens.expect_str().unwrap_err().info,
+            "expected a string literal at end of input"
+        );
+    }
 }
@@ -0,0 +1,1605 @@
+use crate::cfg::Cfg;
+use crate::ir::{BinOp, Function, Inst, Operand, Reg, Ty};
+use crate::{ssa, CompileOptions, Os};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+const ARG_REGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+// Virtual registers are kept in these, and only spilled to the frame when
+// all are in use. Calls preserve those from CALLEE_SAVED on, which values
+// live across one must take. A call's arguments are moved to ARG_REGS in
+// order, so none may come from %r8 or %r9. Instructions work in %rax, %rdi,
+// %rsi, %rdx and %rcx.
+const TEMP_REGS: [&str; 9] = ["r10", "r11", "r8", "r9", "rbx", "r12", "r13", "r14", "r15"];
+const CALLEE_SAVED: usize = 4;
+const ARG_TEMPS: std::ops::Range<usize> = 2..4;
+// With -fstack-protector, the bytes below the saved %rbp holding the canary;
+// the locals go below them.
+pub(crate) const CANARY_SIZE: usize = 16;
+
+// One line of assembly. Instructions are kept apart from their operands so
+// the peephole pass can match on them without parsing text.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line {
+    Inst { op: String, args: Vec<String> }, // AT&T order, source first
+    Label(String),
+    Directive(String),
+    Asm(String),     // inline assembly, opaque to the peephole pass
+    Comment(String), // for --asm-comments
+    // Where the following lines came from, for the source map. It isn't
+    // printed, and the peephole pass looks through it.
+    Source(Option<Range<usize>>),
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Inst { op, args } if args.is_empty() => write!(f, "  {}", op),
+            Line::Inst { op, args } => write!(f, "  {} {}", op, args.join(", ")),
+            Line::Label(label) => write!(f, "{}:", label),
+            Line::Directive(text) | Line::Asm(text) => write!(f, "  {}", text),
+            Line::Comment(text) => write!(f, "  # {}", text),
+            Line::Source(_) => Ok(()),
+        }
+    }
+}
+
+macro_rules! op {
+    ($self:ident, $op:expr $(, $arg:expr)*) => {
+        $self.lines.push(Line::Inst {
+            op: $op.to_string(),
+            args: vec![$($arg.to_string()),*],
+        })
+    };
+}
+
+// Returns the assembly for `function`, one line per entry.
+pub fn emit(function: &Function, options: &CompileOptions) -> Vec<Line> {
+    let mut emitter = Emitter::new(options);
+    emitter.function(function);
+    emitter.lines
+}
+
+// The label after the last instruction of `function`, for -g.
+pub(crate) fn end_label(function: &str) -> String {
+    format!(".L.func_end.{}", function)
+}
+
+// Returns the text of `lines`, ready for the assembler.
+pub fn print(lines: &[Line]) -> String {
+    lines
+        .iter()
+        .filter(|line| !matches!(line, Line::Source(_)))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+// The source span each printed line of `lines` came from.
+pub fn source_map(lines: &[Line]) -> Vec<Option<Range<usize>>> {
+    let mut span = None;
+    let mut map = Vec::with_capacity(lines.len());
+    for line in lines {
+        match line {
+            Line::Source(source) => span = source.clone(),
+            _ => map.push(span.clone()),
+        }
+    }
+    map
+}
+
+struct Emitter {
+    os: Os,
+    pic: bool,
+    stack_protector: bool,
+    debug_info: bool,
+    canary: usize,     // bytes above the locals set aside for the canary
+    ret_label: String, // where returns jump to, .L.return.<function>
+    depth: usize,      // bytes on the stack since the caller's call, return address included
+    lines: Vec<Line>,
+    locs: Vec<String>,          // where each virtual register lives
+    frame: HashMap<Reg, usize>, // see frame_addresses
+    branches: HashSet<Reg>,     // see branch_conditions
+    ints: HashSet<Reg>,         // see int_values
+    flags: Option<BinOp>,       // the comparison the flags were last set by
+    files: Vec<String>,         // numbered by .file, from 1
+}
+
+// The points each virtual register of `function` is live from and to, None
+// for one it doesn't use. Instruction i reads its operands at 2i and writes
+// its result at 2i+1. A register live into a block is live from its start,
+// and one live out of it to its end, so a value carried around a loop is
+// live over the whole of it.
+fn live_ranges(function: &Function) -> Vec<Option<(usize, usize)>> {
+    let cfg = Cfg::new(function);
+    let insts = &function.insts;
+    // the registers each block reads before writing them, and writes
+    let mut reads = vec![HashSet::new(); cfg.blocks.len()];
+    let mut writes = vec![HashSet::new(); cfg.blocks.len()];
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for inst in &insts[block.insts.clone()] {
+            for reg in inst.uses() {
+                if !writes[b].contains(&reg) {
+                    reads[b].insert(reg);
+                }
+            }
+            writes[b].extend(inst.def());
+        }
+    }
+    let mut live_in: Vec<HashSet<Reg>> = reads;
+    let mut live_out: Vec<HashSet<Reg>> = vec![HashSet::new(); cfg.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (b, block) in cfg.blocks.iter().enumerate().rev() {
+            let out: HashSet<Reg> = block
+                .succs
+                .iter()
+                .flat_map(|&succ| live_in[succ].iter().copied())
+                .collect();
+            for &reg in &out {
+                if !writes[b].contains(&reg) {
+                    changed |= live_in[b].insert(reg);
+                }
+            }
+            live_out[b] = out;
+        }
+    }
+
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; function.regs];
+    let mut extend = |reg: Reg, point: usize| {
+        let range = ranges[reg].get_or_insert((point, point));
+        *range = (range.0.min(point), range.1.max(point));
+    };
+    for (i, inst) in insts.iter().enumerate() {
+        for reg in inst.uses() {
+            extend(reg, 2 * i);
+        }
+        if let Some(dst) = inst.def() {
+            extend(dst, 2 * i + 1);
+        }
+    }
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let Some(last) = block.insts.end.checked_sub(1) else {
+            continue;
+        };
+        for &reg in &live_in[b] {
+            extend(reg, 2 * block.insts.start);
+        }
+        for &reg in &live_out[b] {
+            extend(reg, 2 * last + 1);
+        }
+    }
+    ranges
+}
+
+// The operand whose register the result of `inst` is best put in, so that
+// no move is needed if the operand dies there.
+fn hint(inst: &Inst) -> Option<Reg> {
+    match inst {
+        Inst::Copy {
+            src: Operand::Reg(src),
+            ..
+        }
+        | Inst::Extend {
+            src: Operand::Reg(src),
+            ..
+        }
+        | Inst::Neg {
+            src: Operand::Reg(src),
+            ..
+        }
+        | Inst::Binary {
+            lhs: Operand::Reg(src),
+            ..
+        } => Some(*src),
+        _ => None,
+    }
+}
+
+// Where each virtual register of `function` lives, as an operand, and how
+// many of the callee-saved TEMP_REGS and frame slots that takes, with
+// `locals` bytes of the frame already in use. Registers are handed out by a
+// linear scan over the live ranges, and one is free again once the value in
+// it is dead. When none is, whichever value lives on longest goes to a slot.
+// The registers in `skip` get no place.
+fn allocate(
+    function: &Function,
+    locals: usize,
+    skip: &HashMap<Reg, usize>,
+) -> (Vec<String>, usize, usize) {
+    let ranges = live_ranges(function);
+    // calls, and inline assembly, may change the caller-saved registers
+    let calls: Vec<usize> = function
+        .insts
+        .iter()
+        .enumerate()
+        .filter(|(_, inst)| matches!(inst, Inst::Call { .. } | Inst::Asm(_)))
+        .map(|(i, _)| 2 * i)
+        .collect();
+    let args: HashSet<Reg> = function
+        .insts
+        .iter()
+        .filter(|inst| matches!(inst, Inst::Call { .. }))
+        .flat_map(Inst::uses)
+        .collect();
+    let mut order: Vec<(usize, usize, Reg)> = ranges
+        .iter()
+        .enumerate()
+        .filter(|(reg, _)| !skip.contains_key(reg))
+        .filter_map(|(reg, range)| range.map(|(start, end)| (start, end, reg)))
+        .collect();
+    order.sort();
+    // Ok for one of TEMP_REGS, Err for a slot
+    let mut places: Vec<Result<usize, usize>> = vec![Err(0); function.regs];
+    let mut free = [true; TEMP_REGS.len()];
+    let mut free_slots: Vec<usize> = Vec::new();
+    let mut slots = 0;
+    let mut active: Vec<(usize, Reg)> = Vec::new(); // by the end of their range
+    for (start, end, reg) in order {
+        active.retain(|&(active_end, active)| {
+            if active_end >= start {
+                return true;
+            }
+            match places[active] {
+                Ok(r) => free[r] = true,
+                Err(s) => free_slots.push(s),
+            }
+            false
+        });
+        let crosses_call = calls.iter().any(|&call| start <= call && call < end);
+        let usable = if crosses_call { CALLEE_SAVED } else { 0 }..TEMP_REGS.len();
+        let usable: Vec<usize> = usable
+            .filter(|r| !(args.contains(&reg) && ARG_TEMPS.contains(r)))
+            .collect();
+        let hinted = (start % 2 == 1)
+            .then(|| hint(&function.insts[start / 2]))
+            .flatten()
+            .and_then(|src| places[src].ok())
+            .filter(|&r| usable.contains(&r) && free[r]);
+        let place = match hinted.or_else(|| usable.iter().copied().find(|&r| free[r])) {
+            Some(r) => Ok(r),
+            None => {
+                let longest = active
+                    .iter()
+                    .filter(|(_, other)| places[*other].is_ok_and(|r| usable.contains(&r)))
+                    .max_by_key(|(other_end, _)| *other_end)
+                    .copied();
+                match longest {
+                    // The slots free now may have been in use earlier in its
+                    // range, so it gets a new one.
+                    Some((longest_end, longest)) if longest_end > end => {
+                        let r = places[longest].expect("in a register");
+                        places[longest] = Err(slots);
+                        slots += 1;
+                        Ok(r)
+                    }
+                    _ => Err(free_slots.pop().unwrap_or_else(|| {
+                        slots += 1;
+                        slots - 1
+                    })),
+                }
+            }
+        };
+        if let Ok(r) = place {
+            free[r] = false;
+        }
+        places[reg] = place;
+        active.push((end, reg));
+    }
+    let saved = places
+        .iter()
+        .enumerate()
+        .filter(|(reg, _)| ranges[*reg].is_some() && !skip.contains_key(reg))
+        .filter_map(|(_, place)| place.ok())
+        .filter(|&r| r >= CALLEE_SAVED)
+        .map(|r| r - CALLEE_SAVED + 1)
+        .max()
+        .unwrap_or(0);
+    // Slots go below the locals and the saved registers.
+    let locs = places
+        .iter()
+        .enumerate()
+        .map(|(reg, place)| match place {
+            _ if ranges[reg].is_none() || skip.contains_key(&reg) => String::new(),
+            Ok(r) => format!("%{}", TEMP_REGS[*r]),
+            Err(s) => format!("-{}(%rbp)", locals + 8 * (saved + s + 1)),
+        })
+        .collect();
+    (locs, saved, slots)
+}
+
+// `function` with the locals whose address never escapes kept in virtual
+// registers instead of the frame: a load of one becomes a copy, and a store
+// an extension of the value to the local's width.
+fn promote_locals(function: &Function) -> Function {
+    let mut regs = function.regs;
+    let locals: HashMap<usize, Reg> = ssa::promotable(function)
+        .into_keys()
+        .map(|offset| {
+            regs += 1;
+            (offset, regs - 1)
+        })
+        .collect();
+    let addrs: HashMap<Reg, Reg> = function
+        .insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::LocalAddr { dst, offset } => Some((*dst, *locals.get(offset)?)),
+            _ => None,
+        })
+        .collect();
+    let local = |addr: &Operand| match addr {
+        Operand::Reg(addr) => addrs.get(addr).copied(),
+        Operand::Imm(_) => None,
+    };
+    let insts = function
+        .insts
+        .iter()
+        .filter_map(|inst| {
+            Some(match inst {
+                Inst::LocalAddr { dst, .. } if addrs.contains_key(dst) => return None,
+                Inst::Load { dst, addr, .. } if local(addr).is_some() => Inst::Copy {
+                    dst: *dst,
+                    src: Operand::Reg(local(addr)?),
+                },
+                Inst::Store { addr, src, ty, .. } if local(addr).is_some() => Inst::Extend {
+                    dst: local(addr)?,
+                    src: *src,
+                    ty: *ty,
+                },
+                _ => inst.clone(),
+            })
+        })
+        .collect();
+    Function {
+        name: function.name.clone(),
+        insts: forward_loads(insts, function.regs),
+        regs,
+        frame_size: function.frame_size,
+    }
+}
+
+// `insts` with the copies a promoted local, a register from `first` on, is
+// loaded by left out where what they load is only used in the same block
+// before the local is stored to again: the uses read the local instead.
+fn forward_loads(mut insts: Vec<Inst>, first: Reg) -> Vec<Inst> {
+    let mut uses: HashMap<Reg, Vec<usize>> = HashMap::new();
+    for (i, inst) in insts.iter().enumerate() {
+        for reg in inst.uses() {
+            uses.entry(reg).or_default().push(i);
+        }
+    }
+    let mut dropped = vec![false; insts.len()];
+    for i in 0..insts.len() {
+        let Inst::Copy {
+            dst,
+            src: Operand::Reg(local),
+        } = insts[i]
+        else {
+            continue;
+        };
+        if local < first {
+            continue;
+        }
+        let at = uses.get(&dst).map(Vec::as_slice).unwrap_or_default();
+        let end = at.last().copied().unwrap_or(i);
+        let forwardable = at.iter().all(|&j| j > i)
+            && insts[i + 1..end].iter().all(|inst| {
+                inst.def() != Some(local)
+                    && !matches!(
+                        inst,
+                        Inst::Label(_) | Inst::Jump(_) | Inst::JumpIfZero { .. } | Inst::Ret(_)
+                    )
+            });
+        if !forwardable {
+            continue;
+        }
+        dropped[i] = true;
+        for &j in at {
+            for operand in insts[j].operands_mut() {
+                if *operand == Operand::Reg(dst) {
+                    *operand = Operand::Reg(local);
+                }
+            }
+        }
+    }
+    insts
+        .into_iter()
+        .zip(dropped)
+        .filter(|(_, dropped)| !dropped)
+        .map(|(inst, _)| inst)
+        .collect()
+}
+
+// The registers holding the address of a local that is only loaded and
+// stored through, by its offset. They are never set: the loads and stores
+// address the frame directly.
+fn frame_addresses(function: &Function) -> HashMap<Reg, usize> {
+    let mut frame: HashMap<Reg, usize> = function
+        .insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::LocalAddr { dst, offset } => Some((*dst, *offset)),
+            _ => None,
+        })
+        .collect();
+    for inst in &function.insts {
+        // the address is the first operand of a memory access
+        let memory = matches!(
+            inst,
+            Inst::Load { .. }
+                | Inst::Store { .. }
+                | Inst::Exchange { .. }
+                | Inst::FetchAdd { .. }
+                | Inst::CompareSwap { .. }
+        );
+        for (i, operand) in inst.operands().into_iter().enumerate() {
+            if let Operand::Reg(reg) = operand {
+                if !(memory && i == 0) {
+                    frame.remove(&reg);
+                }
+            }
+        }
+    }
+    frame
+}
+
+// The registers only ever holding values that fit an int, sign-extended to
+// 64 bits, which an extension from I32 leaves as they are.
+fn int_values(function: &Function) -> HashSet<Reg> {
+    let mut rv: HashSet<Reg> = function.insts.iter().filter_map(Inst::def).collect();
+    loop {
+        let len = rv.len();
+        for inst in &function.insts {
+            let fits = match inst {
+                Inst::Copy {
+                    src: Operand::Imm(val),
+                    ..
+                } => i32::try_from(*val).is_ok(),
+                Inst::Copy {
+                    src: Operand::Reg(src),
+                    ..
+                } => rv.contains(src),
+                Inst::Binary {
+                    op: BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le,
+                    ..
+                } => true,
+                Inst::Binary { ty, .. } | Inst::Neg { ty, .. } => *ty == Ty::I32,
+                Inst::Load { ty, .. }
+                | Inst::Extend { ty, .. }
+                | Inst::Call { ret: ty, .. }
+                | Inst::Exchange { ty, .. }
+                | Inst::FetchAdd { ty, .. }
+                | Inst::CompareSwap { ty, .. } => *ty != Ty::I64,
+                _ => false,
+            };
+            if !fits {
+                if let Some(dst) = inst.def() {
+                    rv.remove(&dst);
+                }
+            }
+        }
+        if rv.len() == len {
+            return rv;
+        }
+    }
+}
+
+// The results of comparisons only used by the conditional jump right after
+// them, which jumps on the flags instead.
+fn branch_conditions(function: &Function) -> HashSet<Reg> {
+    let mut uses = vec![0; function.regs];
+    for reg in function.insts.iter().flat_map(Inst::uses) {
+        uses[reg] += 1;
+    }
+    let mut rv = HashSet::new();
+    let mut compared = None;
+    for inst in &function.insts {
+        match inst {
+            Inst::Loc { .. } | Inst::Comment(_) | Inst::Span(_) => continue,
+            Inst::JumpIfZero {
+                cond: Operand::Reg(cond),
+                ..
+            } if compared == Some(*cond) && uses[*cond] == 1 => {
+                rv.insert(*cond);
+            }
+            _ => {}
+        }
+        compared = match inst {
+            Inst::Binary {
+                op: BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le,
+                dst,
+                ..
+            } => Some(*dst),
+            _ => None,
+        };
+    }
+    rv
+}
+
+impl Emitter {
+    fn new(options: &CompileOptions) -> Emitter {
+        Emitter {
+            os: options.target.os,
+            pic: options.pic,
+            stack_protector: options.stack_protector,
+            debug_info: options.debug_info,
+            canary: 0,
+            ret_label: String::new(),
+            depth: 0,
+            lines: Vec::new(),
+            locs: Vec::new(),
+            frame: HashMap::new(),
+            branches: HashSet::new(),
+            ints: HashSet::new(),
+            flags: None,
+            files: Vec::new(),
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        // Debug info describes the locals in the frame, and inline assembly
+        // may use any of them.
+        let promoted;
+        let function = if self.debug_info
+            || function
+                .insts
+                .iter()
+                .any(|inst| matches!(inst, Inst::Asm(_)))
+        {
+            function
+        } else {
+            promoted = promote_locals(function);
+            &promoted
+        };
+        // The canary goes right below the saved %rbp, where an overrun of
+        // any local reaches it before the return address.
+        self.canary = if self.stack_protector { CANARY_SIZE } else { 0 };
+        let locals = self.canary + function.frame_size;
+        self.frame = frame_addresses(function);
+        self.branches = branch_conditions(function);
+        self.ints = int_values(function);
+        let (locs, regs, slots) = allocate(function, locals, &self.frame);
+        self.locs = locs;
+        let saved = &TEMP_REGS[CALLEE_SAVED..CALLEE_SAVED + regs];
+        let name = self.symbol(&function.name);
+        self.ret_label = format!(".L.return.{}", function.name);
+        match self.os {
+            Os::Linux => self.directive(".text".to_string()),
+            Os::MacOs => {
+                self.directive(".section __TEXT,__text,regular,pure_instructions".to_string())
+            }
+        }
+        self.directive(format!(".globl {}", name));
+        // ELF symbols say what they are, for nm, objdump and the linker
+        if self.os == Os::Linux {
+            self.directive(format!(".type {}, @function", name));
+        }
+        self.label(&name);
+        // The CFI directives tell unwinders where the caller's frame is:
+        // first 16 bytes above %rsp, then above %rbp.
+        self.directive(".cfi_startproc".to_string());
+        // prologur
+        self.depth = 8;
+        self.push("%rbp");
+        self.directive(".cfi_def_cfa_offset 16".to_string());
+        self.directive(".cfi_offset %rbp, -16".to_string());
+        op!(self, "mov", "%rsp", "%rbp");
+        self.directive(".cfi_def_cfa_register %rbp".to_string());
+        let frame = locals + ((regs + slots) * 8).next_multiple_of(16);
+        op!(self, "sub", format!("${}", frame), "%rsp");
+        self.depth += frame;
+        for (i, reg) in saved.iter().enumerate() {
+            op!(
+                self,
+                "mov",
+                format!("%{}", reg),
+                format!("-{}(%rbp)", locals + 8 * (i + 1))
+            );
+            let cfa_offset = locals + 8 * (i + 1) + 16;
+            self.directive(format!(".cfi_offset %{}, -{}", reg, cfa_offset));
+        }
+        if self.stack_protector {
+            let guard = self.guard("rax");
+            op!(self, "mov", guard, "%rax");
+            op!(self, "mov", "%rax", "-8(%rbp)");
+        }
+        for inst in &function.insts {
+            self.inst(inst);
+        }
+        self.lines.push(Line::Source(None));
+        let ret_label = self.ret_label.clone();
+        self.label(&ret_label);
+        if self.stack_protector {
+            // %rax holds the return value
+            let guard = self.guard("rdi");
+            op!(self, "mov", "-8(%rbp)", "%rdx");
+            op!(self, "sub", guard, "%rdx");
+            let ok = format!(".L.canary_ok.{}", function.name);
+            op!(self, "je", ok);
+            let fail = self.callee("__stack_chk_fail");
+            self.call(fail);
+            self.label(&ok);
+        }
+        for (i, reg) in saved.iter().enumerate() {
+            op!(
+                self,
+                "mov",
+                format!("-{}(%rbp)", locals + 8 * (i + 1)),
+                format!("%{}", reg)
+            );
+        }
+        op!(self, "mov", "%rbp", "%rsp");
+        op!(self, "pop", "%rbp");
+        self.directive(".cfi_def_cfa %rsp, 8".to_string());
+        op!(self, "ret");
+        if self.debug_info {
+            self.label(&end_label(&function.name));
+        }
+        self.directive(".cfi_endproc".to_string());
+        if self.os == Os::Linux {
+            self.directive(format!(".size {}, .-{}", name, name));
+            // or the linker takes the stack to be executable
+            self.directive(".section .note.GNU-stack,\"\",@progbits".to_string());
+        }
+    }
+
+    fn push(&mut self, operand: &str) {
+        op!(self, "push", operand);
+        self.depth += 8;
+    }
+
+    // Call `callee`. The ABI wants %rsp 16-byte aligned at the call, as it
+    // was before the caller's call; callees such as printf use aligned SSE
+    // stores and crash otherwise.
+    fn call(&mut self, callee: String) {
+        let pad = self.depth % 16;
+        if pad != 0 {
+            op!(self, "sub", format!("${}", pad), "%rsp");
+        }
+        op!(self, "call", callee);
+        if pad != 0 {
+            op!(self, "add", format!("${}", pad), "%rsp");
+        }
+    }
+
+    // The operand holding the stack protector's guard value, using `reg` to
+    // reach it if needed. glibc keeps it in the thread control block; macOS
+    // in a global reached through the GOT.
+    fn guard(&mut self, reg: &str) -> String {
+        match self.os {
+            Os::Linux => "%fs:40".to_string(),
+            Os::MacOs => {
+                op!(
+                    self,
+                    "mov",
+                    "___stack_chk_guard@GOTPCREL(%rip)",
+                    format!("%{}", reg)
+                );
+                format!("(%{})", reg)
+            }
+        }
+    }
+
+    // The call operand for the function `name`. The PLT resolves it
+    // wherever it was loaded; Mach-O has no PLT, its linker adds stubs as
+    // needed.
+    fn callee(&self, name: &str) -> String {
+        match self.os {
+            Os::Linux if self.pic => format!("{}@PLT", name),
+            _ => self.symbol(name),
+        }
+    }
+
+    // The assembler name of the C function `name`.
+    fn symbol(&self, name: &str) -> String {
+        match self.os {
+            Os::Linux => name.to_string(),
+            Os::MacOs => format!("_{}", name),
+        }
+    }
+
+    fn label(&mut self, label: &str) {
+        self.lines.push(Line::Label(label.to_string()));
+    }
+
+    fn directive(&mut self, text: String) {
+        self.lines.push(Line::Directive(text));
+    }
+
+    fn operand(&self, operand: Operand) -> String {
+        match operand {
+            Operand::Reg(reg) => self.locs[reg].clone(),
+            Operand::Imm(val) => format!("${}", val),
+        }
+    }
+
+    fn load(&mut self, operand: Operand, reg: &str) {
+        let src = self.operand(operand);
+        op!(self, "mov", src, format!("%{}", reg));
+    }
+
+    // Where to compute the value of `dst`: the register it lives in, or
+    // %rax if it lives in a slot.
+    fn acc(&self, dst: Reg) -> String {
+        match &self.locs[dst] {
+            loc if loc.starts_with('%') => loc.clone(),
+            _ => "%rax".to_string(),
+        }
+    }
+
+    // Move the value of `dst`, computed in `acc`, to where it lives.
+    fn save(&mut self, acc: &str, dst: Reg) {
+        if acc != self.locs[dst] {
+            op!(self, "mov", acc, self.locs[dst]);
+        }
+    }
+
+    // Move `src` to `dst`, through %rax if a mov can't do it directly.
+    fn copy(&mut self, src: Operand, dst: Reg) {
+        let (from, to) = (self.operand(src), self.locs[dst].clone());
+        match src {
+            _ if from == to => {}
+            _ if from.starts_with('%') || to.starts_with('%') => op!(self, "mov", from, to),
+            Operand::Imm(val) if i32::try_from(val).is_ok() => op!(self, "movq", from, to),
+            _ => {
+                op!(self, "mov", from, "%rax");
+                op!(self, "mov", "%rax", to);
+            }
+        }
+    }
+
+    // The memory operand at the address `addr`, which is loaded to %`reg`
+    // unless it is in a register already or is a local's, see
+    // frame_addresses.
+    fn address(&mut self, addr: Operand, reg: &str) -> String {
+        if let Operand::Reg(addr) = addr {
+            if let Some(offset) = self.frame.get(&addr) {
+                return format!("-{}(%rbp)", self.canary + offset);
+            }
+            if self.locs[addr].starts_with('%') {
+                return format!("({})", self.locs[addr]);
+            }
+        }
+        self.load(addr, reg);
+        format!("(%{})", reg)
+    }
+
+    // %rax and %rdx narrowed to `ty`.
+    fn reg_ax(ty: Ty) -> &'static str {
+        match ty {
+            Ty::I8 | Ty::U8 => "%al",
+            Ty::I32 => "%eax",
+            Ty::I64 => "%rax",
+        }
+    }
+
+    fn reg_dx(ty: Ty) -> &'static str {
+        match ty {
+            Ty::I8 | Ty::U8 => "%dl",
+            Ty::I32 => "%edx",
+            Ty::I64 => "%rdx",
+        }
+    }
+
+    // Sign- or zero-extend the low bits of `reg` to the whole of it.
+    fn extend(&mut self, ty: Ty, reg: &str) {
+        match ty {
+            Ty::I8 => op!(self, "movsbq", low8(reg), reg),
+            Ty::U8 => op!(self, "movzbq", low8(reg), reg),
+            Ty::I32 => op!(self, "movslq", low32(reg), reg),
+            Ty::I64 => {}
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => self.copy(*src, *dst),
+            Inst::LocalAddr { dst, offset } => {
+                if self.frame.contains_key(dst) {
+                    return;
+                }
+                let acc = self.acc(*dst);
+                op!(self, "lea", format!("-{}(%rbp)", self.canary + offset), acc);
+                self.save(&acc, *dst);
+            }
+            Inst::Load { dst, addr, ty, .. } => {
+                let addr = self.address(*addr, "rax");
+                let acc = self.acc(*dst);
+                let op = match ty {
+                    Ty::I8 => "movsbq",
+                    Ty::U8 => "movzbq",
+                    Ty::I32 => "movslq",
+                    Ty::I64 => "mov",
+                };
+                op!(self, op, addr, acc);
+                self.save(&acc, *dst);
+            }
+            Inst::Store {
+                addr,
+                src,
+                ty,
+                atomic,
+                ..
+            } => {
+                let addr = self.address(*addr, "rdi");
+                // xchg with memory is implicitly locked, making this a
+                // sequentially consistent store; it swaps the old value into
+                // the register, so that is %rax.
+                let src = match *src {
+                    Operand::Imm(val) if !atomic && i32::try_from(val).is_ok() => match ty {
+                        Ty::I8 | Ty::U8 => format!("${}", val as u8),
+                        Ty::I32 => format!("${}", val as i32),
+                        Ty::I64 => format!("${}", val),
+                    },
+                    Operand::Reg(reg) if !atomic && self.locs[reg].starts_with('%') => {
+                        narrow(&self.locs[reg], *ty)
+                    }
+                    _ => {
+                        self.load(*src, "rax");
+                        Self::reg_ax(*ty).to_string()
+                    }
+                };
+                let op = match ty {
+                    _ if *atomic => "xchg",
+                    Ty::I8 | Ty::U8 => "movb",
+                    Ty::I32 => "movl",
+                    Ty::I64 if src.starts_with('$') => "movq",
+                    Ty::I64 => "mov",
+                };
+                op!(self, op, src, addr);
+            }
+            Inst::Extend { dst, src, ty } => match *src {
+                Operand::Imm(val) => {
+                    let val = match ty {
+                        Ty::I8 => val as i8 as i64,
+                        Ty::U8 => val as u8 as i64,
+                        Ty::I32 => val as i32 as i64,
+                        Ty::I64 => val,
+                    };
+                    self.copy(Operand::Imm(val), *dst);
+                }
+                Operand::Reg(reg)
+                    if *ty == Ty::I64 || *ty == Ty::I32 && self.ints.contains(&reg) =>
+                {
+                    self.copy(*src, *dst)
+                }
+                Operand::Reg(reg) => {
+                    let acc = self.acc(*dst);
+                    let op = match ty {
+                        Ty::I8 => "movsbq",
+                        Ty::U8 => "movzbq",
+                        _ => "movslq",
+                    };
+                    op!(self, op, narrow(&self.locs[reg], *ty), acc);
+                    self.save(&acc, *dst);
+                }
+            },
+            Inst::Neg { dst, src, ty } => {
+                let acc = self.acc(*dst);
+                let src = self.operand(*src);
+                if src != acc {
+                    op!(self, "mov", src, acc);
+                }
+                if *ty == Ty::I32 {
+                    op!(self, "negl", low32(&acc));
+                    self.extend(Ty::I32, &acc);
+                } else {
+                    op!(self, "neg", acc);
+                }
+                self.save(&acc, *dst);
+            }
+            Inst::Binary {
+                op,
+                ty,
+                dst,
+                lhs,
+                rhs,
+            } => {
+                let (mut lhs, mut rhs) = (*lhs, *rhs);
+                let commutes = matches!(op, BinOp::Add | BinOp::Mul | BinOp::Eq | BinOp::Ne);
+                if commutes && self.operand(rhs) == self.locs[*dst] {
+                    (lhs, rhs) = (rhs, lhs);
+                }
+                if self.branches.contains(dst) {
+                    let lhs = match self.operand(lhs) {
+                        lhs if lhs.starts_with('%') => lhs,
+                        _ => {
+                            self.load(lhs, "rax");
+                            "%rax".to_string()
+                        }
+                    };
+                    let rhs = self.rhs(rhs);
+                    self.compare(*ty, &rhs, &lhs);
+                    self.flags = Some(*op);
+                    return;
+                }
+                // Division only works in %rax, and the result's register
+                // can't take the left operand while it holds the right one.
+                let acc = match self.acc(*dst) {
+                    acc if *op == BinOp::Div || self.operand(rhs) == acc => "%rax".to_string(),
+                    acc => acc,
+                };
+                let lhs = self.operand(lhs);
+                if lhs != acc {
+                    op!(self, "mov", lhs, acc);
+                }
+                self.binary(*op, *ty, rhs, &acc);
+                self.save(&acc, *dst);
+            }
+            Inst::Select {
+                dst,
+                cond,
+                then,
+                els,
+            } => {
+                self.load(*then, "rax");
+                self.load(*els, "rdi");
+                self.load(*cond, "rsi");
+                op!(self, "cmp", "$0", "%rsi");
+                op!(self, "cmove", "%rdi", "%rax");
+                self.save("%rax", *dst);
+            }
+            Inst::Call {
+                dst,
+                name,
+                args,
+                ret,
+            } => {
+                for (arg, reg) in args.iter().zip(ARG_REGS) {
+                    self.load(*arg, reg);
+                }
+                op!(self, "mov", "$0", "%rax");
+                self.call(self.callee(name));
+                self.extend(*ret, "%rax");
+                self.save("%rax", *dst);
+            }
+            Inst::Exchange { dst, addr, src, ty } | Inst::FetchAdd { dst, addr, src, ty } => {
+                let addr = self.address(*addr, "rdi");
+                self.load(*src, "rax");
+                let op = if let Inst::Exchange { .. } = inst {
+                    "xchg"
+                } else {
+                    "lock xadd"
+                };
+                op!(self, op, Self::reg_ax(*ty), addr);
+                self.extend(*ty, "%rax");
+                self.save("%rax", *dst);
+            }
+            Inst::CompareSwap {
+                dst,
+                addr,
+                old,
+                new,
+                ty,
+            } => {
+                let addr = self.address(*addr, "rdi");
+                self.load(*old, "rax");
+                self.load(*new, "rdx");
+                op!(self, "lock cmpxchg", Self::reg_dx(*ty), addr);
+                self.extend(*ty, "%rax");
+                self.save("%rax", *dst);
+            }
+            Inst::Phi { .. } => unreachable!("SSA form is not emitted"),
+            Inst::Label(label) => self.label(label),
+            Inst::Jump(label) => op!(self, "jmp", label),
+            Inst::JumpIfZero { cond, target } => {
+                // jump if the comparison the flags are from is false
+                if let Some(op) = self.flags.take() {
+                    let jump = match op {
+                        BinOp::Eq => "jne",
+                        BinOp::Ne => "je",
+                        BinOp::Lt => "jge",
+                        _ => "jg",
+                    };
+                    op!(self, jump, target);
+                    return;
+                }
+                match *cond {
+                    Operand::Imm(0) => op!(self, "jmp", target),
+                    Operand::Imm(_) => {}
+                    Operand::Reg(reg) => {
+                        let cond = self.locs[reg].clone();
+                        // a slot has no size of its own
+                        let cmp = if cond.starts_with('%') { "cmp" } else { "cmpq" };
+                        op!(self, cmp, "$0", cond);
+                        op!(self, "je", target);
+                    }
+                }
+            }
+            Inst::Ret(val) => {
+                if let Some(val) = val {
+                    self.load(*val, "rax");
+                }
+                op!(self, "jmp", self.ret_label);
+            }
+            Inst::Asm(text) => self.lines.push(Line::Asm(text.clone())),
+            Inst::Loc { file, line, col } => {
+                let number = match self.files.iter().position(|f| f == file) {
+                    Some(i) => i + 1,
+                    None => {
+                        self.directive(format!(".file {} {:?}", self.files.len() + 1, file));
+                        self.files.push(file.clone());
+                        self.files.len()
+                    }
+                };
+                self.directive(format!(".loc {} {} {}", number, line, col));
+            }
+            Inst::Comment(text) => self.lines.push(Line::Comment(text.clone())),
+            Inst::Span(span) => self.lines.push(Line::Source(Some(span.clone()))),
+        }
+    }
+
+    // The right operand of an operation. Immediates beyond 32 bits only fit
+    // a mov, so those go to %rdi.
+    fn rhs(&mut self, rhs: Operand) -> String {
+        match rhs {
+            Operand::Imm(val) if i32::try_from(val).is_err() => {
+                self.load(rhs, "rdi");
+                "%rdi".to_string()
+            }
+            _ => self.operand(rhs),
+        }
+    }
+
+    // Set the flags by comparing the register `acc` with `rhs` in the width
+    // `ty`.
+    fn compare(&mut self, ty: Ty, rhs: &str, acc: &str) {
+        if ty == Ty::I32 {
+            op!(self, "cmpl", low32(rhs), low32(acc));
+        } else {
+            op!(self, "cmp", rhs, acc);
+        }
+    }
+
+    // Apply `op` to the register `acc` and `rhs` in the width `ty`; division
+    // needs `acc` to be %rax. An I32 result is sign-extended to the whole of
+    // `acc`.
+    fn binary(&mut self, op: BinOp, ty: Ty, rhs: Operand, acc: &str) {
+        let rhs = self.rhs(rhs);
+        // 32-bit forms take an l suffix and the low halves of registers
+        let (long, narrow_acc, narrow) = match ty {
+            Ty::I32 => ("l", low32(acc), low32(&rhs)),
+            _ => ("", acc.to_string(), rhs.clone()),
+        };
+        match op {
+            BinOp::Add => op!(self, format!("add{}", long), narrow, narrow_acc),
+            BinOp::Sub => op!(self, format!("sub{}", long), narrow, narrow_acc),
+            BinOp::Mul => op!(self, format!("imul{}", long), narrow, narrow_acc),
+            BinOp::Div => {
+                // idiv takes no immediate
+                if rhs != "%rdi" {
+                    op!(self, "mov", rhs, "%rdi");
+                }
+                if ty == Ty::I32 {
+                    op!(self, "cltd");
+                    op!(self, "idivl", "%edi");
+                } else {
+                    op!(self, "cqo");
+                    op!(self, "idiv", "%rdi");
+                }
+            }
+            BinOp::Shl | BinOp::Shr | BinOp::Sar => {
+                let op = match op {
+                    BinOp::Shl => "shl",
+                    BinOp::Shr => "shr",
+                    _ => "sar",
+                };
+                // a shift count is an immediate or %cl
+                if rhs.starts_with('$') {
+                    op!(self, format!("{}{}", op, long), rhs, narrow_acc);
+                } else {
+                    op!(self, "mov", rhs, "%rcx");
+                    op!(self, format!("{}{}", op, long), "%cl", narrow_acc);
+                }
+            }
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le => {
+                let set = match op {
+                    BinOp::Eq => "sete",
+                    BinOp::Ne => "setne",
+                    BinOp::Lt => "setl",
+                    _ => "setle",
+                };
+                self.compare(ty, &rhs, acc);
+                op!(self, set, low8(acc));
+                op!(self, "movzb", low8(acc), acc);
+                return;
+            }
+        }
+        if ty == Ty::I32 {
+            self.extend(Ty::I32, acc);
+        }
+    }
+}
+
+// The low 8 bits of `operand`, a register or frame slot.
+fn low8(operand: &str) -> String {
+    match operand.strip_prefix("%r") {
+        Some(num) if num.starts_with(|c: char| c.is_ascii_digit()) => format!("%r{}b", num),
+        Some(name @ ("si" | "di")) => format!("%{}l", name),
+        Some(name) => format!("%{}l", &name[..1]),
+        None => operand.to_string(),
+    }
+}
+
+// `operand`, a register or frame slot, narrowed to `ty`.
+fn narrow(operand: &str, ty: Ty) -> String {
+    match ty {
+        Ty::I8 | Ty::U8 => low8(operand),
+        Ty::I32 => low32(operand),
+        Ty::I64 => operand.to_string(),
+    }
+}
+
+// The low 32 bits of `operand`, a register, frame slot or immediate. Memory
+// is little-endian, so a slot's low half is at the same address.
+fn low32(operand: &str) -> String {
+    match operand.strip_prefix("%r") {
+        Some(num) if num.starts_with(|c: char| c.is_ascii_digit()) => format!("%r{}d", num),
+        Some(name) => format!("%e{}", name),
+        None => operand.to_string(),
+    }
+}
+
+// Whether `arg` names a whole 64-bit register, so a mov of it to itself
+// changes nothing. A 32-bit one would still clear the upper half.
+fn is_reg64(arg: &str) -> bool {
+    match arg.strip_prefix('%') {
+        Some(name) => {
+            ARG_REGS.contains(&name)
+                || TEMP_REGS.contains(&name)
+                || ["rax", "rbp", "rsp", "r10", "r11"].contains(&name)
+        }
+        None => false,
+    }
+}
+
+// Clean up the emitted assembly: a push popped right back is a mov, a mov
+// to itself is dropped, %rax saved somewhere and loaded right back is
+// already in %rax, and a jump to the label that follows it is dropped. Then
+// jumps are threaded and unused labels dropped, see thread_jumps.
+pub fn peephole(lines: Vec<Line>) -> Vec<Line> {
+    let mut rv: Vec<Line> = Vec::with_capacity(lines.len());
+    for line in lines {
+        let Line::Inst { op, args } = &line else {
+            if let (Line::Label(label), Some((i, Line::Inst { op, args }))) =
+                (&line, last_line(&rv))
+            {
+                if op == "jmp" && args[0] == *label {
+                    rv.remove(i);
+                }
+            }
+            rv.push(line);
+            continue;
+        };
+        let prev = match last_line(&rv) {
+            Some((i, Line::Inst { op, args })) => Some((i, op.as_str(), args.as_slice())),
+            _ => None,
+        };
+        match (prev, op.as_str()) {
+            (Some((i, "push", [src])), "pop") => {
+                let (src, dst) = (src.clone(), args[0].clone());
+                rv.remove(i);
+                // a mov takes at most one memory operand
+                if src != dst && (src.starts_with('%') || dst.starts_with('%')) {
+                    rv.push(Line::Inst {
+                        op: "mov".to_string(),
+                        args: vec![src, dst],
+                    });
+                } else if src != dst {
+                    rv.push(Line::Inst {
+                        op: "push".to_string(),
+                        args: vec![src],
+                    });
+                    rv.push(line);
+                }
+            }
+            (_, "mov") if args[0] == args[1] && is_reg64(&args[0]) => {}
+            (Some((_, "mov", [src, dst])), "mov")
+                if src == "%rax" && args[0] == *dst && args[1] == "%rax" => {}
+            _ => rv.push(line),
+        }
+    }
+    thread_jumps(&mut rv);
+    rv
+}
+
+// Retarget each jump to a label that is followed by an unconditional jump
+// straight to where that one goes, through any chain of them. The .L labels
+// nothing refers to any more are dropped, and so are the jumps that then land
+// on the label right after them or can't be reached after another jump,
+// until none is left.
+fn thread_jumps(lines: &mut Vec<Line>) {
+    let threads = jump_threads(lines);
+    for line in lines.iter_mut() {
+        if let Line::Inst { op, args } = line {
+            if op.starts_with('j') && args.len() == 1 {
+                if let Some(target) = threads.get(&args[0]) {
+                    args[0] = target.clone();
+                }
+            }
+        }
+    }
+    loop {
+        let len = lines.len();
+        let referenced: HashSet<String> = lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Inst { args, .. } => Some(args.iter().cloned()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        // inline assembly may jump to a label too, and debug info refers
+        // to some
+        let asm: Vec<String> = lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Asm(text) | Line::Directive(text) => Some(text.clone()),
+                _ => None,
+            })
+            .collect();
+        let used = |label: &String| {
+            !label.starts_with(".L")
+                || referenced.contains(label)
+                || asm.iter().any(|text| text.contains(label.as_str()))
+        };
+        let mut kept: Vec<Line> = Vec::with_capacity(len);
+        for line in lines.drain(..) {
+            let after_jmp =
+                matches!(last_line(&kept), Some((_, Line::Inst { op, .. })) if op == "jmp");
+            let Line::Label(label) = &line else {
+                if !(after_jmp && matches!(&line, Line::Inst { op, .. } if op == "jmp")) {
+                    kept.push(line);
+                }
+                continue;
+            };
+            if !used(label) {
+                continue;
+            }
+            if let Some((i, Line::Inst { op, args })) = last_line(&kept) {
+                if op == "jmp" && args[0] == *label {
+                    kept.remove(i);
+                }
+            }
+            kept.push(line);
+        }
+        *lines = kept;
+        if lines.len() == len {
+            break;
+        }
+    }
+}
+
+// The last line of `lines` and its index, looking through source markers.
+fn last_line(lines: &[Line]) -> Option<(usize, &Line)> {
+    lines
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, line)| !matches!(line, Line::Source(_)))
+}
+
+// Where a jump to each label that is followed by an unconditional jump ends
+// up. A chain that loops back on itself stops where it would repeat.
+fn jump_threads(lines: &[Line]) -> HashMap<String, String> {
+    let mut next = HashMap::new();
+    for (i, line) in lines.iter().enumerate() {
+        let Line::Label(label) = line else {
+            continue;
+        };
+        let following = lines[i + 1..].iter().find(|line| {
+            !matches!(
+                line,
+                Line::Label(_) | Line::Directive(_) | Line::Comment(_) | Line::Source(_)
+            )
+        });
+        if let Some(Line::Inst { op, args }) = following {
+            if op == "jmp" {
+                next.insert(label.clone(), args[0].clone());
+            }
+        }
+    }
+    next.keys()
+        .map(|label| {
+            let mut seen = HashSet::from([label]);
+            let mut target = &next[label];
+            while let Some(further) = next.get(target) {
+                if !seen.insert(target) {
+                    break;
+                }
+                target = further;
+            }
+            (label.clone(), target.clone())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Target;
+
+    #[test]
+    fn test_allocate() {
+        // ten values live at once: nine registers and a slot
+        let mut insts: Vec<Inst> = (0..10)
+            .map(|dst| Inst::Copy {
+                dst,
+                src: Operand::Imm(dst as i64),
+            })
+            .collect();
+        insts.extend((0..10).map(|reg| Inst::Ret(Some(Operand::Reg(reg)))));
+        insts.push(Inst::Copy {
+            dst: 10,
+            src: Operand::Imm(10),
+        });
+        let function = Function {
+            name: "main".to_string(),
+            insts,
+            regs: 11,
+            frame_size: 16,
+        };
+        let (locs, regs, slots) = allocate(&function, 16, &HashMap::new());
+        assert_eq!((regs, slots), (5, 1));
+        assert_eq!(locs[0], "%r10");
+        assert_eq!(locs[8], "%r15");
+        assert_eq!(locs[9], "-64(%rbp)");
+        // everything is dead again by the last copy
+        assert_eq!(locs[10], "%r10");
+        let asm = print(&emit(&function, &CompileOptions::default()));
+        assert!(
+            asm.contains("  sub $64, %rsp\n  mov %rbx, -24(%rbp)\n  .cfi_offset %rbx, -40\n"),
+            "{}",
+            asm
+        );
+        assert!(
+            asm.contains("  mov -56(%rbp), %r15\n  mov %rbp, %rsp\n"),
+            "{}",
+            asm
+        );
+    }
+
+    #[test]
+    fn test_promote_locals() {
+        let emit_src = |src: &str| {
+            let (parser, program) = crate::parse(src);
+            let options = CompileOptions::default();
+            let function = crate::CodeGenerator::new(parser, options.clone()).lower(program.nodes);
+            print(&emit(&function, &options))
+        };
+        // i and j stay in registers around the loop
+        let asm = emit_src("{ int i=0; int j=0; for (i=0; i<=10; i=i+1) j=i+j; return j; }");
+        let body = &asm[asm.find(".L.begin").unwrap()..asm.find(".L.return.main:").unwrap()];
+        assert!(!body.contains("(%rbp)"), "{}", asm);
+        assert!(!body.contains("push"), "{}", asm);
+        // x lives across the second call, so not in a caller-saved register
+        let asm = emit_src("int ret3(); { int x=ret3(); int y=ret3(); return x+y; }");
+        assert!(
+            asm.contains("  mov %r10, %rbx\n  mov $0, %rax\n  call ret3\n"),
+            "{}",
+            asm
+        );
+        assert!(asm.contains("  mov %rbx, -24(%rbp)\n"), "{}", asm);
+        // a local whose address is taken stays in the frame
+        let asm = emit_src("{ int x=3; int *p=&x; *p=5; return x; }");
+        assert!(asm.contains("(%rbp)\n"), "{}", asm);
+    }
+
+    #[test]
+    fn test_int_width() {
+        let insts = vec![
+            Inst::Copy {
+                dst: 0,
+                src: Operand::Imm(1),
+            },
+            Inst::Binary {
+                op: BinOp::Add,
+                ty: Ty::I32,
+                dst: 1,
+                lhs: Operand::Imm(2),
+                rhs: Operand::Reg(0),
+            },
+            Inst::Ret(Some(Operand::Reg(1))),
+        ];
+        let function = Function {
+            name: "main".to_string(),
+            insts,
+            regs: 2,
+            frame_size: 0,
+        };
+        let asm = print(&emit(&function, &CompileOptions::default()));
+        assert!(
+            asm.contains("  mov $1, %r10\n  addl $2, %r10d\n  movslq %r10d, %r10\n"),
+            "{}",
+            asm
+        );
+        assert_eq!(low32("%r12"), "%r12d");
+        assert_eq!(low32("-8(%rbp)"), "-8(%rbp)");
+    }
+
+    #[test]
+    fn test_call_symbols() {
+        let function = Function {
+            name: "main".to_string(),
+            insts: vec![
+                Inst::Call {
+                    dst: 0,
+                    name: "ret3".to_string(),
+                    args: Vec::new(),
+                    ret: Ty::I32,
+                },
+                Inst::Ret(Some(Operand::Reg(0))),
+            ],
+            regs: 1,
+            frame_size: 0,
+        };
+        let options = CompileOptions {
+            target: Target::x86_64_macos(),
+            ..CompileOptions::default()
+        };
+        let asm = print(&emit(&function, &options));
+        assert!(
+            asm.starts_with(
+                "  .section __TEXT,__text,regular,pure_instructions\n  .globl _main\n_main:\n"
+            ),
+            "{}",
+            asm
+        );
+        assert!(asm.contains("  call _ret3\n"), "{}", asm);
+        assert!(!asm.contains(".note.GNU-stack"), "{}", asm);
+        let options = CompileOptions {
+            target: Target::x86_64(),
+            pic: true,
+            ..CompileOptions::default()
+        };
+        let asm = print(&emit(&function, &options));
+        assert!(asm.contains("  call ret3@PLT\n"), "{}", asm);
+    }
+
+    #[test]
+    fn test_stack_protector() {
+        let function = Function {
+            name: "main".to_string(),
+            insts: vec![
+                Inst::LocalAddr { dst: 0, offset: 8 },
+                Inst::Ret(Some(Operand::Reg(0))),
+            ],
+            regs: 1,
+            frame_size: 16,
+        };
+        let options = CompileOptions {
+            target: Target::x86_64(),
+            stack_protector: true,
+            ..CompileOptions::default()
+        };
+        let asm = print(&emit(&function, &options));
+        // the canary takes the 16 bytes above the locals
+        assert!(asm.contains("  sub $32, %rsp\n"), "{}", asm);
+        assert!(
+            asm.contains("  mov %fs:40, %rax\n  mov %rax, -8(%rbp)\n  lea -24(%rbp), %r10\n"),
+            "{}",
+            asm
+        );
+        assert!(
+            asm.contains("  sub %fs:40, %rdx\n  je .L.canary_ok.main\n  call __stack_chk_fail\n"),
+            "{}",
+            asm
+        );
+    }
+
+    #[test]
+    fn test_call_alignment() {
+        let mut emitter = Emitter::new(&CompileOptions::default());
+        emitter.depth = 16;
+        emitter.call("f".to_string());
+        assert_eq!(print(&emitter.lines), "  call f\n");
+        emitter.lines.clear();
+        emitter.push("%rax");
+        emitter.call("f".to_string());
+        assert_eq!(
+            print(&emitter.lines),
+            "  push %rax\n  sub $8, %rsp\n  call f\n  add $8, %rsp\n"
+        );
+    }
+
+    #[test]
+    fn test_cfi() {
+        let function = Function {
+            name: "main".to_string(),
+            insts: vec![Inst::Ret(Some(Operand::Imm(0)))],
+            regs: 0,
+            frame_size: 0,
+        };
+        let asm = print(&emit(&function, &CompileOptions::default()));
+        assert!(
+            asm.contains(
+                "main:
+  .cfi_startproc
+  push %rbp
+  .cfi_def_cfa_offset 16
+  .cfi_offset %rbp, -16
+  mov %rsp, %rbp
+  .cfi_def_cfa_register %rbp
+"
+            ),
+            "{}",
+            asm
+        );
+        assert!(
+            asm.ends_with(
+                "  pop %rbp\n  .cfi_def_cfa %rsp, 8\n  ret\n  .cfi_endproc\n  .size main, .-main\n  \
+                 .section .note.GNU-stack,\"\",@progbits\n"
+            ),
+            "{}",
+            asm
+        );
+        assert!(
+            asm.starts_with("  .text\n  .globl main\n  .type main, @function\nmain:\n"),
+            "{}",
+            asm
+        );
+    }
+
+    #[test]
+    fn test_peephole() {
+        let inst = |op: &str, args: &[&str]| Line::Inst {
+            op: op.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        let lines = vec![
+            inst("mov", &["%rax", "%rbx"]),
+            inst("mov", &["%rbx", "%rax"]),
+            inst("mov", &["%rax", "%rax"]),
+            inst("push", &["%rax"]),
+            Line::Source(Some(3..5)),
+            inst("pop", &["%rdi"]),
+            inst("push", &["%rdi"]),
+            inst("pop", &["%rdi"]),
+            inst("jmp", &[".L.end.1"]),
+            Line::Source(None),
+            Line::Label(".L.end.1".to_string()),
+            inst("jmp", &[".L.return"]),
+        ];
+        let lines = peephole(lines);
+        assert_eq!(
+            print(&lines),
+            "  mov %rax, %rbx\n  mov %rax, %rdi\n  jmp .L.return\n"
+        );
+        // source markers don't get in the way
+        assert_eq!(source_map(&lines), [None, Some(3..5), None]);
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        let inst = |op: &str, args: &[&str]| Line::Inst {
+            op: op.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        let label = |name: &str| Line::Label(name.to_string());
+        let lines = vec![
+            label("main"),
+            inst("je", &[".L.else.1"]),
+            inst("jmp", &[".L.end.1"]),
+            label(".L.else.1"),
+            label(".L.end.1"),
+            Line::Directive(".loc 1 2 3".to_string()),
+            inst("jmp", &[".L.end.2"]),
+            label(".L.begin.3"),
+            inst("jmp", &[".L.begin.3"]),
+            label(".L.end.2"),
+            inst("jmp", &[".L.return"]),
+            Line::Asm("jmp .L.asm".to_string()),
+            label(".L.asm"),
+            label(".L.return"),
+            inst("ret", &[]),
+        ];
+        assert_eq!(
+            print(&peephole(lines)),
+            "main:\n  je .L.return\n  jmp .L.return\n  .loc 1 2 3\n  jmp .L.return\n\
+             .L.begin.3:\n  jmp .L.begin.3\n  jmp .L.asm\n.L.asm:\n\
+             .L.return:\n  ret\n"
+        );
+    }
+}
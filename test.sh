@@ -3,8 +3,8 @@ assert() {
 	expected="$1"
 	input="$2"
 
-	./chibicc "$input" >tmp.s || exit
-	gcc -static -o tmp tmp.s
+	./chibicc $FLAGS -S -e "$input" -o tmp.s || exit
+	gcc -static -o tmp tmp.s tmp2.o
 	./tmp
 	actual="$?"
 
@@ -19,6 +19,19 @@ assert() {
 cargo build
 mv target/debug/chibicc_rust chibicc
 
+cat <<EOF | gcc -xc -c -o tmp2.o -
+int ret3() { return 3; }
+int add(int x, int y) { return x+y; }
+int sub(int x, int y) { return x-y; }
+int add6(int a, int b, int c, int d, int e, int f) { return a+b+c+d+e+f; }
+int neg(int x) { return -x; }
+unsigned char byte(int x) { return x; }
+long long shl32(long long x) { return x << 32; }
+int get(int *p) { return *p; }
+int aligned16(char *p) { return ((long)p & 15) == 0; }
+int stack_aligned() { return ((long)__builtin_frame_address(0) & 15) == 0; }
+EOF
+
 
 assert 0 '{ return 0; }'
 assert 42 '{ return 42; }'
@@ -28,6 +41,10 @@ assert 47 '{ return 5+6*7; }'
 assert 15 '{ return 5*(9-6); }'
 assert 4 '{ return (3+5)/2; }'
 assert 10 '{ return -10+20; }'
+assert 28 '{ return 7*4; }'
+assert 2 '{ return 9/4; }'
+assert 2 '{ int x=-9; return -(x/4); }'
+assert 9 '{ int x=-9; return -(x/1); }'
 assert 10 '{ return - -10; }'
 assert 10 '{ return - - +10; }'
 
@@ -96,5 +113,281 @@ assert 5 '{ int x=3; return (&x+2)-&x+3; }'
 assert 8 '{ int x, y; x=3; y=5; return x+y; }'
 assert 8 '{ int x=3, y=5; return x+y; }'
 
+assert 4 '{ long long x=4294967296; return x/1073741824; }'
+assert 1 '{ return 8589934593-8589934592; }'
+assert 3 '{ long x=3; long long int *p=&x; return *p; }'
+assert 2 '{ long long x=1099511627776; int y=2; return x*y/x; }'
+
+assert 1 '{ char c=255; return c==-1; }'
+assert 1 '{ signed char c=200; return c<0; }'
+assert 255 '{ unsigned char c=255; return c; }'
+assert 0 '{ unsigned char c=255; return c==-1; }'
+assert 44 '{ unsigned char c; return c=300; }'
+assert 255 '{ int x=0; unsigned char *p=&x; *p=255; return x; }'
+assert 7 '{ int x=0; char *p=&x; *(p+1)=1; *p=7; return x-256; }'
+assert 3 '{ char a; char *p=&a; char *q=p+3; return q-p; }'
+
+assert 3 '{ int x, *p, a[3]; x=3; p=&x; return *p; }'
+assert 5 '{ int a[2]; *a=2; *(a+1)=3; return *a+*(a+1); }'
+assert 7 '{ int x, a[3], y; y=7; *(a+2)=9; return y; }'
+assert 9 '{ int x, a[3], y; *(a+2)=9; return *(a+2); }'
+assert 2 '{ int a[3]; int *p=a+2; return p-a; }'
+assert 6 '{ int a[2][3]; int *p=*(a+1); *(p+2)=6; return *(*(a+1)+2); }'
+assert 12 '{ char a[3], *p, c; *a=5; *(a+1)=7; p=a; return *p+*(p+1); }'
+assert 1 '{ int x=2147483647; return x+1 < 0; }'
+assert 1 '{ int x=65536; return x*x == 0; }'
+assert 1 '{ long x=2147483647; return x+1 > 0; }'
+assert 1 '{ return 2147483647+1 < 0; }'
+assert 7 '{ int x=-7; return x/2 + 10; }'
+assert 21 '{ char a; int b; char c[3]; a=1; b=2; *c=3; *(c+2)=4; return a+b+*c+*(c+2)+11; }'
+assert 1 '{ int x=1, *p=&x, **q=&p; return **q; }'
+
+assert 7 '{ int a[2][3]; int (*p)[3]=a; *(*(p+1)+2)=7; return *(*(a+1)+2); }'
+assert 3 '{ int x=3, *a[2]; *(a+1)=&x; return **(a+1); }'
+assert 1 '{ int a[2][3]; int (*p)[3]=a; return (p+1)-p; }'
+assert 5 '{ int (x)=5; return x; }'
+
+assert 3 '{ volatile int x=3; int * restrict p=&x; return *p; }'
+assert 4 '{ int volatile x=1; volatile char c=3; char * volatile p=&c; return x+*p; }'
+assert 2 '{ volatile int a[2]; *(a+1)=2; return *(a+1); }'
+./chibicc -e '{ restrict int x; return 0; }' 2>/dev/null && { echo "restrict on int accepted"; exit 1; }
+
+assert 1 'int aligned16(char *); { char a; char b __attribute__((aligned(16))); return aligned16(&b); }'
+assert 1 'int aligned16(char *); { __attribute__((aligned)) char a, b; char c; return aligned16(&a)+aligned16(&b)-1; }'
+assert 3 '{ int __attribute__((unused, deprecated("old"), __packed__)) x=3; return x; }'
+assert 2 'int f(int) __attribute__((noreturn)); { return 2; }'
+./chibicc -e '{ int x __attribute__((aligned(3))); return 0; }' 2>/dev/null && { echo "bad alignment accepted"; exit 1; }
+
+assert 5 '{ _Atomic int x; x=5; return x; }'
+assert 7 '{ int _Atomic x=3; int y=x+4; return y; }'
+assert 1 '{ _Atomic char c; c=257; return c; }'
+assert 3 '{ int x=3; int *p=&x; return __atomic_exchange_n(p, 9, 5); }'
+assert 9 '{ int x=3; __atomic_exchange_n(&x, 9, 5); return x; }'
+assert 23 '{ int x=10; int old=__atomic_fetch_add(&x, 3, 5); return old+x; }'
+assert 4 '{ char c=4; return __atomic_fetch_add(&c, 1, 0); }'
+assert 1 '{ _Bool b=256; return b; }'
+assert 2 '{ _Bool b=-1; int x; _Bool p=&x; return b+p; }'
+assert 1 'unsigned char byte(int); { _Bool b=byte(256+2); return b; }'
+assert 0 '_Static_assert(1+1==2, "math"); { return 0; }'
+assert 12 '{ int x=2; __sync_val_compare_and_swap(&x, 2, 12); return x; }'
+assert 2 '{ int x=2; __sync_val_compare_and_swap(&x, 5, 12); return x; }'
+assert 2 '{ int x=2; return __sync_val_compare_and_swap(&x, 2, 12); }'
+
+assert 3 'int ret3(); { return ret3(); }'
+assert 1 'int stack_aligned(); { char a; int b; return stack_aligned(); }'
+assert 8 '{ return 8L; }'
+assert 1 '{ long x=2147483648L; return x/2147483648; }'
+assert 0 '{ return 4294967296UL-4294967296; }'
+assert 7 '{ int x=3u+4ll; return x; }'
+./chibicc -e '{ return 1lL; }' 2>/dev/null && { echo "invalid suffix accepted"; exit 1; }
+./chibicc -e '{ return 9223372036854775808; }' 2>/dev/null && { echo "overflowing literal accepted"; exit 1; }
+./chibicc -e '{ return 18446744073709551615U < 1; }' 2>&1 | grep -q 'too large for its type' || { echo "overflowing unsigned literal accepted"; exit 1; }
+./chibicc -S -e '{ return 1.5; }' 2>&1 | grep -q 'floating-point literals are not supported' || { echo "float literal not lexed"; exit 1; }
+assert 8 'int add(int, int); { return add(3, 5); }'
+assert 2 '{ int sub(int x, int y); return sub(5, 3); }'
+assert 21 'int add6(int, int, int, int, int, int); { return add6(1,2,3,4,5,6); }'
+assert 10 'int add(int, int); int sub(int, int); { return add(sub(7, 2), add(2, 3)); }'
+assert 1 'int neg(int); { return neg(-1); }'
+assert 1 'int neg(int); int add(int, int); { int x=-5; return add(neg(x), -4); }'
+assert 200 'unsigned char byte(int); { return byte(456); }'
+assert 4 'long long shl32(long long); { return shl32(4)/4294967296; }'
+assert 11 'int add(int, int); { int x=20; int y=1; return (((((((x-y)-y)-y)-y)-y)-y)-add(y, y))-y; }'
+assert 27 'int add6(int, int, int, int, int, int); { int y=1; return y+(y+(y+(y+(y+(y+add6(y,y,y,y,y,add6(1,2,3,4,5,y))))))); }'
+assert 9 'int get(int *); { int x=9; return get(&x); }'
+assert 6 'int get(int p[]); { int a[2]; *(a+1)=6; return get(a+1); }'
+assert 5 'int add(int, int); int add(int a, int b); { int x=2; return add(x, 3); }'
+./chibicc -e '{ return ret3(); }' 2>/dev/null && { echo "call without prototype accepted"; exit 1; }
+./chibicc -e 'int add(int, int); { return add(1); }' 2>/dev/null && { echo "argument count not checked"; exit 1; }
+./chibicc -e 'int get(int *); { int x; return get(x); }' 2>/dev/null && { echo "argument type not checked"; exit 1; }
+./chibicc -e 'int add(int, int); long add(int, int); { return 0; }' 2>&1 | grep -q "conflicting types" || { echo "conflicting prototype not reported"; exit 1; }
+
+assert 1 '{ return __builtin_types_compatible_p(int, int); }'
+assert 0 '{ return __builtin_types_compatible_p(int, int *); }'
+assert 1 '{ return __builtin_types_compatible_p(char *[2], char *[2]); }'
+assert 0 '{ return __builtin_types_compatible_p(char, signed char); }'
+assert 5 '{ int x=3; if (__builtin_expect(x==3, 1)) return 5; return 0; }'
+assert 1 '{ return __builtin_constant_p(42); }'
+assert 0 '{ int x=1; return __builtin_constant_p(x); }'
+./chibicc -e '{ return __builtin_nope(1); }' 2>&1 | grep -q "unknown builtin" || { echo "unknown builtin accepted"; exit 1; }
+
+assert 7 '{ asm("mov $7, %rax\n  jmp .L.return.main"); return 0; }'
+assert 3 '{ int x=3; __asm__("nop"); return x; }'
+./chibicc -S -e '{ asm("  # marker"); return 0; }' | grep -q '# marker' || { echo "asm text not emitted"; exit 1; }
+
+mkdir -p tmp-include
+echo 'int x=3;' > tmp-include/tmp1.h
+echo '#include "tmp1.h"' > tmp-include/tmp2.h
+assert 3 $'{\n#include "tmp-include/tmp1.h"\nreturn x; }'
+assert 9 $'#include <stddef.h>\n#include <stdbool.h>\n#include <stdarg.h>\n{ size_t n=8; bool b=true; int *p=NULL; return n+b+(p==NULL)-1; }'
+./chibicc -I tmp-include -S -e $'{\n#include "tmp2.h"\nreturn x; }' > tmp.s || exit 1
+echo '#include "tmp3.h"' > tmp-include/tmp3.h
+./chibicc -I tmp-include -e $'#include "tmp3.h"\n{ return 0; }' > tmp.s 2>&1 && { echo "include cycle not detected"; exit 1; }
+
+assert 10 $'#define N 10\n{ return N; }'
+assert 21 $'#define N 10\n#define M (N+1)\n{ int x=M; return x+N; }'
+assert 3 $'#define x x\n{ int x=3; return x; }'
+assert 7 $'#define ADD(a, b) \\\n  ((a)+(b))\n{ return ADD(3,\\\n 4); }'
+assert 5 $'{ int fi\\\nve=5; re\\\nturn five; }'
+assert 4 $'{\\\n\n\nreturn __LINE__; }'
+assert 7 $'#define ADD(a, b) ((a)+(b))\n{ return ADD(3, 4); }'
+assert 9 $'#define SQ(x) ((x)*(x))\n#define ONE() 1\n{ return SQ(ONE()+2); }'
+assert 6 $'#define SUM(f, ...) f(__VA_ARGS__)\nint add(int, int); { return SUM(add, 2, 4); }'
+assert 3 $'#define CALL(f, ...) f(__VA_ARGS__)\nint ret3(); { return CALL(ret3); }'
+assert 21 $'#define A6(...) add6(1, 2 __VA_OPT__(,) __VA_ARGS__)\nint add6(int, int, int, int, int, int); { return A6(3, 4, 5, 6); }'
+./chibicc -e $'#define ADD(a, b) a+b\n{ return ADD(1); }' 2>/dev/null && { echo "wrong macro arity accepted"; exit 1; }
+assert 2 $'#define N 1\n#undef N\n#define N 2\n{ return N; }'
+assert 7 $'#define N 1\n#undef N\n#ifndef N\n{ return 7; }\n#endif'
+assert 3 $'{\n\nreturn __LINE__; }'
+assert 4 $'{\n#define L __LINE__\n\nreturn L; }'
+assert 1 $'#if defined(__FILE__) && __LINE__ == 1\n{ return 1; }\n#endif'
+assert 5 $'#define N 5\n#if N > 3\n{ return N; }\n#else\n{ return 0; }\n#endif'
+assert 2 $'#ifdef M\n{ return 1; }\n#elif defined(N) || 1\n{ return 2; }\n#endif'
+
+FLAGS=-O1
+assert 7 '{ return (1+2)*3 - 8/4; }'
+assert 3 '{ int x=3; if (0) x=1; while (0) x=2; return x; x=4; }'
+assert 5 '{ int a[2]; *(a+1)=5; return *(a+2-1); }'
+assert 12 '{ int i; int n=4; int s=0; for (i=0; i<n; i=i+1) s=s+3; return s; }'
+assert 6 '{ int x=1; int y; if (x) y=2; else y=3; int z=y*3; return z; }'
+./chibicc -O1 -S -e '{ return (1+2)*3; }' | grep -q 'mov \$9, %rax' || { echo "constants not folded"; exit 1; }
+assert 3 'int ret3(); { int x=ret3(); return -(-(x*1+0)) + (x-x) - x*0; }'
+./chibicc -O1 -S -e 'int ret3(); { int x=ret3(); return x*0 + (x-x); }' | grep -q 'imul\|subl' && { echo "identities not simplified"; exit 1; }
+./chibicc -O1 -S -e '{ int x=3; int y=x*2; return y+x; }' | grep -q 'mov \$9, %rax' || { echo "constants not propagated"; exit 1; }
+./chibicc -O1 -S -e '{ return 1; }' | grep -q 'jmp .L.return' && { echo "jump to next instruction left"; exit 1; }
+./chibicc -O0 -S -e '{ return 1+2; }' | grep -q 'addl \$2, %' || { echo "-O0 optimized"; exit 1; }
+./chibicc -O2 -fpasses= -S -e '{ return 1+2; }' | grep -q 'addl \$2, %' || { echo "-fpasses= ran passes"; exit 1; }
+./chibicc -fpasses=fold -S -e '{ return 1+2; }' | grep -q 'mov \$3, %rax' || { echo "-fpasses=fold not run"; exit 1; }
+./chibicc -fpasses=fold,licm -e '{ return 0; }' 2>/dev/null; [ $? -eq 2 ] || { echo "unknown pass accepted"; exit 1; }
+
+FLAGS=-O2
+assert 4 '{ int x=0; int y=3; if (y<2) x=y; else x=y+1; return x; }'
+assert 3 '{ int x=0; int y=3; if (y>2) { x=y; } else { x=y+1; } return x; }'
+assert 7 '{ int x=7; int y=3; if (y<2) x=y; return x; }'
+assert 2 '{ int x=7; int y=3; if (y) x=y-1; return x; }'
+assert 1 '{ int x=0; int *p=0; if (x) x=*p; else x=1; return x; }'
+./chibicc -O2 -S -e 'int f(); { int x; int y=f(); if (y<2) x=y; else x=y+1; return x; }' | grep -q cmove || { echo "if-conversion not applied"; exit 1; }
+./chibicc -O2 -S -e 'int f(); { volatile int v=1; int x; int y=f(); if (y<2) x=v; else x=y; return x; }' | grep -q cmove && { echo "volatile read speculated"; exit 1; }
+./chibicc -O2 -fpasses=fold,dce,cse -S -e 'int f(); { int x; int y=f(); if (y<2) x=y; else x=y+1; return x; }' | grep -q cmove && { echo "-fpasses= ran ifcvt"; exit 1; }
+./chibicc -fpasses=ifcvt -S -e 'int f(); { int x; int y=f(); if (y<2) x=y; else x=y+1; return x; }' | grep -q cmove || { echo "-fpasses=ifcvt not run"; exit 1; }
+./chibicc -O1 -fpasses=fold -S -e '{ return 1; }' | grep -q 'jmp .L.return' || { echo "-fpasses= ran peephole"; exit 1; }
+FLAGS=
+
+./chibicc --hepl -e '{ return 0; }' 2>/dev/null
+[ "$?" = 2 ] || { echo "unknown flag should exit with 2"; exit 1; }
+./chibicc -e '{ return x; }' 2>/dev/null
+[ "$?" = 1 ] || { echo "compile error should exit with 1"; exit 1; }
+[ "$(./chibicc -ferror-limit=2 -e '{ return a; return b; return c; }' 2>&1 | grep -c 'error:')" = 2 ] || { echo "error limit not applied"; exit 1; }
+./chibicc --max-errors=1 -e '{ return a; return b; }' 2>&1 | grep -q 'too many errors emitted, stopping now' || { echo "--max-errors not applied"; exit 1; }
+./chibicc -e '{ int x; int x; return 0; }' 2>&1 | grep -q 'note: previous declaration was here' || { echo "missing redefinition note"; exit 1; }
+echo '{ int x=1; if (x) x=2; return x; }' > tmp-old.c
+echo '{ int x=1; if (x) x=3; return x; }' > tmp-new.c
+./chibicc diff tmp-old.c tmp-old.c | grep -q . && { echo "diff of identical files not empty"; exit 1; }
+./chibicc diff tmp-old.c tmp-new.c | grep -q '^+  mov \$3, %' || { echo "diff missing change"; exit 1; }
+
+printf '{\n  return y;\n}\n' > tmp-prog.c
+./chibicc tmp-prog.c 2>&1 | grep -q 'tmp-prog.c:2:10: error' || { echo "file name missing from diagnostics"; exit 1; }
+echo '{ return 5; }' > tmp-prog.c
+./chibicc tmp-prog.c -o tmp && ./tmp
+[ "$?" = 5 ] || { echo "compiling a file failed"; exit 1; }
+./chibicc tmp-missing.c 2>/dev/null && { echo "missing input file accepted"; exit 1; }
+./chibicc -S -e '{ return 0; }' -o tmp-out.s | grep -q . && { echo "-o output also went to stdout"; exit 1; }
+grep -q 'main:' tmp-out.s || { echo "-o file not written"; exit 1; }
+(cd tmp-include && ../chibicc -e '{ return 6; }' && ./a.out; [ "$?" = 6 ] && rm a.out) || { echo "a.out not built"; exit 1; }
+(cd tmp-include && ../chibicc -S ../tmp-prog.c && grep -q 'main:' tmp-prog.s && rm tmp-prog.s) || { echo "-S output not named after the input"; exit 1; }
+./chibicc -S tmp-prog.c -o - | grep -q 'main:' || { echo "-o - did not write to stdout"; exit 1; }
+echo '{ return 9; }' | ./chibicc - -o tmp && ./tmp
+[ "$?" = 9 ] || { echo "stdin input failed"; exit 1; }
+echo '{ return y; }' | ./chibicc -S - 2>&1 | grep -q '<stdin>:1:10: error' || { echo "stdin not named in diagnostics"; exit 1; }
+(cd tmp-include && ../chibicc -c ../tmp-prog.c ../tmp-old.c && [ -f tmp-prog.o ] && [ -f tmp-old.o ] && rm tmp-prog.o tmp-old.o) || { echo "-c with several inputs failed"; exit 1; }
+(cd tmp-include && ../chibicc -S ../tmp-prog.c ../tmp-new.c && [ -f tmp-prog.s ] && [ -f tmp-new.s ] && rm tmp-prog.s tmp-new.s) || { echo "-S with several inputs failed"; exit 1; }
+[ "$(./chibicc -S -e '{ return a; }' -e '{ return b; }' 2>&1 | grep -c 'error:')" = 2 ] || { echo "errors of every input not reported"; exit 1; }
+(cd tmp-include && ../chibicc -c ../tmp-prog.c && gcc -o tmp-prog tmp-prog.o && ./tmp-prog; [ "$?" = 5 ] && rm tmp-prog tmp-prog.o) || { echo "-c object not usable"; exit 1; }
+./chibicc -S -e '{ int *p=0; return *p; }' 2>&1 >/dev/null | grep -q "<command line>:1:20: warning: dereference of null pointer 'p' \[-Wnull-dereference\]" || { echo "null dereference not warned"; exit 1; }
+./chibicc -S -Wno-null-dereference -e '{ int *p=0; return *p; }' 2>&1 >/dev/null | grep -q warning && { echo "-Wno-null-dereference ignored"; exit 1; }
+./chibicc -S -e '{ int x; 1; return 0; }' 2>&1 >/dev/null | grep -q warning && { echo "warning outside -Wall"; exit 1; }
+[ "$(./chibicc -S -Wall -e '{ int x; 1; return 0; }' 2>&1 >/dev/null | grep -c -e '-Wunused-variable' -e '-Wunused-value')" = 2 ] || { echo "-Wall warnings missing"; exit 1; }
+./chibicc -S -Werror -e '{ int *p=0; return *p; }' -o tmp.s 2>&1 | grep -q "error: dereference of null pointer 'p' \[-Werror=null-dereference\]" || { echo "-Werror not applied"; exit 1; }
+./chibicc -S -Werror -e '{ int *p=0; return *p; }' -o tmp.s 2>/dev/null && { echo "-Werror compile succeeded"; exit 1; }
+./chibicc -S -Werror -Wno-error=null-dereference -e '{ int *p=0; return *p; }' -o tmp.s 2>/dev/null || { echo "-Wno-error= ignored"; exit 1; }
+./chibicc -S -Werror=unused-variable -e '{ int x; return 0; }' -o tmp.s 2>/dev/null && { echo "-Werror=<name> did not enable the warning"; exit 1; }
+./chibicc -S --color=always -e '{ return y; }' 2>&1 | grep -q $'\e\\[1;31merror:' || { echo "--color=always not colored"; exit 1; }
+./chibicc -S -e '{ return y; }' 2>&1 | grep -q $'\e' && { echo "colored output to a pipe"; exit 1; }
+[ "$(./chibicc -S -e '{ return y; }' 2>&1 | sed -n 2,3p)" = "$(printf '%s\n' '{ return y; }' '         ^')" ] || { echo "caret misplaced"; exit 1; }
+./chibicc -v -c tmp-prog.c -o tmp-prog.o 2>&1 >/dev/null | grep -q '^chibicc_rust: cc -c .*\.s -o tmp-prog.o$' || { echo "-v did not show the cc command"; exit 1; }
+[ "$(./chibicc -v -c tmp-prog.c -o tmp-prog.o 2>&1 | grep -c -e '^chibicc_rust: parse tmp-prog.c: [0-9.]* ms$' -e '^chibicc_rust: temporary file ')" = 2 ] || { echo "-v phases missing"; exit 1; }
+rm -f tmp-prog.o
+mkdir -p tmp-save && cp tmp-prog.c tmp-save/
+(cd tmp-include && ../chibicc --save-temps ../tmp-save/tmp-prog.c -o ../tmp-save/prog && ../tmp-save/prog; [ "$?" = 5 ]) && [ "$(ls tmp-save | tr '\n' ' ')" = "prog tmp-prog.c tmp-prog.i tmp-prog.o tmp-prog.s " ] || { echo "--save-temps files wrong"; ls tmp-save; exit 1; }
+rm -r tmp-save
+(cd tmp-include && ../chibicc -static ../tmp-prog.c -o tmp-prog && readelf -d tmp-prog | grep -q 'no dynamic section' && ./tmp-prog; [ "$?" = 5 ] && rm tmp-prog) || { echo "-static executable not static"; exit 1; }
+./chibicc -fpic -c -e 'int ret3(); { return ret3(); }' -o tmp-pic.o && gcc -shared -o tmp-pic.so tmp-pic.o 2>/dev/null && ./chibicc -fPIC -S -e 'int ret3(); { return ret3(); }' -o - | grep -q 'call ret3@PLT' && rm tmp-pic.o tmp-pic.so || { echo "-fpic failed"; exit 1; }
+./chibicc -fstack-protector -e '{ char a[8]; *(a+8)=1; *(a+16)=1; return 0; }' -o tmp 2>/dev/null && { ./tmp 2>/dev/null; [ "$?" = 134 ]; } || { echo "stack protector did not catch the overrun"; exit 1; }
+./chibicc -c -e '{ int x=1; return x; }' -o tmp-cfi.o && readelf --debug-dump=frames tmp-cfi.o | grep -q 'DW_CFA_def_cfa_register: r6 (rbp)' && rm tmp-cfi.o || { echo "no CFI for main"; exit 1; }
+./chibicc -c -e '{ return 0; }' -o tmp-sym.o && readelf -s tmp-sym.o | grep -Eq '[1-9][0-9]* FUNC +GLOBAL .* main$' && rm tmp-sym.o || { echo "main has no ELF type and size"; exit 1; }
+./chibicc -c -e '{ return 0; }' -o tmp-note.o && readelf -S tmp-note.o | grep -q '\.note\.GNU-stack' && rm tmp-note.o || { echo "no .note.GNU-stack, the stack is executable"; exit 1; }
+FLAGS=-fstack-protector assert 3 'int ret3(); { char a[8]; *a=ret3(); return *a; }'
+./chibicc -fintegrated-as -O1 -e 'int add(int, int); { int i=0; int s=0; for (;i<5;i=i+1) s=add(s, i); return s; }' tmp2.o -o tmp && { ./tmp; [ "$?" = 10 ]; } || { echo "-fintegrated-as executable wrong"; exit 1; }
+./chibicc -v -fintegrated-as -c -e 'int ret3(); { return ret3(); }' -o tmp-ias.o 2>&1 | grep -q '^chibicc_rust: cc' && { echo "-fintegrated-as ran cc"; exit 1; }
+readelf -r tmp-ias.o | grep -q 'R_X86_64_PLT32 .* ret3 - 4' && gcc -static -o tmp tmp-ias.o tmp2.o && { ./tmp; [ "$?" = 3 ]; } && rm tmp-ias.o || { echo "-fintegrated-as object wrong"; exit 1; }
+./chibicc --jit -e '{ int i=0; int j=0; while(i<=10) {j=i+j; i=i+1;} return j; }'
+[ "$?" = 55 ] || { echo "--jit exit value wrong"; exit 1; }
+./chibicc -O2 -fstack-protector --jit -e 'int abs(int); { char a[8]; *a=abs(-4); return *a; }'
+[ "$?" = 4 ] || { echo "--jit call into libc failed"; exit 1; }
+./chibicc -S tmp-prog.c -Itmp-include -o tmp-jit.s && ./chibicc --jit tmp-jit.s
+[ "$?" = 5 ] && rm tmp-jit.s || { echo "--jit of an assembly file failed"; exit 1; }
+./chibicc --jit -e 'int ret3(); { return ret3(); }' 2>&1 | grep -q 'jit: undefined symbol ret3' || { echo "--jit ran an undefined symbol"; exit 1; }
+./chibicc --backend=cranelift -e '{ return 0; }' 2>&1 | grep -q 'needs a build with the cranelift feature' || { echo "--backend=cranelift accepted without the feature"; exit 1; }
+./chibicc -fintegrated-as -c -e '{ asm("vzeroupper"); return 0; }' -o tmp-ias.o 2>&1 | grep -q 'integrated assembler: vzeroupper: unsupported instruction' || { echo "-fintegrated-as accepted unknown assembly"; exit 1; }
+printf 'int add(int, int);\nint seven();\n{ return add(seven(), 3); }\n' > tmp-link.c
+printf '.globl seven\nseven:\n  mov $7, %%eax\n  ret\n.section .note.GNU-stack,"",@progbits\n' > tmp-seven.s
+./chibicc tmp-link.c tmp-seven.s tmp2.o -o tmp-link && ./tmp-link
+[ "$?" = 10 ] || { echo ".s and .o inputs not linked"; exit 1; }
+./chibicc -c tmp-seven.s tmp2.o 2>&1 | grep -q '^warning: tmp2.o: linker input file unused because linking not done$' && rm tmp-seven.o || { echo "-c with .s and .o inputs wrong"; exit 1; }
+./chibicc -std=c89 -S -e '{ int x; x=1; int y; return x; }' -o tmp.s 2>&1 | grep -q 'declaration after statement requires -std=c99' || { echo "-std=c89 accepted mixed declarations"; exit 1; }
+./chibicc -std=c99 -S -e '{ int x; x=1; int y; _Bool b; return x; }' -o tmp.s || { echo "-std=c99 rejected C99 code"; exit 1; }
+./chibicc -std=c99 -S -e '{ _Atomic int a; return 0; }' -o tmp.s 2>/dev/null && { echo "-std=c99 accepted _Atomic"; exit 1; }
+./chibicc -S -e '_Static_assert(0, "nope"); { return 0; }' -o tmp.s 2>&1 | grep -q 'static assertion failed: "nope"' || { echo "_Static_assert did not fail"; exit 1; }
+./chibicc -Wbogus -e '{ return 0; }' 2>/dev/null
+[ "$?" = 2 ] || { echo "unknown warning accepted"; exit 1; }
+./chibicc --target x86_64-linux -e '{ return 4; }' -o tmp && { ./tmp; [ "$?" = 4 ]; } || { echo "--target x86_64-linux failed"; exit 1; }
+./chibicc --target=riscv64-linux -e '{ return 0; }' 2>/dev/null
+[ "$?" = 2 ] || { echo "unknown target accepted"; exit 1; }
+./chibicc --target x86_64-apple-darwin -S -e 'int ret3(); { return ret3(); }' | grep -q 'call _ret3' || { echo "Mach-O symbols not prefixed"; exit 1; }
+printf '{ int x=1;\n  x=x+1;\n  return x; }\n' > tmp-g.c
+./chibicc -g -c tmp-g.c -o tmp-g.o && readelf --debug-dump=decodedline tmp-g.o | grep -q '^tmp-g.c  *3 ' || { echo "line table missing"; exit 1; }
+./chibicc -S tmp-g.c -o - | grep -q '\.loc' && { echo "line table emitted without -g"; exit 1; }
+readelf --debug-dump=info tmp-g.o | grep -A2 'DW_AT_name *: x$' | grep -q 'DW_OP_fbreg: -20' || { echo "x missing from the debug info"; exit 1; }
+./chibicc -g -fintegrated-as tmp-g.c -o tmp 2>&1 | grep -q -- '-g cannot be combined with -fintegrated-as' || { echo "-g -fintegrated-as accepted"; exit 1; }
+./chibicc --asm-comments -S tmp-g.c -o - | grep -q '^  # tmp-g.c:2: x=x+1; (ExprStmt)$' || { echo "--asm-comments missing the source"; exit 1; }
+./chibicc --asm-comments -O1 -fintegrated-as tmp-g.c -o tmp && { ./tmp; [ "$?" = 2 ]; } || { echo "--asm-comments broke the program"; exit 1; }
+printf '#line 7 "gen.y"\n{ int x=1;\n  return x; }\n' > tmp-line.c
+./chibicc -g -c tmp-line.c -o tmp-line.o && readelf --debug-dump=decodedline tmp-line.o | grep -q '^gen.y  *8 ' || { echo "#line not in the line table"; exit 1; }
+printf 'int f(int);\n\nint g(int);\n' > tmp-decl.h
+printf '#include "tmp-decl.h"\n{ return y; }\n' | ./chibicc -S - 2>&1 | grep -q '^MyError: <stdin>:2:10: error' || { echo "location after #include wrong"; exit 1; }
+printf '{ int x=1;\n  return x }\n' | ./chibicc -S - 2>&1 | grep -q "^MyError: <stdin>:2:12: error: expected ';' before '}'$" || { echo "syntax error location wrong"; exit 1; }
+printf '# 40 "gen.y"\n{ return y; }\n' | ./chibicc -S - 2>&1 | grep -q 'gen.y:40:10: error' || { echo "line marker ignored"; exit 1; }
+[ "$(./chibicc --dump-tokens -e $'#define N 42\nint x = N;')" = "$(printf '%s\n' '2:1 reserved int' '2:5 ident x' '2:7 reserved =' '2:9 num 42' '2:11 reserved ;' '3:1 eof')" ] || { echo "token dump wrong"; exit 1; }
+./chibicc --dump-ast -e '{ return 1+2; }' | grep -q '^      Num 2 <int>$' || { echo "AST dump wrong"; exit 1; }
+./chibicc --dump-ast=json -e '{ return 7; }' | grep -q '{"kind":"Num","val":7,"type":{"kind":"I32"},"span":{"start":9,"end":10}}' || { echo "JSON AST dump wrong"; exit 1; }
+if command -v lli >/dev/null && command -v llc >/dev/null; then
+	(cd tmp-include && ../chibicc --emit=llvm-ir ../tmp-prog.c && lli tmp-prog.ll; [ "$?" = 5 ] && rm tmp-prog.ll) || { echo "LLVM IR not runnable"; exit 1; }
+	./chibicc --emit=llvm-ir -e 'int add(int, int); int neg(int); unsigned char byte(int); { char c=255; int x=3; return add(x, neg(2)) + c + byte(258); }' -o tmp.ll && llc tmp.ll -o tmp.s && gcc -static -o tmp tmp.s tmp2.o && ./tmp
+	[ "$?" = 2 ] || { echo "LLVM IR calls wrong"; exit 1; }
+	./chibicc --emit=llvm-ir -e '{ int x=2147483647; int y=65536; return (x+1 < 0) + (y*y == 0); }' -o tmp.ll && lli tmp.ll
+	[ "$?" = 2 ] || { echo "LLVM IR int overflow wrong"; exit 1; }
+	./chibicc --emit=llvm-ir -c -e '{ return 0; }' 2>/dev/null
+	[ "$?" = 2 ] || { echo "--emit=llvm-ir with -c accepted"; exit 1; }
+fi
+./chibicc --emit=qbe -e '{ asm("nop"); return 0; }' 2>&1 | grep -q '<command line>:1:3: error: inline assembly is not supported by the QBE backend' || { echo "QBE accepted inline assembly"; exit 1; }
+if command -v qbe >/dev/null; then
+	./chibicc --emit=qbe -e 'int add(int, int); unsigned char byte(int); { char c=255; int x=3; return add(x, c) + byte(258); }' -o tmp.ssa && qbe tmp.ssa -o tmp.s && gcc -static -o tmp tmp.s tmp2.o && ./tmp
+	[ "$?" = 4 ] || { echo "QBE IL wrong"; exit 1; }
+	./chibicc --emit=qbe -e '{ int x=2147483647; long y=x; return (x+1 < 0) + (y+1 > 0); }' -o tmp.ssa && qbe tmp.ssa -o tmp.s && gcc -static -o tmp tmp.s && ./tmp
+	[ "$?" = 2 ] || { echo "QBE int arithmetic not 32-bit"; exit 1; }
+fi
+
+printf '%s\n' '{"id": 1, "source": "{ return 3; }"}' '{"id": 2, "source": "{ return x; }"}' | ./chibicc --server > tmp.json || exit 1
+[ "$(grep -c '"asm"' tmp.json)" = 1 ] && [ "$(grep -c '"error"' tmp.json)" = 1 ] || { echo "server responses wrong"; cat tmp.json; exit 1; }
+
 echo OK
 